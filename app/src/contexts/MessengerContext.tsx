@@ -1,5 +1,5 @@
 import React, { createContext, useContext, useState, useEffect, useRef, useMemo } from 'react';
-import { Connection, PublicKey } from '@solana/web3.js';
+import { Connection, PublicKey, TransactionInstruction } from '@solana/web3.js';
 import { io, Socket } from 'socket.io-client';
 import nacl from 'tweetnacl';
 import { Buffer } from 'buffer';
@@ -7,7 +7,10 @@ import bs58 from 'bs58';
 import AsyncStorage from '@react-native-async-storage/async-storage';
 import {
   getUserProfilePDA,
-  getWalletDescriptorPDA,
+  getLegacyWalletDescriptorPDA,
+  getRelationshipPDA,
+  getInviteEscrowPDA,
+  getWalletLinkPDA,
   getGroupPDA,
   getGroupInvitePDA,
   getGroupKeySharePDA,
@@ -30,8 +33,19 @@ import {
   createStoreGroupKeyInstruction,
   createCloseGroupKeyInstruction,
   createCheckIsContactInstruction,
+  createMigrateLegacyContactInstruction,
+  createCloseLegacyDescriptorInstruction,
+  createAssociatedTokenAccountIdempotentInstruction,
   buildTransaction,
-  deserializeWalletDescriptor,
+  deserializeLegacyWalletDescriptor,
+  deserializeUserProfile,
+  deserializeRelationship,
+  fetchRelationships,
+  fetchPendingInvite,
+  fetchBlockedPeers,
+  fetchPairBlockRentPayer,
+  fetchProfileKeyShareRentPayers,
+  relationshipSides,
   deserializeGroup,
   deserializeGroupInvite,
   deserializeGroupKeyShare,
@@ -40,7 +54,7 @@ import {
   type GroupKeyShare,
   type TokenGate,
 } from '../utils/transactions';
-import { deriveEncryptionKeypair, getChatHash } from '../utils/encryption';
+import { deriveEncryptionKeypair, getChatHash, deriveBlockKey, blindPeer, sealPeer } from '../utils/encryption';
 import type { WalletContextType } from './WalletContext';
 import { BACKEND_URL, SOLANA_RPC_URL } from '../config';
 import {
@@ -86,6 +100,7 @@ interface MessengerContextType {
   deleteContact: (contactPubkey: PublicKey) => Promise<string>;
   blockContact: (contactPubkey: PublicKey) => Promise<string>;
   unblockContact: (contactPubkey: PublicKey) => Promise<string>;
+  migrateLegacyContacts: () => Promise<string | null>;
   sendMessage: (conversationId: string, content: string, recipientPubkey: PublicKey, replyToMessageId?: string) => Promise<void>;
   deleteMessage: (conversationId: string, messageId: string, deleteForBoth: boolean) => void;
   joinConversation: (conversationId: string) => void;
@@ -123,6 +138,8 @@ interface MessengerContextType {
 
 const MessengerContext = createContext<MessengerContextType | null>(null);
 
+const LEGACY_MIGRATIONS_PER_TX = 4;
+
 export const useMessenger = () => {
   const context = useContext(MessengerContext);
   if (!context) {
@@ -830,7 +847,10 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
 
     setLoading(true);
     try {
-      const instruction = createCloseProfileInstruction(wallet.publicKey);
+      // Rent goes back to whoever paid for registration
+      const profileInfo = await connection.getAccountInfo(getUserProfilePDA(wallet.publicKey));
+      const rentPayer = profileInfo ? deserializeUserProfile(profileInfo.data).rentPayer : wallet.publicKey;
+      const instruction = createCloseProfileInstruction(wallet.publicKey, rentPayer);
       const transaction = await buildTransaction(connection, wallet.publicKey, [instruction]);
       const signedTransaction = await wallet.signTransaction(transaction);
       const txSignature = await connection.sendTransaction(signedTransaction);
@@ -857,10 +877,26 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
 
     setLoading(true);
     try {
-      // Calculate chat hash for the conversation PDA
-      const chatHash = getChatHash(wallet.publicKey, inviteePubkey);
-      const instruction = createInviteInstruction(wallet.publicKey, inviteePubkey, chatHash);
-      const transaction = await buildTransaction(connection, wallet.publicKey, [instruction]);
+      // A linked wallet is invited as the identity it belongs to
+      // (WalletLink: discriminator + primary + linked)
+      const linkInfo = await connection.getAccountInfo(getWalletLinkPDA(inviteePubkey));
+      const identity = linkInfo ? new PublicKey(linkInfo.data.slice(8, 40)) : inviteePubkey;
+
+      // Calculate chat hash for the relationship and conversation PDAs
+      const chatHash = getChatHash(wallet.publicKey, identity);
+
+      // Lock the invitee's required deposit, unregistered wallets take none
+      const inviteeProfileInfo = await connection.getAccountInfo(getUserProfilePDA(identity));
+      const deposit = inviteeProfileInfo ? deserializeUserProfile(inviteeProfileInfo.data).inviteDeposit : null;
+
+      const instructions: TransactionInstruction[] = [];
+      if (deposit?.mint) {
+        instructions.push(
+          createAssociatedTokenAccountIdempotentInstruction(wallet.publicKey, getInviteEscrowPDA(chatHash), deposit.mint)
+        );
+      }
+      instructions.push(createInviteInstruction(wallet.publicKey, inviteePubkey, chatHash, { identity, deposit }));
+      const transaction = await buildTransaction(connection, wallet.publicKey, instructions);
       const signedTransaction = await wallet.signTransaction(transaction);
       const txSignature = await connection.sendTransaction(signedTransaction);
       await connection.confirmTransaction(txSignature, 'confirmed');
@@ -892,13 +928,32 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
     }
   };
 
+  // Intro note and deposit the pending invite with `peer` holds, if any
+  const fetchPendingInviteWith = async (peer: PublicKey) => {
+    if (!wallet?.publicKey) throw new Error('Wallet not connected');
+
+    const chatHash = getChatHash(wallet.publicKey, peer);
+    const relationshipInfo = await connection.getAccountInfo(getRelationshipPDA(chatHash));
+    if (!relationshipInfo) throw new Error('No relationship with this wallet');
+
+    return fetchPendingInvite(connection, deserializeRelationship(relationshipInfo.data));
+  };
+
   const acceptInvitation = async (inviterPubkey: PublicKey) => {
     if (!wallet?.publicKey || !wallet.signTransaction) throw new Error('Wallet not connected');
 
     setLoading(true);
     try {
-      const instruction = createAcceptInvitationInstruction(wallet.publicKey, inviterPubkey);
-      const transaction = await buildTransaction(connection, wallet.publicKey, [instruction]);
+      const pending = await fetchPendingInviteWith(inviterPubkey);
+      const instructions: TransactionInstruction[] = [];
+      // A token deposit is refunded to the inviter's associated token account
+      if (pending?.escrow?.mint) {
+        instructions.push(
+          createAssociatedTokenAccountIdempotentInstruction(wallet.publicKey, pending.escrow.inviter, pending.escrow.mint)
+        );
+      }
+      instructions.push(createAcceptInvitationInstruction(wallet.publicKey, inviterPubkey, pending));
+      const transaction = await buildTransaction(connection, wallet.publicKey, instructions);
       const signedTransaction = await wallet.signTransaction(transaction);
       const txSignature = await connection.sendTransaction(signedTransaction);
       await connection.confirmTransaction(txSignature, 'confirmed');
@@ -936,8 +991,17 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
 
     setLoading(true);
     try {
-      const instruction = createRejectInvitationInstruction(wallet.publicKey, inviterPubkey);
-      const transaction = await buildTransaction(connection, wallet.publicKey, [instruction]);
+      const pending = await fetchPendingInviteWith(inviterPubkey);
+      const instructions: TransactionInstruction[] = [];
+      // The invitee keeps a rejected invite's token deposit
+      if (pending?.escrow?.mint) {
+        instructions.push(
+          createAssociatedTokenAccountIdempotentInstruction(wallet.publicKey, wallet.publicKey, pending.escrow.mint)
+        );
+      }
+      const shareRentPayers = await fetchProfileKeyShareRentPayers(connection, wallet.publicKey, inviterPubkey);
+      instructions.push(createRejectInvitationInstruction(wallet.publicKey, inviterPubkey, pending, shareRentPayers));
+      const transaction = await buildTransaction(connection, wallet.publicKey, instructions);
       const signedTransaction = await wallet.signTransaction(transaction);
       const txSignature = await connection.sendTransaction(signedTransaction);
       await connection.confirmTransaction(txSignature, 'confirmed');
@@ -961,8 +1025,36 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
 
     setLoading(true);
     try {
-      const instruction = createBlockInstruction(wallet.publicKey, contactPubkey);
-      const transaction = await buildTransaction(connection, wallet.publicKey, [instruction]);
+      if (!encryptionKeys) throw new Error('Encryption keys not available - please reconnect wallet');
+
+      // Keep the deposit of the contact's pending invite, as rejecting would
+      const relationshipInfo = await connection.getAccountInfo(
+        getRelationshipPDA(getChatHash(wallet.publicKey, contactPubkey))
+      );
+      const pending = relationshipInfo
+        ? await fetchPendingInvite(connection, deserializeRelationship(relationshipInfo.data))
+        : null;
+      const deposit = pending?.escrow?.invitee.equals(wallet.publicKey) ? pending : null;
+
+      const instructions: TransactionInstruction[] = [];
+      if (deposit?.escrow?.mint) {
+        instructions.push(
+          createAssociatedTokenAccountIdempotentInstruction(wallet.publicKey, wallet.publicKey, deposit.escrow.mint)
+        );
+      }
+
+      // Only this wallet's block key can link the entry to the contact
+      const blockKey = deriveBlockKey(encryptionKeys.secretKey);
+      const { sealed, nonce } = sealPeer(blockKey, contactPubkey);
+      instructions.push(createBlockInstruction(
+        wallet.publicKey,
+        contactPubkey,
+        blindPeer(blockKey, contactPubkey),
+        sealed,
+        nonce,
+        deposit
+      ));
+      const transaction = await buildTransaction(connection, wallet.publicKey, instructions);
       const signedTransaction = await wallet.signTransaction(transaction);
       const txSignature = await connection.sendTransaction(signedTransaction);
       await connection.confirmTransaction(txSignature, 'confirmed');
@@ -982,7 +1074,16 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
 
     setLoading(true);
     try {
-      const instruction = createUnblockInstruction(wallet.publicKey, contactPubkey);
+      if (!encryptionKeys) throw new Error('Encryption keys not available - please reconnect wallet');
+
+      const blockedPeers = await fetchBlockedPeers(connection, wallet.publicKey, deriveBlockKey(encryptionKeys.secretKey));
+      const blocked = blockedPeers.find(b => b.peer.equals(contactPubkey));
+      if (!blocked) throw new Error('Contact is not blocked');
+
+      const pairBlockRentPayer = await fetchPairBlockRentPayer(connection, wallet.publicKey, contactPubkey);
+      if (!pairBlockRentPayer) throw new Error('Pair block not found');
+
+      const instruction = createUnblockInstruction(wallet.publicKey, blocked, pairBlockRentPayer);
       const transaction = await buildTransaction(connection, wallet.publicKey, [instruction]);
       const signedTransaction = await wallet.signTransaction(transaction);
       const txSignature = await connection.sendTransaction(signedTransaction);
@@ -998,6 +1099,49 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
     }
   };
 
+  // Move the contacts of a version 1 wallet descriptor into Relationships
+  // and close it. Returns null when there is nothing to migrate.
+  const migrateLegacyContacts = async () => {
+    if (!wallet?.publicKey || !wallet.signTransaction) throw new Error('Wallet not connected');
+
+    setLoading(true);
+    try {
+      const legacyInfo = await connection.getAccountInfo(getLegacyWalletDescriptorPDA(wallet.publicKey));
+      if (!legacyInfo) return null;
+
+      const { peers } = deserializeLegacyWalletDescriptor(legacyInfo.data);
+      const peerDescriptors = await connection.getMultipleAccountsInfo(
+        peers.map(peer => getLegacyWalletDescriptorPDA(peer.pubkey))
+      );
+      const instructions = peers.map((peer, i) =>
+        createMigrateLegacyContactInstruction(wallet.publicKey!, peer.pubkey, peerDescriptors[i] !== null)
+      );
+      instructions.push(createCloseLegacyDescriptorInstruction(wallet.publicKey));
+
+      // Keep each transaction under the size limit
+      let txSignature = '';
+      for (let i = 0; i < instructions.length; i += LEGACY_MIGRATIONS_PER_TX) {
+        const transaction = await buildTransaction(
+          connection,
+          wallet.publicKey,
+          instructions.slice(i, i + LEGACY_MIGRATIONS_PER_TX)
+        );
+        const signedTransaction = await wallet.signTransaction(transaction);
+        txSignature = await connection.sendTransaction(signedTransaction);
+        await connection.confirmTransaction(txSignature, 'confirmed');
+      }
+
+      console.log(`✅ Migrated ${peers.length} legacy contacts`);
+      await loadContacts();
+      return txSignature;
+    } catch (error) {
+      console.error('Failed to migrate legacy contacts:', error);
+      throw error;
+    } finally {
+      setLoading(false);
+    }
+  };
+
   const sendMessage = async (conversationId: string, content: string, recipientPubkey: PublicKey, replyToMessageId?: string) => {
     if (!wallet?.publicKey || !socket) throw new Error('Not ready');
     if (!encryptionKeys) throw new Error('Encryption keys not available');
@@ -1220,19 +1364,28 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
     if (!wallet?.publicKey) return;
 
     try {
-      const descriptorPDA = getWalletDescriptorPDA(wallet.publicKey);
-      const accountInfo = await connection.getAccountInfo(descriptorPDA);
-
-      if (!accountInfo) {
-        setContacts([]);
-        return;
-      }
-
-      const descriptor = deserializeWalletDescriptor(accountInfo.data);
-      console.log('Found', descriptor.peers.length, 'peers');
+      const relationships = await fetchRelationships(connection, wallet.publicKey);
+      console.log('Found', relationships.length, 'relationships');
+
+      // Blocks are private to this wallet, only its block key opens them
+      const blockedPeers = encryptionKeys
+        ? await fetchBlockedPeers(connection, wallet.publicKey, deriveBlockKey(encryptionKeys.secretKey))
+        : [];
+      const isBlocked = (peer: PublicKey) => blockedPeers.some(b => b.peer.equals(peer));
+
+      const peers: { pubkey: PublicKey; state: Contact['state'] }[] = [
+        ...relationships.map(relationship => {
+          const { peer, myState } = relationshipSides(relationship, wallet.publicKey!);
+          return { pubkey: peer, state: isBlocked(peer) ? 'Blocked' as const : myState };
+        }),
+        // Blocked wallets that never were contacts
+        ...blockedPeers
+          .filter(b => !relationships.some(r => relationshipSides(r, wallet.publicKey!).peer.equals(b.peer)))
+          .map(b => ({ pubkey: b.peer, state: 'Blocked' as const })),
+      ];
 
       const contactsWithKeys = await Promise.all(
-        descriptor.peers
+        peers
           // Load ALL peers (not just Accepted) so we can check state in AddContactScreen
           .map(async (peer) => {
             const peerProfilePDA = getUserProfilePDA(peer.pubkey);
@@ -1243,22 +1396,12 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
             let encryptionPublicKey: Uint8Array | undefined;
 
             if (peerAccountInfo) {
-              const data = peerAccountInfo.data;
-              let offset = 8 + 32;
-              const displayNameLength = data.readUInt32LE(offset);
-              offset += 4;
-              displayName = data.slice(offset, offset + displayNameLength).toString('utf-8');
-              offset += displayNameLength;
-              // Read avatar_type (1 byte enum) - CRITICAL FIX
-              const avatarType = data.readUInt8(offset);
-              offset += 1;
-              const avatarUrlLength = data.readUInt32LE(offset);
-              offset += 4;
-              avatarUrl = data.slice(offset, offset + avatarUrlLength).toString('utf-8');
-              offset += avatarUrlLength;
-              encryptionPublicKey = data.slice(offset, offset + 32);
-
-              if (peer.status === 'Accepted') {
+              const peerProfile = deserializeUserProfile(peerAccountInfo.data);
+              displayName = peerProfile.displayName;
+              avatarUrl = peerProfile.avatarData;
+              encryptionPublicKey = peerProfile.encryptionPublicKey;
+
+              if (peer.state === 'Accepted') {
                 console.log(
                   `Loaded encryption key for ${peer.pubkey.toBase58().slice(0, 8)}...: ${Buffer.from(encryptionPublicKey).toString('hex').slice(0, 16)}...`
                 );
@@ -1270,7 +1413,7 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
               displayName,
               avatarUrl,
               encryptionPublicKey,
-              state: peer.status, // Map status to state field
+              state: peer.state,
             };
           })
       );
@@ -1301,29 +1444,10 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
         return;
       }
 
-      const data = accountInfo.data;
-      let offset = 8;
-
-      offset += 32;
-
-      const displayNameLength = data.readUInt32LE(offset);
-      offset += 4;
-      const displayNameBytes = data.slice(offset, offset + displayNameLength);
-      const displayName = Buffer.from(displayNameBytes).toString('utf8');
-      offset += displayNameLength;
-
-      // Read avatar_type (1 byte enum) - CRITICAL FIX
-      const avatarType = data.readUInt8(offset);
-      offset += 1;
-
-      const avatarUrlLength = data.readUInt32LE(offset);
-      offset += 4;
-      const avatarUrlBytes = data.slice(offset, offset + avatarUrlLength);
-      const avatarUrl = avatarUrlLength > 0 ? Buffer.from(avatarUrlBytes).toString('utf8') : null;
-      offset += avatarUrlLength;
-
-      const encryptionPublicKeyBytes = data.slice(offset, offset + 32);
-      const encryptionPublicKey = Buffer.from(encryptionPublicKeyBytes).toString('hex');
+      const userProfileData = deserializeUserProfile(accountInfo.data);
+      const displayName = userProfileData.displayName;
+      const avatarUrl = userProfileData.avatarData || null;
+      const encryptionPublicKey = Buffer.from(userProfileData.encryptionPublicKey).toString('hex');
 
       console.log('Profile loaded:', { displayName, avatarUrl, encryptionPublicKey });
 
@@ -1358,23 +1482,23 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
       const mxePubKey = await getMXEPubKey(connection);
       console.log('✅ Got MXE public key:', Buffer.from(mxePubKey).toString('hex').slice(0, 16) + '...');
 
-      // 2. Load contact list from on-chain WalletDescriptor
-      const descriptorPDA = getWalletDescriptorPDA(wallet.publicKey);
-      const accountInfo = await connection.getAccountInfo(descriptorPDA);
+      // 2. Load contact list from on-chain Relationships
+      const relationships = await fetchRelationships(connection, wallet.publicKey);
 
-      if (!accountInfo) {
+      if (relationships.length === 0) {
         console.log('No contacts found');
         return false;
       }
 
-      const descriptor = deserializeWalletDescriptor(accountInfo.data);
-      const contactEntries: ContactEntry[] = descriptor.peers.map(peer => ({
-        pubkey: peer.pubkey.toBytes(),
-        status: peer.status === 'Invited' ? 0 :
-                peer.status === 'Requested' ? 1 :
-                peer.status === 'Accepted' ? 2 :
-                peer.status === 'Rejected' ? 3 : 4, // Blocked
-      }));
+      const contactEntries: ContactEntry[] = relationships.map(relationship => {
+        const { peer, myState } = relationshipSides(relationship, wallet.publicKey!);
+        return {
+          pubkey: peer.toBytes(),
+          status: myState === 'Invited' ? 0 :
+                  myState === 'Requested' ? 1 :
+                  myState === 'Accepted' ? 2 : 3, // Rejected
+        };
+      });
 
       console.log(`📋 Encrypting ${contactEntries.length} contacts for MPC verification...`);
 
@@ -1803,10 +1927,12 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
     setLoading(true);
     try {
       // Build instructions: leave group + close key share (to recover rent)
-      const instructions = [
-        createLeaveGroupInstruction(wallet.publicKey, groupId),
-        createCloseGroupKeyInstruction(wallet.publicKey, groupId),
-      ];
+      const keyShareInfo = await connection.getAccountInfo(getGroupKeySharePDA(groupId, wallet.publicKey));
+      const instructions = [createLeaveGroupInstruction(wallet.publicKey, groupId)];
+      if (keyShareInfo) {
+        const keyShare = deserializeGroupKeyShare(keyShareInfo.data);
+        instructions.push(createCloseGroupKeyInstruction(wallet.publicKey, groupId, keyShare.rentPayer));
+      }
 
       const transaction = await buildTransaction(connection, wallet.publicKey, instructions);
       const signedTransaction = await wallet.signTransaction(transaction);
@@ -1877,7 +2003,9 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
 
     setLoading(true);
     try {
-      const instruction = createCloseGroupInstruction(wallet.publicKey, groupId);
+      const groupInfo = await connection.getAccountInfo(getGroupPDA(groupId));
+      if (!groupInfo) throw new Error('Group not found');
+      const instruction = createCloseGroupInstruction(wallet.publicKey, groupId, deserializeGroup(groupInfo.data).rentPayer);
       const transaction = await buildTransaction(connection, wallet.publicKey, [instruction]);
       const signedTransaction = await wallet.signTransaction(transaction);
       const txSignature = await connection.sendTransaction(signedTransaction);
@@ -2271,6 +2399,7 @@ export const MessengerProvider: React.FC<{ children: React.ReactNode; wallet: Wa
     deleteContact,
     blockContact,
     unblockContact,
+    migrateLegacyContacts,
     sendMessage,
     deleteMessage,
     joinConversation,
@@ -8,8 +8,10 @@ import {
   createAcceptInstruction,
   createRejectInstruction,
   buildTransaction,
-  getWalletDescriptorPDA,
   getUserProfilePDA,
+  deserializeUserProfile,
+  fetchRelationships,
+  relationshipSides,
 } from '../utils/transactions';
 import nacl from 'tweetnacl';
 import { Buffer } from 'buffer';
@@ -467,37 +469,12 @@ export function useMukonMessenger(wallet: Wallet | null, cluster: string = 'devn
 
     setLoading(true);
     try {
-      const walletDescriptor = getWalletDescriptorPDA(wallet.publicKey);
-      const accountInfo = await connection.getAccountInfo(walletDescriptor);
-
-      if (!accountInfo) {
-        console.log('No wallet descriptor found');
-        setContacts([]);
-        return;
-      }
-
-      // Manual Borsh deserialization
-      const data = accountInfo.data;
-      let offset = 8; // Skip discriminator
-
-      // Read owner pubkey (32 bytes)
-      offset += 32;
-
-      // Read peers Vec length (4 bytes, little endian)
-      const peersLength = data.readUInt32LE(offset);
-      offset += 4;
-
-      console.log('Found', peersLength, 'peers');
+      const relationships = await fetchRelationships(connection, wallet.publicKey);
+      console.log('Found', relationships.length, 'relationships');
 
       const peers = [];
-      for (let i = 0; i < peersLength; i++) {
-        // Read peer wallet pubkey (32 bytes)
-        const peerPubkey = new PublicKey(data.slice(offset, offset + 32));
-        offset += 32;
-
-        // Read peer state (1 byte: 0=Invited, 1=Requested, 2=Accepted, 3=Rejected)
-        const state = data[offset];
-        offset += 1;
+      for (const relationship of relationships) {
+        const { peer: peerPubkey, myState } = relationshipSides(relationship, wallet.publicKey);
 
         // Fetch peer's UserProfile to get their encryption public key
         let peerEncryptionKey: Uint8Array | null = null;
@@ -508,26 +485,9 @@ export function useMukonMessenger(wallet: Wallet | null, cluster: string = 'devn
           const peerProfileInfo = await connection.getAccountInfo(peerProfilePDA);
 
           if (peerProfileInfo) {
-            const peerData = peerProfileInfo.data;
-            let peerOffset = 8; // Skip discriminator
-
-            // Skip owner pubkey (32 bytes)
-            peerOffset += 32;
-
-            // Read display_name
-            const displayNameLen = peerData.readUInt32LE(peerOffset);
-            peerOffset += 4;
-            const displayNameBytes = peerData.slice(peerOffset, peerOffset + displayNameLen);
-            peerDisplayName = Buffer.from(displayNameBytes).toString('utf8') || peerDisplayName;
-            peerOffset += displayNameLen;
-
-            // Skip avatar_url
-            const avatarUrlLen = peerData.readUInt32LE(peerOffset);
-            peerOffset += 4;
-            peerOffset += avatarUrlLen;
-
-            // Read encryption_public_key (32 bytes)
-            peerEncryptionKey = peerData.slice(peerOffset, peerOffset + 32);
+            const peerProfile = deserializeUserProfile(peerProfileInfo.data);
+            peerDisplayName = peerProfile.displayName || peerDisplayName;
+            peerEncryptionKey = peerProfile.encryptionPublicKey;
 
             console.log(`Loaded encryption key for ${peerDisplayName}:`, Buffer.from(peerEncryptionKey).toString('hex').slice(0, 16) + '...');
           }
@@ -537,7 +497,7 @@ export function useMukonMessenger(wallet: Wallet | null, cluster: string = 'devn
 
         peers.push({
           publicKey: peerPubkey,
-          state: state === 0 ? 'Invited' : state === 1 ? 'Requested' : state === 2 ? 'Accepted' : 'Rejected',
+          state: myState,
           displayName: peerDisplayName,
           encryptionPublicKey: peerEncryptionKey,
         });
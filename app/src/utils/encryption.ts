@@ -87,6 +87,40 @@ export function getChatHash(a: PublicKey, b: PublicKey): Uint8Array {
   return new Uint8Array(hash);
 }
 
+/**
+ * Derives the key that hides who a wallet blocked. Only the blocker can
+ * recompute a BlockEntry's address or open its sealed peer.
+ */
+export function deriveBlockKey(secretKey: Uint8Array): Uint8Array {
+  const input = Buffer.concat([Buffer.from('mukon-messenger:block'), Buffer.from(secretKey)]);
+  return new Uint8Array(sha256.array(input));
+}
+
+/**
+ * Salted hash of a blocked peer, used in the BlockEntry PDA seeds
+ */
+export function blindPeer(blockKey: Uint8Array, peer: PublicKey): Uint8Array {
+  const input = Buffer.concat([Buffer.from(blockKey), peer.toBuffer()]);
+  return new Uint8Array(sha256.array(input));
+}
+
+/**
+ * Encrypts a blocked peer for its BlockEntry (32 byte key + 16 byte tag = 48 bytes)
+ */
+export function sealPeer(blockKey: Uint8Array, peer: PublicKey): { sealed: Uint8Array; nonce: Uint8Array } {
+  const nonce = nacl.randomBytes(nacl.secretbox.nonceLength);
+  const sealed = nacl.secretbox(peer.toBytes(), nonce, blockKey);
+  return { sealed, nonce };
+}
+
+/**
+ * Opens a BlockEntry's sealed peer, null if it wasn't sealed with this key
+ */
+export function openSealedPeer(blockKey: Uint8Array, sealed: Uint8Array, nonce: Uint8Array): PublicKey | null {
+  const opened = nacl.secretbox.open(sealed, nonce, blockKey);
+  return opened ? new PublicKey(opened) : null;
+}
+
 /**
  * Truncates a wallet address for display (e.g., "7xKp...3mNq")
  */
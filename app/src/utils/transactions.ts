@@ -7,8 +7,11 @@ import {
   VersionedTransaction,
 } from '@solana/web3.js';
 import { Buffer } from 'buffer';
+import { getChatHash, openSealedPeer } from './encryption';
 
 const PROGRAM_ID = new PublicKey('GCTzU7Y6yaBNzW6WA1EJR6fnY9vLNZEEPcgsydCD8mpj');
+const TOKEN_PROGRAM_ID = new PublicKey('TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA');
+const ASSOCIATED_TOKEN_PROGRAM_ID = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA1knL');
 
 // Instruction discriminators from IDL
 // NOTE: Group discriminators need to be computed after program deployment
@@ -19,12 +22,14 @@ const DISCRIMINATORS = {
   block: Buffer.from([0xee, 0xea, 0x6e, 0x15, 0x79, 0x2b, 0x32, 0x91]), // eeea6e15792b3291
   close_group: Buffer.from([0x28, 0xbb, 0xc9, 0xbb, 0x12, 0xc2, 0x7a, 0xe8]), // 28bbc9bb12c27ae8
   close_group_key: Buffer.from([0x5d, 0x2b, 0xd4, 0x16, 0x33, 0x97, 0x3e, 0x03]), // 5d2bd41633973e03
+  close_legacy_descriptor: Buffer.from([0xb6, 0xfc, 0x61, 0x05, 0xe9, 0x2c, 0x79, 0x28]), // b6fc6105e92c7928
   close_profile: Buffer.from([0xa7, 0x24, 0xb5, 0x08, 0x88, 0x9e, 0x2e, 0xcf]), // a724b508889e2ecf
   create_group: Buffer.from([0x4f, 0x3c, 0x9e, 0x86, 0x3d, 0xc7, 0x38, 0xf8]), // 4f3c9e863dc738f8
   invite: Buffer.from([0xf2, 0x18, 0xeb, 0xe1, 0x85, 0xd3, 0xbd, 0xfa]), // f218ebe185d3bdfa
   invite_to_group: Buffer.from([0xf2, 0x88, 0x70, 0x57, 0x31, 0xcf, 0xc1, 0x54]), // f288705731cfc154
   kick_member: Buffer.from([0x4e, 0x41, 0xd7, 0xf4, 0x67, 0xca, 0xe4, 0x1b]), // 4e41d7f467cae41b
   leave_group: Buffer.from([0x0a, 0x04, 0x7d, 0x1c, 0x2e, 0x17, 0xe9, 0x1d]), // 0a047d1c2e17e91d
+  migrate_legacy_contact: Buffer.from([0x8b, 0x3e, 0xb2, 0xa8, 0xd6, 0xf7, 0xea, 0x61]), // 8b3eb2a8d6f7ea61
   register: Buffer.from([0xd3, 0x7c, 0x43, 0x0f, 0xd3, 0xc2, 0xb2, 0xf0]), // d37c430fd3c2b2f0
   reject: Buffer.from([0x87, 0x07, 0x3f, 0x55, 0x83, 0x72, 0x6f, 0xe0]), // 87073f5583726fe0
   reject_group_invite: Buffer.from([0xa2, 0xe1, 0x8b, 0x8e, 0x35, 0xb6, 0xd9, 0xe7]), // a2e18b8e35b6d9e7
@@ -42,6 +47,15 @@ const DISCRIMINATORS = {
 
 // PDA derivation helpers
 export function getWalletDescriptorPDA(owner: PublicKey): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('wallet_descriptor'), owner.toBuffer(), Buffer.from([2])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+// Version 1 descriptor holding the old peers list, see migrateLegacyContacts
+export function getLegacyWalletDescriptorPDA(owner: PublicKey): PublicKey {
   const [pda] = PublicKey.findProgramAddressSync(
     [Buffer.from('wallet_descriptor'), owner.toBuffer(), Buffer.from([1])],
     PROGRAM_ID
@@ -65,6 +79,96 @@ export function getConversationPDA(chatHash: Uint8Array): PublicKey {
   return pda;
 }
 
+export function getRelationshipPDA(chatHash: Uint8Array): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('relationship'), chatHash, Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getTombstonePDA(chatHash: Uint8Array): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('tombstone'), chatHash, Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getPairBlockPDA(chatHash: Uint8Array): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('pair_block'), chatHash, Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getInviteNotePDA(chatHash: Uint8Array): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('invite_note'), chatHash, Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getInviteEscrowPDA(chatHash: Uint8Array): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('invite_escrow'), chatHash, Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getEncryptionKeyRecordPDA(owner: PublicKey, epoch: number): PublicKey {
+  const epochBuffer = Buffer.alloc(4);
+  epochBuffer.writeUInt32LE(epoch, 0);
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('encryption_key'), owner.toBuffer(), epochBuffer, Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getWalletLinkPDA(wallet: PublicKey): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('wallet_link'), wallet.toBuffer(), Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getHandlePDA(name: string): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('handle'), Buffer.from(name, 'utf8'), Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getBlockEntryPDA(wallet: PublicKey, blindedPeer: Uint8Array): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('block'), wallet.toBuffer(), Buffer.from(blindedPeer), Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getProfileKeySharePDA(owner: PublicKey, contact: PublicKey): PublicKey {
+  const [pda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('profile_key'), owner.toBuffer(), contact.toBuffer(), Buffer.from([1])],
+    PROGRAM_ID
+  );
+  return pda;
+}
+
+export function getAssociatedTokenAddress(owner: PublicKey, mint: PublicKey): PublicKey {
+  const [address] = PublicKey.findProgramAddressSync(
+    [owner.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), mint.toBuffer()],
+    ASSOCIATED_TOKEN_PROGRAM_ID
+  );
+  return address;
+}
+
 export function getGroupPDA(groupId: Uint8Array): PublicKey {
   const [pda] = PublicKey.findProgramAddressSync(
     [Buffer.from('group'), Buffer.from(groupId), Buffer.from([1])],
@@ -97,6 +201,52 @@ function serializeString(str: string): Buffer {
   return Buffer.concat([length, encoded]);
 }
 
+function serializeBytes(bytes: Uint8Array): Buffer {
+  const length = Buffer.alloc(4);
+  length.writeUInt32LE(bytes.length, 0);
+  return Buffer.concat([length, Buffer.from(bytes)]);
+}
+
+function serializeOptionI64(value: number | null | undefined): Buffer {
+  if (value === null || value === undefined) {
+    return Buffer.from([0]); // None
+  }
+  const buffer = Buffer.alloc(9);
+  buffer.writeUInt8(1, 0); // Some
+  buffer.writeBigInt64LE(BigInt(value), 1);
+  return buffer;
+}
+
+// Anchor reads the program ID in an Option<Account> slot as None
+function optionalAccount(pubkey: PublicKey | null | undefined, isWritable = false) {
+  return pubkey
+    ? { pubkey, isSigner: false, isWritable }
+    : { pubkey: PROGRAM_ID, isSigner: false, isWritable: false };
+}
+
+/**
+ * Build an idempotent create of an associated token account (token
+ * deposits need the escrow's and the recipient's to exist)
+ */
+export function createAssociatedTokenAccountIdempotentInstruction(
+  payer: PublicKey,
+  owner: PublicKey,
+  mint: PublicKey
+): TransactionInstruction {
+  return new TransactionInstruction({
+    keys: [
+      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: getAssociatedTokenAddress(owner, mint), isSigner: false, isWritable: true },
+      { pubkey: owner, isSigner: false, isWritable: false },
+      { pubkey: mint, isSigner: false, isWritable: false },
+      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+    ],
+    programId: ASSOCIATED_TOKEN_PROGRAM_ID,
+    data: Buffer.from([1]), // CreateIdempotent
+  });
+}
+
 /**
  * Build register instruction
  */
@@ -108,6 +258,8 @@ export function createRegisterInstruction(
 ): TransactionInstruction {
   const walletDescriptor = getWalletDescriptorPDA(payer);
   const userProfile = getUserProfilePDA(payer);
+  const keyRecord = getEncryptionKeyRecordPDA(payer, 0);
+  const walletLink = getWalletLinkPDA(payer);
 
   // Serialize instruction data: discriminator + displayName + avatarData + encryptionPublicKey
   const data = Buffer.concat([
@@ -121,7 +273,12 @@ export function createRegisterInstruction(
     keys: [
       { pubkey: walletDescriptor, isSigner: false, isWritable: true },
       { pubkey: userProfile, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: keyRecord, isSigner: false, isWritable: true },
+      // key_log: None, the key record can be logged later
+      optionalAccount(null),
+      { pubkey: walletLink, isSigner: false, isWritable: false },
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      { pubkey: payer, isSigner: true, isWritable: true }, // fee_payer
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     ],
     programId: PROGRAM_ID,
@@ -135,7 +292,8 @@ export function createRegisterInstruction(
  * Useful for testing/redeployment during development.
  */
 export function createCloseProfileInstruction(
-  payer: PublicKey
+  payer: PublicKey,
+  rentPayer: PublicKey = payer
 ): TransactionInstruction {
   const userProfile = getUserProfilePDA(payer);
   const walletDescriptor = getWalletDescriptorPDA(payer);
@@ -146,7 +304,9 @@ export function createCloseProfileInstruction(
     keys: [
       { pubkey: userProfile, isSigner: false, isWritable: true },
       { pubkey: walletDescriptor, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: true, isWritable: false },
+      // Paid for registration, gets the rent back
+      { pubkey: rentPayer, isSigner: false, isWritable: true },
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     ],
     programId: PROGRAM_ID,
@@ -154,57 +314,161 @@ export function createCloseProfileInstruction(
   });
 }
 
+export interface InviteGateProof {
+  tokenAccount?: PublicKey;
+  metadata?: PublicKey;
+  walletLink?: PublicKey;
+  inviterMutualRelationship?: PublicKey;
+  inviteeMutualRelationship?: PublicKey;
+}
+
+export interface InviteOptions {
+  // Handle PDA when the invitee is addressed by handle; `invitee` is then the handle's owner
+  inviteeHandle?: PublicKey;
+  // Primary wallet when the invitee is a linked wallet (chatHash must use it)
+  identity?: PublicKey;
+  expiresAt?: number | null;
+  // Intro sealed to the invitee's encryption key
+  intro?: { ciphertext: Uint8Array; nonce: Uint8Array } | null;
+  // The invitee's required deposit, from their UserProfile
+  deposit?: InviteDeposit | null;
+  gate?: InviteGateProof;
+}
+
 /**
  * Build invite instruction
  */
 export function createInviteInstruction(
   payer: PublicKey,
   invitee: PublicKey,
-  chatHash: Uint8Array
+  chatHash: Uint8Array,
+  options: InviteOptions = {}
 ): TransactionInstruction {
+  const identity = options.identity ?? invitee;
   const payerDescriptor = getWalletDescriptorPDA(payer);
-  const inviteeDescriptor = getWalletDescriptorPDA(invitee);
+  const inviteeLink = getWalletLinkPDA(invitee);
+  const inviteeProfile = getUserProfilePDA(identity);
+  const relationship = getRelationshipPDA(chatHash);
   const conversation = getConversationPDA(chatHash);
-
-  // Serialize instruction data: discriminator + hash (32 bytes, no length prefix for fixed array)
+  const tombstone = getTombstonePDA(chatHash);
+  const pairBlock = getPairBlockPDA(chatHash);
+  const intro = options.intro ?? null;
+  const inviteNote = intro ? getInviteNotePDA(chatHash) : null;
+  const deposit = options.deposit ?? null;
+  const inviteEscrow = deposit ? getInviteEscrowPDA(chatHash) : null;
+  const depositMint = deposit?.mint ?? null;
+  const gate = options.gate ?? {};
+
+  // Serialize instruction data: discriminator + hash + Option<i64> + Option<EncryptedIntro>
   const data = Buffer.concat([
     DISCRIMINATORS.invite,
     Buffer.from(chatHash),
+    serializeOptionI64(options.expiresAt),
+    intro
+      ? Buffer.concat([Buffer.from([1]), serializeBytes(intro.ciphertext), Buffer.from(intro.nonce)])
+      : Buffer.from([0]),
   ]);
 
   return new TransactionInstruction({
     keys: [
-      { pubkey: payer, isSigner: true, isWritable: true },
-      { pubkey: invitee, isSigner: false, isWritable: false },
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      { pubkey: payer, isSigner: true, isWritable: true }, // fee_payer
+      // Exactly one of invitee and invitee_handle is passed
+      optionalAccount(options.inviteeHandle ? null : invitee),
+      optionalAccount(options.inviteeHandle),
+      { pubkey: inviteeLink, isSigner: false, isWritable: false },
       { pubkey: payerDescriptor, isSigner: false, isWritable: true },
-      { pubkey: inviteeDescriptor, isSigner: false, isWritable: true },
+      { pubkey: relationship, isSigner: false, isWritable: true },
       { pubkey: conversation, isSigner: false, isWritable: true },
+      { pubkey: tombstone, isSigner: false, isWritable: false },
+      { pubkey: pairBlock, isSigner: false, isWritable: false },
+      optionalAccount(inviteNote, true),
+      { pubkey: inviteeProfile, isSigner: false, isWritable: false },
+      optionalAccount(inviteEscrow, true),
+      optionalAccount(depositMint && getAssociatedTokenAddress(payer, depositMint), true),
+      optionalAccount(depositMint && inviteEscrow && getAssociatedTokenAddress(inviteEscrow, depositMint), true),
+      optionalAccount(gate.tokenAccount),
+      optionalAccount(gate.metadata),
+      optionalAccount(gate.walletLink),
+      optionalAccount(gate.inviterMutualRelationship),
+      optionalAccount(gate.inviteeMutualRelationship),
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      optionalAccount(depositMint && TOKEN_PROGRAM_ID),
     ],
     programId: PROGRAM_ID,
     data,
   });
 }
 
+/**
+ * Accounts releasing a pending invite's intro note and deposit on accept/reject
+ */
+function pendingInviteKeys(
+  payer: PublicKey,
+  chatHash: Uint8Array,
+  pending: PendingInvite | null,
+  depositRecipient: (escrow: InviteEscrow) => PublicKey
+) {
+  const escrow = pending?.escrow ?? null;
+  const inviteEscrow = escrow ? getInviteEscrowPDA(chatHash) : null;
+  const mint = escrow?.mint ?? null;
+
+  return [
+    optionalAccount(pending?.hasIntro ? getInviteNotePDA(chatHash) : null, true),
+    // invite_rent_payer: any account when there is nothing to release
+    { pubkey: pending?.rentPayer ?? payer, isSigner: false, isWritable: true },
+    optionalAccount(inviteEscrow, true),
+    optionalAccount(mint && inviteEscrow && getAssociatedTokenAddress(inviteEscrow, mint), true),
+    optionalAccount(mint && escrow && getAssociatedTokenAddress(depositRecipient(escrow), mint), true),
+    optionalAccount(mint && TOKEN_PROGRAM_ID),
+  ];
+}
+
+// Who gets the rent back for each profile key share between a pair
+export interface ProfileKeyShareRentPayers {
+  mine: PublicKey;
+  peers: PublicKey;
+}
+
+/**
+ * Accounts closing the profile key shares between a pair that splits up
+ */
+function profileKeyShareKeys(payer: PublicKey, peer: PublicKey, rentPayers: ProfileKeyShareRentPayers) {
+  return [
+    { pubkey: getProfileKeySharePDA(payer, peer), isSigner: false, isWritable: true },
+    { pubkey: rentPayers.mine, isSigner: false, isWritable: true },
+    { pubkey: getProfileKeySharePDA(peer, payer), isSigner: false, isWritable: true },
+    { pubkey: rentPayers.peers, isSigner: false, isWritable: true },
+    { pubkey: getUserProfilePDA(payer), isSigner: false, isWritable: true },
+    { pubkey: getUserProfilePDA(peer), isSigner: false, isWritable: true },
+  ];
+}
+
 /**
  * Build accept instruction
  */
 export function createAcceptInstruction(
   payer: PublicKey,
-  peer: PublicKey
+  peer: PublicKey,
+  pending: PendingInvite | null = null
 ): TransactionInstruction {
-  const payerDescriptor = getWalletDescriptorPDA(payer);
-  const peerDescriptor = getWalletDescriptorPDA(peer);
+  const chatHash = getChatHash(payer, peer);
+  const relationship = getRelationshipPDA(chatHash);
 
   // Serialize instruction data: just discriminator (no args)
   const data = DISCRIMINATORS.accept;
 
   return new TransactionInstruction({
     keys: [
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: false, isWritable: false }, // wallet
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      optionalAccount(null), // session_key
+      optionalAccount(null), // passkey_profile
+      optionalAccount(null), // instructions_sysvar
       { pubkey: peer, isSigner: false, isWritable: false },
-      { pubkey: payerDescriptor, isSigner: false, isWritable: true },
-      { pubkey: peerDescriptor, isSigner: false, isWritable: true },
+      { pubkey: relationship, isSigner: false, isWritable: true },
+      // Accepting refunds the deposit to the inviter
+      ...pendingInviteKeys(payer, chatHash, pending, (escrow) => escrow.inviter),
     ],
     programId: PROGRAM_ID,
     data,
@@ -216,19 +480,26 @@ export function createAcceptInstruction(
  */
 export function createRejectInstruction(
   payer: PublicKey,
-  peer: PublicKey
+  peer: PublicKey,
+  pending: PendingInvite | null = null,
+  shareRentPayers: ProfileKeyShareRentPayers = { mine: payer, peers: payer }
 ): TransactionInstruction {
-  const payerDescriptor = getWalletDescriptorPDA(payer);
-  const peerDescriptor = getWalletDescriptorPDA(peer);
+  const chatHash = getChatHash(payer, peer);
+  const relationship = getRelationshipPDA(chatHash);
 
   const data = DISCRIMINATORS.reject;
 
   return new TransactionInstruction({
     keys: [
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: false, isWritable: true }, // wallet
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      optionalAccount(null), // session_key
       { pubkey: peer, isSigner: false, isWritable: false },
-      { pubkey: payerDescriptor, isSigner: false, isWritable: true },
-      { pubkey: peerDescriptor, isSigner: false, isWritable: true },
+      { pubkey: relationship, isSigner: false, isWritable: true },
+      // Rejecting lets the invitee keep the deposit
+      ...pendingInviteKeys(payer, chatHash, pending, (escrow) => escrow.invitee),
+      // Dropping an accepted contact closes the profile key shares
+      ...profileKeyShareKeys(payer, peer, shareRentPayers),
     ],
     programId: PROGRAM_ID,
     data,
@@ -240,23 +511,54 @@ export const createAcceptInvitationInstruction = createAcceptInstruction;
 export const createRejectInvitationInstruction = createRejectInstruction;
 
 /**
- * Build block instruction (hard block contact)
+ * Build block instruction
+ * The BlockEntry only names the peer blinded and sealed (see blindPeer/sealPeer);
+ * the pair's PairBlock stops invites either way. Pass the peer's pending invite
+ * to keep its deposit.
  */
 export function createBlockInstruction(
   payer: PublicKey,
-  peer: PublicKey
+  peer: PublicKey,
+  blindedPeer: Uint8Array,
+  sealedPeer: Uint8Array,
+  nonce: Uint8Array,
+  pending: PendingInvite | null = null
 ): TransactionInstruction {
-  const payerDescriptor = getWalletDescriptorPDA(payer);
-  const peerDescriptor = getWalletDescriptorPDA(peer);
+  const chatHash = getChatHash(payer, peer);
+  const blockEntry = getBlockEntryPDA(payer, blindedPeer);
+  const pairBlock = getPairBlockPDA(chatHash);
+  const escrow = pending?.escrow ?? null;
+  const inviteEscrow = escrow ? getInviteEscrowPDA(chatHash) : null;
+  const mint = escrow?.mint ?? null;
 
-  const data = DISCRIMINATORS.block;
+  const data = Buffer.concat([
+    DISCRIMINATORS.block,
+    Buffer.from(blindedPeer), // 32 bytes
+    Buffer.from(sealedPeer), // 48 bytes
+    Buffer.from(nonce), // 24 bytes
+  ]);
 
   return new TransactionInstruction({
     keys: [
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: false, isWritable: true }, // wallet
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      optionalAccount(null), // session_key
+      optionalAccount(null), // passkey_profile
+      optionalAccount(null), // instructions_sysvar
+      { pubkey: payer, isSigner: true, isWritable: true }, // fee_payer
       { pubkey: peer, isSigner: false, isWritable: false },
-      { pubkey: payerDescriptor, isSigner: false, isWritable: true },
-      { pubkey: peerDescriptor, isSigner: false, isWritable: true },
+      { pubkey: blockEntry, isSigner: false, isWritable: true },
+      { pubkey: pairBlock, isSigner: false, isWritable: true },
+      optionalAccount(null), // profile_key_share
+      optionalAccount(null), // share_rent_payer
+      optionalAccount(null), // user_profile
+      optionalAccount(escrow && getRelationshipPDA(chatHash), true),
+      optionalAccount(inviteEscrow, true),
+      optionalAccount(escrow?.rentPayer, true),
+      optionalAccount(mint && inviteEscrow && getAssociatedTokenAddress(inviteEscrow, mint), true),
+      optionalAccount(mint && getAssociatedTokenAddress(payer, mint), true),
+      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      optionalAccount(mint && TOKEN_PROGRAM_ID),
     ],
     programId: PROGRAM_ID,
     data,
@@ -264,23 +566,75 @@ export function createBlockInstruction(
 }
 
 /**
- * Build unblock instruction (change Blocked → Rejected)
+ * Build unblock instruction (closes the BlockEntry, and the PairBlock with its last block)
  */
 export function createUnblockInstruction(
   payer: PublicKey,
-  peer: PublicKey
+  blocked: BlockedPeer,
+  pairBlockRentPayer: PublicKey = payer
 ): TransactionInstruction {
-  const payerDescriptor = getWalletDescriptorPDA(payer);
-  const peerDescriptor = getWalletDescriptorPDA(peer);
+  const blockEntry = getBlockEntryPDA(payer, blocked.blindedPeer);
+  const pairBlock = getPairBlockPDA(getChatHash(payer, blocked.peer));
 
   const data = DISCRIMINATORS.unblock;
 
   return new TransactionInstruction({
     keys: [
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: false, isWritable: false }, // wallet
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      optionalAccount(null), // session_key
+      { pubkey: blockEntry, isSigner: false, isWritable: true },
+      { pubkey: blocked.rentPayer, isSigner: false, isWritable: true },
+      { pubkey: blocked.peer, isSigner: false, isWritable: false },
+      { pubkey: pairBlock, isSigner: false, isWritable: true },
+      { pubkey: pairBlockRentPayer, isSigner: false, isWritable: true },
+    ],
+    programId: PROGRAM_ID,
+    data,
+  });
+}
+
+/**
+ * Build migrate_legacy_contact instruction (one peer of the version 1 descriptor)
+ */
+export function createMigrateLegacyContactInstruction(
+  payer: PublicKey,
+  peer: PublicKey,
+  peerHasLegacyDescriptor: boolean
+): TransactionInstruction {
+  const chatHash = getChatHash(payer, peer);
+
+  const data = DISCRIMINATORS.migrate_legacy_contact;
+
+  return new TransactionInstruction({
+    keys: [
+      { pubkey: getLegacyWalletDescriptorPDA(payer), isSigner: false, isWritable: false },
+      // The peer's side is inferred when their descriptor is gone
+      optionalAccount(peerHasLegacyDescriptor ? getLegacyWalletDescriptorPDA(peer) : null),
       { pubkey: peer, isSigner: false, isWritable: false },
-      { pubkey: payerDescriptor, isSigner: false, isWritable: true },
-      { pubkey: peerDescriptor, isSigner: false, isWritable: true },
+      { pubkey: getRelationshipPDA(chatHash), isSigner: false, isWritable: true },
+      { pubkey: getConversationPDA(chatHash), isSigner: false, isWritable: false },
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      { pubkey: payer, isSigner: true, isWritable: true }, // fee_payer
+      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+    ],
+    programId: PROGRAM_ID,
+    data,
+  });
+}
+
+/**
+ * Build close_legacy_descriptor instruction (after its contacts are migrated)
+ */
+export function createCloseLegacyDescriptorInstruction(
+  payer: PublicKey
+): TransactionInstruction {
+  const data = DISCRIMINATORS.close_legacy_descriptor;
+
+  return new TransactionInstruction({
+    keys: [
+      { pubkey: getLegacyWalletDescriptorPDA(payer), isSigner: false, isWritable: true },
+      { pubkey: payer, isSigner: true, isWritable: true },
     ],
     programId: PROGRAM_ID,
     data,
@@ -390,7 +744,8 @@ export function createCreateGroupInstruction(
   return new TransactionInstruction({
     keys: [
       { pubkey: group, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      { pubkey: payer, isSigner: true, isWritable: true }, // fee_payer
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     ],
     programId: PROGRAM_ID,
@@ -427,7 +782,7 @@ export function createUpdateGroupInstruction(
   return new TransactionInstruction({
     keys: [
       { pubkey: group, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: true, isWritable: false },
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     ],
     programId: PROGRAM_ID,
@@ -441,19 +796,23 @@ export function createUpdateGroupInstruction(
 export function createInviteToGroupInstruction(
   payer: PublicKey,
   groupId: Uint8Array,
-  invitee: PublicKey
+  invitee: PublicKey,
+  identity: PublicKey = invitee // Primary wallet when the invitee is a linked wallet
 ): TransactionInstruction {
   const group = getGroupPDA(groupId);
-  const groupInvite = getGroupInvitePDA(groupId, invitee);
+  const groupInvite = getGroupInvitePDA(groupId, identity);
 
   const data = DISCRIMINATORS.invite_to_group;
 
   return new TransactionInstruction({
     keys: [
       { pubkey: group, isSigner: false, isWritable: true },
-      { pubkey: groupInvite, isSigner: false, isWritable: true },
       { pubkey: invitee, isSigner: false, isWritable: false },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      optionalAccount(null), // invitee_handle
+      { pubkey: getWalletLinkPDA(invitee), isSigner: false, isWritable: false },
+      { pubkey: groupInvite, isSigner: false, isWritable: true },
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      { pubkey: payer, isSigner: true, isWritable: true }, // fee_payer
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     ],
     programId: PROGRAM_ID,
@@ -471,7 +830,6 @@ export function createAcceptGroupInviteInstruction(
 ): TransactionInstruction {
   const group = getGroupPDA(groupId);
   const groupInvite = getGroupInvitePDA(groupId, payer);
-  const TOKEN_PROGRAM_ID = new PublicKey('TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA');
 
   const data = DISCRIMINATORS.accept_group_invite;
 
@@ -483,8 +841,10 @@ export function createAcceptGroupInviteInstruction(
     { pubkey: groupInvite, isSigner: false, isWritable: true },
     // ALWAYS include user_token_account - use program ID for None
     { pubkey: userTokenAccount ?? PROGRAM_ID, isSigner: false, isWritable: false },
-    { pubkey: payer, isSigner: true, isWritable: true },
-    { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+    optionalAccount(null), // gate_wallet_link
+    { pubkey: payer, isSigner: false, isWritable: false }, // wallet
+    { pubkey: payer, isSigner: true, isWritable: false }, // authority
+    optionalAccount(null), // session_key
     // ALWAYS include token_program - use program ID for None
     { pubkey: userTokenAccount ? TOKEN_PROGRAM_ID : PROGRAM_ID, isSigner: false, isWritable: false },
   ];
@@ -510,7 +870,9 @@ export function createRejectGroupInviteInstruction(
   return new TransactionInstruction({
     keys: [
       { pubkey: groupInvite, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: false, isWritable: false }, // wallet
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      optionalAccount(null), // session_key
     ],
     programId: PROGRAM_ID,
     data,
@@ -531,8 +893,7 @@ export function createLeaveGroupInstruction(
   return new TransactionInstruction({
     keys: [
       { pubkey: group, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
-      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: payer, isSigner: true, isWritable: false },
     ],
     programId: PROGRAM_ID,
     data,
@@ -555,8 +916,7 @@ export function createKickMemberInstruction(
     keys: [
       { pubkey: group, isSigner: false, isWritable: true },
       { pubkey: member, isSigner: false, isWritable: false },
-      { pubkey: payer, isSigner: true, isWritable: true },
-      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: payer, isSigner: true, isWritable: false },
     ],
     programId: PROGRAM_ID,
     data,
@@ -568,7 +928,8 @@ export function createKickMemberInstruction(
  */
 export function createCloseGroupInstruction(
   payer: PublicKey,
-  groupId: Uint8Array
+  groupId: Uint8Array,
+  rentPayer: PublicKey = payer // Group.rentPayer, gets the rent back
 ): TransactionInstruction {
   const group = getGroupPDA(groupId);
 
@@ -577,7 +938,8 @@ export function createCloseGroupInstruction(
   return new TransactionInstruction({
     keys: [
       { pubkey: group, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: true, isWritable: false },
+      { pubkey: rentPayer, isSigner: false, isWritable: true },
     ],
     programId: PROGRAM_ID,
     data,
@@ -617,7 +979,12 @@ export function createStoreGroupKeyInstruction(
     keys: [
       { pubkey: groupKeyShare, isSigner: false, isWritable: true },
       { pubkey: group, isSigner: false, isWritable: false },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: false, isWritable: false }, // wallet
+      { pubkey: payer, isSigner: true, isWritable: false }, // authority
+      optionalAccount(null), // session_key
+      optionalAccount(null), // passkey_profile
+      optionalAccount(null), // instructions_sysvar
+      { pubkey: payer, isSigner: true, isWritable: true }, // fee_payer
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     ],
     programId: PROGRAM_ID,
@@ -630,7 +997,8 @@ export function createStoreGroupKeyInstruction(
  */
 export function createCloseGroupKeyInstruction(
   payer: PublicKey,
-  groupId: Uint8Array
+  groupId: Uint8Array,
+  rentPayer: PublicKey = payer // GroupKeyShare.rentPayer, gets the rent back
 ): TransactionInstruction {
   const groupKeyShare = getGroupKeySharePDA(groupId, payer);
 
@@ -639,7 +1007,8 @@ export function createCloseGroupKeyInstruction(
   return new TransactionInstruction({
     keys: [
       { pubkey: groupKeyShare, isSigner: false, isWritable: true },
-      { pubkey: payer, isSigner: true, isWritable: true },
+      { pubkey: payer, isSigner: true, isWritable: false },
+      { pubkey: rentPayer, isSigner: false, isWritable: true },
     ],
     programId: PROGRAM_ID,
     data,
@@ -671,17 +1040,18 @@ export async function buildTransaction(
 }
 
 // Deserialization helpers
-export interface Peer {
+export interface LegacyPeer {
   pubkey: PublicKey;
   status: 'Invited' | 'Requested' | 'Accepted' | 'Rejected' | 'Blocked';
 }
 
-export interface WalletDescriptor {
+// Version 1 WalletDescriptor, see getLegacyWalletDescriptorPDA
+export interface LegacyWalletDescriptor {
   owner: PublicKey;
-  peers: Peer[];
+  peers: LegacyPeer[];
 }
 
-export function deserializeWalletDescriptor(data: Buffer): WalletDescriptor {
+export function deserializeLegacyWalletDescriptor(data: Buffer): LegacyWalletDescriptor {
   let offset = 8; // Skip 8-byte discriminator
 
   // Read owner (32 bytes)
@@ -693,7 +1063,7 @@ export function deserializeWalletDescriptor(data: Buffer): WalletDescriptor {
   offset += 4;
 
   // Read each peer
-  const peers: Peer[] = [];
+  const peers: LegacyPeer[] = [];
   for (let i = 0; i < peersLength; i++) {
     // Read peer pubkey (32 bytes)
     const pubkey = new PublicKey(data.slice(offset, offset + 32));
@@ -716,6 +1086,320 @@ export function deserializeWalletDescriptor(data: Buffer): WalletDescriptor {
   return { owner, peers };
 }
 
+export interface InviteDeposit {
+  mint: PublicKey | null; // null = lamports
+  amount: bigint;
+}
+
+export interface UserProfile {
+  owner: PublicKey;
+  rentPayer: PublicKey;
+  displayName: string;
+  avatarType: 'Emoji' | 'Nft';
+  avatarData: string;
+  encryptionPublicKey: Uint8Array;
+  keyEpoch: number;
+  inviteDeposit: InviteDeposit | null;
+}
+
+// Reads the fields up to invite_deposit, the rest is not used by the app
+export function deserializeUserProfile(data: Buffer): UserProfile {
+  let offset = 8; // Skip 8-byte discriminator
+
+  const owner = new PublicKey(data.slice(offset, offset + 32));
+  offset += 32;
+
+  const rentPayer = new PublicKey(data.slice(offset, offset + 32));
+  offset += 32;
+
+  // Skip layout_version (1 byte)
+  offset += 1;
+
+  const displayNameLength = data.readUInt32LE(offset);
+  offset += 4;
+  const displayName = data.slice(offset, offset + displayNameLength).toString('utf8');
+  offset += displayNameLength;
+
+  const avatarType = data.readUInt8(offset) === 0 ? 'Emoji' : 'Nft';
+  offset += 1;
+
+  const avatarDataLength = data.readUInt32LE(offset);
+  offset += 4;
+  const avatarData = data.slice(offset, offset + avatarDataLength).toString('utf8');
+  offset += avatarDataLength;
+
+  const encryptionPublicKey = new Uint8Array(data.slice(offset, offset + 32));
+  offset += 32;
+
+  const keyEpoch = data.readUInt32LE(offset);
+  offset += 4;
+
+  // Read Option<InviteDeposit> { mint: Option<Pubkey>, amount: u64 }
+  let inviteDeposit: InviteDeposit | null = null;
+  if (data.readUInt8(offset) === 1) {
+    offset += 1;
+    let mint: PublicKey | null = null;
+    if (data.readUInt8(offset) === 1) {
+      mint = new PublicKey(data.slice(offset + 1, offset + 33));
+      offset += 32;
+    }
+    offset += 1;
+    inviteDeposit = { mint, amount: data.readBigUInt64LE(offset) };
+  }
+
+  return {
+    owner,
+    rentPayer,
+    displayName,
+    avatarType,
+    avatarData,
+    encryptionPublicKey,
+    keyEpoch,
+    inviteDeposit,
+  };
+}
+
+export type PeerState = 'Invited' | 'Requested' | 'Accepted' | 'Rejected';
+
+export interface Relationship {
+  participants: [PublicKey, PublicKey]; // Sorted, states[i] is participants[i]'s side
+  states: [PeerState, PeerState];
+  createdAt: bigint;
+  updatedAt: bigint;
+  rentPayer: PublicKey;
+  inviteExpiresAt: bigint | null;
+  hasDeposit: boolean;
+  hasIntro: boolean;
+  hasConversation: boolean;
+}
+
+const RELATIONSHIP_SPACE = 250;
+
+// Reads the fields up to has_conversation, the rest is not used by the app
+export function deserializeRelationship(data: Buffer): Relationship {
+  const expectedDiscriminator = Buffer.from([0x59, 0xa9, 0xd5, 0x7a, 0xae, 0xf9, 0x05, 0xfb]);
+  if (!data.slice(0, 8).equals(expectedDiscriminator)) {
+    throw new Error('Invalid Relationship account discriminator');
+  }
+
+  let offset = 8;
+
+  const participants: [PublicKey, PublicKey] = [
+    new PublicKey(data.slice(offset, offset + 32)),
+    new PublicKey(data.slice(offset + 32, offset + 64)),
+  ];
+  offset += 64;
+
+  const stateNames: PeerState[] = ['Invited', 'Requested', 'Accepted', 'Rejected'];
+  const states: [PeerState, PeerState] = [stateNames[data.readUInt8(offset)], stateNames[data.readUInt8(offset + 1)]];
+  offset += 2;
+
+  const createdAt = data.readBigInt64LE(offset);
+  offset += 8;
+
+  const updatedAt = data.readBigInt64LE(offset);
+  offset += 8;
+
+  const rentPayer = new PublicKey(data.slice(offset, offset + 32));
+  offset += 32;
+
+  // Read Option<i64>
+  let inviteExpiresAt: bigint | null = null;
+  if (data.readUInt8(offset) === 1) {
+    inviteExpiresAt = data.readBigInt64LE(offset + 1);
+    offset += 8;
+  }
+  offset += 1;
+
+  const hasDeposit = data.readUInt8(offset) === 1;
+  const hasIntro = data.readUInt8(offset + 1) === 1;
+  const hasConversation = data.readUInt8(offset + 2) === 1;
+
+  return {
+    participants,
+    states,
+    createdAt,
+    updatedAt,
+    rentPayer,
+    inviteExpiresAt,
+    hasDeposit,
+    hasIntro,
+    hasConversation,
+  };
+}
+
+/**
+ * The two sides of a relationship as seen by `wallet`
+ */
+export function relationshipSides(
+  relationship: Relationship,
+  wallet: PublicKey
+): { peer: PublicKey; myState: PeerState; peerState: PeerState } {
+  const mine = relationship.participants[0].equals(wallet) ? 0 : 1;
+  return {
+    peer: relationship.participants[1 - mine],
+    myState: relationship.states[mine],
+    peerState: relationship.states[1 - mine],
+  };
+}
+
+/**
+ * Load every Relationship `wallet` is a participant of
+ */
+export async function fetchRelationships(connection: Connection, wallet: PublicKey): Promise<Relationship[]> {
+  // participants[0] is at offset 8, participants[1] at offset 40
+  const byParticipant = await Promise.all(
+    [8, 40].map((offset) =>
+      connection.getProgramAccounts(PROGRAM_ID, {
+        filters: [
+          { dataSize: RELATIONSHIP_SPACE },
+          { memcmp: { offset, bytes: wallet.toBase58() } },
+        ],
+      })
+    )
+  );
+
+  return byParticipant.flat().map(({ account }) => deserializeRelationship(account.data));
+}
+
+export interface InviteEscrow {
+  inviter: PublicKey;
+  invitee: PublicKey;
+  mint: PublicKey | null; // null = lamports
+  amount: bigint;
+  rentPayer: PublicKey;
+}
+
+export function deserializeInviteEscrow(data: Buffer): InviteEscrow {
+  let offset = 8; // Skip 8-byte discriminator
+
+  const inviter = new PublicKey(data.slice(offset, offset + 32));
+  offset += 32;
+
+  const invitee = new PublicKey(data.slice(offset, offset + 32));
+  offset += 32;
+
+  let mint: PublicKey | null = null;
+  if (data.readUInt8(offset) === 1) {
+    mint = new PublicKey(data.slice(offset + 1, offset + 33));
+    offset += 32;
+  }
+  offset += 1;
+
+  const amount = data.readBigUInt64LE(offset);
+  offset += 8;
+
+  // Skip bump (1 byte)
+  offset += 1;
+
+  const rentPayer = new PublicKey(data.slice(offset, offset + 32));
+
+  return { inviter, invitee, mint, amount, rentPayer };
+}
+
+// Intro note and deposit a pending invite holds, released on accept/reject
+export interface PendingInvite {
+  rentPayer: PublicKey; // Paid for the note and escrow, gets their rent back
+  hasIntro: boolean;
+  escrow: InviteEscrow | null;
+}
+
+export async function fetchPendingInvite(
+  connection: Connection,
+  relationship: Relationship
+): Promise<PendingInvite | null> {
+  if (!relationship.hasIntro && !relationship.hasDeposit) {
+    return null;
+  }
+
+  const chatHash = getChatHash(relationship.participants[0], relationship.participants[1]);
+  const [noteInfo, escrowInfo] = await connection.getMultipleAccountsInfo([
+    getInviteNotePDA(chatHash),
+    getInviteEscrowPDA(chatHash),
+  ]);
+  const escrow = relationship.hasDeposit && escrowInfo ? deserializeInviteEscrow(escrowInfo.data) : null;
+
+  // InviteNote: discriminator + inviter + rent_payer
+  const rentPayer = escrow?.rentPayer ?? (noteInfo ? new PublicKey(noteInfo.data.slice(40, 72)) : null);
+  if (!rentPayer) {
+    throw new Error('Pending invite accounts not found');
+  }
+
+  return { rentPayer, hasIntro: relationship.hasIntro, escrow };
+}
+
+export interface BlockedPeer {
+  peer: PublicKey;
+  blindedPeer: Uint8Array;
+  rentPayer: PublicKey;
+}
+
+const BLOCK_ENTRY_SPACE = 8 + 32 + 32 + 48 + 24 + 32 + 8 + 32;
+
+/**
+ * Load the wallet's BlockEntry accounts and open their sealed peers
+ */
+export async function fetchBlockedPeers(
+  connection: Connection,
+  wallet: PublicKey,
+  blockKey: Uint8Array
+): Promise<BlockedPeer[]> {
+  const accounts = await connection.getProgramAccounts(PROGRAM_ID, {
+    filters: [
+      { dataSize: BLOCK_ENTRY_SPACE },
+      { memcmp: { offset: 8, bytes: wallet.toBase58() } },
+    ],
+  });
+
+  const blocked: BlockedPeer[] = [];
+  for (const { account } of accounts) {
+    // owner (32) + blinded_peer (32) + sealed_peer (48) + nonce (24) + pair_tag (32) + created_at (8) + rent_payer (32)
+    const data = account.data;
+    const blindedPeer = new Uint8Array(data.slice(40, 72));
+    const peer = openSealedPeer(blockKey, new Uint8Array(data.slice(72, 120)), new Uint8Array(data.slice(120, 144)));
+    if (peer) {
+      blocked.push({ peer, blindedPeer, rentPayer: new PublicKey(data.slice(184, 216)) });
+    }
+  }
+
+  return blocked;
+}
+
+/**
+ * Rent payers of the profile key shares between two wallets, `me` for a share that doesn't exist
+ */
+export async function fetchProfileKeyShareRentPayers(
+  connection: Connection,
+  me: PublicKey,
+  peer: PublicKey
+): Promise<ProfileKeyShareRentPayers> {
+  const [mine, peers] = await connection.getMultipleAccountsInfo([
+    getProfileKeySharePDA(me, peer),
+    getProfileKeySharePDA(peer, me),
+  ]);
+  // discriminator (8) + owner (32) + contact (32) + wrapped_key (4 + len) + nonce (24) + key_version (4) + rent_payer (32)
+  const rentPayer = (info: typeof mine) => {
+    if (!info) return me;
+    const offset = 76 + info.data.readUInt32LE(72) + 28;
+    return new PublicKey(info.data.slice(offset, offset + 32));
+  };
+
+  return { mine: rentPayer(mine), peers: rentPayer(peers) };
+}
+
+/**
+ * Who paid for the PairBlock between two wallets, refunded when its last block goes
+ */
+export async function fetchPairBlockRentPayer(
+  connection: Connection,
+  a: PublicKey,
+  b: PublicKey
+): Promise<PublicKey | null> {
+  const info = await connection.getAccountInfo(getPairBlockPDA(getChatHash(a, b)));
+  // discriminator (8) + blocks (4) + rent_payer (32)
+  return info ? new PublicKey(info.data.slice(12, 44)) : null;
+}
+
 // Group types and deserialization
 export interface TokenGate {
   mint: PublicKey;
@@ -730,6 +1414,7 @@ export interface Group {
   members: PublicKey[];
   encryptionPubkey: Uint8Array;
   tokenGate: TokenGate | null;
+  rentPayer: PublicKey;
 }
 
 export interface GroupInvite {
@@ -745,6 +1430,7 @@ export interface GroupKeyShare {
   member: PublicKey;
   encryptedKey: Uint8Array;
   nonce: Uint8Array;
+  rentPayer: PublicKey;
 }
 
 export function deserializeGroup(data: Buffer): Group {
@@ -801,6 +1487,10 @@ export function deserializeGroup(data: Buffer): Group {
     tokenGate = { mint, minBalance };
   }
 
+  // Read rent_payer (32 bytes)
+  const rentPayer = new PublicKey(data.slice(offset, offset + 32));
+  offset += 32;
+
   return {
     groupId,
     creator,
@@ -809,6 +1499,7 @@ export function deserializeGroup(data: Buffer): Group {
     members,
     encryptionPubkey,
     tokenGate,
+    rentPayer,
   };
 }
 
@@ -866,11 +1557,16 @@ export function deserializeGroupKeyShare(data: Buffer): GroupKeyShare {
   const nonce = new Uint8Array(data.slice(offset, offset + 24));
   offset += 24;
 
+  // Read rent_payer (32 bytes)
+  const rentPayer = new PublicKey(data.slice(offset, offset + 32));
+  offset += 32;
+
   return {
     groupId,
     member,
     encryptedKey,
     nonce,
+    rentPayer,
   };
 }
 
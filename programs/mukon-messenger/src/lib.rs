@@ -1,10 +1,18 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Token, TokenAccount};
+#[allow(deprecated)]
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked, ID as INSTRUCTIONS_SYSVAR_ID,
+};
+use anchor_lang::system_program;
+use anchor_spl::associated_token::get_associated_token_address;
+use anchor_spl::token::{self, CloseAccount, Mint, Token, TokenAccount, Transfer};
 use sha2::{Digest, Sha256};
 use arcium_anchor::prelude::*;
 use arcium_client::idl::arcium::types::{CircuitSource, OffChainCircuitSource};
 use arcium_macros::{circuit_hash, comp_def_offset};
 
+pub mod merkle;
+
 declare_id!("GCTzU7Y6yaBNzW6WA1EJR6fnY9vLNZEEPcgsydCD8mpj");
 
 // Arcium MPC computation definition offsets
@@ -14,6 +22,10 @@ const COMP_DEF_OFFSET_ADD_TWO_NUMBERS: u32 = comp_def_offset!("add_two_numbers")
 
 const SIGN_PDA_SEED: [u8; 20] = *b"ArciumSignerAccount";
 
+const TOKEN_METADATA_PROGRAM_ID: Pubkey = pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+const ED25519_PROGRAM_ID: Pubkey = pubkey!("Ed25519SigVerify111111111111111111111111111");
+const SECP256R1_PROGRAM_ID: Pubkey = pubkey!("Secp256r1SigVerify1111111111111111111111111");
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("Already invited")]
@@ -48,6 +60,152 @@ pub enum ErrorCode {
     AbortedComputation,
     #[msg("Cluster not set")]
     ClusterNotSet,
+    #[msg("Cannot invite yourself")]
+    CannotInviteSelf,
+    #[msg("Invite expiry must be in the future")]
+    InvalidExpiry,
+    #[msg("Invite has expired")]
+    InviteExpired,
+    #[msg("Invite has not expired")]
+    InviteNotExpired,
+    #[msg("Intro note too long")]
+    IntroTooLong,
+    #[msg("Intro note and invite note account must be provided together")]
+    IntroAccountMismatch,
+    #[msg("Invite deposit must be greater than zero")]
+    InvalidDeposit,
+    #[msg("Invite deposit accounts missing or unexpected")]
+    DepositAccountMismatch,
+    #[msg("Re-invite cooldown has not elapsed")]
+    ReinviteCooldown,
+    #[msg("Too many invites sent this epoch")]
+    InviteRateLimited,
+    #[msg("Invitee is not accepting invites")]
+    InvitesDisabled,
+    #[msg("Inviter does not meet the invitee's invite policy")]
+    InvitePolicyNotMet,
+    #[msg("Invalid NFT metadata account")]
+    InvalidMetadata,
+    #[msg("Resolve the pending invite first")]
+    InvitePending,
+    #[msg("Relationship cannot be removed")]
+    RelationshipLocked,
+    #[msg("Invalid invite signature")]
+    InvalidInviteSignature,
+    #[msg("Session key does not belong to this wallet")]
+    InvalidSessionKey,
+    #[msg("Session key has expired")]
+    SessionExpired,
+    #[msg("Session key is not allowed to sign this instruction")]
+    SessionScopeDenied,
+    #[msg("Session duration must be in the future and at most MAX_SESSION_DURATION")]
+    InvalidSessionExpiry,
+    #[msg("Unknown or empty session scope")]
+    InvalidSessionScope,
+    #[msg("Device label too long")]
+    DeviceLabelTooLong,
+    #[msg("Device already revoked")]
+    DeviceRevoked,
+    #[msg("One-time prekey pool is full")]
+    PrekeyPoolFull,
+    #[msg("Key record account must be provided exactly when the encryption key changes")]
+    KeyRecordMismatch,
+    #[msg("Key transparency log is full")]
+    KeyLogFull,
+    #[msg("Not a mutual contact")]
+    NotMutualContact,
+    #[msg("Fingerprint does not match the current encryption keys")]
+    KeyFingerprintMismatch,
+    #[msg("Handles are 3-32 characters of a-z, 0-9 and _")]
+    InvalidHandle,
+    #[msg("Handle does not belong to this wallet")]
+    HandleOwnerMismatch,
+    #[msg("Mint is not an NFT (supply 1, 0 decimals)")]
+    NotAnNft,
+    #[msg("NFT avatar data must be the mint address")]
+    AvatarMintMismatch,
+    #[msg("NFT is not part of a verified collection")]
+    UnverifiedCollection,
+    #[msg("Too many collection badges")]
+    TooManyBadges,
+    #[msg("Collection badge not found")]
+    BadgeNotFound,
+    #[msg("Profile owner still holds this NFT")]
+    NftStillHeld,
+    #[msg("Bio too long")]
+    BioTooLong,
+    #[msg("Status too long")]
+    StatusTooLong,
+    #[msg("Too many links or link too long")]
+    InvalidLinks,
+    #[msg("Profile is contacts-only; update its sealed details instead")]
+    ProfileIsPrivate,
+    #[msg("Sealed details must be given exactly for contacts-only profiles")]
+    SealedProfileMismatch,
+    #[msg("Sealed profile too long")]
+    SealedProfileTooLong,
+    #[msg("Wrapped profile key too long")]
+    WrappedKeyTooLong,
+    #[msg("Guardians must be unique, exclude the owner and meet the threshold")]
+    InvalidGuardianSet,
+    #[msg("Recovery time-lock out of range")]
+    InvalidRecoveryTimelock,
+    #[msg("Not a guardian")]
+    NotGuardian,
+    #[msg("Guardian already approved")]
+    AlreadyApproved,
+    #[msg("Recovery has not reached the guardian threshold")]
+    RecoveryNotApproved,
+    #[msg("Recovery time-lock has not elapsed")]
+    RecoveryTimelocked,
+    #[msg("Key record accounts must be provided exactly for key rotations")]
+    RecoveryAccountMismatch,
+    #[msg("Migration target must be a different wallet")]
+    InvalidMigrationTarget,
+    #[msg("Wallet is not the migration target of this identity")]
+    NotMigrationTarget,
+    #[msg("Conversation account missing or unexpected")]
+    ConversationMismatch,
+    #[msg("Wallet cannot be linked: it has its own profile or the primary is linked itself")]
+    InvalidWalletLink,
+    #[msg("Wallet link does not match")]
+    WalletLinkMismatch,
+    #[msg("No passkey registered for this wallet")]
+    PasskeyNotSet,
+    #[msg("Invalid passkey signature")]
+    InvalidPasskeySignature,
+    #[msg("Account already has the current layout")]
+    AlreadyMigrated,
+    #[msg("Rejection tombstone missing or unexpected")]
+    TombstoneMismatch,
+    #[msg("Recovery is still pending")]
+    RecoveryNotExpired,
+    #[msg("Key record is already in the log")]
+    KeyAlreadyLogged,
+    #[msg("Pass either the invitee or their handle")]
+    InviteeAccountMismatch,
+    #[msg("Treasury account missing or does not match the handle config")]
+    TreasuryMismatch,
+    #[msg("Profile key version was retired, rotate the profile key")]
+    ProfileKeyRetired,
+    #[msg("Profile key share accounts missing or unexpected")]
+    ProfileKeyShareMismatch,
+    #[msg("Signed prekey is not signed by the wallet")]
+    InvalidPrekeySignature,
+    #[msg("Too many prekeys claimed this epoch")]
+    PrekeyClaimRateLimited,
+    #[msg("Prekey claims are still counting this epoch")]
+    PrekeyClaimsActive,
+    #[msg("Key epoch overflow")]
+    KeyEpochOverflow,
+    #[msg("Passkey must be a compressed secp256r1 public key")]
+    InvalidPasskey,
+    #[msg("Peer is not in the legacy wallet descriptor")]
+    LegacyContactNotFound,
+    #[msg("Profile still has the legacy layout, see migrate_profile")]
+    ProfileNotMigrated,
+    #[msg("Revoke the wallet's session keys before completing the migration")]
+    SessionKeysOpen,
 }
 
 // Deterministic hash function for chat PDAs
@@ -73,6 +231,569 @@ fn get_chat_hash(a: Pubkey, b: Pubkey) -> [u8; 32] {
     hasher.finalize().into()
 }
 
+// The wallet an invite is addressed to: the handle's owner when invited by
+// handle, otherwise the invitee account
+fn addressed_invitee(invitee: &Option<UncheckedAccount>, handle: &Option<Account<Handle>>) -> Pubkey {
+    match (invitee, handle) {
+        (_, Some(handle)) => handle.owner,
+        (Some(invitee), None) => invitee.key(),
+        (None, None) => Pubkey::default(),
+    }
+}
+
+// The identity behind the addressed wallet: its primary when `link` (the
+// wallet's WalletLink PDA) exists, otherwise the wallet itself
+fn resolve_invitee(addressed: Pubkey, link: &AccountInfo) -> Pubkey {
+    if link.data_is_empty() {
+        return addressed;
+    }
+    link.try_borrow_data().ok()
+        .and_then(|data| WalletLink::try_deserialize(&mut &data[..]).ok())
+        .map_or(addressed, |link| link.primary)
+}
+
+// Safety number of a contact pair: both wallets and their current
+// encryption keys, in relationship.participants order
+fn key_fingerprint(participants: [Pubkey; 2], keys: [[u8; 32]; 2]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for (participant, key) in participants.iter().zip(keys) {
+        hasher.update(participant.as_ref());
+        hasher.update(key);
+    }
+    hasher.finalize().into()
+}
+
+// Token accounts for gates may belong to the wallet or a wallet linked to it
+fn is_token_owner(token_owner: Pubkey, wallet: Pubkey, link: Option<&Account<WalletLink>>) -> bool {
+    token_owner == wallet || link.is_some_and(|l| l.primary == wallet && l.linked == token_owner)
+}
+
+// Same ownership checks as a group TokenGate, plus the mint must be a
+// real NFT so a single other holder proves the owner no longer has it
+fn check_nft_holder(token_account: &Account<TokenAccount>, mint: &Account<Mint>, owner: Pubkey) -> Result<()> {
+    require!(token_account.owner == owner, ErrorCode::InvalidTokenAccount);
+    require!(token_account.mint == mint.key(), ErrorCode::InsufficientTokenBalance);
+    require!(token_account.amount >= 1, ErrorCode::InsufficientTokenBalance);
+    require!(mint.supply == 1 && mint.decimals == 0, ErrorCode::NotAnNft);
+    Ok(())
+}
+
+// Handles are lowercase so lookups are case-insensitive
+fn is_valid_handle(name: &str) -> bool {
+    (MIN_HANDLE_LEN..=MAX_HANDLE_LEN).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+// Verified collection of an NFT, read from its Metaplex metadata account
+fn verified_collection(metadata: &AccountInfo, mint: Pubkey) -> Result<Option<Pubkey>> {
+    require_keys_eq!(*metadata.owner, TOKEN_METADATA_PROGRAM_ID, ErrorCode::InvalidMetadata);
+    let (expected_metadata, _) = Pubkey::find_program_address(
+        &[b"metadata", TOKEN_METADATA_PROGRAM_ID.as_ref(), mint.as_ref()],
+        &TOKEN_METADATA_PROGRAM_ID,
+    );
+    require_keys_eq!(metadata.key(), expected_metadata, ErrorCode::InvalidMetadata);
+
+    let data = metadata.try_borrow_data()?;
+    let mut offset = 1 + 32 + 32;  // key + update_authority + mint
+
+    let read = |offset: &mut usize, len: usize| -> Result<&[u8]> {
+        let bytes = data.get(*offset..*offset + len).ok_or(ErrorCode::InvalidMetadata)?;
+        *offset += len;
+        Ok(bytes)
+    };
+    let read_u32 = |offset: &mut usize| -> Result<usize> {
+        Ok(u32::from_le_bytes(read(offset, 4)?.try_into().unwrap()) as usize)
+    };
+
+    // name, symbol, uri
+    for _ in 0..3 {
+        let len = read_u32(&mut offset)?;
+        read(&mut offset, len)?;
+    }
+    read(&mut offset, 2)?;  // seller_fee_basis_points
+    if read(&mut offset, 1)?[0] == 1 {
+        let creators = read_u32(&mut offset)?;
+        read(&mut offset, creators * (32 + 1 + 1))?;
+    }
+    read(&mut offset, 2)?;  // primary_sale_happened + is_mutable
+    // edition_nonce, token_standard
+    for _ in 0..2 {
+        if read(&mut offset, 1)?[0] == 1 {
+            read(&mut offset, 1)?;
+        }
+    }
+    if read(&mut offset, 1)?[0] == 0 {
+        return Ok(None);
+    }
+    let verified = read(&mut offset, 1)?[0] == 1;
+    let key = Pubkey::try_from(read(&mut offset, 32)?).map_err(|_| ErrorCode::InvalidMetadata)?;
+
+    Ok(verified.then_some(key))
+}
+
+// Accounts an inviter shows for a TokenHolders or NftCollection policy
+struct GateProof<'a, 'info> {
+    token_account: Option<&'a Account<'info, TokenAccount>>,
+    metadata: Option<&'a UncheckedAccount<'info>>,
+    wallet_link: Option<&'a Account<'info, WalletLink>>,  // When a linked wallet holds the token
+}
+
+// Enforce the invitee's inbound policy against the inviter
+fn check_invite_policy(
+    policy: &InvitePolicy,
+    inviter: Pubkey,
+    invitee: Pubkey,
+    gate: GateProof,
+    inviter_mutual: Option<&Account<Relationship>>,
+    invitee_mutual: Option<&Account<Relationship>>,
+) -> Result<()> {
+    match policy {
+        InvitePolicy::Anyone => {},
+        InvitePolicy::Nobody => return Err(ErrorCode::InvitesDisabled.into()),
+        InvitePolicy::TokenHolders(token_gate) => {
+            let token_account = gate.token_account.ok_or(ErrorCode::TokenAccountRequired)?;
+
+            require!(is_token_owner(token_account.owner, inviter, gate.wallet_link), ErrorCode::InvalidTokenAccount);
+            require!(token_account.mint == token_gate.token_mint, ErrorCode::InsufficientTokenBalance);
+            require!(token_account.amount >= token_gate.min_balance, ErrorCode::InsufficientTokenBalance);
+        },
+        InvitePolicy::NftCollection(collection) => {
+            let token_account = gate.token_account.ok_or(ErrorCode::TokenAccountRequired)?;
+            let metadata = gate.metadata.ok_or(ErrorCode::InvalidMetadata)?;
+
+            require!(is_token_owner(token_account.owner, inviter, gate.wallet_link), ErrorCode::InvalidTokenAccount);
+            require!(token_account.amount >= 1, ErrorCode::InsufficientTokenBalance);
+            require!(
+                verified_collection(metadata, token_account.mint)? == Some(*collection),
+                ErrorCode::InvitePolicyNotMet
+            );
+        },
+        InvitePolicy::ContactsOfContacts => {
+            let inviter_mutual = inviter_mutual.ok_or(ErrorCode::InvitePolicyNotMet)?;
+            let invitee_mutual = invitee_mutual.ok_or(ErrorCode::InvitePolicyNotMet)?;
+
+            let mutual = inviter_mutual.peer_of(inviter).ok_or(ErrorCode::InvitePolicyNotMet)?;
+            require!(
+                invitee_mutual.participants == sorted_pair(mutual, invitee),
+                ErrorCode::InvitePolicyNotMet
+            );
+            require!(
+                inviter_mutual.is_mutual_contact() && invitee_mutual.is_mutual_contact(),
+                ErrorCode::InvitePolicyNotMet
+            );
+        },
+    }
+
+    Ok(())
+}
+
+// Message an inviter signs off-chain for accept_signed_invite
+fn signed_invite_message(hash: &[u8; 32], expires_at: i64, nonce: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNED_INVITE_DOMAIN.len() + 32 + 32 + 8 + 8);
+    message.extend_from_slice(SIGNED_INVITE_DOMAIN);
+    message.extend_from_slice(crate::ID.as_ref());
+    message.extend_from_slice(hash);
+    message.extend_from_slice(&expires_at.to_le_bytes());
+    message.extend_from_slice(&nonce.to_le_bytes());
+    message
+}
+
+// Message a wallet signs over its signed prekey, binding it to the wallet
+// and the prekey id
+fn signed_prekey_message(wallet: Pubkey, prekey: &SignedPrekey) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNED_PREKEY_DOMAIN.len() + 32 + 32 + 4 + 32);
+    message.extend_from_slice(SIGNED_PREKEY_DOMAIN);
+    message.extend_from_slice(crate::ID.as_ref());
+    message.extend_from_slice(wallet.as_ref());
+    message.extend_from_slice(&prekey.id.to_le_bytes());
+    message.extend_from_slice(&prekey.key);
+    message
+}
+
+// Whether the instruction right before this one is a signature precompile
+// (Ed25519 or secp256r1, same layout) verification of `message` signed by
+// `signer`
+fn precompile_verified(
+    instructions: &AccountInfo,
+    program_id: Pubkey,
+    signer: &[u8],
+    message: &[u8],
+) -> Result<bool> {
+    let current = load_current_index_checked(instructions)?;
+    if current == 0 {
+        return Ok(false);
+    }
+    let ix = load_instruction_at_checked(current as usize - 1, instructions)?;
+    if ix.program_id != program_id {
+        return Ok(false);
+    }
+
+    // num_signatures (1) + padding, then one 14-byte offsets entry
+    let data = &ix.data;
+    if data.len() < 16 || data[0] != 1 {
+        return Ok(false);
+    }
+    let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let pubkey_offset = read_u16(6) as usize;
+    let message_offset = read_u16(10) as usize;
+    let message_size = read_u16(12) as usize;
+
+    // Signature, pubkey and message must all live in the precompile instruction itself
+    if [4, 8, 14].iter().any(|&index_at| read_u16(index_at) != u16::MAX) {
+        return Ok(false);
+    }
+
+    let pubkey = data.get(pubkey_offset..pubkey_offset + signer.len());
+    let signed = data.get(message_offset..message_offset + message_size);
+    Ok(pubkey == Some(signer) && signed == Some(message))
+}
+
+// Message a passkey signs to act for `wallet`: the action (a SESSION_* bit),
+// what it applies to and the profile's passkey nonce against replays
+fn passkey_message(wallet: Pubkey, scope: u32, target: &[u8; 32], nonce: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(PASSKEY_DOMAIN.len() + 32 + 32 + 4 + 32 + 8);
+    message.extend_from_slice(PASSKEY_DOMAIN);
+    message.extend_from_slice(crate::ID.as_ref());
+    message.extend_from_slice(wallet.as_ref());
+    message.extend_from_slice(&scope.to_le_bytes());
+    message.extend_from_slice(target);
+    message.extend_from_slice(&nonce.to_le_bytes());
+    message
+}
+
+// Accept a signature from the wallet's passkey, verified by the secp256r1
+// precompile, in place of the wallet's; without a passkey profile this is
+// check_authority
+fn check_authority_or_passkey(
+    wallet: &AccountInfo,
+    authority: &Signer,
+    session_key: Option<&Account<SessionKey>>,
+    passkey_profile: Option<&mut Box<Account<UserProfile>>>,
+    instructions: Option<&AccountInfo>,
+    scope: u32,
+    target: &[u8; 32],
+) -> Result<()> {
+    let Some(profile) = passkey_profile else {
+        return check_authority(wallet, authority, session_key, scope);
+    };
+
+    let passkey = profile.passkey.ok_or(ErrorCode::PasskeyNotSet)?;
+    let instructions = instructions.ok_or(ErrorCode::InvalidPasskeySignature)?;
+    let verified = precompile_verified(
+        instructions,
+        SECP256R1_PROGRAM_ID,
+        &passkey,
+        &passkey_message(wallet.key(), scope, target, profile.passkey_nonce),
+    )?;
+    require!(verified, ErrorCode::InvalidPasskeySignature);
+    profile.passkey_nonce += 1;
+
+    Ok(())
+}
+
+// Accept the wallet itself, or one of its unexpired session keys allowed
+// to sign `scope`
+fn check_authority(
+    wallet: &AccountInfo,
+    authority: &Signer,
+    session_key: Option<&Account<SessionKey>>,
+    scope: u32,
+) -> Result<()> {
+    if authority.key() == wallet.key() {
+        return Ok(());
+    }
+
+    let session = session_key.ok_or(ErrorCode::Unauthorized)?;
+    require_keys_eq!(session.wallet, wallet.key(), ErrorCode::InvalidSessionKey);
+    require_keys_eq!(session.session_key, authority.key(), ErrorCode::InvalidSessionKey);
+    require!(Clock::get()?.unix_timestamp < session.expires_at, ErrorCode::SessionExpired);
+    require!(session.allowed & scope == scope, ErrorCode::SessionScopeDenied);
+
+    Ok(())
+}
+
+// Commit a key record to the transparency log
+fn log_key(key_log: &mut KeyLog, key_record: &mut EncryptionKeyRecord) -> Result<()> {
+    let leaf = merkle::key_leaf_hash(
+        &key_record.owner,
+        key_record.epoch,
+        &key_record.key,
+        key_record.activated_at,
+    );
+    let index = key_log.append(leaf)?;
+    key_record.log_index = Some(index);
+
+    emit!(KeyLogAppended {
+        index,
+        leaf,
+        root: key_log.root,
+        tree_size: key_log.tree_size,
+    });
+
+    Ok(())
+}
+
+// Start a new key epoch: record and log the new key, then tell peers
+fn rotate_encryption_key(
+    user_profile: &mut UserProfile,
+    key_record: &mut EncryptionKeyRecord,
+    key_log: &mut KeyLog,
+    key: [u8; 32],
+    rent_payer: Pubkey,
+) -> Result<()> {
+    let old_key = user_profile.encryption_public_key;
+    let now = Clock::get()?.unix_timestamp;
+
+    user_profile.key_epoch = user_profile.next_key_epoch()?;
+    user_profile.encryption_public_key = key;
+
+    key_record.owner = user_profile.owner;
+    key_record.epoch = user_profile.key_epoch;
+    key_record.key = key;
+    key_record.activated_at = now;
+    key_record.rent_payer = rent_payer;
+    log_key(key_log, key_record)?;
+
+    emit!(EncryptionKeyChanged {
+        owner: user_profile.owner,
+        epoch: user_profile.key_epoch,
+        old_key,
+        new_key: key,
+        activated_at: now,
+    });
+
+    Ok(())
+}
+
+// Resize a program account for a new layout, topping its rent up from
+// `payer`; shrinking leaves the surplus on the account
+fn resize_for_layout<'info>(
+    account: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    space: usize,
+) -> Result<()> {
+    let top_up = Rent::get()?.minimum_balance(space).saturating_sub(account.lamports());
+    if top_up > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: account.clone(),
+                },
+            ),
+            top_up,
+        )?;
+    }
+    account.resize(space)?;
+
+    Ok(())
+}
+
+// Participants of a pair in the same order get_chat_hash uses
+fn sorted_pair(a: Pubkey, b: Pubkey) -> [Pubkey; 2] {
+    if a < b { [a, b] } else { [b, a] }
+}
+
+// Withdraw a pending invite. The relationship is closed unless it carries
+// block flags or rejection history, which must survive - then it goes idle.
+fn withdraw_invite<'info>(
+    relationship: &mut Account<'info, Relationship>,
+    rent_payer: &AccountInfo<'info>,
+    now: i64,
+) -> Result<()> {
+    if relationship.has_history() {
+        relationship.states = [PeerState::Rejected, PeerState::Rejected];
+        relationship.invite_expires_at = None;
+        relationship.has_deposit = false;
+        relationship.has_intro = false;
+        relationship.has_conversation = false;
+        relationship.updated_at = now;
+        Ok(())
+    } else {
+        relationship.close(rent_payer.clone())
+    }
+}
+
+// Resolving a pending invite closes its intro note, which must be passed
+// exactly when the invite has one
+fn close_intro(relationship: &mut Relationship, invite_note: &Option<Account<InviteNote>>) -> Result<()> {
+    require!(relationship.has_intro == invite_note.is_some(), ErrorCode::IntroAccountMismatch);
+    relationship.has_intro = false;
+    Ok(())
+}
+
+// A relationship `me` may close: no pending invite, and closing must not
+// wipe rejections I collected, which the next cooldown builds on. Only the
+// side that rejected can let them go.
+fn check_contact_removable(relationship: &Relationship, me: Pubkey, peer: Pubkey) -> Result<()> {
+    require!(
+        matches!(
+            relationship.state_of(me),
+            Some(PeerState::Accepted) | Some(PeerState::Rejected)
+        ),
+        ErrorCode::InvitePending
+    );
+    require!(
+        matches!(
+            relationship.state_of(peer),
+            Some(PeerState::Accepted) | Some(PeerState::Rejected)
+        ),
+        ErrorCode::InvitePending
+    );
+
+    require!(!relationship.was_rejected(me), ErrorCode::RelationshipLocked);
+
+    Ok(())
+}
+
+// Close a profile key share between a pair that is splitting up, if it
+// exists, returning its owner and the key version it held
+fn close_profile_key_share<'info>(
+    share: &AccountInfo<'info>,
+    rent_payer: &AccountInfo<'info>,
+) -> Result<Option<(Pubkey, u32)>> {
+    if share.data_is_empty() {
+        return Ok(None);
+    }
+    require_keys_eq!(*share.owner, crate::ID, ErrorCode::Unauthorized);
+    let key_share = ProfileKeyShare::try_deserialize(&mut &share.try_borrow_data()?[..])?;
+    require_keys_eq!(rent_payer.key(), key_share.rent_payer, ErrorCode::Unauthorized);
+
+    **rent_payer.try_borrow_mut_lamports()? += share.lamports();
+    **share.try_borrow_mut_lamports()? = 0;
+    share.assign(&system_program::ID);
+    share.resize(0)?;
+
+    Ok(Some((key_share.owner, key_share.key_version)))
+}
+
+// Ties a BlockEntry to the pair whose PairBlock it counts toward, without
+// storing the peer
+fn pair_block_tag(hash: &[u8; 32], blinded_peer: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(hash);
+    hasher.update(blinded_peer);
+    hasher.finalize().into()
+}
+
+// Take a closed BlockEntry's count off its pair's PairBlock, closing the
+// marker with the last one
+fn release_pair_block<'info>(
+    entry: &BlockEntry,
+    wallet: Pubkey,
+    peer: Pubkey,
+    pair_block: &mut Account<'info, PairBlock>,
+    rent_payer: &AccountInfo<'info>,
+) -> Result<()> {
+    let hash = get_chat_hash(wallet, peer);
+    require!(entry.pair_tag == pair_block_tag(&hash, &entry.blinded_peer), ErrorCode::Unauthorized);
+
+    pair_block.blocks -= 1;
+    if pair_block.blocks == 0 {
+        require_keys_eq!(rent_payer.key(), pair_block.rent_payer, ErrorCode::Unauthorized);
+        pair_block.close(rent_payer.clone())?;
+    }
+
+    Ok(())
+}
+
+// Retire a profile key version on its owner's profile, read raw because the
+// owner may be gone (account deletion) or still on the legacy layout
+fn retire_profile_key(profile: &AccountInfo, version: u32) -> Result<()> {
+    if profile.data_is_empty() || profile.data_len() == LEGACY_USER_PROFILE_SPACE {
+        return Ok(());
+    }
+    let mut user_profile = UserProfile::try_deserialize(&mut &profile.try_borrow_data()?[..])?;
+    user_profile.retire_profile_key(version);
+    user_profile.try_serialize(&mut &mut profile.try_borrow_mut_data()?[..])?;
+
+    Ok(())
+}
+
+// Token deposits sit in the escrow's associated token account for the
+// deposit mint, nowhere else
+fn check_escrow_token_account(escrow_token_account: &Account<TokenAccount>, escrow: Pubkey, mint: Pubkey) -> Result<()> {
+    require_keys_eq!(
+        escrow_token_account.key(),
+        get_associated_token_address(&escrow, &mint),
+        ErrorCode::InvalidTokenAccount
+    );
+    require_keys_eq!(escrow_token_account.owner, escrow, ErrorCode::InvalidTokenAccount);
+    require_keys_eq!(escrow_token_account.mint, mint, ErrorCode::InvalidTokenAccount);
+    Ok(())
+}
+
+// Pay the escrowed invite deposit to the invitee keeping it (`keeper`), or
+// refund it to whoever funded it when None: lamports to the rent payer
+// (the inviter's fee payer), tokens to the inviter's token account. Then
+// close the escrow, returning its rent (and the escrow token account's
+// rent) to whoever paid it.
+fn release_invite_deposit<'info>(
+    escrow: &Account<'info, InviteEscrow>,
+    escrow_token_account: Option<&Account<'info, TokenAccount>>,
+    keeper: Option<&AccountInfo<'info>>,
+    recipient_token_account: Option<&Account<'info, TokenAccount>>,
+    rent_payer: &AccountInfo<'info>,
+    token_program: Option<&Program<'info, Token>>,
+) -> Result<()> {
+    require_keys_eq!(rent_payer.key(), escrow.rent_payer, ErrorCode::Unauthorized);
+    if let Some(keeper) = keeper {
+        require_keys_eq!(keeper.key(), escrow.invitee, ErrorCode::Unauthorized);
+    }
+
+    match escrow.mint {
+        None => {
+            let recipient = keeper.unwrap_or(rent_payer);
+            **escrow.to_account_info().try_borrow_mut_lamports()? -= escrow.amount;
+            **recipient.try_borrow_mut_lamports()? += escrow.amount;
+        },
+        Some(mint) => {
+            let escrow_token_account = escrow_token_account.ok_or(ErrorCode::TokenAccountRequired)?;
+            let recipient_token_account = recipient_token_account.ok_or(ErrorCode::TokenAccountRequired)?;
+            let token_program = token_program.ok_or(ErrorCode::TokenAccountRequired)?;
+
+            check_escrow_token_account(escrow_token_account, escrow.key(), mint)?;
+            let recipient = keeper.map_or(escrow.inviter, |keeper| keeper.key());
+            require_keys_eq!(recipient_token_account.owner, recipient, ErrorCode::InvalidTokenAccount);
+            require_keys_eq!(recipient_token_account.mint, mint, ErrorCode::InvalidTokenAccount);
+
+            let hash = get_chat_hash(escrow.inviter, escrow.invitee);
+            let signer_seeds: &[&[u8]] = &[
+                b"invite_escrow",
+                hash.as_ref(),
+                INVITE_ESCROW_VERSION.as_ref(),
+                &[escrow.bump],
+            ];
+
+            token::transfer(
+                CpiContext::new_with_signer(
+                    token_program.to_account_info(),
+                    Transfer {
+                        from: escrow_token_account.to_account_info(),
+                        to: recipient_token_account.to_account_info(),
+                        authority: escrow.to_account_info(),
+                    },
+                    &[signer_seeds],
+                ),
+                escrow.amount,
+            )?;
+            token::close_account(CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                CloseAccount {
+                    account: escrow_token_account.to_account_info(),
+                    destination: rent_payer.clone(),
+                    authority: escrow.to_account_info(),
+                },
+                &[signer_seeds],
+            ))?;
+        },
+    }
+
+    escrow.close(rent_payer.clone())
+}
+
 #[arcium_program]
 pub mod mukon_messenger {
     use super::*;
@@ -80,27 +801,41 @@ pub mod mukon_messenger {
     pub fn register(ctx: Context<Register>, display_name: String, avatar_data: String, encryption_public_key: [u8; 32]) -> Result<()> {
         let wallet_descriptor = &mut ctx.accounts.wallet_descriptor;
         let user_profile = &mut ctx.accounts.user_profile;
-        let payer = &ctx.accounts.payer;
+        let authority = &ctx.accounts.authority;
 
         require!(display_name.len() <= 32, ErrorCode::DisplayNameTooLong);
+        // A linked wallet already belongs to its primary's identity
+        require!(ctx.accounts.wallet_link.data_is_empty(), ErrorCode::InvalidWalletLink);
 
-        // Only initialize peers if this is a new account (not created by an invite)
-        // If account was created by invite instruction, peers already has pending invitations
-        if wallet_descriptor.owner == Pubkey::default() {
-            wallet_descriptor.owner = payer.key();
-            wallet_descriptor.peers = vec![];
-        } else {
-            // Account exists (created by invite) - just update owner, preserve peers
-            wallet_descriptor.owner = payer.key();
-        }
+        wallet_descriptor.owner = authority.key();
+        wallet_descriptor.invite_epoch = 0;
+        wallet_descriptor.invites_this_epoch = 0;
+        wallet_descriptor.session_keys = 0;
 
-        user_profile.owner = payer.key();
+        user_profile.owner = authority.key();
+        user_profile.rent_payer = ctx.accounts.fee_payer.key();
+        user_profile.layout_version = PROFILE_LAYOUT_VERSION;
         user_profile.display_name = display_name.clone();
         user_profile.avatar_type = AvatarType::Emoji;
         user_profile.avatar_data = avatar_data;
         user_profile.encryption_public_key = encryption_public_key;
+        user_profile.key_epoch = 0;
+        user_profile.invite_deposit = None;
+        user_profile.invite_policy = InvitePolicy::Anyone;
+        user_profile.visibility = ProfileVisibility::Public;
+
+        let key_record = &mut ctx.accounts.key_record;
+        key_record.owner = authority.key();
+        key_record.epoch = 0;
+        key_record.key = encryption_public_key;
+        key_record.activated_at = Clock::get()?.unix_timestamp;
+        key_record.rent_payer = ctx.accounts.fee_payer.key();
+        key_record.log_index = None;
+        if let Some(key_log) = ctx.accounts.key_log.as_mut() {
+            log_key(key_log, key_record)?;
+        }
 
-        msg!("Register: {:?} with display name: {}", payer.key(), display_name);
+        msg!("Register: {:?} with display name: {}", authority.key(), display_name);
 
         Ok(())
     }
@@ -114,11 +849,18 @@ pub mod mukon_messenger {
     ) -> Result<()> {
         let user_profile = &mut ctx.accounts.user_profile;
 
+        // Contacts-only profiles keep these in the sealed details
+        if display_name.is_some() || avatar_type.is_some() || avatar_data.is_some() {
+            require!(user_profile.visibility == ProfileVisibility::Public, ErrorCode::ProfileIsPrivate);
+        }
+
         if let Some(name) = display_name {
             require!(name.len() <= 32, ErrorCode::DisplayNameTooLong);
             user_profile.display_name = name;
         }
 
+        let avatar_changed = avatar_type.is_some() || avatar_data.is_some();
+
         if let Some(atype) = avatar_type {
             user_profile.avatar_type = atype;
         }
@@ -127,11 +869,240 @@ pub mod mukon_messenger {
             user_profile.avatar_data = adata;
         }
 
-        if let Some(key) = encryption_public_key {
-            user_profile.encryption_public_key = key;
+        // NFT avatars must be held by the profile owner
+        if avatar_changed && user_profile.avatar_type == AvatarType::Nft {
+            let token_account = ctx.accounts.avatar_token_account.as_ref()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+            let mint = ctx.accounts.avatar_mint.as_ref()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+
+            check_nft_holder(token_account, mint, user_profile.owner)?;
+            require!(user_profile.avatar_data == mint.key().to_string(), ErrorCode::AvatarMintMismatch);
+        }
+
+        // A new key starts a new epoch with its own record, so peers can
+        // detect the change and pick the right key for old messages
+        let key_change = encryption_public_key.filter(|key| *key != user_profile.encryption_public_key);
+        match (key_change, ctx.accounts.key_record.as_mut()) {
+            (Some(key), Some(key_record)) => {
+                let key_log = ctx.accounts.key_log.as_mut().ok_or(ErrorCode::KeyRecordMismatch)?;
+                rotate_encryption_key(user_profile, key_record, key_log, key, ctx.accounts.fee_payer.key())?;
+            },
+            (None, None) => {},
+            _ => return Err(ErrorCode::KeyRecordMismatch.into()),
+        }
+
+        msg!("Profile updated: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    /// Replace the optional profile details. The account grows or shrinks to
+    /// fit what is set, so unused fields cost no rent.
+    pub fn update_profile_details(
+        ctx: Context<UpdateProfileDetails>,
+        bio: String,
+        status: String,
+        links: Vec<String>,
+        avatar_content: Option<AvatarContent>
+    ) -> Result<()> {
+        require!(bio.len() <= MAX_BIO_LEN, ErrorCode::BioTooLong);
+        require!(status.len() <= MAX_STATUS_LEN, ErrorCode::StatusTooLong);
+        require!(
+            links.len() <= MAX_PROFILE_LINKS && links.iter().all(|l| l.len() <= MAX_LINK_LEN),
+            ErrorCode::InvalidLinks
+        );
+
+        let user_profile = &mut ctx.accounts.user_profile;
+        require!(user_profile.visibility == ProfileVisibility::Public, ErrorCode::ProfileIsPrivate);
+
+        user_profile.bio = bio;
+        user_profile.status = status;
+        user_profile.links = links;
+        user_profile.avatar_content = avatar_content;
+
+        msg!("Profile details updated: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    /// Switch between a public profile and a contacts-only one. Contacts-only
+    /// profiles clear the plaintext name, avatar, badges and details and keep
+    /// them in `sealed`, encrypted with a profile key that is wrapped for each
+    /// contact with `share_profile_key`. Call again with new sealed details to
+    /// update them; a key version retired by removing a contact can't be reused.
+    pub fn set_profile_visibility(
+        ctx: Context<SetProfileVisibility>,
+        visibility: ProfileVisibility,
+        sealed: Option<SealedProfile>
+    ) -> Result<()> {
+        let user_profile = &mut ctx.accounts.user_profile;
+
+        match (visibility, &sealed) {
+            (ProfileVisibility::Public, None) => {},
+            (ProfileVisibility::ContactsOnly, Some(s)) => {
+                require!(s.ciphertext.len() <= MAX_SEALED_PROFILE_LEN, ErrorCode::SealedProfileTooLong);
+                require!(s.key_version >= user_profile.profile_key_floor, ErrorCode::ProfileKeyRetired);
+
+                // Badges and NFT avatars would name the wallet's NFTs
+                user_profile.display_name = String::new();
+                user_profile.avatar_type = AvatarType::Emoji;
+                user_profile.avatar_data = String::new();
+                user_profile.bio = String::new();
+                user_profile.status = String::new();
+                user_profile.links = Vec::new();
+                user_profile.avatar_content = None;
+                user_profile.badges.clear();
+            },
+            _ => return Err(ErrorCode::SealedProfileMismatch.into()),
+        }
+
+        user_profile.visibility = visibility;
+        user_profile.sealed = sealed;
+
+        msg!("Profile visibility updated: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    /// Wrap the profile key for an accepted contact so they can read the
+    /// sealed details. Overwrites the previous share when the key rotates.
+    pub fn share_profile_key(
+        ctx: Context<ShareProfileKey>,
+        wrapped_key: Vec<u8>,
+        nonce: [u8; 24],
+        key_version: u32
+    ) -> Result<()> {
+        let me = &ctx.accounts.authority;
+        let contact = &ctx.accounts.contact;
+
+        require!(ctx.accounts.relationship.is_mutual_contact(), ErrorCode::NotMutualContact);
+        require!(wrapped_key.len() <= 48, ErrorCode::WrappedKeyTooLong);
+        require!(key_version >= ctx.accounts.user_profile.profile_key_floor, ErrorCode::ProfileKeyRetired);
+
+        let share = &mut ctx.accounts.profile_key_share;
+        if share.owner == Pubkey::default() {
+            share.rent_payer = ctx.accounts.fee_payer.key();
+        }
+        share.owner = me.key();
+        share.contact = contact.key();
+        share.wrapped_key = wrapped_key;
+        share.nonce = nonce;
+        share.key_version = key_version;
+
+        msg!("Profile key shared: owner={:?}, contact={:?}", me.key(), contact.key());
+
+        Ok(())
+    }
+
+    /// Delete a contact's profile key share, by either side
+    pub fn close_profile_key(ctx: Context<CloseProfileKey>) -> Result<()> {
+        let share = &ctx.accounts.profile_key_share;
+        let signer = ctx.accounts.authority.key();
+
+        require!(signer == share.owner || signer == share.contact, ErrorCode::Unauthorized);
+
+        msg!("Profile key share closed: owner={:?}, contact={:?}", share.owner, share.contact);
+
+        Ok(())
+    }
+
+    /// Require inviters to escrow a deposit (lamports or an SPL token) when
+    /// inviting this wallet. Pass None to accept invites for free. Whoever
+    /// funded it (the inviter's fee payer for lamports, the inviter's token
+    /// account for tokens) gets it back on accept, cancel_invite,
+    /// expire_invite and delete_relationship. The invitee keeps it on
+    /// reject, or on block when the pending invite's accounts are passed.
+    pub fn set_invite_deposit(ctx: Context<UpdateProfile>, deposit: Option<InviteDeposit>) -> Result<()> {
+        if let Some(d) = &deposit {
+            require!(d.amount > 0, ErrorCode::InvalidDeposit);
+        }
+
+        ctx.accounts.user_profile.invite_deposit = deposit;
+
+        msg!("Invite deposit updated: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    /// Choose which wallets may invite this wallet
+    pub fn set_invite_policy(ctx: Context<UpdateProfile>, policy: InvitePolicy) -> Result<()> {
+        ctx.accounts.user_profile.invite_policy = policy;
+
+        msg!("Invite policy updated: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    /// Show a verified-collection badge for an NFT this wallet holds
+    pub fn add_collection_badge(ctx: Context<AddCollectionBadge>) -> Result<()> {
+        let user_profile = &mut ctx.accounts.user_profile;
+        let mint = &ctx.accounts.mint;
+
+        require!(user_profile.visibility == ProfileVisibility::Public, ErrorCode::ProfileIsPrivate);
+        check_nft_holder(&ctx.accounts.token_account, mint, user_profile.owner)?;
+        let collection = verified_collection(&ctx.accounts.metadata, mint.key())?
+            .ok_or(ErrorCode::UnverifiedCollection)?;
+
+        // One badge per collection; re-adding switches the backing NFT
+        user_profile.badges.retain(|b| b.collection != collection);
+        require!(user_profile.badges.len() < MAX_COLLECTION_BADGES, ErrorCode::TooManyBadges);
+        user_profile.badges.push(CollectionBadge { collection, mint: mint.key() });
+
+        msg!("Collection badge added: owner={:?}, collection={:?}", user_profile.owner, collection);
+
+        Ok(())
+    }
+
+    pub fn remove_collection_badge(ctx: Context<RemoveCollectionBadge>, collection: Pubkey) -> Result<()> {
+        let user_profile = &mut ctx.accounts.user_profile;
+
+        let before = user_profile.badges.len();
+        user_profile.badges.retain(|b| b.collection != collection);
+        require!(user_profile.badges.len() < before, ErrorCode::BadgeNotFound);
+
+        msg!("Collection badge removed: owner={:?}, collection={:?}", user_profile.owner, collection);
+
+        Ok(())
+    }
+
+    /// Anyone can drop an NFT avatar or badge once the NFT has moved, by
+    /// showing the token account that holds it now
+    pub fn invalidate_nft(ctx: Context<InvalidateNft>) -> Result<()> {
+        let user_profile = &mut ctx.accounts.user_profile;
+        let holder = &ctx.accounts.holder_token_account;
+        let mint = &ctx.accounts.mint;
+
+        require!(holder.owner != user_profile.owner, ErrorCode::NftStillHeld);
+        check_nft_holder(holder, mint, holder.owner)?;
+
+        let mut invalidated = false;
+        if user_profile.avatar_type == AvatarType::Nft && user_profile.avatar_data == mint.key().to_string() {
+            user_profile.avatar_type = AvatarType::Emoji;
+            user_profile.avatar_data = String::new();
+            invalidated = true;
+        }
+
+        let before = user_profile.badges.len();
+        user_profile.badges.retain(|b| b.mint != mint.key());
+        invalidated |= user_profile.badges.len() < before;
+
+        require!(invalidated, ErrorCode::BadgeNotFound);
+
+        msg!("NFT invalidated: owner={:?}, mint={:?}", user_profile.owner, mint.key());
+
+        Ok(())
+    }
+
+    /// Register a secp256r1 passkey (33-byte compressed key) that can sign
+    /// accept, block and store_group_key instead of the wallet. None removes it.
+    pub fn set_passkey(ctx: Context<UpdateProfile>, passkey: Option<[u8; 33]>) -> Result<()> {
+        if let Some(key) = passkey {
+            require!(matches!(key[0], 0x02 | 0x03), ErrorCode::InvalidPasskey);
         }
+        ctx.accounts.user_profile.passkey = passkey;
 
-        msg!("Profile updated: {:?}", ctx.accounts.payer.key());
+        msg!("Passkey updated: {:?}", ctx.accounts.authority.key());
 
         Ok(())
     }
@@ -143,7 +1114,7 @@ pub mod mukon_messenger {
         let (expected_profile_pda, _) = Pubkey::find_program_address(
             &[
                 b"user_profile",
-                ctx.accounts.payer.key().as_ref(),
+                ctx.accounts.authority.key().as_ref(),
                 USER_PROFILE_VERSION.as_ref(),
             ],
             ctx.program_id,
@@ -159,7 +1130,7 @@ pub mod mukon_messenger {
         let (expected_descriptor_pda, _) = Pubkey::find_program_address(
             &[
                 b"wallet_descriptor",
-                ctx.accounts.payer.key().as_ref(),
+                ctx.accounts.authority.key().as_ref(),
                 WALLET_DESCRIPTOR_VERSION.as_ref(),
             ],
             ctx.program_id,
@@ -171,129 +1142,270 @@ pub mod mukon_messenger {
             ErrorCode::InvalidHash
         );
 
+        // Rent goes back to whoever paid for registration (rent_payer follows
+        // owner in UserProfile); legacy and truncated accounts refund the owner
+        let expected_rent_payer = {
+            let data = ctx.accounts.user_profile.try_borrow_data()?;
+            match data.get(8 + 32..8 + 32 + 32) {
+                Some(bytes) if data.len() != LEGACY_USER_PROFILE_SPACE => Pubkey::try_from(bytes).unwrap(),
+                _ => ctx.accounts.authority.key(),
+            }
+        };
+        require_keys_eq!(ctx.accounts.rent_payer.key(), expected_rent_payer, ErrorCode::Unauthorized);
+
         // Close UserProfile
         let profile_lamports = ctx.accounts.user_profile.lamports();
         **ctx.accounts.user_profile.lamports.borrow_mut() = 0;
-        **ctx.accounts.payer.lamports.borrow_mut() += profile_lamports;
+        **ctx.accounts.rent_payer.lamports.borrow_mut() += profile_lamports;
         ctx.accounts.user_profile.try_borrow_mut_data()?.fill(0);
 
         // Close WalletDescriptor
         let descriptor_lamports = ctx.accounts.wallet_descriptor.lamports();
         **ctx.accounts.wallet_descriptor.lamports.borrow_mut() = 0;
-        **ctx.accounts.payer.lamports.borrow_mut() += descriptor_lamports;
+        **ctx.accounts.rent_payer.lamports.borrow_mut() += descriptor_lamports;
         ctx.accounts.wallet_descriptor.try_borrow_mut_data()?.fill(0);
 
-        msg!("Profile and descriptor closed: {:?}", ctx.accounts.payer.key());
+        msg!("Profile and descriptor closed: {:?}", ctx.accounts.authority.key());
         Ok(())
     }
 
-    pub fn invite(ctx: Context<Invite>, _hash: [u8; 32]) -> Result<()> {
-        let inviter = &ctx.accounts.payer;
-        let invitee = &ctx.accounts.invitee;
-        let inviter_descriptor = &mut ctx.accounts.payer_descriptor;
-        let invitee_descriptor = &mut ctx.accounts.invitee_descriptor;
+    pub fn invite(
+        ctx: Context<Invite>,
+        _hash: [u8; 32],
+        expires_at: Option<i64>,
+        intro: Option<EncryptedIntro>
+    ) -> Result<()> {
+        let inviter = &ctx.accounts.authority;
+        require!(
+            ctx.accounts.invitee.is_some() != ctx.accounts.invitee_handle.is_some(),
+            ErrorCode::InviteeAccountMismatch
+        );
+        // A linked wallet is invited as the identity it belongs to
+        let invitee = resolve_invitee(
+            addressed_invitee(&ctx.accounts.invitee, &ctx.accounts.invitee_handle),
+            &ctx.accounts.invitee_link,
+        );
+        let inviter_descriptor = &mut ctx.accounts.authority_descriptor;
+        let relationship = &mut ctx.accounts.relationship;
 
-        // Initialize invitee_descriptor if it's a new account
-        if invitee_descriptor.owner == Pubkey::default() {
-            invitee_descriptor.owner = invitee.key();
-            invitee_descriptor.peers = vec![];
-        }
+        require_keys_neq!(inviter.key(), invitee, ErrorCode::CannotInviteSelf);
 
-        let hash = get_chat_hash(inviter.key(), invitee.key());
+        let hash = get_chat_hash(inviter.key(), invitee);
         require!(hash == _hash, ErrorCode::InvalidHash);
 
-        // Check inviter's side: allow re-invite if Rejected, block if Blocked
-        let inviter_peer = inviter_descriptor.peers.iter_mut()
-            .find(|p| p.wallet == invitee.key());
+        // A blocked pair reads as an existing relationship
+        require!(ctx.accounts.pair_block.data_is_empty(), ErrorCode::AlreadyInvited);
 
-        match inviter_peer {
-            Some(peer) if peer.state == PeerState::Rejected => {
-                // Re-inviting rejected contact - update state
-                peer.state = PeerState::Invited;
-            },
-            Some(peer) if peer.state == PeerState::Blocked => {
-                // Cannot invite blocked user
-                return Err(ErrorCode::AlreadyInvited.into());
-            },
-            Some(_) => {
-                // Peer exists with non-Rejected/non-Blocked status
-                return Err(ErrorCode::AlreadyInvited.into());
+        // Invitee's inbound settings - unregistered wallets accept anyone for free
+        let invitee_profile = &ctx.accounts.invitee_profile;
+        let (invite_policy, required_deposit) = if invitee_profile.data_is_empty() {
+            (InvitePolicy::Anyone, None)
+        } else {
+            // A legacy layout profile has to go through migrate_profile first
+            require!(invitee_profile.data_len() != LEGACY_USER_PROFILE_SPACE, ErrorCode::ProfileNotMigrated);
+            let profile = UserProfile::try_deserialize(&mut &invitee_profile.try_borrow_data()?[..])?;
+            (profile.invite_policy, profile.invite_deposit)
+        };
+
+        check_invite_policy(
+            &invite_policy,
+            inviter.key(),
+            invitee,
+            GateProof {
+                token_account: ctx.accounts.gate_token_account.as_deref(),
+                metadata: ctx.accounts.gate_metadata.as_ref(),
+                wallet_link: ctx.accounts.gate_wallet_link.as_deref(),
             },
-            None => {
-                // New peer - add to list
-                inviter_descriptor.peers.push(Peer {
-                    wallet: invitee.key(),
-                    state: PeerState::Invited,
-                });
-            }
+            ctx.accounts.inviter_mutual_relationship.as_deref(),
+            ctx.accounts.invitee_mutual_relationship.as_deref(),
+        )?;
+
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+
+        // Per-wallet cap on outgoing invites, reset every epoch
+        if inviter_descriptor.invite_epoch != clock.epoch {
+            inviter_descriptor.invite_epoch = clock.epoch;
+            inviter_descriptor.invites_this_epoch = 0;
         }
+        require!(
+            inviter_descriptor.invites_this_epoch < MAX_INVITES_PER_EPOCH,
+            ErrorCode::InviteRateLimited
+        );
+        inviter_descriptor.invites_this_epoch += 1;
 
-        // Check invitee's side: allow re-invite if Rejected, block if Blocked
-        let invitee_peer = invitee_descriptor.peers.iter_mut()
-            .find(|p| p.wallet == inviter.key());
+        if let Some(expiry) = expires_at {
+            require!(expiry > now, ErrorCode::InvalidExpiry);
+        }
 
-        match invitee_peer {
-            Some(peer) if peer.state == PeerState::Rejected => {
-                // Re-inviting rejected contact - update state
-                peer.state = PeerState::Requested;
-            },
-            Some(peer) if peer.state == PeerState::Blocked => {
-                // Cannot invite blocked user
-                return Err(ErrorCode::AlreadyInvited.into());
-            },
-            Some(_) => {
-                // Peer exists with non-Rejected/non-Blocked status
-                return Err(ErrorCode::AlreadyInvited.into());
-            },
-            None => {
-                // New peer - add to list
-                invitee_descriptor.peers.push(Peer {
-                    wallet: inviter.key(),
-                    state: PeerState::Requested,
-                });
+        if relationship.participants[0] == Pubkey::default() {
+            // New relationship - initialize the pair
+            relationship.participants = sorted_pair(inviter.key(), invitee);
+            relationship.created_at = now;
+            relationship.rent_payer = ctx.accounts.fee_payer.key();
+
+            // Rejections collected under a deleted identity still count
+            if !ctx.accounts.tombstone.data_is_empty() {
+                let tombstone = RejectionTombstone::try_deserialize(&mut &ctx.accounts.tombstone.try_borrow_data()?[..])?;
+                if tombstone.peer == invitee {
+                    relationship.restore_rejections(inviter.key(), tombstone.rejections, tombstone.last_rejected_at);
+                }
             }
+        } else {
+            // Re-inviting is only allowed once both sides are Rejected;
+            // any pending/accepted state cannot be re-invited
+            require!(
+                relationship.state_of(inviter.key()) == Some(PeerState::Rejected),
+                ErrorCode::AlreadyInvited
+            );
+            require!(
+                relationship.state_of(invitee) == Some(PeerState::Rejected),
+                ErrorCode::AlreadyInvited
+            );
         }
 
+        // Each rejection of this inviter doubles the wait before re-inviting
+        require!(
+            now >= relationship.reinvite_allowed_at(inviter.key()),
+            ErrorCode::ReinviteCooldown
+        );
+
+        relationship.set_state(inviter.key(), PeerState::Invited);
+        relationship.set_state(invitee, PeerState::Requested);
+        relationship.invite_expires_at = expires_at;
+        relationship.has_conversation = true;
+        relationship.updated_at = now;
+
         let conversation = &mut ctx.accounts.conversation;
-        conversation.participants = [inviter.key(), invitee.key()];
-        conversation.created_at = Clock::get()?.unix_timestamp;
+        conversation.participants = [inviter.key(), invitee];
+        conversation.created_at = now;
+        conversation.rent_payer = ctx.accounts.fee_payer.key();
+
+        // Lock the invitee's required deposit (if any) in escrow
+        match (required_deposit, ctx.accounts.invite_escrow.as_mut()) {
+            (Some(deposit), Some(escrow)) => {
+                escrow.inviter = inviter.key();
+                escrow.invitee = invitee;
+                escrow.mint = deposit.mint;
+                escrow.amount = deposit.amount;
+                escrow.rent_payer = ctx.accounts.fee_payer.key();
+                escrow.bump = ctx.bumps.invite_escrow.ok_or(ErrorCode::DepositAccountMismatch)?;
+
+                match deposit.mint {
+                    None => {
+                        system_program::transfer(
+                            CpiContext::new(
+                                ctx.accounts.system_program.to_account_info(),
+                                system_program::Transfer {
+                                    from: ctx.accounts.fee_payer.to_account_info(),
+                                    to: escrow.to_account_info(),
+                                },
+                            ),
+                            deposit.amount,
+                        )?;
+                    },
+                    Some(mint) => {
+                        let inviter_token_account = ctx.accounts.inviter_token_account.as_ref()
+                            .ok_or(ErrorCode::TokenAccountRequired)?;
+                        let escrow_token_account = ctx.accounts.escrow_token_account.as_ref()
+                            .ok_or(ErrorCode::TokenAccountRequired)?;
+                        let token_program = ctx.accounts.token_program.as_ref()
+                            .ok_or(ErrorCode::TokenAccountRequired)?;
+
+                        require_keys_eq!(inviter_token_account.owner, inviter.key(), ErrorCode::InvalidTokenAccount);
+                        require_keys_eq!(inviter_token_account.mint, mint, ErrorCode::InsufficientTokenBalance);
+                        check_escrow_token_account(escrow_token_account, escrow.key(), mint)?;
+
+                        token::transfer(
+                            CpiContext::new(
+                                token_program.to_account_info(),
+                                Transfer {
+                                    from: inviter_token_account.to_account_info(),
+                                    to: escrow_token_account.to_account_info(),
+                                    authority: inviter.to_account_info(),
+                                },
+                            ),
+                            deposit.amount,
+                        )?;
+                    },
+                }
+                relationship.has_deposit = true;
+            },
+            (None, None) => relationship.has_deposit = false,
+            _ => return Err(ErrorCode::DepositAccountMismatch.into()),
+        }
+
+        // Optional intro sealed to the invitee's encryption_public_key
+        match (intro, ctx.accounts.invite_note.as_mut()) {
+            (Some(intro), Some(note)) => {
+                require!(intro.ciphertext.len() <= MAX_INTRO_LEN, ErrorCode::IntroTooLong);
+                note.inviter = inviter.key();
+                note.rent_payer = ctx.accounts.fee_payer.key();
+                note.ciphertext = intro.ciphertext;
+                note.nonce = intro.nonce;
+                relationship.has_intro = true;
+            },
+            (None, None) => relationship.has_intro = false,
+            _ => return Err(ErrorCode::IntroAccountMismatch.into()),
+        }
 
         msg!("Invite: sender={:?}, target={:?}, chat={:?}",
-             inviter.key(), invitee.key(), hash);
+             inviter.key(), invitee, hash);
+        if let Some(handle) = &ctx.accounts.invitee_handle {
+            msg!("Invite target handle: @{}", handle.name);
+        }
 
         Ok(())
     }
 
     pub fn accept(ctx: Context<Accept>) -> Result<()> {
-        let me = &ctx.accounts.payer;
+        check_authority_or_passkey(
+            &ctx.accounts.wallet,
+            &ctx.accounts.authority,
+            ctx.accounts.session_key.as_ref(),
+            ctx.accounts.passkey_profile.as_mut(),
+            ctx.accounts.instructions_sysvar.as_ref(),
+            SESSION_ACCEPT,
+            &ctx.accounts.peer.key().to_bytes(),
+        )?;
+
+        let me = &ctx.accounts.wallet;
         let peer = &ctx.accounts.peer;
-        let me_descriptor = &mut ctx.accounts.payer_descriptor;
-        let peer_descriptor = &mut ctx.accounts.peer_descriptor;
+        let relationship = &mut ctx.accounts.relationship;
 
         require!(
-            me_descriptor.peers.iter()
-                .any(|p| p.wallet == peer.key() && p.state == PeerState::Requested),
+            relationship.state_of(me.key()) == Some(PeerState::Requested),
             ErrorCode::NotRequested
         );
         require!(
-            peer_descriptor.peers.iter()
-                .any(|p| p.wallet == me.key() && p.state == PeerState::Invited),
+            relationship.state_of(peer.key()) == Some(PeerState::Invited),
             ErrorCode::NotInvited
         );
 
-        for p in me_descriptor.peers.iter_mut() {
-            if p.wallet == peer.key() {
-                p.state = PeerState::Accepted;
-                break;
-            }
-        }
-        for p in peer_descriptor.peers.iter_mut() {
-            if p.wallet == me.key() {
-                p.state = PeerState::Accepted;
-                break;
-            }
+        let now = Clock::get()?.unix_timestamp;
+        require!(!relationship.is_invite_expired(now), ErrorCode::InviteExpired);
+        close_intro(relationship, &ctx.accounts.invite_note)?;
+
+        // Deposit goes back to the inviter
+        if relationship.has_deposit {
+            let escrow = ctx.accounts.invite_escrow.as_ref()
+                .ok_or(ErrorCode::DepositAccountMismatch)?;
+            release_invite_deposit(
+                escrow,
+                ctx.accounts.escrow_token_account.as_ref(),
+                None,
+                ctx.accounts.recipient_token_account.as_ref(),
+                &ctx.accounts.invite_rent_payer,
+                ctx.accounts.token_program.as_ref(),
+            )?;
+            relationship.has_deposit = false;
         }
 
+        relationship.set_state(me.key(), PeerState::Accepted);
+        relationship.set_state(peer.key(), PeerState::Accepted);
+        relationship.invite_expires_at = None;
+        relationship.updated_at = now;
+
         msg!("Accept: accepter={:?}, inviter={:?}, chat={:?}",
              me.key(), peer.key(), get_chat_hash(me.key(), peer.key()));
 
@@ -301,823 +1413,4763 @@ pub mod mukon_messenger {
     }
 
     pub fn reject(ctx: Context<Reject>) -> Result<()> {
-        let me = &ctx.accounts.payer;
+        check_authority(
+            &ctx.accounts.wallet,
+            &ctx.accounts.authority,
+            ctx.accounts.session_key.as_ref(),
+            SESSION_REJECT,
+        )?;
+
+        let me = &ctx.accounts.wallet;
         let peer = &ctx.accounts.peer;
-        let me_descriptor = &mut ctx.accounts.payer_descriptor;
-        let peer_descriptor = &mut ctx.accounts.peer_descriptor;
+        let relationship = &mut ctx.accounts.relationship;
 
         // Allow rejecting pending invites OR deleting accepted contacts
         require!(
-            me_descriptor.peers.iter()
-                .any(|p| p.wallet == peer.key() &&
-                     (p.state == PeerState::Requested || p.state == PeerState::Accepted)),
+            matches!(
+                relationship.state_of(me.key()),
+                Some(PeerState::Requested) | Some(PeerState::Accepted)
+            ),
             ErrorCode::NotRequested
         );
         require!(
-            peer_descriptor.peers.iter()
-                .any(|p| p.wallet == me.key() &&
-                     (p.state == PeerState::Invited || p.state == PeerState::Accepted)),
+            matches!(
+                relationship.state_of(peer.key()),
+                Some(PeerState::Invited) | Some(PeerState::Accepted)
+            ),
             ErrorCode::NotInvited
         );
 
-        for p in me_descriptor.peers.iter_mut() {
-            if p.wallet == peer.key() {
-                p.state = PeerState::Rejected;
-                break;
-            }
+        let now = Clock::get()?.unix_timestamp;
+
+        // Rejecting a pending invite starts the inviter's re-invite cooldown
+        if relationship.state_of(me.key()) == Some(PeerState::Requested) {
+            relationship.record_rejection(peer.key(), now);
         }
-        for p in peer_descriptor.peers.iter_mut() {
-            if p.wallet == me.key() {
-                p.state = PeerState::Rejected;
-                break;
+        close_intro(relationship, &ctx.accounts.invite_note)?;
+
+        // Dropping an accepted contact ends the profile key shares between
+        // the pair, as remove_contact does
+        if relationship.state_of(me.key()) == Some(PeerState::Accepted) {
+            for (share, rent_payer, owner_profile) in [
+                (&ctx.accounts.my_profile_key_share, &ctx.accounts.my_share_rent_payer, &ctx.accounts.user_profile),
+                (&ctx.accounts.peer_profile_key_share, &ctx.accounts.peer_share_rent_payer, &ctx.accounts.peer_profile),
+            ] {
+                if let Some((_, version)) = close_profile_key_share(share, rent_payer)? {
+                    retire_profile_key(owner_profile, version)?;
+                }
             }
         }
 
+        // Invitee keeps the deposit of a rejected invite
+        if relationship.has_deposit {
+            let escrow = ctx.accounts.invite_escrow.as_ref()
+                .ok_or(ErrorCode::DepositAccountMismatch)?;
+            release_invite_deposit(
+                escrow,
+                ctx.accounts.escrow_token_account.as_ref(),
+                Some(&me.to_account_info()),
+                ctx.accounts.recipient_token_account.as_ref(),
+                &ctx.accounts.invite_rent_payer,
+                ctx.accounts.token_program.as_ref(),
+            )?;
+            relationship.has_deposit = false;
+        }
+
+        relationship.set_state(me.key(), PeerState::Rejected);
+        relationship.set_state(peer.key(), PeerState::Rejected);
+        relationship.invite_expires_at = None;
+        relationship.updated_at = now;
+
         msg!("Reject: rejecter={:?}, inviter={:?}",
              me.key(), peer.key());
 
         Ok(())
     }
 
-    pub fn block(ctx: Context<Block>) -> Result<()> {
-        let me = &ctx.accounts.payer;
-        let peer = &ctx.accounts.peer;
-        let me_descriptor = &mut ctx.accounts.payer_descriptor;
-        let peer_descriptor = &mut ctx.accounts.peer_descriptor;
+    /// Redeem an invite the inviter signed off-chain (QR code / deeplink).
+    /// The transaction must carry an Ed25519 program instruction verifying
+    /// the inviter's signature right before this one. The invitee pays for
+    /// everything, so the inviter needs no SOL and doesn't have to be online.
+    pub fn accept_signed_invite(
+        ctx: Context<AcceptSignedInvite>,
+        _hash: [u8; 32],
+        expires_at: i64,
+        nonce: u64
+    ) -> Result<()> {
+        let me = &ctx.accounts.authority;
+        let inviter = &ctx.accounts.inviter;
+        let relationship = &mut ctx.accounts.relationship;
 
-        // Can block anyone you have a relationship with (any state except doesn't exist)
-        require!(
-            me_descriptor.peers.iter()
-                .any(|p| p.wallet == peer.key()),
-            ErrorCode::NotInvited
-        );
-        require!(
-            peer_descriptor.peers.iter()
-                .any(|p| p.wallet == me.key()),
-            ErrorCode::NotInvited
-        );
+        require_keys_neq!(me.key(), inviter.key(), ErrorCode::CannotInviteSelf);
 
-        // Set both sides to Blocked (symmetric)
-        for p in me_descriptor.peers.iter_mut() {
-            if p.wallet == peer.key() {
-                p.state = PeerState::Blocked;
-                break;
-            }
-        }
-        for p in peer_descriptor.peers.iter_mut() {
-            if p.wallet == me.key() {
-                p.state = PeerState::Blocked;
-                break;
-            }
+        let hash = get_chat_hash(me.key(), inviter.key());
+        require!(hash == _hash, ErrorCode::InvalidHash);
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < expires_at, ErrorCode::InviteExpired);
+        require!(ctx.accounts.pair_block.data_is_empty(), ErrorCode::AlreadyInvited);
+
+        let verified = precompile_verified(
+            &ctx.accounts.instructions_sysvar,
+            ED25519_PROGRAM_ID,
+            inviter.key().as_ref(),
+            &signed_invite_message(&hash, expires_at, nonce),
+        )?;
+        require!(verified, ErrorCode::InvalidInviteSignature);
+
+        if relationship.participants[0] == Pubkey::default() {
+            // New relationship - initialize the pair
+            relationship.participants = sorted_pair(me.key(), inviter.key());
+            relationship.created_at = now;
+            relationship.rent_payer = ctx.accounts.fee_payer.key();
+        } else {
+            // Only idle relationships - pending invites go through accept
+            require!(
+                relationship.states == [PeerState::Rejected, PeerState::Rejected],
+                ErrorCode::AlreadyInvited
+            );
         }
 
-        msg!("Block: blocker={:?}, blocked={:?}",
-             me.key(), peer.key());
+        relationship.set_state(me.key(), PeerState::Accepted);
+        relationship.set_state(inviter.key(), PeerState::Accepted);
+        relationship.invite_expires_at = None;
+        relationship.has_conversation = true;
+        relationship.updated_at = now;
+
+        let conversation = &mut ctx.accounts.conversation;
+        conversation.participants = [inviter.key(), me.key()];
+        conversation.created_at = now;
+        conversation.rent_payer = ctx.accounts.fee_payer.key();
+
+        let used = &mut ctx.accounts.used_invite_token;
+        used.inviter = inviter.key();
+        used.nonce = nonce;
+        used.redeemed_by = me.key();
+
+        msg!("Signed invite accepted: accepter={:?}, inviter={:?}, chat={:?}",
+             me.key(), inviter.key(), hash);
 
         Ok(())
     }
 
-    pub fn unblock(ctx: Context<Unblock>) -> Result<()> {
-        let me = &ctx.accounts.payer;
+    /// Withdraw a pending invite. Closes the conversation (and the
+    /// relationship if it has no history), returning rent to whoever paid.
+    pub fn cancel_invite(ctx: Context<CancelInvite>) -> Result<()> {
+        let me = &ctx.accounts.authority;
         let peer = &ctx.accounts.peer;
-        let me_descriptor = &mut ctx.accounts.payer_descriptor;
-        let peer_descriptor = &mut ctx.accounts.peer_descriptor;
+        let relationship = &mut ctx.accounts.relationship;
 
-        // Can only unblock if currently blocked
         require!(
-            me_descriptor.peers.iter()
-                .any(|p| p.wallet == peer.key() && p.state == PeerState::Blocked),
+            relationship.state_of(me.key()) == Some(PeerState::Invited),
             ErrorCode::NotInvited
         );
         require!(
-            peer_descriptor.peers.iter()
-                .any(|p| p.wallet == me.key() && p.state == PeerState::Blocked),
-            ErrorCode::NotInvited
+            relationship.state_of(peer.key()) == Some(PeerState::Requested),
+            ErrorCode::NotRequested
         );
-
-        // Change Blocked → Rejected (allows re-invite after unblock)
-        for p in me_descriptor.peers.iter_mut() {
-            if p.wallet == peer.key() {
-                p.state = PeerState::Rejected;
-                break;
-            }
-        }
-        for p in peer_descriptor.peers.iter_mut() {
-            if p.wallet == me.key() {
-                p.state = PeerState::Rejected;
-                break;
-            }
+        close_intro(relationship, &ctx.accounts.invite_note)?;
+
+        if relationship.has_deposit {
+            let escrow = ctx.accounts.invite_escrow.as_ref()
+                .ok_or(ErrorCode::DepositAccountMismatch)?;
+            release_invite_deposit(
+                escrow,
+                ctx.accounts.escrow_token_account.as_ref(),
+                None,
+                ctx.accounts.recipient_token_account.as_ref(),
+                &ctx.accounts.invite_rent_payer,
+                ctx.accounts.token_program.as_ref(),
+            )?;
         }
 
-        msg!("Unblock: unblocker={:?}, unblocked={:?}",
+        withdraw_invite(relationship, &ctx.accounts.rent_payer, Clock::get()?.unix_timestamp)?;
+
+        msg!("Invite cancelled: inviter={:?}, invitee={:?}",
              me.key(), peer.key());
 
         Ok(())
     }
 
-    // ========== GROUP CHAT INSTRUCTIONS ==========
+    /// Clean up an invite past its expiry. Callable by anyone; rent goes
+    /// back to whoever paid for the relationship and conversation.
+    pub fn expire_invite(ctx: Context<ExpireInvite>) -> Result<()> {
+        let inviter = &ctx.accounts.inviter;
+        let invitee = &ctx.accounts.invitee;
+        let relationship = &mut ctx.accounts.relationship;
 
-    pub fn create_group(
-        ctx: Context<CreateGroup>,
-        group_id: [u8; 32],
-        name: String,
-        encryption_pubkey: [u8; 32],
-        token_gate: Option<TokenGate>
-    ) -> Result<()> {
-        require!(name.len() <= 64, ErrorCode::GroupNameTooLong);
+        require!(
+            relationship.state_of(inviter.key()) == Some(PeerState::Invited),
+            ErrorCode::NotInvited
+        );
+        require!(
+            relationship.state_of(invitee.key()) == Some(PeerState::Requested),
+            ErrorCode::NotRequested
+        );
+        let now = Clock::get()?.unix_timestamp;
+        require!(relationship.is_invite_expired(now), ErrorCode::InviteNotExpired);
+        close_intro(relationship, &ctx.accounts.invite_note)?;
+
+        if relationship.has_deposit {
+            let escrow = ctx.accounts.invite_escrow.as_ref()
+                .ok_or(ErrorCode::DepositAccountMismatch)?;
+            release_invite_deposit(
+                escrow,
+                ctx.accounts.escrow_token_account.as_ref(),
+                None,
+                ctx.accounts.recipient_token_account.as_ref(),
+                &ctx.accounts.invite_rent_payer,
+                ctx.accounts.token_program.as_ref(),
+            )?;
+        }
 
-        let group = &mut ctx.accounts.group;
-        group.group_id = group_id;
-        group.creator = ctx.accounts.payer.key();
-        group.name = name.clone();
-        group.created_at = Clock::get()?.unix_timestamp;
-        group.members = vec![ctx.accounts.payer.key()];
-        group.encryption_pubkey = encryption_pubkey;
-        group.token_gate = token_gate;
+        withdraw_invite(relationship, &ctx.accounts.rent_payer, now)?;
 
-        msg!("Group created: id={:?}, name={}, creator={:?}",
-             group_id, name, ctx.accounts.payer.key());
+        msg!("Invite expired: inviter={:?}, invitee={:?}",
+             inviter.key(), invitee.key());
 
         Ok(())
     }
 
-    pub fn update_group(
-        ctx: Context<UpdateGroup>,
-        name: Option<String>,
-        token_gate: Option<TokenGate>
-    ) -> Result<()> {
-        let group = &mut ctx.accounts.group;
+    /// Delete a relationship for both sides and close its conversation,
+    /// returning rent to whoever paid for them
+    pub fn remove_contact(ctx: Context<RemoveContact>) -> Result<()> {
+        let me = &ctx.accounts.authority;
+        let peer = &ctx.accounts.peer;
 
-        // Only creator can update group
+        let relationship = &ctx.accounts.relationship;
+        check_contact_removable(relationship, me.key(), peer.key())?;
         require!(
-            group.creator == ctx.accounts.payer.key(),
-            ErrorCode::NotGroupAdmin
+            relationship.has_conversation == ctx.accounts.conversation.is_some(),
+            ErrorCode::ConversationMismatch
         );
 
-        if let Some(new_name) = name {
-            require!(new_name.len() <= 64, ErrorCode::GroupNameTooLong);
-            group.name = new_name;
+        // Neither side keeps getting the other's profile key, and both must
+        // rotate before sealing their profile again
+        let accounts = &ctx.accounts;
+        for (share, rent_payer, owner_profile) in [
+            (&accounts.my_profile_key_share, &accounts.my_share_rent_payer, &accounts.user_profile),
+            (&accounts.peer_profile_key_share, &accounts.peer_share_rent_payer, &accounts.peer_profile),
+        ] {
+            if let Some((_, version)) = close_profile_key_share(share, rent_payer)? {
+                retire_profile_key(owner_profile, version)?;
+            }
         }
 
-        if let Some(new_gate) = token_gate {
-            group.token_gate = Some(new_gate);
+        msg!("Contact removed: remover={:?}, peer={:?}",
+             me.key(), peer.key());
+
+        Ok(())
+    }
+
+    /// Block a wallet. The entry lives at the blocker's own address, keyed
+    /// by `blinded_peer` (a hash of the peer under a salt only the
+    /// blocker's clients know) with the peer sealed for the blocker's other
+    /// devices. The pair's PairBlock counts blocks without saying who set
+    /// them; while it exists, invites between the two fail with
+    /// AlreadyInvited, the same as for a pending or existing relationship.
+    pub fn block(
+        ctx: Context<Block>,
+        blinded_peer: [u8; 32],
+        sealed_peer: [u8; 48],
+        nonce: [u8; 24]
+    ) -> Result<()> {
+        let hash = get_chat_hash(ctx.accounts.wallet.key(), ctx.accounts.peer.key());
+        // A passkey signs the tag, so a relayer can't swap in another peer
+        let pair_tag = pair_block_tag(&hash, &blinded_peer);
+        check_authority_or_passkey(
+            &ctx.accounts.wallet,
+            &ctx.accounts.authority,
+            ctx.accounts.session_key.as_ref(),
+            ctx.accounts.passkey_profile.as_mut(),
+            ctx.accounts.instructions_sysvar.as_ref(),
+            SESSION_BLOCK,
+            &pair_tag,
+        )?;
+
+        // Optionally stop sharing the profile key with the blocked wallet.
+        // The share is keyed by the peer, so passing it ties this block to them.
+        match (&ctx.accounts.profile_key_share, &ctx.accounts.share_rent_payer) {
+            (Some(share), Some(rent_payer)) => {
+                if let Some((owner, version)) = close_profile_key_share(share, rent_payer)? {
+                    require_keys_eq!(owner, ctx.accounts.wallet.key(), ErrorCode::Unauthorized);
+                    match (ctx.accounts.passkey_profile.as_mut(), &ctx.accounts.user_profile) {
+                        (Some(profile), None) => profile.retire_profile_key(version),
+                        (None, Some(profile)) => retire_profile_key(profile, version)?,
+                        _ => return Err(ErrorCode::ProfileKeyShareMismatch.into()),
+                    }
+                }
+            },
+            (None, None) => {},
+            _ => return Err(ErrorCode::ProfileKeyShareMismatch.into()),
         }
 
-        msg!("Group updated: id={:?}", group.group_id);
+        let entry = &mut ctx.accounts.block_entry;
+        entry.owner = ctx.accounts.wallet.key();
+        entry.blinded_peer = blinded_peer;
+        entry.sealed_peer = sealed_peer;
+        entry.nonce = nonce;
+        entry.pair_tag = pair_tag;
+        entry.created_at = Clock::get()?.unix_timestamp;
+        entry.rent_payer = ctx.accounts.fee_payer.key();
+
+        let pair_block = &mut ctx.accounts.pair_block;
+        if pair_block.blocks == 0 {
+            pair_block.rent_payer = ctx.accounts.fee_payer.key();
+        }
+        pair_block.blocks += 1;
+
+        // The blocker keeps the deposit of the peer's pending invite, as on reject
+        if let Some(relationship) = ctx.accounts.relationship.as_mut() {
+            if relationship.has_deposit && relationship.state_of(ctx.accounts.wallet.key()) == Some(PeerState::Requested) {
+                let escrow = ctx.accounts.invite_escrow.as_deref()
+                    .ok_or(ErrorCode::DepositAccountMismatch)?;
+                let rent_payer = ctx.accounts.escrow_rent_payer.as_ref()
+                    .ok_or(ErrorCode::DepositAccountMismatch)?;
+                release_invite_deposit(
+                    escrow,
+                    ctx.accounts.escrow_token_account.as_deref(),
+                    Some(&ctx.accounts.wallet),
+                    ctx.accounts.recipient_token_account.as_deref(),
+                    rent_payer,
+                    ctx.accounts.token_program.as_ref(),
+                )?;
+                relationship.has_deposit = false;
+            }
+        }
 
         Ok(())
     }
 
-    pub fn invite_to_group(ctx: Context<InviteToGroup>) -> Result<()> {
-        let group = &ctx.accounts.group;
+    /// Close the block entry and take it off the pair's PairBlock
+    pub fn unblock(ctx: Context<Unblock>) -> Result<()> {
+        check_authority(
+            &ctx.accounts.wallet,
+            &ctx.accounts.authority,
+            ctx.accounts.session_key.as_ref(),
+            SESSION_BLOCK,
+        )?;
 
-        // Any member can invite (creator can kick bad actors)
-        require!(
-            group.members.contains(&ctx.accounts.payer.key()),
-            ErrorCode::NotGroupMember
-        );
+        release_pair_block(
+            &ctx.accounts.block_entry,
+            ctx.accounts.wallet.key(),
+            ctx.accounts.peer.key(),
+            &mut ctx.accounts.pair_block,
+            &ctx.accounts.pair_block_rent_payer,
+        )?;
 
-        // Check if group is full
-        require!(group.members.len() < 30, ErrorCode::GroupFull);
+        Ok(())
+    }
 
-        // Check if already a member or invited
+    /// Attest that the caller compared safety numbers with the peer and
+    /// they match both current encryption keys. The pair counts as verified
+    /// once both sides attested the same keys; rotating either key makes
+    /// the attestations stale.
+    pub fn verify_contact_key(ctx: Context<VerifyContactKey>, fingerprint: [u8; 32]) -> Result<()> {
+        let me = &ctx.accounts.authority;
+        let my_profile = &ctx.accounts.user_profile;
+        let peer_profile = &ctx.accounts.peer_profile;
+        let relationship = &mut ctx.accounts.relationship;
+
+        require!(relationship.is_mutual_contact(), ErrorCode::NotMutualContact);
+
+        let (profiles, i) = if relationship.participants[0] == me.key() {
+            ([my_profile, peer_profile], 0)
+        } else {
+            ([peer_profile, my_profile], 1)
+        };
+        let keys = [profiles[0].encryption_public_key, profiles[1].encryption_public_key];
+        let key_epochs = [profiles[0].key_epoch, profiles[1].key_epoch];
         require!(
-            !group.members.contains(&ctx.accounts.invitee.key()),
-            ErrorCode::AlreadyInvited
+            fingerprint == key_fingerprint(relationship.participants, keys),
+            ErrorCode::KeyFingerprintMismatch
         );
 
-        // Create or update invite
-        let invite = &mut ctx.accounts.group_invite;
-        invite.group_id = group.group_id;
-        invite.inviter = ctx.accounts.payer.key();
-        invite.invitee = ctx.accounts.invitee.key();
-        invite.status = GroupInviteStatus::Pending;
-        invite.created_at = Clock::get()?.unix_timestamp;
+        let now = Clock::get()?.unix_timestamp;
+        relationship.verifications[i] = Some(KeyVerification {
+            fingerprint,
+            key_epochs,
+            verified_at: now,
+        });
 
-        msg!("Group invite: group={:?}, invitee={:?}",
-             group.group_id, ctx.accounts.invitee.key());
+        msg!("Contact key verified: verifier={:?}, mutual={}",
+             me.key(), relationship.is_key_verified(key_epochs));
 
         Ok(())
     }
 
-    pub fn accept_group_invite(ctx: Context<AcceptGroupInvite>) -> Result<()> {
-        let group = &mut ctx.accounts.group;
-        let invite = &mut ctx.accounts.group_invite;
+    // ========== SESSION KEY INSTRUCTIONS ==========
 
-        // Verify invite status
+    /// Authorize an ephemeral key to act for this wallet until `expires_at`,
+    /// limited to the SESSION_* instructions set in `allowed`
+    pub fn create_session_key(
+        ctx: Context<CreateSessionKey>,
+        session_key: Pubkey,
+        expires_at: i64,
+        allowed: u32
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
         require!(
-            invite.status == GroupInviteStatus::Pending,
-            ErrorCode::NotInvited
+            expires_at > now && expires_at <= now + MAX_SESSION_DURATION,
+            ErrorCode::InvalidSessionExpiry
         );
-
-        // Verify invitee is the signer
         require!(
-            invite.invitee == ctx.accounts.payer.key(),
-            ErrorCode::NotInvited
+            allowed != 0 && allowed & !SESSION_ALL == 0,
+            ErrorCode::InvalidSessionScope
         );
 
-        // Check token gate if exists
-        if let Some(gate) = &group.token_gate {
-            let token_account = ctx.accounts.user_token_account.as_ref()
-                .ok_or(ErrorCode::TokenAccountRequired)?;
+        let session = &mut ctx.accounts.session;
+        session.wallet = ctx.accounts.authority.key();
+        session.session_key = session_key;
+        session.expires_at = expires_at;
+        session.allowed = allowed;
+        session.rent_payer = ctx.accounts.fee_payer.key();
+        ctx.accounts.wallet_descriptor.session_keys += 1;
 
-            // SECURITY FIX: Verify token account ownership
-            require!(
-                token_account.owner == ctx.accounts.payer.key(),
-                ErrorCode::InvalidTokenAccount
-            );
+        msg!("Session key created: wallet={:?}, key={:?}, expires_at={}",
+             session.wallet, session_key, expires_at);
 
-            require!(token_account.mint == gate.token_mint, ErrorCode::InsufficientTokenBalance);
-            require!(token_account.amount >= gate.min_balance, ErrorCode::InsufficientTokenBalance);
-        }
+        Ok(())
+    }
 
-        // Check if group is full
-        require!(group.members.len() < 30, ErrorCode::GroupFull);
+    /// Revoke a session key before it expires, returning its rent
+    pub fn revoke_session_key(ctx: Context<RevokeSessionKey>) -> Result<()> {
+        ctx.accounts.wallet_descriptor.session_keys -= 1;
 
-        // Add to group
-        group.members.push(ctx.accounts.payer.key());
+        msg!("Session key revoked: wallet={:?}, key={:?}",
+             ctx.accounts.authority.key(), ctx.accounts.session.session_key);
 
-        // Update invite status
-        invite.status = GroupInviteStatus::Accepted;
+        Ok(())
+    }
 
-        msg!("Group invite accepted: group={:?}, member={:?}",
-             group.group_id, ctx.accounts.payer.key());
+    // ========== KEY TRANSPARENCY INSTRUCTIONS ==========
+
+    /// Create the global key transparency log. Every key registration and
+    /// rotation after this is appended to it.
+    pub fn init_key_log(ctx: Context<InitKeyLog>) -> Result<()> {
+        let key_log = &mut ctx.accounts.key_log;
+        key_log.tree_size = 0;
+        key_log.root = [0; 32];
+
+        msg!("Key log initialized");
 
         Ok(())
     }
 
-    pub fn reject_group_invite(ctx: Context<RejectGroupInvite>) -> Result<()> {
-        let invite = &mut ctx.accounts.group_invite;
+    /// Append a key record that was created without the log (anyone can call)
+    pub fn log_key_record(ctx: Context<LogKeyRecord>) -> Result<()> {
+        let key_record = &mut ctx.accounts.key_record;
+        require!(key_record.log_index.is_none(), ErrorCode::KeyAlreadyLogged);
+        log_key(&mut ctx.accounts.key_log, key_record)?;
 
-        // Verify invite status
-        require!(
-            invite.status == GroupInviteStatus::Pending,
-            ErrorCode::NotInvited
-        );
+        msg!("Key record logged: owner={:?}, epoch={}", key_record.owner, key_record.epoch);
 
-        // Verify invitee is the signer
-        require!(
-            invite.invitee == ctx.accounts.payer.key(),
-            ErrorCode::NotInvited
-        );
+        Ok(())
+    }
 
-        // Update invite status
-        invite.status = GroupInviteStatus::Rejected;
+    // ========== HANDLE INSTRUCTIONS ==========
 
-        msg!("Group invite rejected: group={:?}, invitee={:?}",
-             invite.group_id, ctx.accounts.payer.key());
+    /// Set the handle claim fee and where it goes. Only the program's
+    /// upgrade authority can call this; a zero fee makes claims free.
+    pub fn set_handle_config(ctx: Context<SetHandleConfig>, claim_fee: u64, treasury: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.handle_config;
+        config.claim_fee = claim_fee;
+        config.treasury = treasury;
+
+        msg!("Handle config: claim_fee={}, treasury={:?}", claim_fee, treasury);
 
         Ok(())
     }
 
-    pub fn leave_group(ctx: Context<LeaveGroup>) -> Result<()> {
-        let group = &mut ctx.accounts.group;
+    /// Claim an unused `@name` for this wallet, paying the claim fee
+    pub fn claim_handle(ctx: Context<ClaimHandle>, name: String) -> Result<()> {
+        require!(is_valid_handle(&name), ErrorCode::InvalidHandle);
+
+        // No config yet means claims are free
+        let claim_fee = ctx.accounts.handle_config.as_ref().map_or(0, |config| config.claim_fee);
+        if claim_fee > 0 {
+            let treasury = ctx.accounts.treasury.as_ref().ok_or(ErrorCode::TreasuryMismatch)?;
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.fee_payer.to_account_info(),
+                        to: treasury.to_account_info(),
+                    },
+                ),
+                claim_fee,
+            )?;
+        }
 
-        // Cannot leave if you're the creator
-        require!(
-            group.creator != ctx.accounts.payer.key(),
-            ErrorCode::CannotRemoveCreator
-        );
+        let handle = &mut ctx.accounts.handle;
+        handle.name = name;
+        handle.owner = ctx.accounts.authority.key();
+        handle.claimed_at = Clock::get()?.unix_timestamp;
+        handle.rent_payer = ctx.accounts.fee_payer.key();
 
-        // Verify member is in group
-        require!(
-            group.members.contains(&ctx.accounts.payer.key()),
-            ErrorCode::NotGroupMember
-        );
+        msg!("Handle claimed: @{}, owner={:?}", handle.name, handle.owner);
 
-        // Remove from members
-        group.members.retain(|m| m != &ctx.accounts.payer.key());
+        Ok(())
+    }
 
-        msg!("Left group: group={:?}, member={:?}",
-             group.group_id, ctx.accounts.payer.key());
+    /// Give up a handle so anyone can claim it again
+    pub fn release_handle(ctx: Context<ReleaseHandle>) -> Result<()> {
+        msg!("Handle released: @{}, owner={:?}",
+             ctx.accounts.handle.name, ctx.accounts.authority.key());
 
         Ok(())
     }
 
-    pub fn kick_member(ctx: Context<KickMember>) -> Result<()> {
-        let group = &mut ctx.accounts.group;
+    /// Hand a handle over to another wallet, signed by both. Rent stays
+    /// with the original payer.
+    pub fn transfer_handle(ctx: Context<TransferHandle>) -> Result<()> {
+        let new_owner = ctx.accounts.new_owner.key();
+        let handle = &mut ctx.accounts.handle;
+        handle.owner = new_owner;
 
-        // Only creator can kick (admin-only for MVP)
-        require!(
-            group.creator == ctx.accounts.payer.key(),
-            ErrorCode::NotGroupAdmin
-        );
+        msg!("Handle transferred: @{}, from={:?}, to={:?}",
+             handle.name, ctx.accounts.authority.key(), new_owner);
 
-        // Cannot kick the creator
+        Ok(())
+    }
+
+    // ========== RECOVERY INSTRUCTIONS ==========
+
+    /// Choose the guardians that can recover this identity, how many of
+    /// them must agree and how long the owner has to cancel
+    pub fn set_guardians(
+        ctx: Context<SetGuardians>,
+        guardians: Vec<Pubkey>,
+        threshold: u8,
+        timelock: i64
+    ) -> Result<()> {
+        let owner = ctx.accounts.authority.key();
+
+        require!(guardians.len() <= MAX_GUARDIANS, ErrorCode::InvalidGuardianSet);
+        require!(threshold >= 1 && usize::from(threshold) <= guardians.len(), ErrorCode::InvalidGuardianSet);
+        require!(!guardians.contains(&owner), ErrorCode::InvalidGuardianSet);
         require!(
-            ctx.accounts.member.key() != group.creator,
-            ErrorCode::CannotRemoveCreator
+            guardians.iter().enumerate().all(|(i, g)| !guardians[..i].contains(g)),
+            ErrorCode::InvalidGuardianSet
         );
-
-        // Verify member is in group
         require!(
-            group.members.contains(&ctx.accounts.member.key()),
-            ErrorCode::NotGroupMember
+            (MIN_RECOVERY_TIMELOCK..=MAX_RECOVERY_TIMELOCK).contains(&timelock),
+            ErrorCode::InvalidRecoveryTimelock
         );
 
-        // Remove from members
-        group.members.retain(|m| m != &ctx.accounts.member.key());
+        let guardian_set = &mut ctx.accounts.guardian_set;
+        if guardian_set.owner == Pubkey::default() {
+            guardian_set.rent_payer = ctx.accounts.fee_payer.key();
+        }
+        guardian_set.owner = owner;
+        guardian_set.guardians = guardians;
+        guardian_set.threshold = threshold;
+        guardian_set.timelock = timelock;
 
-        msg!("Kicked from group: group={:?}, member={:?}",
-             group.group_id, ctx.accounts.member.key());
+        msg!("Guardians set: owner={:?}, threshold={}/{}",
+             owner, threshold, guardian_set.guardians.len());
 
         Ok(())
     }
 
-    pub fn close_group(ctx: Context<CloseGroup>) -> Result<()> {
-        let group = &ctx.accounts.group;
+    /// A guardian starts recovering `owner`'s identity and approves it
+    pub fn propose_recovery(ctx: Context<ProposeRecovery>, action: RecoveryAction) -> Result<()> {
+        let guardian = ctx.accounts.authority.key();
+        let guardian_set = &ctx.accounts.guardian_set;
+        require!(guardian_set.guardians.contains(&guardian), ErrorCode::NotGuardian);
+
+        let now = Clock::get()?.unix_timestamp;
+        let recovery = &mut ctx.accounts.recovery;
+        recovery.owner = guardian_set.owner;
+        recovery.proposer = guardian;
+        recovery.action = action.clone();
+        recovery.approvals = vec![guardian];
+        recovery.proposed_at = now;
+        recovery.approved_at = (guardian_set.threshold == 1).then_some(now);
+        recovery.rent_payer = ctx.accounts.fee_payer.key();
+
+        emit!(RecoveryProposed {
+            owner: recovery.owner,
+            guardian,
+            action,
+        });
 
-        // Only creator can delete
-        require!(
-            group.creator == ctx.accounts.payer.key(),
-            ErrorCode::NotGroupAdmin
-        );
+        msg!("Recovery proposed: owner={:?}, guardian={:?}", recovery.owner, guardian);
 
-        // Transfer lamports back to creator
-        let group_lamports = ctx.accounts.group.to_account_info().lamports();
-        **ctx.accounts.group.to_account_info().lamports.borrow_mut() = 0;
-        **ctx.accounts.payer.lamports.borrow_mut() += group_lamports;
+        Ok(())
+    }
 
-        msg!("Group closed: group={:?}", group.group_id);
+    /// Add a guardian's approval. The time-lock starts once the threshold is met.
+    pub fn approve_recovery(ctx: Context<ApproveRecovery>) -> Result<()> {
+        let guardian = ctx.accounts.authority.key();
+        let guardian_set = &ctx.accounts.guardian_set;
+        let recovery = &mut ctx.accounts.recovery;
+
+        require!(guardian_set.guardians.contains(&guardian), ErrorCode::NotGuardian);
+        require!(!recovery.approvals.contains(&guardian), ErrorCode::AlreadyApproved);
+
+        // Drop approvals from guardians rotated out, so the list never
+        // outgrows the current set
+        recovery.approvals.retain(|a| guardian_set.guardians.contains(a));
+        recovery.approvals.push(guardian);
+        if guardian_set.approvals(&recovery.approvals) < guardian_set.threshold {
+            recovery.approved_at = None;
+        } else if recovery.approved_at.is_none() {
+            recovery.approved_at = Some(Clock::get()?.unix_timestamp);
+        }
+
+        msg!("Recovery approved: owner={:?}, guardian={:?}, approvals={}",
+             recovery.owner, guardian, recovery.approvals.len());
 
         Ok(())
     }
 
-    pub fn store_group_key(
-        ctx: Context<StoreGroupKey>,
-        _group_id: [u8; 32],
-        encrypted_key: Vec<u8>,
-        nonce: [u8; 24],
-    ) -> Result<()> {
-        let key_share = &mut ctx.accounts.group_key_share;
-        let group = &ctx.accounts.group;
+    /// The owner still has their wallet: stop the recovery
+    pub fn cancel_recovery(ctx: Context<CancelRecovery>) -> Result<()> {
+        msg!("Recovery cancelled: owner={:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
 
-        // Verify payer is a member of the group
+    /// Close a proposal that hasn't reached the threshold within
+    /// MAX_RECOVERY_AGE (anyone can call), so it can't sit there forever
+    pub fn expire_recovery(ctx: Context<ExpireRecovery>) -> Result<()> {
+        let guardian_set = &ctx.accounts.guardian_set;
+        let recovery = &ctx.accounts.recovery;
+
+        let approved = recovery.approved_at.is_some()
+            && guardian_set.approvals(&recovery.approvals) >= guardian_set.threshold;
         require!(
-            group.members.contains(&ctx.accounts.payer.key()),
-            ErrorCode::NotGroupMember
+            !approved && Clock::get()?.unix_timestamp >= recovery.proposed_at.saturating_add(MAX_RECOVERY_AGE),
+            ErrorCode::RecoveryNotExpired
         );
 
-        // Store the encrypted key share
-        key_share.group_id = group.group_id;
-        key_share.member = ctx.accounts.payer.key();
-        key_share.encrypted_key = encrypted_key;
-        key_share.nonce = nonce;
-
-        msg!("Group key stored for member: {:?}", ctx.accounts.payer.key());
+        msg!("Recovery expired: owner={:?}, proposer={:?}", recovery.owner, recovery.proposer);
 
         Ok(())
     }
 
-    pub fn close_group_key(ctx: Context<CloseGroupKey>) -> Result<()> {
-        // Verify the key share belongs to the payer
+    /// Carry out an approved recovery once the time-lock has passed (anyone
+    /// can call). A key rotation starts a new key epoch; moving the identity
+    /// lets the new wallet take over the profile through a wallet migration.
+    pub fn execute_recovery(ctx: Context<ExecuteRecovery>) -> Result<()> {
+        let guardian_set = &ctx.accounts.guardian_set;
+        let recovery = &ctx.accounts.recovery;
+        let user_profile = &mut ctx.accounts.user_profile;
+
+        // Guardians removed since approving no longer count
+        let approved_at = recovery.approved_at.ok_or(ErrorCode::RecoveryNotApproved)?;
         require!(
-            ctx.accounts.group_key_share.member == ctx.accounts.payer.key(),
-            ErrorCode::Unauthorized
+            guardian_set.approvals(&recovery.approvals) >= guardian_set.threshold,
+            ErrorCode::RecoveryNotApproved
+        );
+        require!(
+            Clock::get()?.unix_timestamp >= approved_at.saturating_add(guardian_set.timelock),
+            ErrorCode::RecoveryTimelocked
         );
 
-        // Transfer lamports back to member
-        let key_share_lamports = ctx.accounts.group_key_share.to_account_info().lamports();
-        **ctx.accounts.group_key_share.to_account_info().lamports.borrow_mut() = 0;
-        **ctx.accounts.payer.lamports.borrow_mut() += key_share_lamports;
+        match (&recovery.action, ctx.accounts.key_record.as_mut(), ctx.accounts.key_log.as_mut()) {
+            (RecoveryAction::RotateKey { new_key }, Some(key_record), Some(key_log)) => {
+                rotate_encryption_key(user_profile, key_record, key_log, *new_key, ctx.accounts.fee_payer.key())?;
+            },
+            (RecoveryAction::MoveIdentity { new_wallet }, None, None) => {
+                user_profile.migration_target = Some(*new_wallet);
+            },
+            _ => return Err(ErrorCode::RecoveryAccountMismatch.into()),
+        }
 
-        msg!("Group key share closed for member: {:?}", ctx.accounts.payer.key());
+        msg!("Recovery executed: owner={:?}", recovery.owner);
 
         Ok(())
     }
 
-    // ========== ARCIUM MPC INSTRUCTIONS ==========
+    // ========== MIGRATION INSTRUCTIONS ==========
+
+    /// First step of moving this identity to another wallet, signed by the
+    /// old wallet (or done by a guardian recovery when it is lost)
+    pub fn start_migration(ctx: Context<SetMigrationTarget>, new_wallet: Pubkey) -> Result<()> {
+        require_keys_neq!(new_wallet, ctx.accounts.authority.key(), ErrorCode::InvalidMigrationTarget);
+
+        ctx.accounts.user_profile.migration_target = Some(new_wallet);
+
+        msg!("Migration started: old={:?}, new={:?}", ctx.accounts.authority.key(), new_wallet);
 
-    /// Initialize computation definition for is_accepted_contact circuit
-    pub fn init_is_accepted_contact_comp_def(ctx: Context<InitIsAcceptedContactCompDef>) -> Result<()> {
-        init_comp_def(
-            ctx.accounts,
-            Some(CircuitSource::OffChain(OffChainCircuitSource {
-                source: "https://mukon-circuits.fly.dev/is_accepted_contact.arcis".to_string(),
-                hash: circuit_hash!("is_accepted_contact"),
-            })),
-            None,
-        )?;
-        msg!("Initialized comp def: is_accepted_contact");
         Ok(())
     }
 
-    /// Initialize computation definition for count_accepted circuit
-    pub fn init_count_accepted_comp_def(ctx: Context<InitCountAcceptedCompDef>) -> Result<()> {
-        init_comp_def(
-            ctx.accounts,
-            Some(CircuitSource::OffChain(OffChainCircuitSource {
-                source: "https://mukon-circuits.fly.dev/count_accepted.arcis".to_string(),
-                hash: circuit_hash!("count_accepted"),
-            })),
-            None,
-        )?;
-        msg!("Initialized comp def: count_accepted");
+    pub fn cancel_migration(ctx: Context<SetMigrationTarget>) -> Result<()> {
+        ctx.accounts.user_profile.migration_target = None;
+
+        msg!("Migration cancelled: {:?}", ctx.accounts.authority.key());
+
         Ok(())
     }
 
-    /// Initialize computation definition for add_two_numbers circuit (demo)
-    pub fn init_add_two_numbers_comp_def(ctx: Context<InitAddTwoNumbersCompDef>) -> Result<()> {
-        init_comp_def(
-            ctx.accounts,
-            Some(CircuitSource::OffChain(OffChainCircuitSource {
-                source: "https://mukon-circuits.fly.dev/add_two_numbers.arcis".to_string(),
-                hash: circuit_hash!("add_two_numbers"),
-            })),
-            None,
-        )?;
-        msg!("Initialized comp def: add_two_numbers");
+    /// Second step, signed by the new wallet: the profile and descriptor
+    /// move to it, the current key is recorded (and logged) for the new
+    /// wallet and a WalletForward is left at the old wallet. Relationships,
+    /// group memberships, group keys, profile key shares, handles, the
+    /// guardian set and wallet links follow one at a time with the migrate_*
+    /// instructions below. Devices, prekeys and old key records stay with
+    /// the old wallet. Its session keys must be revoked first, or they
+    /// could still act on relationships not yet migrated.
+    pub fn complete_migration(ctx: Context<CompleteMigration>) -> Result<()> {
+        let old_wallet = ctx.accounts.old_wallet.key();
+        let new_wallet = ctx.accounts.authority.key();
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(ctx.accounts.new_wallet_link.data_is_empty(), ErrorCode::InvalidWalletLink);
+        require!(ctx.accounts.old_descriptor.session_keys == 0, ErrorCode::SessionKeysOpen);
+
+        let mut profile = UserProfile::clone(&ctx.accounts.old_profile);
+        profile.owner = new_wallet;
+        profile.rent_payer = ctx.accounts.fee_payer.key();
+        profile.migration_target = None;
+
+        // The passkey was registered by the old wallet
+        profile.passkey = None;
+        profile.passkey_nonce = 0;
+
+        // NFT avatars and badges proved what the old wallet held
+        profile.badges.clear();
+        if profile.avatar_type == AvatarType::Nft {
+            profile.avatar_type = AvatarType::Emoji;
+            profile.avatar_data = String::new();
+        }
+
+        // Same epoch and key, now bound to the new wallet in the log
+        let key_record = &mut ctx.accounts.key_record;
+        key_record.owner = new_wallet;
+        key_record.epoch = profile.key_epoch;
+        key_record.key = profile.encryption_public_key;
+        key_record.activated_at = now;
+        key_record.rent_payer = ctx.accounts.fee_payer.key();
+        key_record.log_index = None;
+        if let Some(key_log) = ctx.accounts.key_log.as_mut() {
+            log_key(key_log, key_record)?;
+        }
+        ctx.accounts.user_profile.set_inner(profile);
+
+        let old_descriptor = &ctx.accounts.old_descriptor;
+        let descriptor = &mut ctx.accounts.wallet_descriptor;
+        descriptor.owner = new_wallet;
+        descriptor.invite_epoch = old_descriptor.invite_epoch;
+        descriptor.invites_this_epoch = old_descriptor.invites_this_epoch;
+
+        let forward = &mut ctx.accounts.wallet_forward;
+        forward.old_wallet = old_wallet;
+        forward.new_wallet = new_wallet;
+        forward.migrated_at = now;
+
+        emit!(WalletMigrated {
+            old_wallet,
+            new_wallet,
+            migrated_at: now,
+        });
+
+        msg!("Migration completed: old={:?}, new={:?}", old_wallet, new_wallet);
+
         Ok(())
     }
 
-    /// Queue MPC computation to check if a contact is accepted
-    pub fn check_is_contact(
-        ctx: Context<CheckIsContact>,
-        computation_offset: u64,
-        encrypted_contact_list: Vec<u8>,
-        encrypted_query_pubkey: Vec<u8>,
-        pub_key: [u8; 32],
-        nonce_list: u128,
-        nonce_query: u128,
-    ) -> Result<()> {
-        let args = ArgBuilder::new()
-            .x25519_pubkey(pub_key)
-            .plaintext_u128(nonce_list)
-            .encrypted_bytes(encrypted_contact_list)
-            .x25519_pubkey(pub_key)
-            .plaintext_u128(nonce_query)
-            .encrypted_bytes(encrypted_query_pubkey)
-            .build();
+    /// Move one relationship (and its conversation) of a migrated wallet
+    /// to the new wallet. Key verifications covered the old wallet and are reset.
+    pub fn migrate_relationship(ctx: Context<MigrateRelationship>) -> Result<()> {
+        let old_wallet = ctx.accounts.wallet_forward.old_wallet;
+        let new_wallet = ctx.accounts.authority.key();
+        let peer = ctx.accounts.peer.key();
+        let old_relationship = &ctx.accounts.old_relationship;
 
-        ctx.accounts.sign_pda_account.bump = ctx.bumps.sign_pda_account;
+        // Invite notes and escrows are keyed by the old chat hash
+        require!(
+            matches!(
+                old_relationship.state_of(old_wallet),
+                Some(PeerState::Accepted) | Some(PeerState::Rejected)
+            ),
+            ErrorCode::InvitePending
+        );
+        require!(
+            matches!(
+                old_relationship.state_of(peer),
+                Some(PeerState::Accepted) | Some(PeerState::Rejected)
+            ),
+            ErrorCode::InvitePending
+        );
+        require!(!old_relationship.has_deposit, ErrorCode::InvitePending);
+        require!(
+            ctx.accounts.old_conversation.is_some() == old_relationship.has_conversation,
+            ErrorCode::ConversationMismatch
+        );
 
-        queue_computation(
-            ctx.accounts,
-            computation_offset,
-            args,
-            None,
-            vec![CheckIsContactCallback::callback_ix(
-                computation_offset,
-                &ctx.accounts.mxe_account,
-                &[],
-            )?],
-            1,
-            0,
-        )?;
+        let mut relationship = Relationship::clone(old_relationship);
+        relationship.replace_participant(old_wallet, new_wallet);
+        relationship.rent_payer = ctx.accounts.fee_payer.key();
+        relationship.verifications = [None, None];
+        relationship.updated_at = Clock::get()?.unix_timestamp;
+
+        // Withdrawn invites with rejection history leave no conversation
+        match (&ctx.accounts.old_conversation, ctx.accounts.new_conversation.as_mut()) {
+            (Some(old_conversation), Some(conversation)) => {
+                conversation.participants = relationship.participants;
+                conversation.created_at = old_conversation.created_at;
+                conversation.rent_payer = ctx.accounts.fee_payer.key();
+            },
+            (None, None) => {},
+            _ => return Err(ErrorCode::ConversationMismatch.into()),
+        }
+
+        ctx.accounts.new_relationship.set_inner(relationship);
+
+        emit!(ContactMigrated {
+            old_wallet,
+            new_wallet,
+            peer,
+        });
+
+        msg!("Relationship migrated: old={:?}, new={:?}, peer={:?}", old_wallet, new_wallet, peer);
 
-        msg!("Queued is_contact computation: offset={}", computation_offset);
         Ok(())
     }
 
-    /// Callback for is_accepted_contact computation
-    #[arcium_callback(encrypted_ix = "is_accepted_contact")]
-    pub fn check_is_contact_callback(
-        ctx: Context<CheckIsContactCallback>,
-        output: SignedComputationOutputs<IsAcceptedContactOutput>,
-    ) -> Result<()> {
-        let result = match output.verify_output(
-            &ctx.accounts.cluster_account,
-            &ctx.accounts.computation_account,
-        ) {
-            Ok(IsAcceptedContactOutput { field_0 }) => field_0,
-            Err(e) => {
-                msg!("MPC computation failed: {}", e);
-                return Err(ErrorCode::AbortedComputation.into());
-            }
-        };
+    /// Replace a migrated wallet in a group's member list (and as creator)
+    pub fn migrate_group_membership(ctx: Context<MigrateGroupMembership>) -> Result<()> {
+        let old_wallet = ctx.accounts.wallet_forward.old_wallet;
+        let new_wallet = ctx.accounts.authority.key();
+        let group = &mut ctx.accounts.group;
 
-        emit!(ContactCheckResult {
-            ciphertext: result.ciphertexts[0],
-            nonce: result.nonce,
-            encryption_key: result.encryption_key,
+        let i = group.members.iter().position(|m| *m == old_wallet)
+            .ok_or(ErrorCode::NotGroupMember)?;
+        require!(!group.members.contains(&new_wallet), ErrorCode::AlreadyInvited);
+
+        group.members[i] = new_wallet;
+        if group.creator == old_wallet {
+            group.creator = new_wallet;
+        }
+
+        emit!(GroupMemberMigrated {
+            group_id: group.group_id,
+            old_wallet,
+            new_wallet,
         });
 
-        msg!("is_contact computation completed");
+        msg!("Group membership migrated: group={:?}, old={:?}, new={:?}",
+             group.group_id, old_wallet, new_wallet);
+
         Ok(())
     }
 
-    /// Queue MPC computation to count accepted contacts
-    pub fn count_accepted_contacts(
-        ctx: Context<CountAcceptedContacts>,
-        computation_offset: u64,
-        encrypted_contact_list: Vec<u8>,
-        pub_key: [u8; 32],
-        nonce_list: u128,
-    ) -> Result<()> {
-        let args = ArgBuilder::new()
-            .x25519_pubkey(pub_key)
-            .plaintext_u128(nonce_list)
-            .encrypted_bytes(encrypted_contact_list)
-            .build();
+    /// Move a migrated wallet's GroupKeyShare to the new wallet
+    pub fn migrate_group_key(ctx: Context<MigrateGroupKey>) -> Result<()> {
+        let old_share = &ctx.accounts.old_group_key_share;
+        let share = &mut ctx.accounts.group_key_share;
 
-        ctx.accounts.sign_pda_account.bump = ctx.bumps.sign_pda_account;
+        share.group_id = old_share.group_id;
+        share.member = ctx.accounts.authority.key();
+        share.encrypted_key = old_share.encrypted_key.clone();
+        share.nonce = old_share.nonce;
+        share.rent_payer = ctx.accounts.fee_payer.key();
 
-        queue_computation(
-            ctx.accounts,
-            computation_offset,
-            args,
-            None,
-            vec![CountAcceptedContactsCallback::callback_ix(
-                computation_offset,
-                &ctx.accounts.mxe_account,
-                &[],
-            )?],
-            1,
-            0,
-        )?;
+        msg!("Group key migrated: group={:?}, new={:?}", share.group_id, share.member);
 
-        msg!("Queued count_accepted computation: offset={}", computation_offset);
         Ok(())
     }
 
-    /// Callback for count_accepted computation
-    #[arcium_callback(encrypted_ix = "count_accepted")]
-    pub fn count_accepted_contacts_callback(
-        ctx: Context<CountAcceptedContactsCallback>,
-        output: SignedComputationOutputs<CountAcceptedOutput>,
-    ) -> Result<()> {
-        let result = match output.verify_output(
-            &ctx.accounts.cluster_account,
-            &ctx.accounts.computation_account,
-        ) {
-            Ok(CountAcceptedOutput { field_0 }) => field_0,
-            Err(e) => {
-                msg!("MPC computation failed: {}", e);
-                return Err(ErrorCode::AbortedComputation.into());
-            }
-        };
+    /// Move a profile key share the migrated wallet gave or received. The
+    /// encryption key moved with the identity, so the wrapped key still opens.
+    pub fn migrate_profile_key_share(ctx: Context<MigrateProfileKeyShare>) -> Result<()> {
+        let forward = &ctx.accounts.wallet_forward;
+        let old_share = &ctx.accounts.old_profile_key_share;
+        require!(
+            old_share.owner == forward.old_wallet || old_share.contact == forward.old_wallet,
+            ErrorCode::Unauthorized
+        );
 
-        emit!(ContactCountResult {
-            ciphertext: result.ciphertexts[0],
-            nonce: result.nonce,
-            encryption_key: result.encryption_key,
-        });
+        let share = &mut ctx.accounts.profile_key_share;
+        share.owner = forward.forwarded(old_share.owner);
+        share.contact = forward.forwarded(old_share.contact);
+        share.wrapped_key = old_share.wrapped_key.clone();
+        share.nonce = old_share.nonce;
+        share.key_version = old_share.key_version;
+        share.rent_payer = ctx.accounts.fee_payer.key();
+
+        msg!("Profile key share migrated: owner={:?}, contact={:?}", share.owner, share.contact);
 
-        msg!("count_accepted computation completed");
         Ok(())
     }
-}
 
-// ========== ACCOUNT STRUCTURES ==========
+    /// Point a handle of the migrated wallet at the new wallet
+    pub fn migrate_handle(ctx: Context<MigrateHandle>) -> Result<()> {
+        let handle = &mut ctx.accounts.handle;
+        handle.owner = ctx.accounts.authority.key();
 
-const WALLET_DESCRIPTOR_VERSION: [u8; 1] = [1];
-const USER_PROFILE_VERSION: [u8; 1] = [1];
-const CONVERSATION_VERSION: [u8; 1] = [1];
-const GROUP_VERSION: [u8; 1] = [1];
-const GROUP_INVITE_VERSION: [u8; 1] = [1];
-const GROUP_KEY_SHARE_VERSION: [u8; 1] = [1];
+        msg!("Handle migrated: @{}, new={:?}", handle.name, handle.owner);
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum PeerState {
-    Invited = 0,
-    Requested = 1,
-    Accepted = 2,
-    Rejected = 3,
-    Blocked = 4,
-}
+        Ok(())
+    }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum AvatarType {
-    Emoji = 0,
-    Nft = 1,
-}
+    /// Move the migrated wallet's guardians to the new wallet
+    pub fn migrate_guardian_set(ctx: Context<MigrateGuardianSet>) -> Result<()> {
+        let new_wallet = ctx.accounts.authority.key();
+        let old_set = &ctx.accounts.old_guardian_set;
+        require!(!old_set.guardians.contains(&new_wallet), ErrorCode::InvalidGuardianSet);
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum GroupInviteStatus {
-    Pending = 0,
-    Accepted = 1,
-    Rejected = 2,
-}
+        let guardian_set = &mut ctx.accounts.guardian_set;
+        guardian_set.owner = new_wallet;
+        guardian_set.guardians = old_set.guardians.clone();
+        guardian_set.threshold = old_set.threshold;
+        guardian_set.timelock = old_set.timelock;
+        guardian_set.rent_payer = ctx.accounts.fee_payer.key();
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Peer {
-    pub wallet: Pubkey,
-    pub state: PeerState,
-}
+        msg!("Guardian set migrated: new={:?}", new_wallet);
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct TokenGate {
-    pub token_mint: Pubkey,
-    pub min_balance: u64,
-}
+        Ok(())
+    }
 
-#[account]
-pub struct WalletDescriptor {
-    pub owner: Pubkey,
-    pub peers: Vec<Peer>,
-}
+    /// Keep a wallet linked to the migrated identity, now under the new wallet
+    pub fn migrate_wallet_link(ctx: Context<MigrateWalletLink>) -> Result<()> {
+        let link = &mut ctx.accounts.wallet_link;
+        link.primary = ctx.accounts.authority.key();
 
-#[account]
-pub struct UserProfile {
-    pub owner: Pubkey,
-    pub display_name: String,
-    pub avatar_type: AvatarType,
-    pub avatar_data: String,
-    pub encryption_public_key: [u8; 32],
-}
+        msg!("Wallet link migrated: primary={:?}, linked={:?}", link.primary, link.linked);
 
-#[account]
-pub struct Conversation {
-    pub participants: [Pubkey; 2],
-    pub created_at: i64,
-}
+        Ok(())
+    }
 
-#[account]
-pub struct Group {
-    pub group_id: [u8; 32],
-    pub creator: Pubkey,
-    pub name: String,
-    pub created_at: i64,
-    pub members: Vec<Pubkey>,
-    pub encryption_pubkey: [u8; 32],
-    pub token_gate: Option<TokenGate>,
-}
+    // ========== LINKED WALLET INSTRUCTIONS ==========
 
-#[account]
-pub struct GroupInvite {
-    pub group_id: [u8; 32],
-    pub inviter: Pubkey,
-    pub invitee: Pubkey,
-    pub status: GroupInviteStatus,
-    pub created_at: i64,
-}
+    /// Link another wallet (a Ledger, a multisig...) to this profile. Both
+    /// wallets sign. The linked wallet resolves to this one for invites and
+    /// can hold the tokens for token gates.
+    pub fn link_wallet(ctx: Context<LinkWallet>) -> Result<()> {
+        let primary = ctx.accounts.authority.key();
+        let linked = ctx.accounts.linked_wallet.key();
 
-#[account]
-pub struct GroupKeyShare {
-    pub group_id: [u8; 32],
-    pub member: Pubkey,
-    pub encrypted_key: Vec<u8>,
-    pub nonce: [u8; 24],
-}
+        require_keys_neq!(primary, linked, ErrorCode::InvalidWalletLink);
+        // One identity per wallet, and no chains of links
+        require!(ctx.accounts.linked_profile.data_is_empty(), ErrorCode::InvalidWalletLink);
+        require!(ctx.accounts.primary_link.data_is_empty(), ErrorCode::InvalidWalletLink);
 
-// ========== CONTEXT STRUCTURES ==========
+        let link = &mut ctx.accounts.wallet_link;
+        link.primary = primary;
+        link.linked = linked;
+        link.linked_at = Clock::get()?.unix_timestamp;
+        link.rent_payer = ctx.accounts.fee_payer.key();
 
-#[derive(Accounts)]
-#[instruction(display_name: String, avatar_data: String, encryption_public_key: [u8; 32])]
-pub struct Register<'info> {
+        msg!("Wallet linked: primary={:?}, linked={:?}", primary, linked);
+
+        Ok(())
+    }
+
+    /// Remove a link, signed by either wallet
+    pub fn unlink_wallet(ctx: Context<UnlinkWallet>) -> Result<()> {
+        let link = &ctx.accounts.wallet_link;
+        let signer = ctx.accounts.authority.key();
+
+        require!(signer == link.primary || signer == link.linked, ErrorCode::Unauthorized);
+
+        msg!("Wallet unlinked: primary={:?}, linked={:?}", link.primary, link.linked);
+
+        Ok(())
+    }
+
+    // ========== ACCOUNT DELETION INSTRUCTIONS ==========
+
+    /// First step of deleting this identity: the profile and descriptor are
+    /// closed right away and an AccountDeletion record is opened. The
+    /// delete_* instructions below then remove the wallet from its
+    /// relationships, groups, group keys and every other account of the
+    /// wallet, as many per transaction as fit, and finish_account_deletion
+    /// closes the record.
+    pub fn start_account_deletion(ctx: Context<StartAccountDeletion>) -> Result<()> {
+        let owner = ctx.accounts.authority.key();
+        let now = Clock::get()?.unix_timestamp;
+
+        let deletion = &mut ctx.accounts.account_deletion;
+        deletion.owner = owner;
+        deletion.started_at = now;
+        deletion.relationships_closed = 0;
+        deletion.groups_left = 0;
+        deletion.group_keys_closed = 0;
+        deletion.accounts_closed = 0;
+        deletion.rent_payer = ctx.accounts.fee_payer.key();
+
+        emit!(AccountDeleted {
+            wallet: owner,
+            deleted_at: now,
+        });
+
+        msg!("Account deletion started: {:?}", owner);
+
+        Ok(())
+    }
+
+    /// Close one relationship (and its conversation) of a deleting wallet,
+    /// whatever its state. A pending invite's deposit goes back to the
+    /// inviter and its intro note is closed. If the peer rejected me, a
+    /// RejectionTombstone keeps my re-invite cooldown for them without
+    /// naming me, so re-registering doesn't reset it.
+    pub fn delete_relationship(ctx: Context<DeleteRelationship>) -> Result<()> {
+        let me = ctx.accounts.authority.key();
+        let peer = ctx.accounts.peer.key();
+        let relationship = &mut ctx.accounts.relationship;
+
+        require!(
+            relationship.has_conversation == ctx.accounts.conversation.is_some(),
+            ErrorCode::ConversationMismatch
+        );
+        close_intro(relationship, &ctx.accounts.invite_note)?;
+
+        if relationship.has_deposit {
+            let escrow = ctx.accounts.invite_escrow.as_ref()
+                .ok_or(ErrorCode::DepositAccountMismatch)?;
+            release_invite_deposit(
+                escrow,
+                ctx.accounts.escrow_token_account.as_ref(),
+                None,
+                ctx.accounts.recipient_token_account.as_ref(),
+                &ctx.accounts.invite_rent_payer,
+                ctx.accounts.token_program.as_ref(),
+            )?;
+            relationship.has_deposit = false;
+        }
+
+        match (relationship.was_rejected(me), ctx.accounts.tombstone.as_mut()) {
+            (true, Some(tombstone)) => {
+                let (rejections, last_rejected_at) = relationship.rejection_history(me);
+                tombstone.peer = peer;
+                tombstone.rejections = rejections;
+                tombstone.last_rejected_at = last_rejected_at;
+                tombstone.rent_payer = ctx.accounts.fee_payer.key();
+            },
+            (false, None) => {},
+            _ => return Err(ErrorCode::TombstoneMismatch.into()),
+        }
+
+        // My profile is already closed; the peer must rotate the key I held
+        let accounts = &ctx.accounts;
+        for (share, rent_payer, owner_profile) in [
+            (&accounts.my_profile_key_share, &accounts.my_share_rent_payer, &accounts.user_profile),
+            (&accounts.peer_profile_key_share, &accounts.peer_share_rent_payer, &accounts.peer_profile),
+        ] {
+            if let Some((_, version)) = close_profile_key_share(share, rent_payer)? {
+                retire_profile_key(owner_profile, version)?;
+            }
+        }
+
+        ctx.accounts.account_deletion.relationships_closed += 1;
+
+        emit!(ContactDeleted {
+            wallet: me,
+            peer,
+        });
+
+        msg!("Relationship deleted: wallet={:?}, peer={:?}", me, peer);
+
+        Ok(())
+    }
+
+    /// Close a tombstone left by a deleted peer, giving up the cooldown it kept
+    pub fn close_tombstone(ctx: Context<CloseTombstone>) -> Result<()> {
+        msg!("Tombstone closed: peer={:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    /// Remove a deleting wallet from a group's members. A creator hands the
+    /// group to the longest-standing remaining member; a creator alone in
+    /// the group closes it with close_group instead.
+    pub fn delete_group_membership(ctx: Context<DeleteGroupMembership>) -> Result<()> {
+        let me = ctx.accounts.authority.key();
+        let group = &mut ctx.accounts.group;
+
+        require!(group.members.contains(&me), ErrorCode::NotGroupMember);
+        group.members.retain(|m| *m != me);
+
+        if group.creator == me {
+            group.creator = *group.members.first().ok_or(ErrorCode::CannotRemoveCreator)?;
+        }
+
+        let deletion = &mut ctx.accounts.account_deletion;
+        deletion.groups_left += 1;
+
+        msg!("Group membership deleted: group={:?}, member={:?}", group.group_id, me);
+
+        Ok(())
+    }
+
+    /// Close one GroupKeyShare of a deleting wallet
+    pub fn delete_group_key(ctx: Context<DeleteGroupKey>) -> Result<()> {
+        ctx.accounts.account_deletion.group_keys_closed += 1;
+
+        msg!("Group key deleted: group={:?}, member={:?}",
+             ctx.accounts.group_key_share.group_id, ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    // Close the deleting wallet's other accounts, one per call: its handle,
+    // profile key shares (its own and those wrapped for it), devices,
+    // prekeys, session keys, key records, guardian set, pending recovery,
+    // wallet links and block entries. Each refunds whoever paid its rent.
+
+    pub fn delete_handle(ctx: Context<DeleteHandle>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Handle deleted: @{}", ctx.accounts.handle.name);
+
+        Ok(())
+    }
+
+    pub fn delete_profile_key_share(ctx: Context<DeleteProfileKeyShare>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Profile key share deleted: owner={:?}, contact={:?}",
+             ctx.accounts.profile_key_share.owner, ctx.accounts.profile_key_share.contact);
+
+        Ok(())
+    }
+
+    pub fn delete_device(ctx: Context<DeleteDevice>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Device deleted: {:?}", ctx.accounts.device.device_id);
+
+        Ok(())
+    }
+
+    pub fn delete_prekey_bundle(ctx: Context<DeletePrekeyBundle>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Prekey bundle deleted: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    pub fn delete_session_key(ctx: Context<DeleteSessionKey>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Session key deleted: {:?}", ctx.accounts.session.session_key);
+
+        Ok(())
+    }
+
+    pub fn delete_key_record(ctx: Context<DeleteKeyRecord>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Key record deleted: epoch={}", ctx.accounts.key_record.epoch);
+
+        Ok(())
+    }
+
+    pub fn delete_guardian_set(ctx: Context<DeleteGuardianSet>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Guardian set deleted: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    pub fn delete_recovery(ctx: Context<DeleteRecovery>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Recovery deleted: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    pub fn delete_wallet_link(ctx: Context<DeleteWalletLink>) -> Result<()> {
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Wallet link deleted: primary={:?}, linked={:?}",
+             ctx.accounts.wallet_link.primary, ctx.accounts.wallet_link.linked);
+
+        Ok(())
+    }
+
+    pub fn delete_block_entry(ctx: Context<DeleteBlockEntry>) -> Result<()> {
+        release_pair_block(
+            &ctx.accounts.block_entry,
+            ctx.accounts.authority.key(),
+            ctx.accounts.peer.key(),
+            &mut ctx.accounts.pair_block,
+            &ctx.accounts.pair_block_rent_payer,
+        )?;
+        ctx.accounts.account_deletion.accounts_closed += 1;
+
+        msg!("Block entry deleted: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    /// Close the deletion record once the client found nothing left to remove
+    pub fn finish_account_deletion(ctx: Context<FinishAccountDeletion>) -> Result<()> {
+        let deletion = &ctx.accounts.account_deletion;
+
+        msg!("Account deletion finished: {:?}, relationships={}, groups={}, group_keys={}, other={}",
+             deletion.owner, deletion.relationships_closed, deletion.groups_left, deletion.group_keys_closed,
+             deletion.accounts_closed);
+
+        Ok(())
+    }
+
+    // ========== DEVICE INSTRUCTIONS ==========
+
+    /// Register a device with its own X25519 encryption key. Senders encrypt
+    /// to every non-revoked Device of a wallet.
+    pub fn add_device(
+        ctx: Context<AddDevice>,
+        device_id: [u8; 16],
+        encryption_key: [u8; 32],
+        label: String
+    ) -> Result<()> {
+        require!(label.len() <= MAX_DEVICE_LABEL_LEN, ErrorCode::DeviceLabelTooLong);
+
+        let device = &mut ctx.accounts.device;
+        device.owner = ctx.accounts.authority.key();
+        device.device_id = device_id;
+        device.encryption_key = encryption_key;
+        device.label = label;
+        device.added_at = Clock::get()?.unix_timestamp;
+        device.revoked_at = None;
+        device.rent_payer = ctx.accounts.fee_payer.key();
+
+        msg!("Device added: owner={:?}, device={:?}", device.owner, device_id);
+
+        Ok(())
+    }
+
+    /// Revoke a device so it stops receiving new messages. The entry is
+    /// kept so clients can tell a revoked device from an unknown one.
+    pub fn revoke_device(ctx: Context<RevokeDevice>) -> Result<()> {
+        let device = &mut ctx.accounts.device;
+        require!(device.revoked_at.is_none(), ErrorCode::DeviceRevoked);
+
+        device.revoked_at = Some(Clock::get()?.unix_timestamp);
+
+        msg!("Device revoked: owner={:?}, device={:?}", device.owner, device.device_id);
+
+        Ok(())
+    }
+
+    // ========== PREKEY INSTRUCTIONS ==========
+
+    /// Publish (or rotate) the signed prekey and top up the one-time prekey
+    /// pool used for X3DH. `signed_prekey.signature` is the wallet's ed25519
+    /// signature over signed_prekey_message, checked by an Ed25519 precompile
+    /// instruction right before this one.
+    pub fn publish_prekeys(
+        ctx: Context<PublishPrekeys>,
+        signed_prekey: SignedPrekey,
+        one_time_prekeys: Vec<OneTimePrekey>
+    ) -> Result<()> {
+        let authority = ctx.accounts.authority.key();
+
+        let verified = precompile_verified(
+            &ctx.accounts.instructions_sysvar,
+            ED25519_PROGRAM_ID,
+            authority.as_ref(),
+            &signed_prekey_message(authority, &signed_prekey),
+        )?;
+        require!(verified, ErrorCode::InvalidPrekeySignature);
+
+        let bundle = &mut ctx.accounts.prekey_bundle;
+
+        if bundle.owner == Pubkey::default() {
+            bundle.owner = authority;
+            bundle.rent_payer = ctx.accounts.fee_payer.key();
+        }
+
+        require!(
+            bundle.one_time_prekeys.len() + one_time_prekeys.len() <= MAX_ONE_TIME_PREKEYS,
+            ErrorCode::PrekeyPoolFull
+        );
+
+        bundle.signed_prekey = signed_prekey;
+        bundle.one_time_prekeys.extend(one_time_prekeys);
+        bundle.updated_at = Clock::get()?.unix_timestamp;
+
+        msg!("Prekeys published: owner={:?}, one_time_prekeys={}",
+             bundle.owner, bundle.one_time_prekeys.len());
+
+        Ok(())
+    }
+
+    /// Atomically take one of `owner`'s one-time prekeys to start an X3DH
+    /// handshake. The claimed key is removed from the pool and returned in
+    /// a PrekeyClaimed event; None once the pool is empty (X3DH then
+    /// proceeds with the signed prekey only). Only contacts and the two
+    /// sides of a pending invite can claim, at most
+    /// MAX_PREKEY_CLAIMS_PER_EPOCH one-time prekeys per epoch each.
+    pub fn claim_prekey(ctx: Context<ClaimPrekey>) -> Result<()> {
+        let claimer = &ctx.accounts.authority;
+        let owner = &ctx.accounts.owner;
+        let relationship = &ctx.accounts.relationship;
+
+        // Only contacts or a pending invite can drain the pool
+        require!(relationship.state_of(claimer.key()).is_some(), ErrorCode::Unauthorized);
+        require!(
+            relationship.is_mutual_contact() || relationship.states.contains(&PeerState::Invited),
+            ErrorCode::NotMutualContact
+        );
+
+        let claims = &mut ctx.accounts.prekey_claims;
+        if claims.owner == Pubkey::default() {
+            claims.owner = owner.key();
+            claims.claimer = claimer.key();
+            claims.rent_payer = ctx.accounts.fee_payer.key();
+        }
+
+        let bundle = &mut ctx.accounts.prekey_bundle;
+        let one_time_prekey = if bundle.one_time_prekeys.is_empty() {
+            None
+        } else {
+            // Per-claimer cap on one-time prekeys taken, reset every epoch
+            let epoch = Clock::get()?.epoch;
+            if claims.claim_epoch != epoch {
+                claims.claim_epoch = epoch;
+                claims.claims_this_epoch = 0;
+            }
+            require!(
+                claims.claims_this_epoch < MAX_PREKEY_CLAIMS_PER_EPOCH,
+                ErrorCode::PrekeyClaimRateLimited
+            );
+            claims.claims_this_epoch += 1;
+
+            Some(bundle.one_time_prekeys.remove(0))
+        };
+
+        emit!(PrekeyClaimed {
+            owner: owner.key(),
+            claimer: claimer.key(),
+            signed_prekey: bundle.signed_prekey.clone(),
+            one_time_prekey,
+        });
+
+        msg!("Prekey claimed: owner={:?}, claimer={:?}, remaining={}",
+             owner.key(), claimer.key(), bundle.one_time_prekeys.len());
+
+        Ok(())
+    }
+
+    /// Close a claimer's PrekeyClaims once its epoch is over (anyone can
+    /// call), refunding the rent payer. A new epoch resets the count anyway.
+    pub fn close_prekey_claims(ctx: Context<ClosePrekeyClaims>) -> Result<()> {
+        let claims = &ctx.accounts.prekey_claims;
+        require!(claims.claim_epoch != Clock::get()?.epoch, ErrorCode::PrekeyClaimsActive);
+
+        msg!("Prekey claims closed: owner={:?}, claimer={:?}", claims.owner, claims.claimer);
+
+        Ok(())
+    }
+
+    // ========== GROUP CHAT INSTRUCTIONS ==========
+
+    pub fn create_group(
+        ctx: Context<CreateGroup>,
+        group_id: [u8; 32],
+        name: String,
+        encryption_pubkey: [u8; 32],
+        token_gate: Option<TokenGate>
+    ) -> Result<()> {
+        require!(name.len() <= 64, ErrorCode::GroupNameTooLong);
+
+        let group = &mut ctx.accounts.group;
+        group.group_id = group_id;
+        group.creator = ctx.accounts.authority.key();
+        group.name = name.clone();
+        group.created_at = Clock::get()?.unix_timestamp;
+        group.members = vec![ctx.accounts.authority.key()];
+        group.encryption_pubkey = encryption_pubkey;
+        group.token_gate = token_gate;
+        group.rent_payer = ctx.accounts.fee_payer.key();
+
+        msg!("Group created: id={:?}, name={}, creator={:?}",
+             group_id, name, ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    pub fn update_group(
+        ctx: Context<UpdateGroup>,
+        name: Option<String>,
+        token_gate: Option<TokenGate>
+    ) -> Result<()> {
+        let group = &mut ctx.accounts.group;
+
+        // Only creator can update group
+        require!(
+            group.creator == ctx.accounts.authority.key(),
+            ErrorCode::NotGroupAdmin
+        );
+
+        if let Some(new_name) = name {
+            require!(new_name.len() <= 64, ErrorCode::GroupNameTooLong);
+            group.name = new_name;
+        }
+
+        if let Some(new_gate) = token_gate {
+            group.token_gate = Some(new_gate);
+        }
+
+        msg!("Group updated: id={:?}", group.group_id);
+
+        Ok(())
+    }
+
+    pub fn invite_to_group(ctx: Context<InviteToGroup>) -> Result<()> {
+        let group = &ctx.accounts.group;
+        require!(
+            ctx.accounts.invitee.is_some() != ctx.accounts.invitee_handle.is_some(),
+            ErrorCode::InviteeAccountMismatch
+        );
+        // A linked wallet is invited as the identity it belongs to
+        let invitee = resolve_invitee(
+            addressed_invitee(&ctx.accounts.invitee, &ctx.accounts.invitee_handle),
+            &ctx.accounts.invitee_link,
+        );
+
+        // Any member can invite (creator can kick bad actors)
+        require!(
+            group.members.contains(&ctx.accounts.authority.key()),
+            ErrorCode::NotGroupMember
+        );
+
+        // Check if group is full
+        require!(group.members.len() < MAX_GROUP_MEMBERS, ErrorCode::GroupFull);
+
+        // Check if already a member or invited
+        require!(
+            !group.members.contains(&invitee),
+            ErrorCode::AlreadyInvited
+        );
+
+        // Create or update invite
+        let invite = &mut ctx.accounts.group_invite;
+        invite.group_id = group.group_id;
+        invite.inviter = ctx.accounts.authority.key();
+        invite.invitee = invitee;
+        invite.status = GroupInviteStatus::Pending;
+        invite.created_at = Clock::get()?.unix_timestamp;
+
+        msg!("Group invite: group={:?}, invitee={:?}",
+             group.group_id, invitee);
+        if let Some(handle) = &ctx.accounts.invitee_handle {
+            msg!("Group invite target handle: @{}", handle.name);
+        }
+
+        Ok(())
+    }
+
+    pub fn accept_group_invite(ctx: Context<AcceptGroupInvite>) -> Result<()> {
+        check_authority(
+            &ctx.accounts.wallet,
+            &ctx.accounts.authority,
+            ctx.accounts.session_key.as_ref(),
+            SESSION_GROUP_INVITES,
+        )?;
+
+        let group = &mut ctx.accounts.group;
+        let invite = &mut ctx.accounts.group_invite;
+
+        // Verify invite status
+        require!(
+            invite.status == GroupInviteStatus::Pending,
+            ErrorCode::NotInvited
+        );
+
+        // Verify the invite is for this wallet
+        require!(
+            invite.invitee == ctx.accounts.wallet.key(),
+            ErrorCode::NotInvited
+        );
+
+        // Check token gate if exists
+        if let Some(gate) = &group.token_gate {
+            let token_account = ctx.accounts.user_token_account.as_ref()
+                .ok_or(ErrorCode::TokenAccountRequired)?;
+
+            // SECURITY FIX: Verify token account ownership
+            require!(
+                is_token_owner(token_account.owner, ctx.accounts.wallet.key(), ctx.accounts.gate_wallet_link.as_ref()),
+                ErrorCode::InvalidTokenAccount
+            );
+
+            require!(token_account.mint == gate.token_mint, ErrorCode::InsufficientTokenBalance);
+            require!(token_account.amount >= gate.min_balance, ErrorCode::InsufficientTokenBalance);
+        }
+
+        // Check if group is full
+        require!(group.members.len() < MAX_GROUP_MEMBERS, ErrorCode::GroupFull);
+
+        // Add to group
+        group.members.push(ctx.accounts.wallet.key());
+
+        // Update invite status
+        invite.status = GroupInviteStatus::Accepted;
+
+        msg!("Group invite accepted: group={:?}, member={:?}",
+             group.group_id, ctx.accounts.wallet.key());
+
+        Ok(())
+    }
+
+    pub fn reject_group_invite(ctx: Context<RejectGroupInvite>) -> Result<()> {
+        check_authority(
+            &ctx.accounts.wallet,
+            &ctx.accounts.authority,
+            ctx.accounts.session_key.as_ref(),
+            SESSION_GROUP_INVITES,
+        )?;
+
+        let invite = &mut ctx.accounts.group_invite;
+
+        // Verify invite status
+        require!(
+            invite.status == GroupInviteStatus::Pending,
+            ErrorCode::NotInvited
+        );
+
+        // Verify the invite is for this wallet
+        require!(
+            invite.invitee == ctx.accounts.wallet.key(),
+            ErrorCode::NotInvited
+        );
+
+        // Update invite status
+        invite.status = GroupInviteStatus::Rejected;
+
+        msg!("Group invite rejected: group={:?}, invitee={:?}",
+             invite.group_id, ctx.accounts.wallet.key());
+
+        Ok(())
+    }
+
+    pub fn leave_group(ctx: Context<LeaveGroup>) -> Result<()> {
+        let group = &mut ctx.accounts.group;
+
+        // Cannot leave if you're the creator
+        require!(
+            group.creator != ctx.accounts.authority.key(),
+            ErrorCode::CannotRemoveCreator
+        );
+
+        // Verify member is in group
+        require!(
+            group.members.contains(&ctx.accounts.authority.key()),
+            ErrorCode::NotGroupMember
+        );
+
+        // Remove from members
+        group.members.retain(|m| m != &ctx.accounts.authority.key());
+
+        msg!("Left group: group={:?}, member={:?}",
+             group.group_id, ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    pub fn kick_member(ctx: Context<KickMember>) -> Result<()> {
+        let group = &mut ctx.accounts.group;
+
+        // Only creator can kick (admin-only for MVP)
+        require!(
+            group.creator == ctx.accounts.authority.key(),
+            ErrorCode::NotGroupAdmin
+        );
+
+        // Cannot kick the creator
+        require!(
+            ctx.accounts.member.key() != group.creator,
+            ErrorCode::CannotRemoveCreator
+        );
+
+        // Verify member is in group
+        require!(
+            group.members.contains(&ctx.accounts.member.key()),
+            ErrorCode::NotGroupMember
+        );
+
+        // Remove from members
+        group.members.retain(|m| m != &ctx.accounts.member.key());
+
+        msg!("Kicked from group: group={:?}, member={:?}",
+             group.group_id, ctx.accounts.member.key());
+
+        Ok(())
+    }
+
+    pub fn close_group(ctx: Context<CloseGroup>) -> Result<()> {
+        let group = &ctx.accounts.group;
+
+        // Only creator can delete
+        require!(
+            group.creator == ctx.accounts.authority.key(),
+            ErrorCode::NotGroupAdmin
+        );
+
+        // Transfer lamports back to whoever paid for the group
+        let group_lamports = ctx.accounts.group.to_account_info().lamports();
+        **ctx.accounts.group.to_account_info().lamports.borrow_mut() = 0;
+        **ctx.accounts.rent_payer.lamports.borrow_mut() += group_lamports;
+
+        msg!("Group closed: group={:?}", group.group_id);
+
+        Ok(())
+    }
+
+    pub fn store_group_key(
+        ctx: Context<StoreGroupKey>,
+        group_id: [u8; 32],
+        encrypted_key: Vec<u8>,
+        nonce: [u8; 24],
+    ) -> Result<()> {
+        // A passkey signs over the share it stores
+        let mut hasher = Sha256::new();
+        hasher.update(group_id);
+        hasher.update(&encrypted_key);
+        hasher.update(nonce);
+        let target: [u8; 32] = hasher.finalize().into();
+
+        check_authority_or_passkey(
+            &ctx.accounts.wallet,
+            &ctx.accounts.authority,
+            ctx.accounts.session_key.as_ref(),
+            ctx.accounts.passkey_profile.as_mut(),
+            ctx.accounts.instructions_sysvar.as_ref(),
+            SESSION_STORE_GROUP_KEY,
+            &target,
+        )?;
+
+        let key_share = &mut ctx.accounts.group_key_share;
+        let group = &ctx.accounts.group;
+
+        // Verify wallet is a member of the group
+        require!(
+            group.members.contains(&ctx.accounts.wallet.key()),
+            ErrorCode::NotGroupMember
+        );
+
+        // Rent is owed to whoever created the share, not later updaters
+        if key_share.member == Pubkey::default() {
+            key_share.rent_payer = ctx.accounts.fee_payer.key();
+        }
+
+        // Store the encrypted key share
+        key_share.group_id = group.group_id;
+        key_share.member = ctx.accounts.wallet.key();
+        key_share.encrypted_key = encrypted_key;
+        key_share.nonce = nonce;
+
+        msg!("Group key stored for member: {:?}", ctx.accounts.wallet.key());
+
+        Ok(())
+    }
+
+    pub fn close_group_key(ctx: Context<CloseGroupKey>) -> Result<()> {
+        // Verify the key share belongs to the authority
+        require!(
+            ctx.accounts.group_key_share.member == ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+
+        // Transfer lamports back to whoever paid for the key share
+        let key_share_lamports = ctx.accounts.group_key_share.to_account_info().lamports();
+        **ctx.accounts.group_key_share.to_account_info().lamports.borrow_mut() = 0;
+        **ctx.accounts.rent_payer.lamports.borrow_mut() += key_share_lamports;
+
+        msg!("Group key share closed for member: {:?}", ctx.accounts.authority.key());
+
+        Ok(())
+    }
+
+    // ========== LAYOUT MIGRATION INSTRUCTIONS ==========
+
+    /// Upgrade a profile written with an older layout in place, so it keeps
+    /// its address. Layout 1 profiles also get the epoch 0 key record and a
+    /// wallet descriptor; the owner becomes their rent payer.
+    pub fn migrate_profile(ctx: Context<MigrateProfile>) -> Result<()> {
+        let info = ctx.accounts.user_profile.to_account_info();
+        let owner = ctx.accounts.authority.key();
+
+        let legacy = {
+            let data = info.try_borrow_data()?;
+            require!(data.len() == LEGACY_USER_PROFILE_SPACE, ErrorCode::AlreadyMigrated);
+            require!(data[..8] == *UserProfile::DISCRIMINATOR, ErrorCode::AlreadyMigrated);
+            LegacyUserProfile::deserialize(&mut &data[8..])?
+        };
+        require_keys_eq!(legacy.owner, owner, ErrorCode::Unauthorized);
+
+        let profile = UserProfile {
+            owner,
+            rent_payer: owner,
+            layout_version: PROFILE_LAYOUT_VERSION,
+            display_name: legacy.display_name,
+            avatar_type: legacy.avatar_type,
+            avatar_data: legacy.avatar_data,
+            encryption_public_key: legacy.encryption_public_key,
+            key_epoch: 0,
+            invite_deposit: None,
+            invite_policy: InvitePolicy::Anyone,
+            badges: Vec::new(),
+            bio: String::new(),
+            status: String::new(),
+            links: Vec::new(),
+            avatar_content: None,
+            visibility: ProfileVisibility::Public,
+            sealed: None,
+            migration_target: None,
+            passkey: None,
+            passkey_nonce: 0,
+            profile_key_floor: 0,
+        };
+        resize_for_layout(&info, &ctx.accounts.fee_payer, &ctx.accounts.system_program, profile.space())?;
+        profile.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        let wallet_descriptor = &mut ctx.accounts.wallet_descriptor;
+        wallet_descriptor.owner = owner;
+        wallet_descriptor.invite_epoch = 0;
+        wallet_descriptor.invites_this_epoch = 0;
+        wallet_descriptor.session_keys = 0;
+
+        let key_record = &mut ctx.accounts.key_record;
+        key_record.owner = owner;
+        key_record.epoch = 0;
+        key_record.key = profile.encryption_public_key;
+        key_record.activated_at = Clock::get()?.unix_timestamp;
+        key_record.rent_payer = ctx.accounts.fee_payer.key();
+        key_record.log_index = None;
+        if let Some(key_log) = ctx.accounts.key_log.as_mut() {
+            log_key(key_log, key_record)?;
+        }
+
+        msg!("Profile migrated: {:?}", owner);
+
+        Ok(())
+    }
+
+    /// Upgrade a conversation created before rent payers were tracked. The
+    /// participant migrating it becomes its rent payer.
+    pub fn migrate_conversation(ctx: Context<MigrateConversation>) -> Result<()> {
+        let info = ctx.accounts.conversation.to_account_info();
+        let me = ctx.accounts.authority.key();
+
+        let legacy = {
+            let data = info.try_borrow_data()?;
+            require!(data.len() == LEGACY_CONVERSATION_SPACE, ErrorCode::AlreadyMigrated);
+            require!(data[..8] == *Conversation::DISCRIMINATOR, ErrorCode::AlreadyMigrated);
+            LegacyConversation::deserialize(&mut &data[8..])?
+        };
+        require!(legacy.participants.contains(&me), ErrorCode::Unauthorized);
+
+        let conversation = Conversation {
+            participants: legacy.participants,
+            created_at: legacy.created_at,
+            rent_payer: me,
+        };
+        resize_for_layout(&info, &ctx.accounts.fee_payer, &ctx.accounts.system_program, Conversation::SPACE)?;
+        conversation.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        msg!("Conversation migrated: participant={:?}, peer={:?}", me, ctx.accounts.peer.key());
+
+        Ok(())
+    }
+
+    /// Upgrade a group created before rent payers were tracked. The creator
+    /// becomes its rent payer; fee_payer covers growing it to full size.
+    pub fn migrate_group(ctx: Context<MigrateGroup>, _group_id: [u8; 32]) -> Result<()> {
+        let info = ctx.accounts.group.to_account_info();
+        let creator = ctx.accounts.authority.key();
+
+        let legacy = {
+            let data = info.try_borrow_data()?;
+            require!(data.len() >= 8 && data[..8] == *Group::DISCRIMINATOR, ErrorCode::AlreadyMigrated);
+            let legacy = LegacyGroup::deserialize(&mut &data[8..])?;
+            require!(LegacyGroup::is_legacy_size(data.len(), legacy.members.len()), ErrorCode::AlreadyMigrated);
+            legacy
+        };
+        require_keys_eq!(legacy.creator, creator, ErrorCode::NotGroupAdmin);
+
+        let group = Group {
+            group_id: legacy.group_id,
+            creator: legacy.creator,
+            name: legacy.name,
+            created_at: legacy.created_at,
+            members: legacy.members,
+            encryption_pubkey: legacy.encryption_pubkey,
+            token_gate: legacy.token_gate,
+            rent_payer: creator,
+        };
+        resize_for_layout(&info, &ctx.accounts.fee_payer, &ctx.accounts.system_program, Group::space(MAX_GROUP_MEMBERS))?;
+        group.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        msg!("Group migrated: group={:?}", group.group_id);
+
+        Ok(())
+    }
+
+    /// Upgrade a group key share stored before rent payers were tracked.
+    /// The member becomes its rent payer.
+    pub fn migrate_group_key_share(ctx: Context<MigrateGroupKeyShare>, _group_id: [u8; 32]) -> Result<()> {
+        let info = ctx.accounts.group_key_share.to_account_info();
+        let member = ctx.accounts.authority.key();
+
+        let legacy = {
+            let data = info.try_borrow_data()?;
+            require!(data.len() == LEGACY_GROUP_KEY_SHARE_SPACE, ErrorCode::AlreadyMigrated);
+            require!(data[..8] == *GroupKeyShare::DISCRIMINATOR, ErrorCode::AlreadyMigrated);
+            LegacyGroupKeyShare::deserialize(&mut &data[8..])?
+        };
+        require_keys_eq!(legacy.member, member, ErrorCode::Unauthorized);
+
+        let share = GroupKeyShare {
+            group_id: legacy.group_id,
+            member,
+            encrypted_key: legacy.encrypted_key,
+            nonce: legacy.nonce,
+            rent_payer: member,
+        };
+        resize_for_layout(&info, &ctx.accounts.fee_payer, &ctx.accounts.system_program, GroupKeyShare::SPACE)?;
+        share.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        msg!("Group key share migrated: group={:?}, member={:?}", share.group_id, member);
+
+        Ok(())
+    }
+
+    /// Move one contact of a version 1 wallet descriptor (the peers list)
+    /// into its Relationship. The peer's legacy descriptor, when it still
+    /// exists, gives their side; otherwise it is inferred from mine. Legacy
+    /// blocks become idle relationships, re-block with block(). A no-op if
+    /// the peer already migrated the pair.
+    pub fn migrate_legacy_contact(ctx: Context<MigrateLegacyContact>) -> Result<()> {
+        let me = ctx.accounts.authority.key();
+        let peer = ctx.accounts.peer.key();
+        require_keys_neq!(me, peer, ErrorCode::CannotInviteSelf);
+
+        let relationship = &mut ctx.accounts.relationship;
+        if relationship.participants[0] != Pubkey::default() {
+            msg!("Legacy contact already migrated: {:?}, {:?}", me, peer);
+            return Ok(());
+        }
+
+        let mine = LegacyWalletDescriptor::read(&ctx.accounts.legacy_descriptor)?
+            .and_then(|descriptor| descriptor.state_of(peer))
+            .ok_or(ErrorCode::LegacyContactNotFound)?;
+        let theirs = match ctx.accounts.peer_legacy_descriptor.as_ref() {
+            Some(descriptor) => LegacyWalletDescriptor::read(descriptor)?.and_then(|d| d.state_of(me)),
+            None => None,
+        };
+        let (my_state, peer_state) = legacy_pair_states(mine, theirs);
+
+        let now = Clock::get()?.unix_timestamp;
+        relationship.participants = sorted_pair(me, peer);
+        relationship.set_state(me, my_state);
+        relationship.set_state(peer, peer_state);
+        relationship.created_at = now;
+        relationship.updated_at = now;
+        relationship.rent_payer = ctx.accounts.fee_payer.key();
+        relationship.has_conversation = !ctx.accounts.conversation.data_is_empty();
+
+        msg!("Legacy contact migrated: {:?}, {:?}", me, peer);
+
+        Ok(())
+    }
+
+    /// Close my version 1 wallet descriptor once its contacts are migrated,
+    /// returning its rent to me (I paid for it)
+    pub fn close_legacy_descriptor(ctx: Context<CloseLegacyDescriptor>) -> Result<()> {
+        let descriptor = &ctx.accounts.legacy_descriptor;
+        let owner = ctx.accounts.authority.to_account_info();
+        require!(
+            LegacyWalletDescriptor::read(descriptor)?.is_some_and(|d| d.owner == owner.key()),
+            ErrorCode::Unauthorized
+        );
+
+        **owner.try_borrow_mut_lamports()? += descriptor.lamports();
+        **descriptor.try_borrow_mut_lamports()? = 0;
+        descriptor.assign(&system_program::ID);
+        descriptor.resize(0)?;
+
+        msg!("Legacy wallet descriptor closed: {:?}", owner.key());
+
+        Ok(())
+    }
+
+    // ========== ARCIUM MPC INSTRUCTIONS ==========
+
+    /// Initialize computation definition for is_accepted_contact circuit
+    pub fn init_is_accepted_contact_comp_def(ctx: Context<InitIsAcceptedContactCompDef>) -> Result<()> {
+        init_comp_def(
+            ctx.accounts,
+            Some(CircuitSource::OffChain(OffChainCircuitSource {
+                source: "https://mukon-circuits.fly.dev/is_accepted_contact.arcis".to_string(),
+                hash: circuit_hash!("is_accepted_contact"),
+            })),
+            None,
+        )?;
+        msg!("Initialized comp def: is_accepted_contact");
+        Ok(())
+    }
+
+    /// Initialize computation definition for count_accepted circuit
+    pub fn init_count_accepted_comp_def(ctx: Context<InitCountAcceptedCompDef>) -> Result<()> {
+        init_comp_def(
+            ctx.accounts,
+            Some(CircuitSource::OffChain(OffChainCircuitSource {
+                source: "https://mukon-circuits.fly.dev/count_accepted.arcis".to_string(),
+                hash: circuit_hash!("count_accepted"),
+            })),
+            None,
+        )?;
+        msg!("Initialized comp def: count_accepted");
+        Ok(())
+    }
+
+    /// Initialize computation definition for add_two_numbers circuit (demo)
+    pub fn init_add_two_numbers_comp_def(ctx: Context<InitAddTwoNumbersCompDef>) -> Result<()> {
+        init_comp_def(
+            ctx.accounts,
+            Some(CircuitSource::OffChain(OffChainCircuitSource {
+                source: "https://mukon-circuits.fly.dev/add_two_numbers.arcis".to_string(),
+                hash: circuit_hash!("add_two_numbers"),
+            })),
+            None,
+        )?;
+        msg!("Initialized comp def: add_two_numbers");
+        Ok(())
+    }
+
+    /// Queue MPC computation to check if a contact is accepted
+    pub fn check_is_contact(
+        ctx: Context<CheckIsContact>,
+        computation_offset: u64,
+        encrypted_contact_list: Vec<u8>,
+        encrypted_query_pubkey: Vec<u8>,
+        pub_key: [u8; 32],
+        nonce_list: u128,
+        nonce_query: u128,
+    ) -> Result<()> {
+        let args = ArgBuilder::new()
+            .x25519_pubkey(pub_key)
+            .plaintext_u128(nonce_list)
+            .encrypted_bytes(encrypted_contact_list)
+            .x25519_pubkey(pub_key)
+            .plaintext_u128(nonce_query)
+            .encrypted_bytes(encrypted_query_pubkey)
+            .build();
+
+        ctx.accounts.sign_pda_account.bump = ctx.bumps.sign_pda_account;
+
+        queue_computation(
+            ctx.accounts,
+            computation_offset,
+            args,
+            None,
+            vec![CheckIsContactCallback::callback_ix(
+                computation_offset,
+                &ctx.accounts.mxe_account,
+                &[],
+            )?],
+            1,
+            0,
+        )?;
+
+        msg!("Queued is_contact computation: offset={}", computation_offset);
+        Ok(())
+    }
+
+    /// Callback for is_accepted_contact computation
+    #[arcium_callback(encrypted_ix = "is_accepted_contact")]
+    pub fn check_is_contact_callback(
+        ctx: Context<CheckIsContactCallback>,
+        output: SignedComputationOutputs<IsAcceptedContactOutput>,
+    ) -> Result<()> {
+        let result = match output.verify_output(
+            &ctx.accounts.cluster_account,
+            &ctx.accounts.computation_account,
+        ) {
+            Ok(IsAcceptedContactOutput { field_0 }) => field_0,
+            Err(e) => {
+                msg!("MPC computation failed: {}", e);
+                return Err(ErrorCode::AbortedComputation.into());
+            }
+        };
+
+        emit!(ContactCheckResult {
+            ciphertext: result.ciphertexts[0],
+            nonce: result.nonce,
+            encryption_key: result.encryption_key,
+        });
+
+        msg!("is_contact computation completed");
+        Ok(())
+    }
+
+    /// Queue MPC computation to count accepted contacts
+    pub fn count_accepted_contacts(
+        ctx: Context<CountAcceptedContacts>,
+        computation_offset: u64,
+        encrypted_contact_list: Vec<u8>,
+        pub_key: [u8; 32],
+        nonce_list: u128,
+    ) -> Result<()> {
+        let args = ArgBuilder::new()
+            .x25519_pubkey(pub_key)
+            .plaintext_u128(nonce_list)
+            .encrypted_bytes(encrypted_contact_list)
+            .build();
+
+        ctx.accounts.sign_pda_account.bump = ctx.bumps.sign_pda_account;
+
+        queue_computation(
+            ctx.accounts,
+            computation_offset,
+            args,
+            None,
+            vec![CountAcceptedContactsCallback::callback_ix(
+                computation_offset,
+                &ctx.accounts.mxe_account,
+                &[],
+            )?],
+            1,
+            0,
+        )?;
+
+        msg!("Queued count_accepted computation: offset={}", computation_offset);
+        Ok(())
+    }
+
+    /// Callback for count_accepted computation
+    #[arcium_callback(encrypted_ix = "count_accepted")]
+    pub fn count_accepted_contacts_callback(
+        ctx: Context<CountAcceptedContactsCallback>,
+        output: SignedComputationOutputs<CountAcceptedOutput>,
+    ) -> Result<()> {
+        let result = match output.verify_output(
+            &ctx.accounts.cluster_account,
+            &ctx.accounts.computation_account,
+        ) {
+            Ok(CountAcceptedOutput { field_0 }) => field_0,
+            Err(e) => {
+                msg!("MPC computation failed: {}", e);
+                return Err(ErrorCode::AbortedComputation.into());
+            }
+        };
+
+        emit!(ContactCountResult {
+            ciphertext: result.ciphertexts[0],
+            nonce: result.nonce,
+            encryption_key: result.encryption_key,
+        });
+
+        msg!("count_accepted computation completed");
+        Ok(())
+    }
+}
+
+// ========== ACCOUNT STRUCTURES ==========
+
+const WALLET_DESCRIPTOR_VERSION: [u8; 1] = [2];
+// Descriptors that held the peers list, see migrate_legacy_contact
+const LEGACY_WALLET_DESCRIPTOR_VERSION: [u8; 1] = [1];
+const USER_PROFILE_VERSION: [u8; 1] = [1];
+const CONVERSATION_VERSION: [u8; 1] = [1];
+const RELATIONSHIP_VERSION: [u8; 1] = [1];
+const GROUP_VERSION: [u8; 1] = [1];
+const GROUP_INVITE_VERSION: [u8; 1] = [1];
+const GROUP_KEY_SHARE_VERSION: [u8; 1] = [1];
+const INVITE_NOTE_VERSION: [u8; 1] = [1];
+const INVITE_ESCROW_VERSION: [u8; 1] = [1];
+
+const USED_INVITE_TOKEN_VERSION: [u8; 1] = [1];
+const SESSION_KEY_VERSION: [u8; 1] = [1];
+const DEVICE_VERSION: [u8; 1] = [1];
+const PREKEY_BUNDLE_VERSION: [u8; 1] = [1];
+const PREKEY_CLAIMS_VERSION: [u8; 1] = [1];
+const ENCRYPTION_KEY_RECORD_VERSION: [u8; 1] = [1];
+const KEY_LOG_VERSION: [u8; 1] = [1];
+const HANDLE_VERSION: [u8; 1] = [1];
+const HANDLE_CONFIG_VERSION: [u8; 1] = [1];
+const PROFILE_KEY_SHARE_VERSION: [u8; 1] = [1];
+const GUARDIAN_SET_VERSION: [u8; 1] = [1];
+const RECOVERY_VERSION: [u8; 1] = [1];
+const WALLET_FORWARD_VERSION: [u8; 1] = [1];
+const WALLET_LINK_VERSION: [u8; 1] = [1];
+const ACCOUNT_DELETION_VERSION: [u8; 1] = [1];
+const BLOCK_VERSION: [u8; 1] = [1];
+const PAIR_BLOCK_VERSION: [u8; 1] = [1];
+const TOMBSTONE_VERSION: [u8; 1] = [1];
+
+// Layout of UserProfile, bumped instead of USER_PROFILE_VERSION so the
+// profile keeps its address; 1 is LegacyUserProfile
+const PROFILE_LAYOUT_VERSION: u8 = 2;
+
+const MAX_INTRO_LEN: usize = 256;
+const SIGNED_INVITE_DOMAIN: &[u8] = b"mukon-messenger:invite";
+const PASSKEY_DOMAIN: &[u8] = b"mukon-messenger:passkey";
+const SIGNED_PREKEY_DOMAIN: &[u8] = b"mukon-messenger:prekey";
+const MAX_INVITES_PER_EPOCH: u16 = 50;
+const REINVITE_COOLDOWN_BASE: i64 = 24 * 60 * 60;  // 1 day after the first rejection
+const REINVITE_COOLDOWN_MAX: i64 = 30 * 24 * 60 * 60;
+const MAX_SESSION_DURATION: i64 = 7 * 24 * 60 * 60;
+const MAX_DEVICE_LABEL_LEN: usize = 32;
+const MAX_ONE_TIME_PREKEYS: usize = 100;
+const MAX_GROUP_MEMBERS: usize = 30;
+const MAX_PREKEY_CLAIMS_PER_EPOCH: u8 = 10;
+const KEY_LOG_MAX_DEPTH: usize = 32;
+const MIN_HANDLE_LEN: usize = 3;
+const MAX_HANDLE_LEN: usize = 32;  // Also the max PDA seed length
+const MAX_COLLECTION_BADGES: usize = 4;
+const MAX_BIO_LEN: usize = 160;
+const MAX_STATUS_LEN: usize = 64;
+const MAX_PROFILE_LINKS: usize = 4;
+const MAX_LINK_LEN: usize = 100;
+const MAX_SEALED_PROFILE_LEN: usize = 1024;
+const MAX_GUARDIANS: usize = 10;
+const MIN_RECOVERY_TIMELOCK: i64 = 24 * 60 * 60;
+const MAX_RECOVERY_TIMELOCK: i64 = 30 * 24 * 60 * 60;
+const MAX_RECOVERY_AGE: i64 = 30 * 24 * 60 * 60;  // Unapproved proposals expire after this
+
+// Instructions a session key can be allowed to sign (SessionKey.allowed bits)
+pub const SESSION_ACCEPT: u32 = 1 << 0;
+pub const SESSION_REJECT: u32 = 1 << 1;
+pub const SESSION_BLOCK: u32 = 1 << 2;  // block and unblock
+pub const SESSION_STORE_GROUP_KEY: u32 = 1 << 3;
+pub const SESSION_GROUP_INVITES: u32 = 1 << 4;  // accept/reject group invites
+const SESSION_ALL: u32 = SESSION_ACCEPT | SESSION_REJECT | SESSION_BLOCK
+    | SESSION_STORE_GROUP_KEY | SESSION_GROUP_INVITES;
+
+// There is no Blocked state: blocks live in the blocker's BlockEntry,
+// which no instruction the peer can call reads, so a block never changes
+// what the blocked party sees or which of their calls succeed
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    Invited = 0,
+    Requested = 1,
+    Accepted = 2,
+    Rejected = 3,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum AvatarType {
+    Emoji = 0,
+    Nft = 1,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum GroupInviteStatus {
+    Pending = 0,
+    Accepted = 1,
+    Rejected = 2,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct TokenGate {
+    pub token_mint: Pubkey,
+    pub min_balance: u64,
+}
+
+/// Which wallets may invite a user
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub enum InvitePolicy {
+    Anyone,
+    Nobody,
+    TokenHolders(TokenGate),
+    NftCollection(Pubkey),  // Verified Metaplex collection
+    ContactsOfContacts,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct InviteDeposit {
+    pub mint: Option<Pubkey>,  // None = lamports
+    pub amount: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SignedPrekey {
+    pub id: u32,
+    pub key: [u8; 32],  // X25519
+    pub signature: [u8; 64],  // Wallet's ed25519 signature over signed_prekey_message
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct OneTimePrekey {
+    pub id: u32,
+    pub key: [u8; 32],  // X25519
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct EncryptedIntro {
+    pub ciphertext: Vec<u8>,
+    pub nonce: [u8; 24],
+}
+
+#[account]
+pub struct WalletDescriptor {
+    pub owner: Pubkey,
+    pub invite_epoch: u64,  // Epoch invites_this_epoch counts for
+    pub invites_this_epoch: u16,
+    pub session_keys: u16,  // Open SessionKey accounts; a migration waits for them
+}
+
+#[account]
+pub struct UserProfile {
+    pub owner: Pubkey,
+    pub rent_payer: Pubkey,  // Paid for this account and the WalletDescriptor
+    pub layout_version: u8,  // PROFILE_LAYOUT_VERSION when written, see migrate_profile
+    pub display_name: String,
+    pub avatar_type: AvatarType,
+    pub avatar_data: String,
+    pub encryption_public_key: [u8; 32],
+    pub key_epoch: u32,  // Epoch of encryption_public_key's EncryptionKeyRecord
+    pub invite_deposit: Option<InviteDeposit>,
+    pub invite_policy: InvitePolicy,
+    pub badges: Vec<CollectionBadge>,  // Verified collections of NFTs the owner holds
+    // Optional details, sized to what is set
+    pub bio: String,
+    pub status: String,
+    pub links: Vec<String>,
+    pub avatar_content: Option<AvatarContent>,
+    pub visibility: ProfileVisibility,
+    pub sealed: Option<SealedProfile>,  // Set when visibility is ContactsOnly
+    pub migration_target: Option<Pubkey>,  // Wallet allowed to take over this identity
+    pub passkey: Option<[u8; 33]>,  // secp256r1, compressed
+    pub passkey_nonce: u64,  // Signed into every passkey message, bumped on use
+    pub profile_key_floor: u32,  // Lowest profile key version still allowed
+}
+
+impl UserProfile {
+    /// Account size with the given optional details and sealed details;
+    /// everything before them is sized for its maximum
+    pub fn space_for(
+        bio: &str,
+        status: &str,
+        links: &[String],
+        avatar_content: &Option<AvatarContent>,
+        sealed: &Option<SealedProfile>,
+    ) -> usize {
+        8 + 32 + 32 + 1 + (4 + 32) + 1 + (4 + 128) + 32 + 4 + (1 + (1 + 32) + 8) + (1 + 32 + 8)
+            + (4 + MAX_COLLECTION_BADGES * 64)
+            + (4 + bio.len())
+            + (4 + status.len())
+            + (4 + links.iter().map(|l| 4 + l.len()).sum::<usize>())
+            + (1 + avatar_content.as_ref().map_or(0, |_| 32 + 1))
+            + 1
+            + (1 + sealed.as_ref().map_or(0, |s| (4 + s.ciphertext.len()) + 24 + 4))
+            + (1 + 32)
+            + (1 + 33) + 8
+            + 4
+    }
+
+    pub fn space(&self) -> usize {
+        Self::space_for(&self.bio, &self.status, &self.links, &self.avatar_content, &self.sealed)
+    }
+
+    /// Epoch the next key rotation starts
+    pub fn next_key_epoch(&self) -> Result<u32> {
+        self.key_epoch.checked_add(1).ok_or_else(|| ErrorCode::KeyEpochOverflow.into())
+    }
+
+    /// A contact that held profile key `version` is gone: sealed profiles
+    /// and shares must use a newer key from now on
+    pub fn retire_profile_key(&mut self, version: u32) {
+        self.profile_key_floor = self.profile_key_floor.max(version.saturating_add(1));
+    }
+
+    /// Size after set_profile_visibility: sealing clears the plaintext details
+    pub fn space_with_sealed(&self, sealed: &Option<SealedProfile>) -> usize {
+        match sealed {
+            Some(_) => Self::space_for("", "", &[], &None, sealed),
+            None => Self::space_for(&self.bio, &self.status, &self.links, &self.avatar_content, sealed),
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileVisibility {
+    Public,
+    ContactsOnly,  // Name, avatar and details only in `sealed`
+}
+
+/// Profile details encrypted with the owner's profile key
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct SealedProfile {
+    pub ciphertext: Vec<u8>,
+    pub nonce: [u8; 24],
+    pub key_version: u32,  // Matches ProfileKeyShare.key_version
+}
+
+/// Profile key of `owner` wrapped for one of their contacts
+#[account]
+pub struct ProfileKeyShare {
+    pub owner: Pubkey,
+    pub contact: Pubkey,
+    pub wrapped_key: Vec<u8>,
+    pub nonce: [u8; 24],
+    pub key_version: u32,
+    pub rent_payer: Pubkey,
+}
+
+impl ProfileKeyShare {
+    pub const SPACE: usize = 8 + 32 + 32 + (4 + 48) + 24 + 4 + 32;
+}
+
+/// Where an avatar image is stored
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AvatarStorage {
+    Ipfs,
+    Arweave,
+    Relay,
+}
+
+/// Hash of the avatar image at `avatar_data`, so clients can verify what they download
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct AvatarContent {
+    pub sha256: [u8; 32],
+    pub storage: AvatarStorage,
+}
+
+/// Verified collection shown on a profile, backed by an NFT from it
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct CollectionBadge {
+    pub collection: Pubkey,
+    pub mint: Pubkey,
+}
+
+#[account]
+pub struct Conversation {
+    pub participants: [Pubkey; 2],
+    pub created_at: i64,
+    pub rent_payer: Pubkey,
+}
+
+impl Conversation {
+    pub const SPACE: usize = 8 + 64 + 8 + 32;
+}
+
+/// Contact relationship between two wallets, one account per pair.
+/// `participants` is sorted (same order as get_chat_hash), `states[i]`
+/// is the state of the relationship as seen by `participants[i]`.
+#[account]
+pub struct Relationship {
+    pub participants: [Pubkey; 2],
+    pub states: [PeerState; 2],
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub rent_payer: Pubkey,
+    pub invite_expires_at: Option<i64>,
+    pub has_deposit: bool,  // Pending invite has an InviteEscrow
+    pub has_intro: bool,  // Pending invite has an InviteNote
+    pub has_conversation: bool,  // The pair's Conversation is open
+    pub rejections: [u8; 2],  // Times participants[i]'s invites were rejected
+    pub last_rejected_at: [i64; 2],
+    pub verifications: [Option<KeyVerification>; 2],  // Attested by participants[i]
+}
+
+/// Safety number a participant attested, with the key epochs it covers
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct KeyVerification {
+    pub fingerprint: [u8; 32],
+    pub key_epochs: [u32; 2],
+    pub verified_at: i64,
+}
+
+impl Relationship {
+    pub const SPACE: usize = 8 + 64 + 2 + 8 + 8 + 32 + (1 + 8) + 1 + 1 + 1 + 2 + 16 + 2 * (1 + 32 + 8 + 8);
+
+    fn side(&self, wallet: Pubkey) -> Option<usize> {
+        self.participants.iter().position(|p| *p == wallet)
+    }
+
+    pub fn peer_of(&self, wallet: Pubkey) -> Option<Pubkey> {
+        self.side(wallet).map(|i| self.participants[1 - i])
+    }
+
+    /// Accepted on both sides
+    pub fn is_mutual_contact(&self) -> bool {
+        self.states == [PeerState::Accepted, PeerState::Accepted]
+    }
+
+    pub fn state_of(&self, wallet: Pubkey) -> Option<PeerState> {
+        self.side(wallet).map(|i| self.states[i])
+    }
+
+    pub fn set_state(&mut self, wallet: Pubkey, state: PeerState) {
+        if let Some(i) = self.side(wallet) {
+            self.states[i] = state;
+        }
+    }
+
+    pub fn is_invite_expired(&self, now: i64) -> bool {
+        self.invite_expires_at.is_some_and(|expiry| now >= expiry)
+    }
+
+    pub fn record_rejection(&mut self, inviter: Pubkey, now: i64) {
+        if let Some(i) = self.side(inviter) {
+            self.rejections[i] = self.rejections[i].saturating_add(1);
+            self.last_rejected_at[i] = now;
+        }
+    }
+
+    pub fn was_rejected(&self, inviter: Pubkey) -> bool {
+        self.side(inviter).is_some_and(|i| self.rejections[i] > 0)
+    }
+
+    pub fn rejection_history(&self, inviter: Pubkey) -> (u8, i64) {
+        self.side(inviter).map_or((0, 0), |i| (self.rejections[i], self.last_rejected_at[i]))
+    }
+
+    /// Carry over rejections `inviter` collected under a deleted identity
+    pub fn restore_rejections(&mut self, inviter: Pubkey, rejections: u8, last_rejected_at: i64) {
+        if let Some(i) = self.side(inviter) {
+            self.rejections[i] = rejections;
+            self.last_rejected_at[i] = last_rejected_at;
+        }
+    }
+
+    /// Earliest time `inviter` may invite again: the cooldown doubles with
+    /// every rejection, up to REINVITE_COOLDOWN_MAX
+    pub fn reinvite_allowed_at(&self, inviter: Pubkey) -> i64 {
+        let Some(i) = self.side(inviter) else { return 0 };
+        if self.rejections[i] == 0 {
+            return 0;
+        }
+        let doublings = u32::from(self.rejections[i] - 1).min(16);
+        let cooldown = (REINVITE_COOLDOWN_BASE << doublings).min(REINVITE_COOLDOWN_MAX);
+        self.last_rejected_at[i].saturating_add(cooldown)
+    }
+
+    /// Both sides attested the same fingerprint and neither key has been
+    /// rotated since (`key_epochs` are the participants' current epochs)
+    pub fn is_key_verified(&self, key_epochs: [u32; 2]) -> bool {
+        match self.verifications {
+            [Some(a), Some(b)] => {
+                a.fingerprint == b.fingerprint && a.key_epochs == key_epochs && b.key_epochs == key_epochs
+            }
+            _ => false,
+        }
+    }
+
+    /// Hand `old`'s side to `new`, keeping participants sorted
+    pub fn replace_participant(&mut self, old: Pubkey, new: Pubkey) {
+        let Some(i) = self.side(old) else { return };
+        self.participants[i] = new;
+        if self.participants[0] > self.participants[1] {
+            self.participants.swap(0, 1);
+            self.states.swap(0, 1);
+            self.rejections.swap(0, 1);
+            self.last_rejected_at.swap(0, 1);
+            self.verifications.swap(0, 1);
+        }
+    }
+
+    /// Rejection history that must outlive a withdrawn invite
+    pub fn has_history(&self) -> bool {
+        self.rejections.iter().any(|&n| n > 0)
+    }
+}
+
+/// A wallet the owner blocked. Only the owner's clients can tell who:
+/// the address and `blinded_peer` use a salted hash of the peer, and
+/// `sealed_peer` is the peer's key encrypted for the owner.
+#[account]
+pub struct BlockEntry {
+    pub owner: Pubkey,
+    pub blinded_peer: [u8; 32],
+    pub sealed_peer: [u8; 48],
+    pub nonce: [u8; 24],
+    pub pair_tag: [u8; 32],  // Hash of the pair's chat hash and blinded_peer
+    pub created_at: i64,
+    pub rent_payer: Pubkey,
+}
+
+/// Blocks standing between two wallets, at the pair's chat hash. It does
+/// not say which side set them.
+#[account]
+pub struct PairBlock {
+    pub blocks: u32,
+    pub rent_payer: Pubkey,
+}
+
+/// Marks a signed invite nonce as redeemed so the token can't be replayed
+#[account]
+pub struct UsedInviteToken {
+    pub inviter: Pubkey,
+    pub nonce: u64,
+    pub redeemed_by: Pubkey,
+}
+
+/// Ephemeral key a wallet has authorized to sign some instructions for it,
+/// so the app doesn't need a wallet prompt for every action
+#[account]
+pub struct SessionKey {
+    pub wallet: Pubkey,
+    pub session_key: Pubkey,
+    pub expires_at: i64,
+    pub allowed: u32,  // SESSION_* bits
+    pub rent_payer: Pubkey,
+}
+
+/// An encryption key a wallet used, one per epoch. Lets peers find the key
+/// that was current when an old message was sent.
+#[account]
+pub struct EncryptionKeyRecord {
+    pub owner: Pubkey,
+    pub epoch: u32,
+    pub key: [u8; 32],
+    pub activated_at: i64,
+    pub log_index: Option<u64>,  // Leaf index in the KeyLog, once logged
+    pub rent_payer: Pubkey,
+}
+
+impl EncryptionKeyRecord {
+    pub const SPACE: usize = 8 + 32 + 4 + 32 + 8 + (1 + 8) + 32;
+}
+
+/// Append-only Merkle tree (RFC 9162 hashing, see merkle.rs) committing
+/// every key registration and rotation, so a substituted key shows up
+/// to anyone auditing the log
+#[account]
+pub struct KeyLog {
+    pub tree_size: u64,
+    pub root: [u8; 32],
+    pub frontier: [[u8; 32]; KEY_LOG_MAX_DEPTH],  // Perfect subtree roots by height
+}
+
+impl KeyLog {
+    /// Append a leaf hash, returning its index
+    pub fn append(&mut self, leaf: [u8; 32]) -> Result<u64> {
+        let index = self.tree_size;
+        require!(index < (1u64 << KEY_LOG_MAX_DEPTH) - 1, ErrorCode::KeyLogFull);
+
+        // Merge with every complete subtree on the right edge
+        let mut node = leaf;
+        let mut height = 0;
+        while (index >> height) & 1 == 1 {
+            node = merkle::node_hash(&self.frontier[height], &node);
+            height += 1;
+        }
+        self.frontier[height] = node;
+        self.tree_size += 1;
+
+        // Root folds the subtrees from the smallest (rightmost) up
+        let mut root: Option<[u8; 32]> = None;
+        for (height, subtree) in self.frontier.iter().enumerate() {
+            if (self.tree_size >> height) & 1 == 1 {
+                root = Some(match root {
+                    None => *subtree,
+                    Some(acc) => merkle::node_hash(subtree, &acc),
+                });
+            }
+        }
+        self.root = root.unwrap_or_default();
+
+        Ok(index)
+    }
+}
+
+/// One of a wallet's devices, each with its own encryption key
+#[account]
+pub struct Device {
+    pub owner: Pubkey,
+    pub device_id: [u8; 16],
+    pub encryption_key: [u8; 32],  // X25519
+    pub label: String,
+    pub added_at: i64,
+    pub revoked_at: Option<i64>,
+    pub rent_payer: Pubkey,
+}
+
+/// `@name` registered to a wallet, one account per name
+#[account]
+pub struct Handle {
+    pub name: String,
+    pub owner: Pubkey,
+    pub claimed_at: i64,
+    pub rent_payer: Pubkey,
+}
+
+/// Global handle settings, managed by the program's upgrade authority
+#[account]
+pub struct HandleConfig {
+    pub claim_fee: u64,  // Lamports, 0 = free
+    pub treasury: Pubkey,
+}
+
+/// Guardians that can jointly recover a wallet's identity
+#[account]
+pub struct GuardianSet {
+    pub owner: Pubkey,
+    pub guardians: Vec<Pubkey>,
+    pub threshold: u8,
+    pub timelock: i64,  // Seconds the owner has to cancel an approved recovery
+    pub rent_payer: Pubkey,
+}
+
+impl GuardianSet {
+    /// Approvals from wallets that are still guardians
+    pub fn approvals(&self, approvals: &[Pubkey]) -> u8 {
+        approvals.iter().filter(|a| self.guardians.contains(a)).count() as u8
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub enum RecoveryAction {
+    RotateKey { new_key: [u8; 32] },
+    MoveIdentity { new_wallet: Pubkey },
+}
+
+/// Pending recovery of a wallet's identity, one per proposing guardian
+#[account]
+pub struct Recovery {
+    pub owner: Pubkey,
+    pub proposer: Pubkey,
+    pub action: RecoveryAction,
+    pub approvals: Vec<Pubkey>,
+    pub proposed_at: i64,
+    pub approved_at: Option<i64>,  // When the threshold was reached
+    pub rent_payer: Pubkey,
+}
+
+/// Left at a wallet that migrated its identity, pointing to the new wallet
+#[account]
+pub struct WalletForward {
+    pub old_wallet: Pubkey,
+    pub new_wallet: Pubkey,
+    pub migrated_at: i64,
+}
+
+impl WalletForward {
+    /// The new wallet in place of the old one, any other wallet unchanged
+    pub fn forwarded(&self, wallet: Pubkey) -> Pubkey {
+        if wallet == self.old_wallet { self.new_wallet } else { wallet }
+    }
+}
+
+/// A wallet linked to the profile of `primary`, one per linked wallet
+#[account]
+pub struct WalletLink {
+    pub primary: Pubkey,
+    pub linked: Pubkey,
+    pub linked_at: i64,
+    pub rent_payer: Pubkey,
+}
+
+/// Progress of a wallet's account deletion. Clients resume by listing
+/// what the wallet still has, so only counts are kept.
+#[account]
+pub struct AccountDeletion {
+    pub owner: Pubkey,
+    pub started_at: i64,
+    pub relationships_closed: u32,
+    pub groups_left: u32,
+    pub group_keys_closed: u32,
+    pub accounts_closed: u32,  // Every other per-wallet account
+    pub rent_payer: Pubkey,
+}
+
+/// Re-invite cooldown a deleted wallet was serving toward `peer`, at the
+/// pair's chat hash. The deleted wallet's key is not stored.
+#[account]
+pub struct RejectionTombstone {
+    pub peer: Pubkey,  // Who rejected the deleted wallet
+    pub rejections: u8,
+    pub last_rejected_at: i64,
+    pub rent_payer: Pubkey,
+}
+
+/// X3DH prekeys of a wallet: one signed prekey plus a pool of one-time
+/// prekeys that senders claim one at a time
+#[account]
+pub struct PrekeyBundle {
+    pub owner: Pubkey,
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+    pub updated_at: i64,
+    pub rent_payer: Pubkey,
+}
+
+/// One-time prekeys a claimer took from an owner's pool this epoch
+#[account]
+pub struct PrekeyClaims {
+    pub owner: Pubkey,
+    pub claimer: Pubkey,
+    pub claim_epoch: u64,
+    pub claims_this_epoch: u8,
+    pub rent_payer: Pubkey,
+}
+
+/// Deposit locked by the inviter until the invite is resolved. Lamport
+/// deposits sit on this account; token deposits in its associated token
+/// account for the mint.
+#[account]
+pub struct InviteEscrow {
+    pub inviter: Pubkey,
+    pub invitee: Pubkey,
+    pub mint: Option<Pubkey>,
+    pub amount: u64,
+    pub bump: u8,
+    pub rent_payer: Pubkey,
+}
+
+/// Encrypted introduction attached to a pending invite. Closed back to
+/// whoever paid for it once the invite is accepted, rejected, cancelled or
+/// expired.
+#[account]
+pub struct InviteNote {
+    pub inviter: Pubkey,
+    pub rent_payer: Pubkey,
+    pub ciphertext: Vec<u8>,
+    pub nonce: [u8; 24],
+}
+
+/// Sized for MAX_GROUP_MEMBERS for its whole life, so joins and leaves
+/// never move rent: the rent payer funds it and gets it all back on close
+#[account]
+pub struct Group {
+    pub group_id: [u8; 32],
+    pub creator: Pubkey,
+    pub name: String,
+    pub created_at: i64,
+    pub members: Vec<Pubkey>,
+    pub encryption_pubkey: [u8; 32],
+    pub token_gate: Option<TokenGate>,
+    pub rent_payer: Pubkey,
+}
+
+impl Group {
+    /// Account size for `members` members (the name is sized for its maximum)
+    pub fn space(members: usize) -> usize {
+        8 + 32 + 32 + (4 + 64) + 8 + (4 + members * 32) + 32 + (1 + 32 + 8) + 32
+    }
+}
+
+#[account]
+pub struct GroupInvite {
+    pub group_id: [u8; 32],
+    pub inviter: Pubkey,
+    pub invitee: Pubkey,
+    pub status: GroupInviteStatus,
+    pub created_at: i64,
+}
+
+#[account]
+pub struct GroupKeyShare {
+    pub group_id: [u8; 32],
+    pub member: Pubkey,
+    pub encrypted_key: Vec<u8>,
+    pub nonce: [u8; 24],
+    pub rent_payer: Pubkey,
+}
+
+impl GroupKeyShare {
+    pub const SPACE: usize = 8 + 32 + 32 + (4 + 48) + 24 + 32;  // disc + group_id + member + Vec(encrypted_key) + nonce + rent_payer
+}
+
+// ========== LEGACY LAYOUTS ==========
+// Accounts written before rent payers were tracked, upgraded in place by
+// the migrate_* layout instructions. Their seeds are unchanged.
+
+#[derive(AnchorDeserialize)]
+pub struct LegacyGroup {
+    pub group_id: [u8; 32],
+    pub creator: Pubkey,
+    pub name: String,
+    pub created_at: i64,
+    pub members: Vec<Pubkey>,
+    pub encryption_pubkey: [u8; 32],
+    pub token_gate: Option<TokenGate>,
+}
+
+impl LegacyGroup {
+    // Groups were created with room for 30 members, then resized to the
+    // member count from the first join on; all without the rent payer
+    pub fn is_legacy_size(data_len: usize, members: usize) -> bool {
+        data_len == Group::space(members) - 32 || (members == 1 && data_len == Group::space(30) - 32)
+    }
+}
+
+/// PeerState of version 1 descriptors, which still had a Blocked state
+#[derive(AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyPeerState {
+    Invited,
+    Requested,
+    Accepted,
+    Rejected,
+    Blocked,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct LegacyPeer {
+    pub wallet: Pubkey,
+    pub state: LegacyPeerState,
+}
+
+/// Version 1 wallet descriptor: each side's view of its contacts, at
+/// LEGACY_WALLET_DESCRIPTOR_VERSION
+#[derive(AnchorDeserialize)]
+pub struct LegacyWalletDescriptor {
+    pub owner: Pubkey,
+    pub peers: Vec<LegacyPeer>,
+}
+
+impl LegacyWalletDescriptor {
+    /// The descriptor at `info`, None once it is closed
+    pub fn read(info: &AccountInfo) -> Result<Option<Self>> {
+        if info.data_is_empty() {
+            return Ok(None);
+        }
+        require_keys_eq!(*info.owner, crate::ID, ErrorCode::Unauthorized);
+        let data = info.try_borrow_data()?;
+        require!(data.len() >= 8 && data[..8] == *WalletDescriptor::DISCRIMINATOR, ErrorCode::AlreadyMigrated);
+        Ok(Some(Self::deserialize(&mut &data[8..])?))
+    }
+
+    pub fn state_of(&self, wallet: Pubkey) -> Option<LegacyPeerState> {
+        self.peers.iter().find(|p| p.wallet == wallet).map(|p| p.state)
+    }
+}
+
+// States of a legacy pair as (mine, the peer's). A missing side is the
+// counterpart of mine; blocked or mismatched pairs go idle.
+fn legacy_pair_states(mine: LegacyPeerState, theirs: Option<LegacyPeerState>) -> (PeerState, PeerState) {
+    use LegacyPeerState::*;
+    let theirs = theirs.unwrap_or(match mine {
+        Invited => Requested,
+        Requested => Invited,
+        Accepted => Accepted,
+        Rejected | Blocked => Rejected,
+    });
+    match (mine, theirs) {
+        (Invited, Requested) => (PeerState::Invited, PeerState::Requested),
+        (Requested, Invited) => (PeerState::Requested, PeerState::Invited),
+        (Accepted, Accepted) => (PeerState::Accepted, PeerState::Accepted),
+        _ => (PeerState::Rejected, PeerState::Rejected),
+    }
+}
+
+#[derive(AnchorDeserialize)]
+pub struct LegacyGroupKeyShare {
+    pub group_id: [u8; 32],
+    pub member: Pubkey,
+    pub encrypted_key: Vec<u8>,
+    pub nonce: [u8; 24],
+}
+
+const LEGACY_GROUP_KEY_SHARE_SPACE: usize = GroupKeyShare::SPACE - 32;
+
+#[derive(AnchorDeserialize)]
+pub struct LegacyConversation {
+    pub participants: [Pubkey; 2],
+    pub created_at: i64,
+}
+
+const LEGACY_CONVERSATION_SPACE: usize = Conversation::SPACE - 32;
+
+/// Profile layout 1, before rent payers, key epochs and profile details
+#[derive(AnchorDeserialize)]
+pub struct LegacyUserProfile {
+    pub owner: Pubkey,
+    pub display_name: String,
+    pub avatar_type: AvatarType,
+    pub avatar_data: String,
+    pub encryption_public_key: [u8; 32],
+}
+
+const LEGACY_USER_PROFILE_SPACE: usize = 8 + 32 + (4 + 32) + 1 + (4 + 128) + 32;
+
+// ========== CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+#[instruction(display_name: String, avatar_data: String, encryption_public_key: [u8; 32])]
+pub struct Register<'info> {
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 8 + 2 + 2,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = UserProfile::space_for("", "", &[], &None, &None),
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = EncryptionKeyRecord::SPACE,
+        seeds = [b"encryption_key", authority.key().as_ref(), 0u32.to_le_bytes().as_ref(), ENCRYPTION_KEY_RECORD_VERSION.as_ref()],
+        bump
+    )]
+    pub key_record: Account<'info, EncryptionKeyRecord>,
+    // Optional so registering doesn't wait on the global log; the record
+    // can be logged later with log_key_record
+    #[account(mut, seeds = [b"key_log", KEY_LOG_VERSION.as_ref()], bump)]
+    pub key_log: Option<Box<Account<'info, KeyLog>>>,
+    /// CHECK: must be empty, a linked wallet can't register its own profile
+    #[account(
+        seeds = [b"wallet_link", authority.key().as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_link: UncheckedAccount<'info>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateProfile<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump,
+        realloc = user_profile.space(),
+        realloc::payer = fee_payer,
+        realloc::zero = true
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    // Record for the next key epoch, only when the encryption key changes
+    #[account(
+        init,
+        payer = fee_payer,
+        space = EncryptionKeyRecord::SPACE,
+        seeds = [b"encryption_key", authority.key().as_ref(), user_profile.next_key_epoch()?.to_le_bytes().as_ref(), ENCRYPTION_KEY_RECORD_VERSION.as_ref()],
+        bump
+    )]
+    pub key_record: Option<Account<'info, EncryptionKeyRecord>>,
+    #[account(mut, seeds = [b"key_log", KEY_LOG_VERSION.as_ref()], bump)]
+    pub key_log: Option<Box<Account<'info, KeyLog>>>,
+    // Proof of ownership when setting an NFT avatar
+    pub avatar_token_account: Option<Account<'info, TokenAccount>>,
+    pub avatar_mint: Option<Account<'info, Mint>>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bio: String, status: String, links: Vec<String>, avatar_content: Option<AvatarContent>)]
+pub struct UpdateProfileDetails<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump,
+        realloc = UserProfile::space_for(&bio, &status, &links, &avatar_content, &user_profile.sealed),
+        realloc::payer = fee_payer,
+        realloc::zero = true
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(visibility: ProfileVisibility, sealed: Option<SealedProfile>)]
+pub struct SetProfileVisibility<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump,
+        realloc = user_profile.space_with_sealed(&sealed),
+        realloc::payer = fee_payer,
+        realloc::zero = true
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ShareProfileKey<'info> {
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = ProfileKeyShare::SPACE,
+        seeds = [b"profile_key", authority.key().as_ref(), contact.key().as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub profile_key_share: Account<'info, ProfileKeyShare>,
+    /// CHECK: contact is a public key
+    pub contact: AccountInfo<'info>,
+    #[account(
+        seeds = [b"relationship", get_chat_hash(authority.key(), contact.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    #[account(
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Box<Account<'info, UserProfile>>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseProfileKey<'info> {
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"profile_key", profile_key_share.owner.as_ref(), profile_key_share.contact.as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub profile_key_share: Account<'info, ProfileKeyShare>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match profile_key_share.rent_payer
+    #[account(mut, address = profile_key_share.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddCollectionBadge<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    pub authority: Signer<'info>,
+    pub token_account: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
+    /// CHECK: Metaplex metadata, verified in verified_collection
+    pub metadata: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveCollectionBadge<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InvalidateNft<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", user_profile.owner.as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    // Current holder of the NFT, someone other than the profile owner
+    pub holder_token_account: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct CloseProfile<'info> {
+    /// CHECK: Old account structure may not deserialize. Client must pass correct PDA.
+    #[account(mut)]
+    pub user_profile: UncheckedAccount<'info>,
+    /// CHECK: Old WalletDescriptor may not deserialize. Client must pass correct PDA.
+    #[account(mut)]
+    pub wallet_descriptor: UncheckedAccount<'info>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, checked against user_profile.rent_payer
+    #[account(mut)]
+    pub rent_payer: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32], expires_at: Option<i64>, intro: Option<EncryptedIntro>)]
+pub struct Invite<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    /// CHECK: invitee is a public key, omitted when addressed by handle
+    pub invitee: Option<UncheckedAccount<'info>>,
+    // Invitee addressed by handle instead: the handle's owner is invited
+    pub invitee_handle: Option<Account<'info, Handle>>,
+    /// CHECK: the addressed wallet's WalletLink, read only if it exists
+    #[account(
+        seeds = [b"wallet_link", addressed_invitee(&invitee, &invitee_handle).as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub invitee_link: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub authority_descriptor: Account<'info, WalletDescriptor>,
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = Relationship::SPACE,
+        seeds = [b"relationship", _hash.as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = Conversation::SPACE,
+        seeds = [b"conversation", _hash.as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    /// CHECK: the pair's RejectionTombstone, read only if it exists
+    #[account(seeds = [b"tombstone", _hash.as_ref(), TOMBSTONE_VERSION.as_ref()], bump)]
+    pub tombstone: UncheckedAccount<'info>,
+    /// CHECK: the pair's PairBlock, which must not exist
+    #[account(seeds = [b"pair_block", _hash.as_ref(), PAIR_BLOCK_VERSION.as_ref()], bump)]
+    pub pair_block: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + (4 + intro.as_ref().map_or(0, |i| i.ciphertext.len())) + 24,
+        seeds = [b"invite_note", _hash.as_ref(), INVITE_NOTE_VERSION.as_ref()],
+        bump
+    )]
+    pub invite_note: Option<Account<'info, InviteNote>>,
+    /// CHECK: may not exist if the invitee hasn't registered yet
+    #[account(
+        seeds = [b"user_profile", resolve_invitee(addressed_invitee(&invitee, &invitee_handle), &invitee_link).as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub invitee_profile: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + (1 + 32) + 8 + 1 + 32,
+        seeds = [b"invite_escrow", _hash.as_ref(), INVITE_ESCROW_VERSION.as_ref()],
+        bump
+    )]
+    pub invite_escrow: Option<Account<'info, InviteEscrow>>,
+    #[account(mut)]
+    pub inviter_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    // Proof for the invitee's invite policy, when it requires one
+    pub gate_token_account: Option<Box<Account<'info, TokenAccount>>>,
+    /// CHECK: Metaplex metadata, verified in verified_collection
+    pub gate_metadata: Option<UncheckedAccount<'info>>,
+    // Links the wallet holding the gate token to the inviter
+    pub gate_wallet_link: Option<Box<Account<'info, WalletLink>>>,
+    pub inviter_mutual_relationship: Option<Box<Account<'info, Relationship>>>,
+    pub invitee_mutual_relationship: Option<Box<Account<'info, Relationship>>>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct Accept<'info> {
+    /// CHECK: acting wallet; authority must be it or one of its session keys, or its passkey signs
+    pub wallet: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    pub session_key: Option<Account<'info, SessionKey>>,
+    // Passkey signature in place of the wallet's
+    #[account(
+        mut,
+        seeds = [b"user_profile", wallet.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub passkey_profile: Option<Box<Account<'info, UserProfile>>>,
+    /// CHECK: instructions sysvar
+    #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+    pub instructions_sysvar: Option<AccountInfo<'info>>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"relationship", get_chat_hash(wallet.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    #[account(
+        mut,
+        close = invite_rent_payer,
+        constraint = invite_note.inviter == peer.key() @ ErrorCode::Unauthorized,
+        constraint = invite_note.rent_payer == invite_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"invite_note", get_chat_hash(wallet.key(), peer.key()).as_ref(), INVITE_NOTE_VERSION.as_ref()],
+        bump
+    )]
+    pub invite_note: Option<Account<'info, InviteNote>>,
+    /// CHECK: receives the invite note and escrow rent, checked against their rent_payer
+    #[account(mut)]
+    pub invite_rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"invite_escrow", get_chat_hash(wallet.key(), peer.key()).as_ref(), INVITE_ESCROW_VERSION.as_ref()],
+        bump = invite_escrow.bump
+    )]
+    pub invite_escrow: Option<Account<'info, InviteEscrow>>,
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub recipient_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct Reject<'info> {
+    /// CHECK: acting wallet; authority must be it or one of its session keys
+    #[account(mut)]
+    pub wallet: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    pub session_key: Option<Account<'info, SessionKey>>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"relationship", get_chat_hash(wallet.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    #[account(
+        mut,
+        close = invite_rent_payer,
+        constraint = invite_note.inviter == peer.key() @ ErrorCode::Unauthorized,
+        constraint = invite_note.rent_payer == invite_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"invite_note", get_chat_hash(wallet.key(), peer.key()).as_ref(), INVITE_NOTE_VERSION.as_ref()],
+        bump
+    )]
+    pub invite_note: Option<Account<'info, InviteNote>>,
+    /// CHECK: receives the invite note and escrow rent, checked against their rent_payer
+    #[account(mut)]
+    pub invite_rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"invite_escrow", get_chat_hash(wallet.key(), peer.key()).as_ref(), INVITE_ESCROW_VERSION.as_ref()],
+        bump = invite_escrow.bump
+    )]
+    pub invite_escrow: Option<Account<'info, InviteEscrow>>,
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub recipient_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+    /// CHECK: my ProfileKeyShare for the peer, closed if it exists
+    #[account(
+        mut,
+        seeds = [b"profile_key", wallet.key().as_ref(), peer.key().as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub my_profile_key_share: UncheckedAccount<'info>,
+    /// CHECK: receives its rent, checked against its rent_payer
+    #[account(mut)]
+    pub my_share_rent_payer: AccountInfo<'info>,
+    /// CHECK: the peer's ProfileKeyShare for me, closed if it exists
+    #[account(
+        mut,
+        seeds = [b"profile_key", peer.key().as_ref(), wallet.key().as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub peer_profile_key_share: UncheckedAccount<'info>,
+    /// CHECK: receives its rent, checked against its rent_payer
+    #[account(mut)]
+    pub peer_share_rent_payer: AccountInfo<'info>,
+    /// CHECK: my profile, retires the key my share held (if it still exists)
+    #[account(
+        mut,
+        seeds = [b"user_profile", wallet.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: UncheckedAccount<'info>,
+    /// CHECK: the peer's profile, retires the key their share held (if it exists)
+    #[account(
+        mut,
+        seeds = [b"user_profile", peer.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub peer_profile: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(_hash: [u8; 32], expires_at: i64, nonce: u64)]
+pub struct AcceptSignedInvite<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    /// CHECK: inviter is a public key, authenticated by the Ed25519 instruction
+    pub inviter: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = Relationship::SPACE,
+        seeds = [b"relationship", _hash.as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = Conversation::SPACE,
+        seeds = [b"conversation", _hash.as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 8 + 32,
+        seeds = [b"invite_token", inviter.key().as_ref(), nonce.to_le_bytes().as_ref(), USED_INVITE_TOKEN_VERSION.as_ref()],
+        bump
+    )]
+    pub used_invite_token: Account<'info, UsedInviteToken>,
+    /// CHECK: the pair's PairBlock, which must not exist
+    #[account(seeds = [b"pair_block", _hash.as_ref(), PAIR_BLOCK_VERSION.as_ref()], bump)]
+    pub pair_block: UncheckedAccount<'info>,
+    /// CHECK: instructions sysvar
+    #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+    pub instructions_sysvar: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelInvite<'info> {
+    pub authority: Signer<'info>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"relationship", get_chat_hash(authority.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    #[account(
+        mut,
+        close = conversation_rent_payer,
+        seeds = [b"conversation", get_chat_hash(authority.key(), peer.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    /// CHECK: receives the conversation rent, must match conversation.rent_payer
+    #[account(mut, address = conversation.rent_payer)]
+    pub conversation_rent_payer: AccountInfo<'info>,
+    /// CHECK: receives the rent, must match relationship.rent_payer
+    #[account(mut, address = relationship.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        close = invite_rent_payer,
+        constraint = invite_note.inviter == authority.key() @ ErrorCode::Unauthorized,
+        constraint = invite_note.rent_payer == invite_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"invite_note", get_chat_hash(authority.key(), peer.key()).as_ref(), INVITE_NOTE_VERSION.as_ref()],
+        bump
+    )]
+    pub invite_note: Option<Account<'info, InviteNote>>,
+    /// CHECK: receives the invite note and escrow rent, checked against their rent_payer
+    #[account(mut)]
+    pub invite_rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"invite_escrow", get_chat_hash(authority.key(), peer.key()).as_ref(), INVITE_ESCROW_VERSION.as_ref()],
+        bump = invite_escrow.bump
+    )]
+    pub invite_escrow: Option<Account<'info, InviteEscrow>>,
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub recipient_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireInvite<'info> {
+    /// CHECK: inviter is a public key
+    pub inviter: AccountInfo<'info>,
+    /// CHECK: invitee is a public key
+    pub invitee: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"relationship", get_chat_hash(inviter.key(), invitee.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    #[account(
+        mut,
+        close = conversation_rent_payer,
+        seeds = [b"conversation", get_chat_hash(inviter.key(), invitee.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: Account<'info, Conversation>,
+    /// CHECK: receives the conversation rent, must match conversation.rent_payer
+    #[account(mut, address = conversation.rent_payer)]
+    pub conversation_rent_payer: AccountInfo<'info>,
+    /// CHECK: receives the rent, must match relationship.rent_payer
+    #[account(mut, address = relationship.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        close = invite_rent_payer,
+        constraint = invite_note.inviter == inviter.key() @ ErrorCode::Unauthorized,
+        constraint = invite_note.rent_payer == invite_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"invite_note", get_chat_hash(inviter.key(), invitee.key()).as_ref(), INVITE_NOTE_VERSION.as_ref()],
+        bump
+    )]
+    pub invite_note: Option<Account<'info, InviteNote>>,
+    /// CHECK: receives the invite note and escrow rent, checked against their rent_payer
+    #[account(mut)]
+    pub invite_rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"invite_escrow", get_chat_hash(inviter.key(), invitee.key()).as_ref(), INVITE_ESCROW_VERSION.as_ref()],
+        bump = invite_escrow.bump
+    )]
+    pub invite_escrow: Option<Account<'info, InviteEscrow>>,
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub recipient_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveContact<'info> {
+    pub authority: Signer<'info>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"relationship", get_chat_hash(authority.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    // Required while relationship.has_conversation
+    #[account(
+        mut,
+        close = conversation_rent_payer,
+        constraint = conversation.rent_payer == conversation_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"conversation", get_chat_hash(authority.key(), peer.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: Option<Account<'info, Conversation>>,
+    /// CHECK: receives the conversation rent, checked against conversation.rent_payer
+    #[account(mut)]
+    pub conversation_rent_payer: AccountInfo<'info>,
+    /// CHECK: receives the rent, must match relationship.rent_payer
+    #[account(mut, address = relationship.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    /// CHECK: my ProfileKeyShare for the peer, closed if it exists
+    #[account(
+        mut,
+        seeds = [b"profile_key", authority.key().as_ref(), peer.key().as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub my_profile_key_share: UncheckedAccount<'info>,
+    /// CHECK: receives its rent, checked against its rent_payer
+    #[account(mut)]
+    pub my_share_rent_payer: AccountInfo<'info>,
+    /// CHECK: the peer's ProfileKeyShare for me, closed if it exists
+    #[account(
+        mut,
+        seeds = [b"profile_key", peer.key().as_ref(), authority.key().as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub peer_profile_key_share: UncheckedAccount<'info>,
+    /// CHECK: receives its rent, checked against its rent_payer
+    #[account(mut)]
+    pub peer_share_rent_payer: AccountInfo<'info>,
+    /// CHECK: my profile, retires the key my share held (if it still exists)
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: UncheckedAccount<'info>,
+    /// CHECK: the peer's profile, retires the key their share held (if it exists)
+    #[account(
+        mut,
+        seeds = [b"user_profile", peer.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub peer_profile: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(blinded_peer: [u8; 32])]
+pub struct Block<'info> {
+    /// CHECK: acting wallet; authority must be it or one of its session keys, or its passkey signs
+    #[account(mut)]
+    pub wallet: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    pub session_key: Option<Account<'info, SessionKey>>,
+    // Passkey signature in place of the wallet's
+    #[account(
+        mut,
+        seeds = [b"user_profile", wallet.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub passkey_profile: Option<Box<Account<'info, UserProfile>>>,
+    /// CHECK: instructions sysvar
+    #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+    pub instructions_sysvar: Option<AccountInfo<'info>>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    /// CHECK: the blocked wallet
+    pub peer: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + 48 + 24 + 32 + 8 + 32,
+        seeds = [b"block", wallet.key().as_ref(), blinded_peer.as_ref(), BLOCK_VERSION.as_ref()],
+        bump
+    )]
+    pub block_entry: Account<'info, BlockEntry>,
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = 8 + 4 + 32,
+        seeds = [b"pair_block", get_chat_hash(wallet.key(), peer.key()).as_ref(), PAIR_BLOCK_VERSION.as_ref()],
+        bump
+    )]
+    pub pair_block: Account<'info, PairBlock>,
+    /// CHECK: the wallet's ProfileKeyShare for the blocked peer, closed if passed
+    #[account(mut)]
+    pub profile_key_share: Option<UncheckedAccount<'info>>,
+    /// CHECK: receives the share's rent, checked against its rent_payer
+    #[account(mut)]
+    pub share_rent_payer: Option<UncheckedAccount<'info>>,
+    /// CHECK: the wallet's profile when closing a share without a passkey
+    #[account(
+        mut,
+        seeds = [b"user_profile", wallet.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Option<UncheckedAccount<'info>>,
+    // The pair's relationship and escrow, to keep a pending invite's deposit
+    #[account(
+        mut,
+        seeds = [b"relationship", get_chat_hash(wallet.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Option<Box<Account<'info, Relationship>>>,
+    #[account(
+        mut,
+        seeds = [b"invite_escrow", get_chat_hash(wallet.key(), peer.key()).as_ref(), INVITE_ESCROW_VERSION.as_ref()],
+        bump = invite_escrow.bump
+    )]
+    pub invite_escrow: Option<Box<Account<'info, InviteEscrow>>>,
+    /// CHECK: receives the escrow rent, checked against its rent_payer
+    #[account(mut)]
+    pub escrow_rent_payer: Option<AccountInfo<'info>>,
+    #[account(mut)]
+    pub escrow_token_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub recipient_token_account: Option<Box<Account<'info, TokenAccount>>>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct Unblock<'info> {
+    /// CHECK: acting wallet; authority must be it or one of its session keys
+    pub wallet: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    pub session_key: Option<Account<'info, SessionKey>>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"block", wallet.key().as_ref(), block_entry.blinded_peer.as_ref(), BLOCK_VERSION.as_ref()],
+        bump
+    )]
+    pub block_entry: Account<'info, BlockEntry>,
+    /// CHECK: receives the rent, must match block_entry.rent_payer
+    #[account(mut, address = block_entry.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    /// CHECK: the blocked wallet, checked against block_entry.pair_tag
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"pair_block", get_chat_hash(wallet.key(), peer.key()).as_ref(), PAIR_BLOCK_VERSION.as_ref()],
+        bump
+    )]
+    pub pair_block: Account<'info, PairBlock>,
+    /// CHECK: receives the marker's rent when its last block goes, checked in release_pair_block
+    #[account(mut)]
+    pub pair_block_rent_payer: AccountInfo<'info>,
+}
+
+// ========== SESSION KEY CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+#[instruction(session_key: Pubkey)]
+pub struct CreateSessionKey<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + 8 + 4 + 32,
+        seeds = [b"session_key", authority.key().as_ref(), session_key.as_ref(), SESSION_KEY_VERSION.as_ref()],
+        bump
+    )]
+    pub session: Account<'info, SessionKey>,
+    #[account(
+        mut,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeSessionKey<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"session_key", authority.key().as_ref(), session.session_key.as_ref(), SESSION_KEY_VERSION.as_ref()],
+        bump
+    )]
+    pub session: Account<'info, SessionKey>,
+    /// CHECK: receives the rent, must match session.rent_payer
+    #[account(mut, address = session.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+}
+
+// ========== KEY TRANSPARENCY CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct InitKeyLog<'info> {
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 8 + 32 + KEY_LOG_MAX_DEPTH * 32,
+        seeds = [b"key_log", KEY_LOG_VERSION.as_ref()],
+        bump
+    )]
+    pub key_log: Box<Account<'info, KeyLog>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LogKeyRecord<'info> {
+    #[account(
+        mut,
+        seeds = [b"encryption_key", key_record.owner.as_ref(), key_record.epoch.to_le_bytes().as_ref(), ENCRYPTION_KEY_RECORD_VERSION.as_ref()],
+        bump
+    )]
+    pub key_record: Account<'info, EncryptionKeyRecord>,
+    #[account(mut, seeds = [b"key_log", KEY_LOG_VERSION.as_ref()], bump)]
+    pub key_log: Box<Account<'info, KeyLog>>,
+}
+
+// ========== HANDLE CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct SetHandleConfig<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = 8 + 8 + 32,
+        seeds = [b"handle_config", HANDLE_CONFIG_VERSION.as_ref()],
+        bump
+    )]
+    pub handle_config: Account<'info, HandleConfig>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::MukonMessenger>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(authority.key()) @ ErrorCode::Unauthorized)]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ClaimHandle<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + (4 + MAX_HANDLE_LEN) + 32 + 8 + 32,
+        seeds = [b"handle", name.as_bytes(), HANDLE_VERSION.as_ref()],
+        bump
+    )]
+    pub handle: Account<'info, Handle>,
+    #[account(
+        seeds = [b"handle_config", HANDLE_CONFIG_VERSION.as_ref()],
+        bump,
+        constraint = treasury.as_ref().map(|t| t.key()) == Some(handle_config.treasury) @ ErrorCode::TreasuryMismatch
+    )]
+    pub handle_config: Option<Account<'info, HandleConfig>>,
+    /// CHECK: receives the claim fee, checked against handle_config.treasury
+    #[account(mut)]
+    pub treasury: Option<AccountInfo<'info>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReleaseHandle<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"handle", handle.name.as_bytes(), HANDLE_VERSION.as_ref()],
+        bump,
+        constraint = handle.owner == authority.key() @ ErrorCode::HandleOwnerMismatch
+    )]
+    pub handle: Account<'info, Handle>,
+    /// CHECK: receives the handle rent
+    #[account(mut, address = handle.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TransferHandle<'info> {
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"handle", handle.name.as_bytes(), HANDLE_VERSION.as_ref()],
+        bump,
+        constraint = handle.owner == authority.key() @ ErrorCode::HandleOwnerMismatch
+    )]
+    pub handle: Account<'info, Handle>,
+    pub new_owner: Signer<'info>,
+}
+
+// ========== RECOVERY CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct SetGuardians<'info> {
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = 8 + 32 + (4 + MAX_GUARDIANS * 32) + 1 + 8 + 32,
+        seeds = [b"guardian_set", authority.key().as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub guardian_set: Account<'info, GuardianSet>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeRecovery<'info> {
+    #[account(
+        seeds = [b"guardian_set", guardian_set.owner.as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub guardian_set: Account<'info, GuardianSet>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + (1 + 32) + (4 + MAX_GUARDIANS * 32) + 8 + (1 + 8) + 32,
+        seeds = [b"recovery", guardian_set.owner.as_ref(), authority.key().as_ref(), RECOVERY_VERSION.as_ref()],
+        bump
+    )]
+    pub recovery: Account<'info, Recovery>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveRecovery<'info> {
+    #[account(
+        seeds = [b"guardian_set", recovery.owner.as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub guardian_set: Account<'info, GuardianSet>,
+    #[account(
+        mut,
+        seeds = [b"recovery", recovery.owner.as_ref(), recovery.proposer.as_ref(), RECOVERY_VERSION.as_ref()],
+        bump
+    )]
+    pub recovery: Account<'info, Recovery>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelRecovery<'info> {
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"recovery", authority.key().as_ref(), recovery.proposer.as_ref(), RECOVERY_VERSION.as_ref()],
+        bump
+    )]
+    pub recovery: Account<'info, Recovery>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match recovery.rent_payer
+    #[account(mut, address = recovery.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireRecovery<'info> {
+    #[account(
+        seeds = [b"guardian_set", recovery.owner.as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub guardian_set: Account<'info, GuardianSet>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"recovery", recovery.owner.as_ref(), recovery.proposer.as_ref(), RECOVERY_VERSION.as_ref()],
+        bump
+    )]
+    pub recovery: Account<'info, Recovery>,
+    /// CHECK: receives the rent, must match recovery.rent_payer
+    #[account(mut, address = recovery.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteRecovery<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", recovery.owner.as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Box<Account<'info, UserProfile>>,
+    #[account(
+        seeds = [b"guardian_set", recovery.owner.as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub guardian_set: Account<'info, GuardianSet>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"recovery", recovery.owner.as_ref(), recovery.proposer.as_ref(), RECOVERY_VERSION.as_ref()],
+        bump
+    )]
+    pub recovery: Account<'info, Recovery>,
+    /// CHECK: receives the recovery rent, must match recovery.rent_payer
+    #[account(mut, address = recovery.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    // Record for the next key epoch, only for RotateKey
+    #[account(
+        init,
+        payer = fee_payer,
+        space = EncryptionKeyRecord::SPACE,
+        seeds = [b"encryption_key", recovery.owner.as_ref(), user_profile.next_key_epoch()?.to_le_bytes().as_ref(), ENCRYPTION_KEY_RECORD_VERSION.as_ref()],
+        bump
+    )]
+    pub key_record: Option<Account<'info, EncryptionKeyRecord>>,
+    #[account(mut, seeds = [b"key_log", KEY_LOG_VERSION.as_ref()], bump)]
+    pub key_log: Option<Box<Account<'info, KeyLog>>>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+// ========== MIGRATION CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct SetMigrationTarget<'info> {
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CompleteMigration<'info> {
+    /// CHECK: wallet the identity moves away from
+    pub old_wallet: AccountInfo<'info>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"user_profile", old_wallet.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump,
+        constraint = old_profile.migration_target == Some(authority.key()) @ ErrorCode::NotMigrationTarget
+    )]
+    pub old_profile: Box<Account<'info, UserProfile>>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"wallet_descriptor", old_wallet.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub old_descriptor: Account<'info, WalletDescriptor>,
+    /// CHECK: receives the old profile and descriptor rent
+    #[account(mut, address = old_profile.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = old_profile.space(),
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Box<Account<'info, UserProfile>>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 8 + 2 + 2,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + 8,
+        seeds = [b"wallet_forward", old_wallet.key().as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = EncryptionKeyRecord::SPACE,
+        seeds = [b"encryption_key", authority.key().as_ref(), old_profile.key_epoch.to_le_bytes().as_ref(), ENCRYPTION_KEY_RECORD_VERSION.as_ref()],
+        bump
+    )]
+    pub key_record: Box<Account<'info, EncryptionKeyRecord>>,
+    #[account(mut, seeds = [b"key_log", KEY_LOG_VERSION.as_ref()], bump)]
+    pub key_log: Option<Box<Account<'info, KeyLog>>>,
+    /// CHECK: must be empty, a linked wallet can't take over an identity
+    #[account(
+        seeds = [b"wallet_link", authority.key().as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub new_wallet_link: UncheckedAccount<'info>,
+    // New wallet
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateRelationship<'info> {
+    #[account(
+        seeds = [b"wallet_forward", wallet_forward.old_wallet.as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump,
+        constraint = wallet_forward.new_wallet == authority.key() @ ErrorCode::NotMigrationTarget
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        close = old_rent_payer,
+        seeds = [b"relationship", get_chat_hash(wallet_forward.old_wallet, peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub old_relationship: Box<Account<'info, Relationship>>,
+    #[account(
+        mut,
+        close = old_conversation_rent_payer,
+        constraint = old_conversation.rent_payer == old_conversation_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"conversation", get_chat_hash(wallet_forward.old_wallet, peer.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub old_conversation: Option<Account<'info, Conversation>>,
+    /// CHECK: receives the old conversation rent, checked against old_conversation.rent_payer
+    #[account(mut)]
+    pub old_conversation_rent_payer: AccountInfo<'info>,
+    /// CHECK: receives the old relationship rent
+    #[account(mut, address = old_relationship.rent_payer)]
+    pub old_rent_payer: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = Relationship::SPACE,
+        seeds = [b"relationship", get_chat_hash(authority.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub new_relationship: Box<Account<'info, Relationship>>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = Conversation::SPACE,
+        seeds = [b"conversation", get_chat_hash(authority.key(), peer.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub new_conversation: Option<Account<'info, Conversation>>,
+    // New wallet
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateGroupMembership<'info> {
+    #[account(
+        seeds = [b"wallet_forward", wallet_forward.old_wallet.as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump,
+        constraint = wallet_forward.new_wallet == authority.key() @ ErrorCode::NotMigrationTarget
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    #[account(
+        mut,
+        seeds = [b"group", group.group_id.as_ref(), GROUP_VERSION.as_ref()],
+        bump
+    )]
+    pub group: Account<'info, Group>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateGroupKey<'info> {
+    #[account(
+        seeds = [b"wallet_forward", wallet_forward.old_wallet.as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump,
+        constraint = wallet_forward.new_wallet == authority.key() @ ErrorCode::NotMigrationTarget
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    #[account(
+        mut,
+        close = key_share_rent_payer,
+        seeds = [b"group_key", old_group_key_share.group_id.as_ref(), wallet_forward.old_wallet.as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub old_group_key_share: Account<'info, GroupKeyShare>,
+    /// CHECK: receives the old key share rent, must match its rent_payer
+    #[account(mut, address = old_group_key_share.rent_payer)]
+    pub key_share_rent_payer: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = GroupKeyShare::SPACE,
+        seeds = [b"group_key", old_group_key_share.group_id.as_ref(), authority.key().as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub group_key_share: Account<'info, GroupKeyShare>,
+    // New wallet
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateProfileKeyShare<'info> {
+    #[account(
+        seeds = [b"wallet_forward", wallet_forward.old_wallet.as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump,
+        constraint = wallet_forward.new_wallet == authority.key() @ ErrorCode::NotMigrationTarget
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    #[account(
+        mut,
+        close = key_share_rent_payer,
+        seeds = [b"profile_key", old_profile_key_share.owner.as_ref(), old_profile_key_share.contact.as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub old_profile_key_share: Account<'info, ProfileKeyShare>,
+    /// CHECK: receives the old key share rent, must match its rent_payer
+    #[account(mut, address = old_profile_key_share.rent_payer)]
+    pub key_share_rent_payer: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = ProfileKeyShare::SPACE,
+        seeds = [
+            b"profile_key",
+            wallet_forward.forwarded(old_profile_key_share.owner).as_ref(),
+            wallet_forward.forwarded(old_profile_key_share.contact).as_ref(),
+            PROFILE_KEY_SHARE_VERSION.as_ref()
+        ],
+        bump
+    )]
+    pub profile_key_share: Account<'info, ProfileKeyShare>,
+    // New wallet
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateHandle<'info> {
+    #[account(
+        seeds = [b"wallet_forward", wallet_forward.old_wallet.as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump,
+        constraint = wallet_forward.new_wallet == authority.key() @ ErrorCode::NotMigrationTarget
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    #[account(
+        mut,
+        seeds = [b"handle", handle.name.as_bytes(), HANDLE_VERSION.as_ref()],
+        bump,
+        constraint = handle.owner == wallet_forward.old_wallet @ ErrorCode::HandleOwnerMismatch
+    )]
+    pub handle: Account<'info, Handle>,
+    // New wallet
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateGuardianSet<'info> {
+    #[account(
+        seeds = [b"wallet_forward", wallet_forward.old_wallet.as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump,
+        constraint = wallet_forward.new_wallet == authority.key() @ ErrorCode::NotMigrationTarget
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    #[account(
+        mut,
+        close = guardian_set_rent_payer,
+        seeds = [b"guardian_set", wallet_forward.old_wallet.as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub old_guardian_set: Account<'info, GuardianSet>,
+    /// CHECK: receives the old guardian set rent, must match its rent_payer
+    #[account(mut, address = old_guardian_set.rent_payer)]
+    pub guardian_set_rent_payer: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + (4 + MAX_GUARDIANS * 32) + 1 + 8 + 32,
+        seeds = [b"guardian_set", authority.key().as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub guardian_set: Account<'info, GuardianSet>,
+    // New wallet
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateWalletLink<'info> {
+    #[account(
+        seeds = [b"wallet_forward", wallet_forward.old_wallet.as_ref(), WALLET_FORWARD_VERSION.as_ref()],
+        bump,
+        constraint = wallet_forward.new_wallet == authority.key() @ ErrorCode::NotMigrationTarget
+    )]
+    pub wallet_forward: Account<'info, WalletForward>,
+    #[account(
+        mut,
+        seeds = [b"wallet_link", wallet_link.linked.as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump,
+        constraint = wallet_link.primary == wallet_forward.old_wallet @ ErrorCode::InvalidWalletLink
+    )]
+    pub wallet_link: Account<'info, WalletLink>,
+    // New wallet
+    pub authority: Signer<'info>,
+}
+
+// ========== LINKED WALLET CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct LinkWallet<'info> {
+    // Primary wallet
+    pub authority: Signer<'info>,
+    pub linked_wallet: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    #[account(
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Box<Account<'info, UserProfile>>,
+    /// CHECK: must be empty, the linked wallet can't have its own profile
+    #[account(
+        seeds = [b"user_profile", linked_wallet.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub linked_profile: UncheckedAccount<'info>,
+    /// CHECK: must be empty, the primary can't be linked to another wallet
+    #[account(
+        seeds = [b"wallet_link", authority.key().as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub primary_link: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + 8 + 32,
+        seeds = [b"wallet_link", linked_wallet.key().as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_link: Account<'info, WalletLink>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnlinkWallet<'info> {
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"wallet_link", wallet_link.linked.as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_link: Account<'info, WalletLink>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match wallet_link.rent_payer
+    #[account(mut, address = wallet_link.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+// ========== ACCOUNT DELETION CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct StartAccountDeletion<'info> {
+    #[account(
+        mut,
+        close = profile_rent_payer,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Box<Account<'info, UserProfile>>,
+    #[account(
+        mut,
+        close = profile_rent_payer,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    /// CHECK: receives the profile and descriptor rent
+    #[account(mut, address = user_profile.rent_payer)]
+    pub profile_rent_payer: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 8 + 4 + 4 + 4 + 4 + 32,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteRelationship<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"relationship", get_chat_hash(authority.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Box<Account<'info, Relationship>>,
+    // Required while relationship.has_conversation
+    #[account(
+        mut,
+        close = conversation_rent_payer,
+        constraint = conversation.rent_payer == conversation_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"conversation", get_chat_hash(authority.key(), peer.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: Option<Account<'info, Conversation>>,
+    /// CHECK: receives the conversation rent, checked against conversation.rent_payer
+    #[account(mut)]
+    pub conversation_rent_payer: AccountInfo<'info>,
+    /// CHECK: receives the rent, must match relationship.rent_payer
+    #[account(mut, address = relationship.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    // Required when the peer rejected me
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = 8 + 32 + 1 + 8 + 32,
+        seeds = [b"tombstone", get_chat_hash(authority.key(), peer.key()).as_ref(), TOMBSTONE_VERSION.as_ref()],
+        bump
+    )]
+    pub tombstone: Option<Account<'info, RejectionTombstone>>,
+    #[account(
+        mut,
+        close = invite_rent_payer,
+        constraint = invite_note.rent_payer == invite_rent_payer.key() @ ErrorCode::Unauthorized,
+        seeds = [b"invite_note", get_chat_hash(authority.key(), peer.key()).as_ref(), INVITE_NOTE_VERSION.as_ref()],
+        bump
+    )]
+    pub invite_note: Option<Account<'info, InviteNote>>,
+    /// CHECK: receives the invite note and escrow rent, checked against their rent_payer
+    #[account(mut)]
+    pub invite_rent_payer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"invite_escrow", get_chat_hash(authority.key(), peer.key()).as_ref(), INVITE_ESCROW_VERSION.as_ref()],
+        bump = invite_escrow.bump
+    )]
+    pub invite_escrow: Option<Account<'info, InviteEscrow>>,
+    #[account(mut)]
+    pub escrow_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub recipient_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+    pub system_program: Program<'info, System>,
+    /// CHECK: my ProfileKeyShare for the peer, closed if it exists
+    #[account(
+        mut,
+        seeds = [b"profile_key", authority.key().as_ref(), peer.key().as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub my_profile_key_share: UncheckedAccount<'info>,
+    /// CHECK: receives its rent, checked against its rent_payer
+    #[account(mut)]
+    pub my_share_rent_payer: AccountInfo<'info>,
+    /// CHECK: the peer's ProfileKeyShare for me, closed if it exists
+    #[account(
+        mut,
+        seeds = [b"profile_key", peer.key().as_ref(), authority.key().as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub peer_profile_key_share: UncheckedAccount<'info>,
+    /// CHECK: receives its rent, checked against its rent_payer
+    #[account(mut)]
+    pub peer_share_rent_payer: AccountInfo<'info>,
+    /// CHECK: my profile, retires the key my share held (if it still exists)
+    #[account(
+        mut,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: UncheckedAccount<'info>,
+    /// CHECK: the peer's profile, retires the key their share held (if it exists)
+    #[account(
+        mut,
+        seeds = [b"user_profile", peer.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub peer_profile: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseTombstone<'info> {
+    #[account(
+        mut,
+        close = rent_payer,
+        constraint = tombstone.peer == authority.key() @ ErrorCode::Unauthorized
+    )]
+    pub tombstone: Account<'info, RejectionTombstone>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match tombstone.rent_payer
+    #[account(mut, address = tombstone.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteGroupMembership<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        seeds = [b"group", group.group_id.as_ref(), GROUP_VERSION.as_ref()],
+        bump
+    )]
+    pub group: Account<'info, Group>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteGroupKey<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"group_key", group_key_share.group_id.as_ref(), authority.key().as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub group_key_share: Account<'info, GroupKeyShare>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match group_key_share.rent_payer
+    #[account(mut, address = group_key_share.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteHandle<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        constraint = handle.owner == authority.key() @ ErrorCode::Unauthorized,
+        seeds = [b"handle", handle.name.as_bytes(), HANDLE_VERSION.as_ref()],
+        bump
+    )]
+    pub handle: Account<'info, Handle>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match handle.rent_payer
+    #[account(mut, address = handle.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteProfileKeyShare<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        constraint = profile_key_share.owner == authority.key() || profile_key_share.contact == authority.key() @ ErrorCode::Unauthorized,
+        seeds = [b"profile_key", profile_key_share.owner.as_ref(), profile_key_share.contact.as_ref(), PROFILE_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub profile_key_share: Account<'info, ProfileKeyShare>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match profile_key_share.rent_payer
+    #[account(mut, address = profile_key_share.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteDevice<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"device", authority.key().as_ref(), device.device_id.as_ref(), DEVICE_VERSION.as_ref()],
+        bump
+    )]
+    pub device: Account<'info, Device>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match device.rent_payer
+    #[account(mut, address = device.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeletePrekeyBundle<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"prekey_bundle", authority.key().as_ref(), PREKEY_BUNDLE_VERSION.as_ref()],
+        bump
+    )]
+    pub prekey_bundle: Account<'info, PrekeyBundle>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match prekey_bundle.rent_payer
+    #[account(mut, address = prekey_bundle.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteSessionKey<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"session_key", authority.key().as_ref(), session.session_key.as_ref(), SESSION_KEY_VERSION.as_ref()],
+        bump
+    )]
+    pub session: Account<'info, SessionKey>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match session.rent_payer
+    #[account(mut, address = session.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteKeyRecord<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"encryption_key", authority.key().as_ref(), key_record.epoch.to_le_bytes().as_ref(), ENCRYPTION_KEY_RECORD_VERSION.as_ref()],
+        bump
+    )]
+    pub key_record: Account<'info, EncryptionKeyRecord>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match key_record.rent_payer
+    #[account(mut, address = key_record.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteGuardianSet<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"guardian_set", authority.key().as_ref(), GUARDIAN_SET_VERSION.as_ref()],
+        bump
+    )]
+    pub guardian_set: Account<'info, GuardianSet>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match guardian_set.rent_payer
+    #[account(mut, address = guardian_set.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteRecovery<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        seeds = [b"recovery", authority.key().as_ref(), recovery.proposer.as_ref(), RECOVERY_VERSION.as_ref()],
+        bump
+    )]
+    pub recovery: Account<'info, Recovery>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match recovery.rent_payer
+    #[account(mut, address = recovery.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteWalletLink<'info> {
+    #[account(
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
+    )]
+    pub account_deletion: Account<'info, AccountDeletion>,
+    #[account(
+        mut,
+        close = rent_payer,
+        constraint = wallet_link.primary == authority.key() || wallet_link.linked == authority.key() @ ErrorCode::Unauthorized,
+        seeds = [b"wallet_link", wallet_link.linked.as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_link: Account<'info, WalletLink>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match wallet_link.rent_payer
+    #[account(mut, address = wallet_link.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteBlockEntry<'info> {
     #[account(
-        init_if_needed,
-        payer = payer,
-        space = 8 + 32 + 4 + 100 * (32 + 1),  // Same size as invite creates
-        seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        mut,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
         bump
     )]
-    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    pub account_deletion: Account<'info, AccountDeletion>,
     #[account(
-        init,
-        payer = payer,
-        space = 8 + 32 + (4 + 32) + 1 + (4 + 128) + 32,
-        seeds = [b"user_profile", payer.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        mut,
+        close = rent_payer,
+        seeds = [b"block", authority.key().as_ref(), block_entry.blinded_peer.as_ref(), BLOCK_VERSION.as_ref()],
         bump
     )]
-    pub user_profile: Account<'info, UserProfile>,
+    pub block_entry: Account<'info, BlockEntry>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match block_entry.rent_payer
+    #[account(mut, address = block_entry.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+    /// CHECK: the blocked wallet, checked against block_entry.pair_tag
+    pub peer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"pair_block", get_chat_hash(authority.key(), peer.key()).as_ref(), PAIR_BLOCK_VERSION.as_ref()],
+        bump
+    )]
+    pub pair_block: Account<'info, PairBlock>,
+    /// CHECK: receives the marker's rent when its last block goes, checked in release_pair_block
     #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    pub pair_block_rent_payer: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateProfile<'info> {
+pub struct FinishAccountDeletion<'info> {
     #[account(
         mut,
-        seeds = [b"user_profile", payer.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
-        bump,
-        realloc = 8 + 32 + (4 + 32) + 1 + (4 + 128) + 32,
-        realloc::payer = payer,
-        realloc::zero = true
+        close = rent_payer,
+        seeds = [b"account_deletion", authority.key().as_ref(), ACCOUNT_DELETION_VERSION.as_ref()],
+        bump
     )]
-    pub user_profile: Account<'info, UserProfile>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    pub account_deletion: Account<'info, AccountDeletion>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match account_deletion.rent_payer
+    #[account(mut, address = account_deletion.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
 }
 
+// ========== DEVICE CONTEXT STRUCTURES ==========
+
 #[derive(Accounts)]
-pub struct CloseProfile<'info> {
-    /// CHECK: Old account structure may not deserialize. Client must pass correct PDA.
-    #[account(mut)]
-    pub user_profile: UncheckedAccount<'info>,
-    /// CHECK: Old WalletDescriptor may not deserialize. Client must pass correct PDA.
+#[instruction(device_id: [u8; 16])]
+pub struct AddDevice<'info> {
+    pub authority: Signer<'info>,
     #[account(mut)]
-    pub wallet_descriptor: UncheckedAccount<'info>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    pub fee_payer: Signer<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 16 + 32 + (4 + 32) + 8 + (1 + 8) + 32,
+        seeds = [b"device", authority.key().as_ref(), device_id.as_ref(), DEVICE_VERSION.as_ref()],
+        bump
+    )]
+    pub device: Account<'info, Device>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(_hash: [u8; 32])]
-pub struct Invite<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK: invitee is a public key
-    pub invitee: AccountInfo<'info>,
+pub struct RevokeDevice<'info> {
+    pub authority: Signer<'info>,
     #[account(
         mut,
-        seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
-        bump,
-        realloc = 8 + 32 + 4 + (payer_descriptor.peers.len() + 1) * (32 + 1),
-        realloc::payer = payer,
-        realloc::zero = true
-    )]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
-    #[account(
-        init_if_needed,
-        payer = payer,
-        space = 8 + 32 + 4 + 100 * (32 + 1),
-        seeds = [b"wallet_descriptor", invitee.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        seeds = [b"device", authority.key().as_ref(), device.device_id.as_ref(), DEVICE_VERSION.as_ref()],
         bump
     )]
-    pub invitee_descriptor: Account<'info, WalletDescriptor>,
+    pub device: Account<'info, Device>,
+}
+
+// ========== PREKEY CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct PublishPrekeys<'info> {
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
     #[account(
         init_if_needed,
-        payer = payer,
-        space = 8 + 64 + 8,
-        seeds = [b"conversation", _hash.as_ref(), CONVERSATION_VERSION.as_ref()],
+        payer = fee_payer,
+        space = 8 + 32 + (4 + 32 + 64) + (4 + MAX_ONE_TIME_PREKEYS * (4 + 32)) + 8 + 32,
+        seeds = [b"prekey_bundle", authority.key().as_ref(), PREKEY_BUNDLE_VERSION.as_ref()],
         bump
     )]
-    pub conversation: Account<'info, Conversation>,
+    pub prekey_bundle: Account<'info, PrekeyBundle>,
+    /// CHECK: instructions sysvar
+    #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+    pub instructions_sysvar: AccountInfo<'info>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Accept<'info> {
+pub struct ClaimPrekey<'info> {
+    pub authority: Signer<'info>,
     #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK: peer is a public key
-    pub peer: AccountInfo<'info>,
-    #[account(
-        mut,
-        seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
-        bump
-    )]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    pub fee_payer: Signer<'info>,
+    /// CHECK: owner of the prekey bundle is a public key
+    pub owner: AccountInfo<'info>,
     #[account(
         mut,
-        seeds = [b"wallet_descriptor", peer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        seeds = [b"prekey_bundle", owner.key().as_ref(), PREKEY_BUNDLE_VERSION.as_ref()],
         bump
     )]
-    pub peer_descriptor: Account<'info, WalletDescriptor>,
-}
-
-#[derive(Accounts)]
-pub struct Reject<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK: peer is a public key
-    pub peer: AccountInfo<'info>,
+    pub prekey_bundle: Account<'info, PrekeyBundle>,
     #[account(
-        mut,
-        seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        seeds = [b"relationship", get_chat_hash(authority.key(), owner.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
         bump
     )]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    pub relationship: Account<'info, Relationship>,
     #[account(
-        mut,
-        seeds = [b"wallet_descriptor", peer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        init_if_needed,
+        payer = fee_payer,
+        space = 8 + 32 + 32 + 8 + 1 + 32,
+        seeds = [b"prekey_claims", owner.key().as_ref(), authority.key().as_ref(), PREKEY_CLAIMS_VERSION.as_ref()],
         bump
     )]
-    pub peer_descriptor: Account<'info, WalletDescriptor>,
+    pub prekey_claims: Account<'info, PrekeyClaims>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Block<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    /// CHECK: peer is a public key
-    pub peer: AccountInfo<'info>,
-    #[account(
-        mut,
-        seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
-        bump
-    )]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
+pub struct ClosePrekeyClaims<'info> {
     #[account(
         mut,
-        seeds = [b"wallet_descriptor", peer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        close = rent_payer,
+        seeds = [b"prekey_claims", prekey_claims.owner.as_ref(), prekey_claims.claimer.as_ref(), PREKEY_CLAIMS_VERSION.as_ref()],
         bump
     )]
-    pub peer_descriptor: Account<'info, WalletDescriptor>,
+    pub prekey_claims: Account<'info, PrekeyClaims>,
+    /// CHECK: receives the rent, must match prekey_claims.rent_payer
+    #[account(mut, address = prekey_claims.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Unblock<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+pub struct VerifyContactKey<'info> {
+    pub authority: Signer<'info>,
     /// CHECK: peer is a public key
     pub peer: AccountInfo<'info>,
     #[account(
-        mut,
-        seeds = [b"wallet_descriptor", payer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: Account<'info, UserProfile>,
+    #[account(
+        seeds = [b"user_profile", peer.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
         bump
     )]
-    pub payer_descriptor: Account<'info, WalletDescriptor>,
+    pub peer_profile: Account<'info, UserProfile>,
     #[account(
         mut,
-        seeds = [b"wallet_descriptor", peer.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        seeds = [b"relationship", get_chat_hash(authority.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
         bump
     )]
-    pub peer_descriptor: Account<'info, WalletDescriptor>,
+    pub relationship: Account<'info, Relationship>,
 }
 
 // ========== GROUP CONTEXT STRUCTURES ==========
@@ -1127,14 +6179,15 @@ pub struct Unblock<'info> {
 pub struct CreateGroup<'info> {
     #[account(
         init,
-        payer = payer,
-        space = 8 + 32 + 32 + (4 + 64) + 8 + (4 + 30 * 32) + 32 + (1 + 32 + 8),
+        payer = fee_payer,
+        space = Group::space(MAX_GROUP_MEMBERS),
         seeds = [b"group", group_id.as_ref(), GROUP_VERSION.as_ref()],
         bump
     )]
     pub group: Account<'info, Group>,
+    pub authority: Signer<'info>,
     #[account(mut)]
-    pub payer: Signer<'info>,
+    pub fee_payer: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
@@ -1146,8 +6199,7 @@ pub struct UpdateGroup<'info> {
         bump
     )]
     pub group: Account<'info, Group>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
@@ -1159,18 +6211,32 @@ pub struct InviteToGroup<'info> {
         bump
     )]
     pub group: Account<'info, Group>,
+    /// CHECK: invitee is a public key, omitted when addressed by handle
+    pub invitee: Option<UncheckedAccount<'info>>,
+    // Invitee addressed by handle instead: the handle's owner is invited
+    pub invitee_handle: Option<Account<'info, Handle>>,
+    /// CHECK: the addressed wallet's WalletLink, read only if it exists
+    #[account(
+        seeds = [b"wallet_link", addressed_invitee(&invitee, &invitee_handle).as_ref(), WALLET_LINK_VERSION.as_ref()],
+        bump
+    )]
+    pub invitee_link: UncheckedAccount<'info>,
     #[account(
         init_if_needed,
-        payer = payer,
+        payer = fee_payer,
         space = 8 + 32 + 32 + 32 + 1 + 8,
-        seeds = [b"group_invite", group.group_id.as_ref(), invitee.key().as_ref(), GROUP_INVITE_VERSION.as_ref()],
+        seeds = [
+            b"group_invite",
+            group.group_id.as_ref(),
+            resolve_invitee(addressed_invitee(&invitee, &invitee_handle), &invitee_link).as_ref(),
+            GROUP_INVITE_VERSION.as_ref()
+        ],
         bump
     )]
     pub group_invite: Account<'info, GroupInvite>,
-    /// CHECK: invitee is a public key
-    pub invitee: AccountInfo<'info>,
+    pub authority: Signer<'info>,
     #[account(mut)]
-    pub payer: Signer<'info>,
+    pub fee_payer: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
@@ -1179,22 +6245,22 @@ pub struct AcceptGroupInvite<'info> {
     #[account(
         mut,
         seeds = [b"group", group.group_id.as_ref(), GROUP_VERSION.as_ref()],
-        bump,
-        realloc = 8 + 32 + 32 + (4 + 64) + 8 + (4 + (group.members.len() + 1) * 32) + 32 + (1 + 32 + 8),
-        realloc::payer = payer,
-        realloc::zero = false
+        bump
     )]
     pub group: Account<'info, Group>,
     #[account(
         mut,
-        seeds = [b"group_invite", group.group_id.as_ref(), payer.key().as_ref(), GROUP_INVITE_VERSION.as_ref()],
+        seeds = [b"group_invite", group.group_id.as_ref(), wallet.key().as_ref(), GROUP_INVITE_VERSION.as_ref()],
         bump
     )]
     pub group_invite: Account<'info, GroupInvite>,
     pub user_token_account: Option<Account<'info, TokenAccount>>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    // Links the wallet holding the gate token to `wallet`
+    pub gate_wallet_link: Option<Account<'info, WalletLink>>,
+    /// CHECK: acting wallet; authority must be it or one of its session keys
+    pub wallet: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    pub session_key: Option<Account<'info, SessionKey>>,
     pub token_program: Option<Program<'info, Token>>,
 }
 
@@ -1202,12 +6268,14 @@ pub struct AcceptGroupInvite<'info> {
 pub struct RejectGroupInvite<'info> {
     #[account(
         mut,
-        seeds = [b"group_invite", group_invite.group_id.as_ref(), payer.key().as_ref(), GROUP_INVITE_VERSION.as_ref()],
+        seeds = [b"group_invite", group_invite.group_id.as_ref(), wallet.key().as_ref(), GROUP_INVITE_VERSION.as_ref()],
         bump
     )]
     pub group_invite: Account<'info, GroupInvite>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    /// CHECK: acting wallet; authority must be it or one of its session keys
+    pub wallet: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    pub session_key: Option<Account<'info, SessionKey>>,
 }
 
 #[derive(Accounts)]
@@ -1215,15 +6283,10 @@ pub struct LeaveGroup<'info> {
     #[account(
         mut,
         seeds = [b"group", group.group_id.as_ref(), GROUP_VERSION.as_ref()],
-        bump,
-        realloc = 8 + 32 + 32 + (4 + 64) + 8 + (4 + (group.members.len().saturating_sub(1)) * 32) + 32 + (1 + 32 + 8),
-        realloc::payer = payer,
-        realloc::zero = false
+        bump
     )]
     pub group: Account<'info, Group>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
@@ -1231,30 +6294,27 @@ pub struct KickMember<'info> {
     #[account(
         mut,
         seeds = [b"group", group.group_id.as_ref(), GROUP_VERSION.as_ref()],
-        bump,
-        realloc = 8 + 32 + 32 + (4 + 64) + 8 + (4 + (group.members.len().saturating_sub(1)) * 32) + 32 + (1 + 32 + 8),
-        realloc::payer = payer,
-        realloc::zero = false
+        bump
     )]
     pub group: Account<'info, Group>,
     /// CHECK: member to kick
     pub member: AccountInfo<'info>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
 pub struct CloseGroup<'info> {
     #[account(
         mut,
-        close = payer,
+        close = rent_payer,
         seeds = [b"group", group.group_id.as_ref(), GROUP_VERSION.as_ref()],
         bump
     )]
     pub group: Account<'info, Group>,
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match group.rent_payer
+    #[account(mut, address = group.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
@@ -1262,9 +6322,9 @@ pub struct CloseGroup<'info> {
 pub struct StoreGroupKey<'info> {
     #[account(
         init_if_needed,
-        payer = payer,
-        space = 8 + 32 + 32 + (4 + 48) + 24,  // disc + group_id + member + Vec(encrypted_key) + nonce
-        seeds = [b"group_key", group_id.as_ref(), payer.key().as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
+        payer = fee_payer,
+        space = GroupKeyShare::SPACE,
+        seeds = [b"group_key", group_id.as_ref(), wallet.key().as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
         bump
     )]
     pub group_key_share: Account<'info, GroupKeyShare>,
@@ -1273,8 +6333,22 @@ pub struct StoreGroupKey<'info> {
         bump
     )]
     pub group: Account<'info, Group>,
+    /// CHECK: acting wallet; authority must be it or one of its session keys, or its passkey signs
+    pub wallet: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    pub session_key: Option<Account<'info, SessionKey>>,
+    // Passkey signature in place of the wallet's
+    #[account(
+        mut,
+        seeds = [b"user_profile", wallet.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub passkey_profile: Option<Box<Account<'info, UserProfile>>>,
+    /// CHECK: instructions sysvar
+    #[account(address = INSTRUCTIONS_SYSVAR_ID)]
+    pub instructions_sysvar: Option<AccountInfo<'info>>,
     #[account(mut)]
-    pub payer: Signer<'info>,
+    pub fee_payer: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
@@ -1282,13 +6356,155 @@ pub struct StoreGroupKey<'info> {
 pub struct CloseGroupKey<'info> {
     #[account(
         mut,
-        close = payer,
-        seeds = [b"group_key", group_key_share.group_id.as_ref(), payer.key().as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
+        close = rent_payer,
+        seeds = [b"group_key", group_key_share.group_id.as_ref(), authority.key().as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
         bump
     )]
     pub group_key_share: Account<'info, GroupKeyShare>,
+    pub authority: Signer<'info>,
+    /// CHECK: receives the rent, must match group_key_share.rent_payer
+    #[account(mut, address = group_key_share.rent_payer)]
+    pub rent_payer: AccountInfo<'info>,
+}
+
+// ========== LAYOUT MIGRATION CONTEXT STRUCTURES ==========
+
+#[derive(Accounts)]
+pub struct MigrateProfile<'info> {
+    /// CHECK: legacy layout, parsed in migrate_profile
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"user_profile", authority.key().as_ref(), USER_PROFILE_VERSION.as_ref()],
+        bump
+    )]
+    pub user_profile: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = 8 + 32 + 8 + 2 + 2,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub wallet_descriptor: Account<'info, WalletDescriptor>,
+    #[account(
+        init,
+        payer = fee_payer,
+        space = EncryptionKeyRecord::SPACE,
+        seeds = [b"encryption_key", authority.key().as_ref(), 0u32.to_le_bytes().as_ref(), ENCRYPTION_KEY_RECORD_VERSION.as_ref()],
+        bump
+    )]
+    pub key_record: Account<'info, EncryptionKeyRecord>,
+    // Optional so registering doesn't wait on the global log; the record
+    // can be logged later with log_key_record
+    #[account(mut, seeds = [b"key_log", KEY_LOG_VERSION.as_ref()], bump)]
+    pub key_log: Option<Box<Account<'info, KeyLog>>>,
+    pub authority: Signer<'info>,
     #[account(mut)]
-    pub payer: Signer<'info>,
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateConversation<'info> {
+    /// CHECK: legacy layout, parsed in migrate_conversation
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"conversation", get_chat_hash(authority.key(), peer.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: UncheckedAccount<'info>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(group_id: [u8; 32])]
+pub struct MigrateGroup<'info> {
+    /// CHECK: legacy layout, parsed in migrate_group
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"group", group_id.as_ref(), GROUP_VERSION.as_ref()],
+        bump
+    )]
+    pub group: UncheckedAccount<'info>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(group_id: [u8; 32])]
+pub struct MigrateGroupKeyShare<'info> {
+    /// CHECK: legacy layout, parsed in migrate_group_key_share
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [b"group_key", group_id.as_ref(), authority.key().as_ref(), GROUP_KEY_SHARE_VERSION.as_ref()],
+        bump
+    )]
+    pub group_key_share: UncheckedAccount<'info>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateLegacyContact<'info> {
+    /// CHECK: legacy layout, parsed in migrate_legacy_contact
+    #[account(
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), LEGACY_WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub legacy_descriptor: UncheckedAccount<'info>,
+    /// CHECK: legacy layout, parsed in migrate_legacy_contact; may be closed
+    #[account(
+        seeds = [b"wallet_descriptor", peer.key().as_ref(), LEGACY_WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub peer_legacy_descriptor: Option<UncheckedAccount<'info>>,
+    /// CHECK: peer is a public key
+    pub peer: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = fee_payer,
+        space = Relationship::SPACE,
+        seeds = [b"relationship", get_chat_hash(authority.key(), peer.key()).as_ref(), RELATIONSHIP_VERSION.as_ref()],
+        bump
+    )]
+    pub relationship: Account<'info, Relationship>,
+    /// CHECK: the pair's conversation, if one was opened
+    #[account(
+        seeds = [b"conversation", get_chat_hash(authority.key(), peer.key()).as_ref(), CONVERSATION_VERSION.as_ref()],
+        bump
+    )]
+    pub conversation: UncheckedAccount<'info>,
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseLegacyDescriptor<'info> {
+    /// CHECK: legacy layout, checked in close_legacy_descriptor
+    #[account(
+        mut,
+        seeds = [b"wallet_descriptor", authority.key().as_ref(), LEGACY_WALLET_DESCRIPTOR_VERSION.as_ref()],
+        bump
+    )]
+    pub legacy_descriptor: UncheckedAccount<'info>,
+    // Receives the descriptor's rent
+    #[account(mut)]
+    pub authority: Signer<'info>,
 }
 
 // ========== ARCIUM MPC CONTEXT STRUCTURES ==========
@@ -1438,6 +6654,85 @@ pub struct CountAcceptedContactsCallback<'info> {
     pub instructions_sysvar: AccountInfo<'info>,
 }
 
+// ========== EVENTS ==========
+
+/// Emitted by claim_prekey with the keys the claimer should use for X3DH
+#[event]
+pub struct PrekeyClaimed {
+    pub owner: Pubkey,
+    pub claimer: Pubkey,
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekey: Option<OneTimePrekey>,
+}
+
+/// Emitted when the encryption key changes (update_profile or a guardian
+/// recovery), so clients can warn that the peer's security code changed
+#[event]
+pub struct EncryptionKeyChanged {
+    pub owner: Pubkey,
+    pub epoch: u32,
+    pub old_key: [u8; 32],
+    pub new_key: [u8; 32],
+    pub activated_at: i64,
+}
+
+/// Emitted when a guardian starts a recovery, so the owner can cancel it
+#[event]
+pub struct RecoveryProposed {
+    pub owner: Pubkey,
+    pub guardian: Pubkey,
+    pub action: RecoveryAction,
+}
+
+/// Emitted when a wallet's identity moves to a new wallet
+#[event]
+pub struct WalletMigrated {
+    pub old_wallet: Pubkey,
+    pub new_wallet: Pubkey,
+    pub migrated_at: i64,
+}
+
+/// Emitted per relationship moved to a migrated wallet, so the peer can
+/// update its contact list
+#[event]
+pub struct ContactMigrated {
+    pub old_wallet: Pubkey,
+    pub new_wallet: Pubkey,
+    pub peer: Pubkey,
+}
+
+#[event]
+pub struct GroupMemberMigrated {
+    pub group_id: [u8; 32],
+    pub old_wallet: Pubkey,
+    pub new_wallet: Pubkey,
+}
+
+/// Emitted when a wallet starts deleting its account; its profile is gone
+#[event]
+pub struct AccountDeleted {
+    pub wallet: Pubkey,
+    pub deleted_at: i64,
+}
+
+/// Emitted per relationship a deleting wallet closes, so the peer can
+/// update its contact list
+#[event]
+pub struct ContactDeleted {
+    pub wallet: Pubkey,
+    pub peer: Pubkey,
+}
+
+/// Emitted for every leaf appended to the KeyLog, so auditors can rebuild
+/// the tree and check the on-chain root
+#[event]
+pub struct KeyLogAppended {
+    pub index: u64,
+    pub leaf: [u8; 32],
+    pub root: [u8; 32],
+    pub tree_size: u64,
+}
+
 // ========== ARCIUM MPC EVENTS ==========
 
 /// Event emitted when contact check computation completes
@@ -1455,3 +6750,78 @@ pub struct ContactCountResult {
     pub nonce: u128,
     pub encryption_key: [u8; 32],
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relationship(a: Pubkey, b: Pubkey) -> Relationship {
+        Relationship {
+            participants: sorted_pair(a, b),
+            states: [PeerState::Rejected, PeerState::Rejected],
+            created_at: 0,
+            updated_at: 0,
+            rent_payer: a,
+            invite_expires_at: None,
+            has_deposit: false,
+            has_intro: false,
+            has_conversation: false,
+            rejections: [0, 0],
+            last_rejected_at: [0, 0],
+            verifications: [None, None],
+        }
+    }
+
+    #[test]
+    fn reinvite_cooldown_doubles_with_every_rejection() {
+        let (inviter, invitee) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut relationship = relationship(inviter, invitee);
+        assert_eq!(relationship.reinvite_allowed_at(inviter), 0);
+
+        relationship.record_rejection(inviter, 1_000);
+        assert_eq!(relationship.reinvite_allowed_at(inviter), 1_000 + REINVITE_COOLDOWN_BASE);
+
+        // Second rejection once the first cooldown is over waits twice as long
+        let second = 1_000 + REINVITE_COOLDOWN_BASE;
+        relationship.record_rejection(inviter, second);
+        assert_eq!(relationship.reinvite_allowed_at(inviter), second + 2 * REINVITE_COOLDOWN_BASE);
+
+        // The invitee's own invites are not held back
+        assert_eq!(relationship.reinvite_allowed_at(invitee), 0);
+    }
+
+    #[test]
+    fn rejected_side_cannot_remove_after_cooldown() {
+        let (inviter, invitee) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut relationship = relationship(inviter, invitee);
+        relationship.record_rejection(inviter, 1_000);
+
+        // Closing would reset the count the next cooldown doubles from,
+        // however long ago the rejection was
+        assert!(check_contact_removable(&relationship, inviter, invitee).is_err());
+        assert!(check_contact_removable(&relationship, invitee, inviter).is_ok());
+    }
+
+    #[test]
+    fn legacy_pairs_map_to_consistent_states() {
+        use LegacyPeerState::*;
+        let pending = (PeerState::Invited, PeerState::Requested);
+        let idle = (PeerState::Rejected, PeerState::Rejected);
+
+        assert!(legacy_pair_states(Invited, Some(Requested)) == pending);
+        assert!(legacy_pair_states(Invited, None) == pending);
+        assert!(legacy_pair_states(Accepted, None) == (PeerState::Accepted, PeerState::Accepted));
+        assert!(legacy_pair_states(Blocked, Some(Accepted)) == idle);
+        assert!(legacy_pair_states(Accepted, Some(Requested)) == idle);
+    }
+
+    #[test]
+    fn reinvite_cooldown_is_capped() {
+        let (inviter, invitee) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut relationship = relationship(inviter, invitee);
+        for _ in 0..u8::MAX {
+            relationship.record_rejection(inviter, 1_000);
+        }
+        assert_eq!(relationship.reinvite_allowed_at(inviter), 1_000 + REINVITE_COOLDOWN_MAX);
+    }
+}
@@ -0,0 +1,290 @@
+//! Merkle tree hashing and proof verification for the key transparency log.
+//!
+//! The tree follows RFC 9162 (Certificate Transparency v2): leaves are
+//! hashed as `SHA-256(0x00 || data)` and interior nodes as
+//! `SHA-256(0x01 || left || right)`, so standard CT tooling can audit it.
+
+use anchor_lang::prelude::Pubkey;
+use sha2::{Digest, Sha256};
+
+pub fn leaf_hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+pub fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Leaf committed to the log for every key registration and rotation
+pub fn key_leaf_hash(owner: &Pubkey, epoch: u32, key: &[u8; 32], activated_at: i64) -> [u8; 32] {
+    let mut data = Vec::with_capacity(32 + 4 + 32 + 8);
+    data.extend_from_slice(owner.as_ref());
+    data.extend_from_slice(&epoch.to_le_bytes());
+    data.extend_from_slice(key);
+    data.extend_from_slice(&activated_at.to_le_bytes());
+    leaf_hash(&data)
+}
+
+/// Check that `leaf` is the `index`-th leaf of the tree of `tree_size`
+/// leaves with the given `root` (RFC 9162 section 2.1.3.2)
+pub fn verify_inclusion(
+    leaf: &[u8; 32],
+    index: u64,
+    tree_size: u64,
+    proof: &[[u8; 32]],
+    root: &[u8; 32],
+) -> bool {
+    if index >= tree_size {
+        return false;
+    }
+
+    let mut fn_ = index;
+    let mut sn = tree_size - 1;
+    let mut r = *leaf;
+
+    for p in proof {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            r = node_hash(p, &r);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+
+    sn == 0 && r == *root
+}
+
+/// Check that the tree of `new_size` leaves with `new_root` is an
+/// append-only extension of the tree of `old_size` leaves with `old_root`
+/// (RFC 9162 section 2.1.4.2)
+pub fn verify_consistency(
+    old_size: u64,
+    new_size: u64,
+    old_root: &[u8; 32],
+    new_root: &[u8; 32],
+    proof: &[[u8; 32]],
+) -> bool {
+    if old_size > new_size {
+        return false;
+    }
+    if old_size == new_size {
+        return proof.is_empty() && old_root == new_root;
+    }
+    if old_size == 0 {
+        return proof.is_empty();
+    }
+    if proof.is_empty() {
+        return false;
+    }
+
+    // A power-of-two old tree is itself a node of the new tree
+    let mut path = Vec::with_capacity(proof.len() + 1);
+    if old_size.is_power_of_two() {
+        path.push(*old_root);
+    }
+    path.extend_from_slice(proof);
+
+    let mut fn_ = old_size - 1;
+    let mut sn = new_size - 1;
+    while fn_ & 1 == 1 {
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+
+    let mut fr = path[0];
+    let mut sr = path[0];
+
+    for c in &path[1..] {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+
+    fr == *old_root && sr == *new_root && sn == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Leaves and roots from the RFC 6962 / RFC 9162 reference test vectors
+    const LEAVES: [&str; 8] = [
+        "",
+        "00",
+        "10",
+        "2021",
+        "3031",
+        "40414243",
+        "5051525354555657",
+        "606162636465666768696a6b6c6d6e6f",
+    ];
+    const ROOTS: [&str; 8] = [
+        "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+        "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+        "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+        "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+        "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+        "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+        "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+    ];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn hash(s: &str) -> [u8; 32] {
+        hex(s).try_into().unwrap()
+    }
+
+    fn leaves(n: usize) -> Vec<[u8; 32]> {
+        LEAVES[..n].iter().map(|l| leaf_hash(&hex(l))).collect()
+    }
+
+    // Largest power of two smaller than n
+    fn split(n: usize) -> usize {
+        let mut k = 1;
+        while k * 2 < n {
+            k *= 2;
+        }
+        k
+    }
+
+    // MTH from RFC 9162 section 2.1.1
+    fn root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        let k = split(leaves.len());
+        node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+    }
+
+    // PATH from RFC 9162 section 2.1.3.1
+    fn inclusion_proof(m: usize, leaves: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        if leaves.len() == 1 {
+            return vec![];
+        }
+        let k = split(leaves.len());
+        if m < k {
+            let mut proof = inclusion_proof(m, &leaves[..k]);
+            proof.push(root(&leaves[k..]));
+            proof
+        } else {
+            let mut proof = inclusion_proof(m - k, &leaves[k..]);
+            proof.push(root(&leaves[..k]));
+            proof
+        }
+    }
+
+    // SUBPROOF from RFC 9162 section 2.1.4.1
+    fn subproof(m: usize, leaves: &[[u8; 32]], complete: bool) -> Vec<[u8; 32]> {
+        let n = leaves.len();
+        if m == n {
+            return if complete { vec![] } else { vec![root(leaves)] };
+        }
+        let k = split(n);
+        if m <= k {
+            let mut proof = subproof(m, &leaves[..k], complete);
+            proof.push(root(&leaves[k..]));
+            proof
+        } else {
+            let mut proof = subproof(m - k, &leaves[k..], false);
+            proof.push(root(&leaves[..k]));
+            proof
+        }
+    }
+
+    #[test]
+    fn roots_match_reference_vectors() {
+        for n in 1..=8 {
+            assert_eq!(root(&leaves(n)), hash(ROOTS[n - 1]), "size {}", n);
+        }
+    }
+
+    #[test]
+    fn inclusion_proofs_verify_for_every_leaf() {
+        for n in 1..=8 {
+            let leaves = leaves(n);
+            let root = hash(ROOTS[n - 1]);
+            for m in 0..n {
+                let proof = inclusion_proof(m, &leaves);
+                assert!(verify_inclusion(&leaves[m], m as u64, n as u64, &proof, &root), "leaf {} of {}", m, n);
+
+                // The same proof must not verify another leaf or index
+                let other = (m + 1) % n;
+                if other != m {
+                    assert!(!verify_inclusion(&leaves[other], m as u64, n as u64, &proof, &root));
+                    assert!(!verify_inclusion(&leaves[m], other as u64, n as u64, &proof, &root));
+                }
+            }
+            assert!(!verify_inclusion(&leaves[0], n as u64, n as u64, &[], &root));
+        }
+    }
+
+    #[test]
+    fn inclusion_proof_reference_vector() {
+        // Leaf 0 of the 8-leaf tree
+        let proof = [
+            hash("96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7"),
+            hash("5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e"),
+            hash("6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4"),
+        ];
+        assert_eq!(inclusion_proof(0, &leaves(8)), proof.to_vec());
+        assert!(verify_inclusion(&leaves(8)[0], 0, 8, &proof, &hash(ROOTS[7])));
+    }
+
+    #[test]
+    fn consistency_proofs_verify_between_all_sizes() {
+        let all = leaves(8);
+        for m in 1..=8 {
+            for n in m..=8 {
+                let proof = subproof(m, &all[..n], true);
+                let (old_root, new_root) = (hash(ROOTS[m - 1]), hash(ROOTS[n - 1]));
+                assert!(verify_consistency(m as u64, n as u64, &old_root, &new_root, &proof), "{} -> {}", m, n);
+                if m < n {
+                    assert!(!verify_consistency(m as u64, n as u64, &new_root, &new_root, &proof));
+                    assert!(!verify_consistency(m as u64, n as u64, &old_root, &old_root, &proof));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_proof_reference_vector() {
+        // From the 6-leaf tree to the 8-leaf tree
+        let proof = [
+            hash("0ebc5d3437fbe2db158b9f126a1d118e308181031d0a949f8dededebc558ef6a"),
+            hash("ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0"),
+            hash("d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7"),
+        ];
+        assert_eq!(subproof(6, &leaves(8), true), proof.to_vec());
+        assert!(verify_consistency(6, 8, &hash(ROOTS[5]), &hash(ROOTS[7]), &proof));
+    }
+}
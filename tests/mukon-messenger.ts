@@ -14,9 +14,23 @@ describe("mukon-messenger", () => {
   const alice = Keypair.generate();
   const bob = Keypair.generate();
 
-  const WALLET_DESCRIPTOR_VERSION = Buffer.from([1]);
+  const WALLET_DESCRIPTOR_VERSION = Buffer.from([2]);
   const USER_PROFILE_VERSION = Buffer.from([1]);
   const CONVERSATION_VERSION = Buffer.from([1]);
+  const RELATIONSHIP_VERSION = Buffer.from([1]);
+  const INVITE_NOTE_VERSION = Buffer.from([1]);
+  const INVITE_ESCROW_VERSION = Buffer.from([1]);
+  const USED_INVITE_TOKEN_VERSION = Buffer.from([1]);
+  const SESSION_KEY_VERSION = Buffer.from([1]);
+  const DEVICE_VERSION = Buffer.from([1]);
+  const PREKEY_BUNDLE_VERSION = Buffer.from([1]);
+  const PREKEY_CLAIMS_VERSION = Buffer.from([1]);
+  const ENCRYPTION_KEY_RECORD_VERSION = Buffer.from([1]);
+  const KEY_LOG_VERSION = Buffer.from([1]);
+  const ACCOUNT_DELETION_VERSION = Buffer.from([1]);
+  const BLOCK_VERSION = Buffer.from([1]);
+  const PAIR_BLOCK_VERSION = Buffer.from([1]);
+  const TOMBSTONE_VERSION = Buffer.from([1]);
 
   // Helper function to get chat hash
   function getChatHash(a: PublicKey, b: PublicKey): Buffer {
@@ -35,6 +49,105 @@ describe("mukon-messenger", () => {
     return crypto.createHash('sha256').update(combined).digest();
   }
 
+  function getRelationshipPda(a: PublicKey, b: PublicKey): PublicKey {
+    const [relationship] = PublicKey.findProgramAddressSync(
+      [Buffer.from("relationship"), getChatHash(a, b), RELATIONSHIP_VERSION],
+      program.programId
+    );
+    return relationship;
+  }
+
+  function getTombstonePda(chatHash: Buffer): PublicKey {
+    const [tombstone] = PublicKey.findProgramAddressSync(
+      [Buffer.from("tombstone"), chatHash, TOMBSTONE_VERSION],
+      program.programId
+    );
+    return tombstone;
+  }
+
+  function getPairBlockPda(chatHash: Buffer): PublicKey {
+    const [pairBlock] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pair_block"), chatHash, PAIR_BLOCK_VERSION],
+      program.programId
+    );
+    return pairBlock;
+  }
+
+  // State of the relationship as seen by `wallet`
+  function stateOf(relationship: any, wallet: PublicKey): any {
+    const side = relationship.participants.findIndex((p: PublicKey) => p.equals(wallet));
+    return relationship.states[side];
+  }
+
+  // Salt the blocker's clients keep, so block entries don't name the peer
+  const blockSalt = Keypair.generate().publicKey.toBuffer();
+
+  function getBlockEntry(wallet: PublicKey, peer: PublicKey): { blindedPeer: number[]; blockEntry: PublicKey; pairBlock: PublicKey } {
+    const crypto = require('crypto');
+    const blinded = crypto.createHash('sha256').update(Buffer.concat([blockSalt, peer.toBuffer()])).digest();
+    const [blockEntry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("block"), wallet.toBuffer(), blinded, BLOCK_VERSION],
+      program.programId
+    );
+    return { blindedPeer: Array.from(blinded), blockEntry, pairBlock: getPairBlockPda(getChatHash(wallet, peer)) };
+  }
+
+  // Stand-in for the peer's key sealed to the blocker
+  const sealedPeer = Array(48).fill(7);
+  const sealNonce = Array(24).fill(0);
+
+  function getUserProfilePda(wallet: PublicKey): PublicKey {
+    const [userProfile] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_profile"), wallet.toBuffer(), USER_PROFILE_VERSION],
+      program.programId
+    );
+    return userProfile;
+  }
+
+  function getKeyRecordPda(wallet: PublicKey, epoch: number): PublicKey {
+    const epochBytes = Buffer.alloc(4);
+    epochBytes.writeUInt32LE(epoch);
+    const [keyRecord] = PublicKey.findProgramAddressSync(
+      [Buffer.from("encryption_key"), wallet.toBuffer(), epochBytes, ENCRYPTION_KEY_RECORD_VERSION],
+      program.programId
+    );
+    return keyRecord;
+  }
+
+  function getProfileKeySharePda(owner: PublicKey, contact: PublicKey): PublicKey {
+    const [share] = PublicKey.findProgramAddressSync(
+      [Buffer.from("profile_key"), owner.toBuffer(), contact.toBuffer(), Buffer.from([1])],
+      program.programId
+    );
+    return share;
+  }
+
+  // Profile key shares between a pair and their owners' profiles, closed
+  // when the pair splits up
+  function profileKeyShareAccounts(me: PublicKey, peer: PublicKey, rentPayer: PublicKey) {
+    return {
+      myProfileKeyShare: getProfileKeySharePda(me, peer),
+      myShareRentPayer: rentPayer,
+      peerProfileKeyShare: getProfileKeySharePda(peer, me),
+      peerShareRentPayer: rentPayer,
+      userProfile: getUserProfilePda(me),
+      peerProfile: getUserProfilePda(peer),
+    };
+  }
+
+  function getWalletLinkPda(wallet: PublicKey): PublicKey {
+    const [walletLink] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_link"), wallet.toBuffer(), Buffer.from([1])],
+      program.programId
+    );
+    return walletLink;
+  }
+
+  const [keyLog] = PublicKey.findProgramAddressSync(
+    [Buffer.from("key_log"), KEY_LOG_VERSION],
+    program.programId
+  );
+
   // Get PDAs for Alice
   const [aliceWalletDescriptor] = PublicKey.findProgramAddressSync(
     [Buffer.from("wallet_descriptor"), alice.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
@@ -72,6 +185,20 @@ describe("mukon-messenger", () => {
     await provider.connection.confirmTransaction(airdropBob);
   });
 
+  it("Initializes the key transparency log", async () => {
+    await program.methods
+      .initKeyLog()
+      .accounts({
+        feePayer: provider.wallet.publicKey,
+        keyLog,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const log = await program.account.keyLog.fetch(keyLog);
+    assert.equal(log.treeSize.toNumber(), 0);
+  });
+
   it("Registers Alice with a display name", async () => {
     const displayName = "Alice";
 
@@ -80,7 +207,11 @@ describe("mukon-messenger", () => {
       .accounts({
         walletDescriptor: aliceWalletDescriptor,
         userProfile: aliceUserProfile,
-        payer: alice.publicKey,
+        keyRecord: getKeyRecordPda(alice.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(alice.publicKey),
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
         systemProgram: SystemProgram.programId,
       })
       .signers([alice])
@@ -89,7 +220,6 @@ describe("mukon-messenger", () => {
     // Verify wallet descriptor
     const walletDescriptor = await program.account.walletDescriptor.fetch(aliceWalletDescriptor);
     assert.ok(walletDescriptor.owner.equals(alice.publicKey));
-    assert.equal(walletDescriptor.peers.length, 0);
 
     // Verify user profile
     const userProfile = await program.account.userProfile.fetch(aliceUserProfile);
@@ -105,7 +235,11 @@ describe("mukon-messenger", () => {
       .accounts({
         walletDescriptor: bobWalletDescriptor,
         userProfile: bobUserProfile,
-        payer: bob.publicKey,
+        keyRecord: getKeyRecordPda(bob.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(bob.publicKey),
+        authority: bob.publicKey,
+        feePayer: bob.publicKey,
         systemProgram: SystemProgram.programId,
       })
       .signers([bob])
@@ -116,234 +250,2476 @@ describe("mukon-messenger", () => {
     assert.equal(userProfile.displayName, displayName);
   });
 
-  it("Alice updates her profile", async () => {
-    const newDisplayName = "Alice Smith";
-    const avatarUrl = "https://example.com/avatar.png";
+  it("A profile with the current layout cannot be migrated again", async () => {
+    const before = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.equal(before.layoutVersion, 2);
 
-    await program.methods
-      .updateProfile(newDisplayName, avatarUrl)
-      .accounts({
-        userProfile: aliceUserProfile,
-        payer: alice.publicKey,
-        systemProgram: SystemProgram.programId,
-      })
-      .signers([alice])
-      .rpc();
+    try {
+      await program.methods
+        .migrateProfile()
+        .accounts({
+          userProfile: aliceUserProfile,
+          walletDescriptor: aliceWalletDescriptor,
+          keyRecord: getKeyRecordPda(alice.publicKey, 0),
+          keyLog,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.notInclude(err.toString(), "Should have failed");
+    }
 
-    const userProfile = await program.account.userProfile.fetch(aliceUserProfile);
-    assert.equal(userProfile.displayName, newDisplayName);
-    assert.equal(userProfile.avatarUrl, avatarUrl);
+    const after = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.equal(after.displayName, before.displayName);
+    assert.equal(after.keyEpoch, before.keyEpoch);
   });
 
-  it("Alice invites Bob", async () => {
+  it("Only contacts listed in a legacy wallet descriptor can be migrated", async () => {
+    const [legacyDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), alice.publicKey.toBuffer(), Buffer.from([1])],
+      program.programId
+    );
     const chatHash = getChatHash(alice.publicKey, bob.publicKey);
-
     const [conversation] = PublicKey.findProgramAddressSync(
       [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
       program.programId
     );
 
+    // Alice registered with the current layout and has no legacy peers
+    try {
+      await program.methods
+        .migrateLegacyContact()
+        .accounts({
+          legacyDescriptor,
+          peerLegacyDescriptor: null,
+          peer: bob.publicKey,
+          relationship: getRelationshipPda(alice.publicKey, bob.publicKey),
+          conversation,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "LegacyContactNotFound");
+    }
+    assert.isNull(await program.account.relationship.fetchNullable(getRelationshipPda(alice.publicKey, bob.publicKey)));
+  });
+
+  it("A sponsor pays rent for a wallet with no SOL and gets it back on close", async () => {
+    const ivy = Keypair.generate();
+    const [ivyWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), ivy.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+    const ivyUserProfile = getUserProfilePda(ivy.publicKey);
+
     await program.methods
-      .invite(Array.from(chatHash))
+      .register("Ivy", "🌿", Array(32).fill(0))
       .accounts({
-        payer: alice.publicKey,
-        invitee: bob.publicKey,
-        payerDescriptor: aliceWalletDescriptor,
-        inviteeDescriptor: bobWalletDescriptor,
-        conversation,
+        walletDescriptor: ivyWalletDescriptor,
+        userProfile: ivyUserProfile,
+        keyRecord: getKeyRecordPda(ivy.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(ivy.publicKey),
+        authority: ivy.publicKey,
+        feePayer: alice.publicKey,
         systemProgram: SystemProgram.programId,
       })
-      .signers([alice])
+      .signers([ivy, alice])
       .rpc();
 
-    // Verify Alice's descriptor shows invited state
-    const aliceDescriptor = await program.account.walletDescriptor.fetch(aliceWalletDescriptor);
-    assert.equal(aliceDescriptor.peers.length, 1);
-    assert.ok(aliceDescriptor.peers[0].wallet.equals(bob.publicKey));
-    assert.deepEqual(aliceDescriptor.peers[0].state, { invited: {} });
+    const profile = await program.account.userProfile.fetch(ivyUserProfile);
+    assert.ok(profile.owner.equals(ivy.publicKey));
+    assert.ok(profile.rentPayer.equals(alice.publicKey));
+    assert.equal(await provider.connection.getBalance(ivy.publicKey), 0);
 
-    // Verify Bob's descriptor shows requested state
-    const bobDescriptor = await program.account.walletDescriptor.fetch(bobWalletDescriptor);
-    assert.equal(bobDescriptor.peers.length, 1);
-    assert.ok(bobDescriptor.peers[0].wallet.equals(alice.publicKey));
-    assert.deepEqual(bobDescriptor.peers[0].state, { requested: {} });
+    // Closing refunds Alice, not Ivy
+    const aliceBalanceBefore = await provider.connection.getBalance(alice.publicKey);
+    await program.methods
+      .closeProfile()
+      .accounts({
+        userProfile: ivyUserProfile,
+        walletDescriptor: ivyWalletDescriptor,
+        authority: ivy.publicKey,
+        rentPayer: alice.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([ivy])
+      .rpc();
 
-    // Verify conversation was created
-    const conv = await program.account.conversation.fetch(conversation);
-    assert.ok(conv.participants[0].equals(alice.publicKey) || conv.participants[1].equals(alice.publicKey));
-    assert.ok(conv.participants[0].equals(bob.publicKey) || conv.participants[1].equals(bob.publicKey));
+    const aliceBalanceAfter = await provider.connection.getBalance(alice.publicKey);
+    assert.isAbove(aliceBalanceAfter, aliceBalanceBefore);
+    assert.equal(await provider.connection.getBalance(ivy.publicKey), 0);
   });
 
-  it("Bob accepts Alice's invitation", async () => {
+  it("Migrates an identity to a new wallet, leaving a forwarding pointer", async () => {
+    const oldWallet = Keypair.generate();
+    const newWallet = Keypair.generate();
+    const getDescriptorPda = (wallet: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("wallet_descriptor"), wallet.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+        program.programId
+      )[0];
+    const [walletForward] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_forward"), oldWallet.publicKey.toBuffer(), Buffer.from([1])],
+      program.programId
+    );
+
     await program.methods
-      .accept()
+      .register("Jack", "🎒", Array(32).fill(8))
       .accounts({
-        payer: bob.publicKey,
-        peer: alice.publicKey,
-        payerDescriptor: bobWalletDescriptor,
-        peerDescriptor: aliceWalletDescriptor,
+        walletDescriptor: getDescriptorPda(oldWallet.publicKey),
+        userProfile: getUserProfilePda(oldWallet.publicKey),
+        keyRecord: getKeyRecordPda(oldWallet.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(oldWallet.publicKey),
+        authority: oldWallet.publicKey,
+        feePayer: alice.publicKey,
+        systemProgram: SystemProgram.programId,
       })
-      .signers([bob])
+      .signers([oldWallet, alice])
       .rpc();
 
-    // Verify both descriptors show accepted state
-    const aliceDescriptor = await program.account.walletDescriptor.fetch(aliceWalletDescriptor);
-    assert.deepEqual(aliceDescriptor.peers[0].state, { accepted: {} });
+    const getGuardianSetPda = (wallet: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("guardian_set"), wallet.toBuffer(), Buffer.from([1])],
+        program.programId
+      )[0];
+    await program.methods
+      .setGuardians([bob.publicKey], 1, new anchor.BN(24 * 60 * 60))
+      .accounts({
+        guardianSet: getGuardianSetPda(oldWallet.publicKey),
+        authority: oldWallet.publicKey,
+        feePayer: alice.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([oldWallet, alice])
+      .rpc();
 
-    const bobDescriptor = await program.account.walletDescriptor.fetch(bobWalletDescriptor);
-    assert.deepEqual(bobDescriptor.peers[0].state, { accepted: {} });
-  });
+    // A session key of the old wallet is still open
+    const ephemeral = Keypair.generate();
+    const [session] = PublicKey.findProgramAddressSync(
+      [Buffer.from("session_key"), oldWallet.publicKey.toBuffer(), ephemeral.publicKey.toBuffer(), SESSION_KEY_VERSION],
+      program.programId
+    );
+    await program.methods
+      .createSessionKey(ephemeral.publicKey, new anchor.BN(Math.floor(Date.now() / 1000) + 3600), 1)
+      .accounts({
+        authority: oldWallet.publicKey,
+        feePayer: alice.publicKey,
+        session,
+        walletDescriptor: getDescriptorPda(oldWallet.publicKey),
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([oldWallet, alice])
+      .rpc();
 
-  it("Cannot invite the same person twice", async () => {
-    const charlie = Keypair.generate();
+    // The old wallet names the new one, then the new wallet takes over
+    await program.methods
+      .startMigration(newWallet.publicKey)
+      .accounts({ userProfile: getUserProfilePda(oldWallet.publicKey), authority: oldWallet.publicKey })
+      .signers([oldWallet])
+      .rpc();
 
-    // Airdrop to Charlie
-    const airdrop = await provider.connection.requestAirdrop(
-      charlie.publicKey,
-      2 * anchor.web3.LAMPORTS_PER_SOL
-    );
-    await provider.connection.confirmTransaction(airdrop);
+    const completeMigration = () =>
+      program.methods
+        .completeMigration()
+        .accounts({
+          oldWallet: oldWallet.publicKey,
+          oldProfile: getUserProfilePda(oldWallet.publicKey),
+          oldDescriptor: getDescriptorPda(oldWallet.publicKey),
+          rentPayer: alice.publicKey,
+          userProfile: getUserProfilePda(newWallet.publicKey),
+          walletDescriptor: getDescriptorPda(newWallet.publicKey),
+          walletForward,
+          keyRecord: getKeyRecordPda(newWallet.publicKey, 0),
+          keyLog,
+          newWalletLink: getWalletLinkPda(newWallet.publicKey),
+          authority: newWallet.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([newWallet, alice])
+        .rpc();
 
-    // Register Charlie
-    const [charlieWalletDescriptor] = PublicKey.findProgramAddressSync(
-      [Buffer.from("wallet_descriptor"), charlie.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
-      program.programId
-    );
+    // It could keep acting on relationships that haven't moved yet
+    try {
+      await completeMigration();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "SessionKeysOpen");
+    }
 
-    const [charlieUserProfile] = PublicKey.findProgramAddressSync(
-      [Buffer.from("user_profile"), charlie.publicKey.toBuffer(), USER_PROFILE_VERSION],
-      program.programId
-    );
+    await program.methods
+      .revokeSessionKey()
+      .accounts({
+        authority: oldWallet.publicKey,
+        session,
+        rentPayer: alice.publicKey,
+        walletDescriptor: getDescriptorPda(oldWallet.publicKey),
+      })
+      .signers([oldWallet])
+      .rpc();
+    await completeMigration();
 
     await program.methods
-      .register("Charlie")
+      .migrateGuardianSet()
       .accounts({
-        walletDescriptor: charlieWalletDescriptor,
-        userProfile: charlieUserProfile,
-        payer: charlie.publicKey,
+        walletForward,
+        oldGuardianSet: getGuardianSetPda(oldWallet.publicKey),
+        guardianSetRentPayer: alice.publicKey,
+        guardianSet: getGuardianSetPda(newWallet.publicKey),
+        authority: newWallet.publicKey,
+        feePayer: alice.publicKey,
         systemProgram: SystemProgram.programId,
       })
-      .signers([charlie])
+      .signers([newWallet, alice])
       .rpc();
 
-    // Alice invites Charlie
-    const chatHash1 = getChatHash(alice.publicKey, charlie.publicKey);
-    const [conversation1] = PublicKey.findProgramAddressSync(
-      [Buffer.from("conversation"), chatHash1, CONVERSATION_VERSION],
-      program.programId
-    );
+    const profile = await program.account.userProfile.fetch(getUserProfilePda(newWallet.publicKey));
+    assert.ok(profile.owner.equals(newWallet.publicKey));
+    assert.equal(profile.displayName, "Jack");
+    assert.isNull(profile.passkey);
+    assert.deepEqual(Array.from(profile.encryptionPublicKey), Array(32).fill(8));
+    assert.isNull(await provider.connection.getAccountInfo(getUserProfilePda(oldWallet.publicKey)));
+
+    const forward = await program.account.walletForward.fetch(walletForward);
+    assert.ok(forward.newWallet.equals(newWallet.publicKey));
+
+    // The key is on record and in the log for the new wallet
+    const keyRecord = await program.account.encryptionKeyRecord.fetch(getKeyRecordPda(newWallet.publicKey, 0));
+    assert.deepEqual(Array.from(keyRecord.key), Array(32).fill(8));
+    assert.isNotNull(keyRecord.logIndex);
+
+    const guardianSet = await program.account.guardianSet.fetch(getGuardianSetPda(newWallet.publicKey));
+    assert.ok(guardianSet.owner.equals(newWallet.publicKey));
+    assert.isNull(await provider.connection.getAccountInfo(getGuardianSetPda(oldWallet.publicKey)));
+  });
+
+  it("Alice updates her profile", async () => {
+    const newDisplayName = "Alice Smith";
+    const avatarUrl = "https://example.com/avatar.png";
 
     await program.methods
-      .invite(Array.from(chatHash1))
+      .updateProfile(newDisplayName, avatarUrl)
       .accounts({
-        payer: alice.publicKey,
-        invitee: charlie.publicKey,
-        payerDescriptor: aliceWalletDescriptor,
-        inviteeDescriptor: charlieWalletDescriptor,
-        conversation: conversation1,
+        userProfile: aliceUserProfile,
+        keyRecord: null,
+        keyLog: null,
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
         systemProgram: SystemProgram.programId,
       })
       .signers([alice])
       .rpc();
 
-    // Try to invite again - should fail (already invited error)
-    try {
-      const chatHash2 = getChatHash(alice.publicKey, charlie.publicKey);
-      const [conversation2] = PublicKey.findProgramAddressSync(
-        [Buffer.from("conversation"), chatHash2, CONVERSATION_VERSION],
-        program.programId
-      );
+    const userProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.equal(userProfile.displayName, newDisplayName);
+    assert.equal(userProfile.avatarUrl, avatarUrl);
+  });
 
-      await program.methods
-        .invite(Array.from(chatHash2))
+  it("Profile details resize the account to what is set", async () => {
+    const sizeOf = async () => (await provider.connection.getAccountInfo(aliceUserProfile)).data.length;
+    const updateDetails = (bio: string, status: string, links: string[], avatarContent: any) =>
+      program.methods
+        .updateProfileDetails(bio, status, links, avatarContent)
         .accounts({
-          payer: alice.publicKey,
-          invitee: charlie.publicKey,
-          payerDescriptor: aliceWalletDescriptor,
-          inviteeDescriptor: charlieWalletDescriptor,
-          conversation: conversation2,
+          userProfile: aliceUserProfile,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
           systemProgram: SystemProgram.programId,
         })
         .signers([alice])
         .rpc();
 
+    const emptySize = await sizeOf();
+    const avatarContent = { sha256: Array(32).fill(7), storage: { ipfs: {} } };
+    await updateDetails("Builder", "In a meeting", ["https://alice.dev"], avatarContent);
+
+    const userProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.equal(userProfile.bio, "Builder");
+    assert.equal(userProfile.status, "In a meeting");
+    assert.deepEqual(userProfile.links, ["https://alice.dev"]);
+    assert.deepEqual(userProfile.avatarContent.storage, { ipfs: {} });
+    assert.equal(
+      await sizeOf(),
+      emptySize + "Builder".length + "In a meeting".length + 4 + "https://alice.dev".length + 33
+    );
+
+    try {
+      await updateDetails("x".repeat(161), "", [], null);
       assert.fail("Should have failed");
     } catch (err) {
-      // Should fail because either AlreadyInvited or conversation account already exists
-      assert.ok(
-        err.toString().includes("AlreadyInvited") ||
-        err.toString().includes("already in use") ||
-        err.toString().includes("custom program error: 0x0")
-      );
+      assert.include(err.toString(), "BioTooLong");
     }
-  });
 
-  it("Can reject an invitation", async () => {
-    const dave = Keypair.generate();
+    // Clearing the details shrinks the account back
+    await updateDetails("", "", [], null);
+    assert.equal(await sizeOf(), emptySize);
+  });
 
-    // Setup Dave
-    const airdrop = await provider.connection.requestAirdrop(
-      dave.publicKey,
-      2 * anchor.web3.LAMPORTS_PER_SOL
-    );
-    await provider.connection.confirmTransaction(airdrop);
+  it("An NFT avatar needs a token account proving ownership", async () => {
+    try {
+      await program.methods
+        .updateProfile(null, { nft: {} }, Keypair.generate().publicKey.toBase58(), null)
+        .accounts({
+          userProfile: aliceUserProfile,
+          keyRecord: null,
+          keyLog: null,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "TokenAccountRequired");
+    }
 
-    const [daveWalletDescriptor] = PublicKey.findProgramAddressSync(
-      [Buffer.from("wallet_descriptor"), dave.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
-      program.programId
-    );
+    const userProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.deepEqual(userProfile.avatarType, { emoji: {} });
+    assert.deepEqual(userProfile.badges, []);
+  });
 
-    const [daveUserProfile] = PublicKey.findProgramAddressSync(
-      [Buffer.from("user_profile"), dave.publicKey.toBuffer(), USER_PROFILE_VERSION],
-      program.programId
-    );
+  it("Rotating the encryption key records a new epoch", async () => {
+    const newKey = Array(32).fill(5);
+    const keyRecord = getKeyRecordPda(bob.publicKey, 1);
 
     await program.methods
-      .register("Dave")
+      .updateProfile(null, null, null, newKey)
       .accounts({
-        walletDescriptor: daveWalletDescriptor,
-        userProfile: daveUserProfile,
-        payer: dave.publicKey,
+        userProfile: bobUserProfile,
+        keyRecord,
+        keyLog,
+        authority: bob.publicKey,
+        feePayer: bob.publicKey,
         systemProgram: SystemProgram.programId,
       })
-      .signers([dave])
+      .signers([bob])
       .rpc();
 
-    // Alice invites Dave
-    const chatHash = getChatHash(alice.publicKey, dave.publicKey);
-    const [conversation] = PublicKey.findProgramAddressSync(
-      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+    const profile = await program.account.userProfile.fetch(bobUserProfile);
+    assert.equal(profile.keyEpoch, 1);
+    assert.deepEqual(Array.from(profile.encryptionPublicKey), newKey);
+
+    // The key that was valid before stays on record under epoch 0
+    const record = await program.account.encryptionKeyRecord.fetch(keyRecord);
+    assert.equal(record.epoch, 1);
+    assert.deepEqual(Array.from(record.key), newKey);
+    assert.ok(await program.account.encryptionKeyRecord.fetch(getKeyRecordPda(bob.publicKey, 0)));
+
+    // The rotation was committed to the transparency log after the registrations
+    const log = await program.account.keyLog.fetch(keyLog);
+    assert.equal(record.logIndex.toNumber(), log.treeSize.toNumber() - 1);
+  });
+
+  it("Alice registers a second device and revokes it", async () => {
+    const deviceId = Array(16).fill(1);
+    const [device] = PublicKey.findProgramAddressSync(
+      [Buffer.from("device"), alice.publicKey.toBuffer(), Buffer.from(deviceId), DEVICE_VERSION],
       program.programId
     );
 
     await program.methods
-      .invite(Array.from(chatHash))
+      .addDevice(deviceId, Array(32).fill(9), "Desktop")
       .accounts({
-        payer: alice.publicKey,
-        invitee: dave.publicKey,
-        payerDescriptor: aliceWalletDescriptor,
-        inviteeDescriptor: daveWalletDescriptor,
-        conversation,
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        device,
         systemProgram: SystemProgram.programId,
       })
       .signers([alice])
       .rpc();
 
-    // Dave rejects
+    const added = await program.account.device.fetch(device);
+    assert.ok(added.owner.equals(alice.publicKey));
+    assert.equal(added.label, "Desktop");
+    assert.isNull(added.revokedAt);
+
     await program.methods
-      .reject()
+      .revokeDevice()
       .accounts({
-        payer: dave.publicKey,
-        peer: alice.publicKey,
-        payerDescriptor: daveWalletDescriptor,
-        peerDescriptor: aliceWalletDescriptor,
+        authority: alice.publicKey,
+        device,
       })
-      .signers([dave])
+      .signers([alice])
+      .rpc();
+
+    const revoked = await program.account.device.fetch(device);
+    assert.isNotNull(revoked.revokedAt);
+  });
+
+  it("Alice invites Bob", async () => {
+    const chatHash = getChatHash(alice.publicKey, bob.publicKey);
+
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .invite(Array.from(chatHash), null, null)
+      .accounts({
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        inviteeLink: getWalletLinkPda(bob.publicKey),
+        invitee: bob.publicKey,
+        inviteeProfile: getUserProfilePda(bob.publicKey),
+        authorityDescriptor: aliceWalletDescriptor,
+        relationship: getRelationshipPda(alice.publicKey, bob.publicKey),
+        conversation,
+        tombstone: getTombstonePda(chatHash),
+        pairBlock: getPairBlockPda(chatHash),
+        inviteNote: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    // Verify Alice sees invited state and Bob sees requested state
+    const relationship = await program.account.relationship.fetch(
+      getRelationshipPda(alice.publicKey, bob.publicKey)
+    );
+    assert.deepEqual(stateOf(relationship, alice.publicKey), { invited: {} });
+    assert.deepEqual(stateOf(relationship, bob.publicKey), { requested: {} });
+
+    // Verify conversation was created
+    const conv = await program.account.conversation.fetch(conversation);
+    assert.ok(conv.participants[0].equals(alice.publicKey) || conv.participants[1].equals(alice.publicKey));
+    assert.ok(conv.participants[0].equals(bob.publicKey) || conv.participants[1].equals(bob.publicKey));
+  });
+
+  it("Bob accepts Alice's invitation", async () => {
+    await program.methods
+      .accept()
+      .accounts({
+        wallet: bob.publicKey,
+        authority: bob.publicKey,
+        sessionKey: null,
+        passkeyProfile: null,
+        instructionsSysvar: null,
+        peer: alice.publicKey,
+        relationship: getRelationshipPda(bob.publicKey, alice.publicKey),
+        inviteNote: null,
+        inviteRentPayer: alice.publicKey,
+      })
+      .signers([bob])
+      .rpc();
+
+    // Verify both sides show accepted state
+    const relationship = await program.account.relationship.fetch(
+      getRelationshipPda(alice.publicKey, bob.publicKey)
+    );
+    assert.deepEqual(stateOf(relationship, alice.publicKey), { accepted: {} });
+    assert.deepEqual(stateOf(relationship, bob.publicKey), { accepted: {} });
+  });
+
+  it("Bob claims one of Alice's one-time prekeys", async () => {
+    const [prekeyBundle] = PublicKey.findProgramAddressSync(
+      [Buffer.from("prekey_bundle"), alice.publicKey.toBuffer(), PREKEY_BUNDLE_VERSION],
+      program.programId
+    );
+
+    const [prekeyClaims] = PublicKey.findProgramAddressSync(
+      [Buffer.from("prekey_claims"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), PREKEY_CLAIMS_VERSION],
+      program.programId
+    );
+
+    // Alice signs the signed prekey with her wallet
+    const prekeyKey = Buffer.alloc(32, 3);
+    const prekeyId = Buffer.alloc(4);
+    prekeyId.writeUInt32LE(1);
+    const ed25519Ix = anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: alice.secretKey,
+      message: Buffer.concat([
+        Buffer.from("mukon-messenger:prekey"),
+        program.programId.toBuffer(),
+        alice.publicKey.toBuffer(),
+        prekeyId,
+        prekeyKey,
+      ]),
+    });
+    // Signature sits after the 16-byte header and the 32-byte pubkey
+    const signature = Array.from(ed25519Ix.data.subarray(48, 112));
+    const signedPrekey = { id: 1, key: Array.from(prekeyKey), signature };
+    const oneTimePrekeys = [1, 2].map((id) => ({ id, key: Array(32).fill(id) }));
+
+    const publish = (preInstructions: anchor.web3.TransactionInstruction[]) =>
+      program.methods
+        .publishPrekeys(signedPrekey, oneTimePrekeys)
+        .accounts({
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          prekeyBundle,
+          instructionsSysvar: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          systemProgram: SystemProgram.programId,
+        })
+        .preInstructions(preInstructions)
+        .signers([alice])
+        .rpc();
+
+    // Without the wallet's signature the prekey is refused
+    try {
+      await publish([]);
+      assert.fail("Should have thrown InvalidPrekeySignature");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidPrekeySignature");
+    }
+
+    await publish([ed25519Ix]);
+
+    await program.methods
+      .claimPrekey()
+      .accounts({
+        authority: bob.publicKey,
+        feePayer: bob.publicKey,
+        owner: alice.publicKey,
+        prekeyBundle,
+        relationship: getRelationshipPda(bob.publicKey, alice.publicKey),
+        prekeyClaims,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const claims = await program.account.prekeyClaims.fetch(prekeyClaims);
+    assert.equal(claims.claimsThisEpoch, 1);
+
+    // The count is still live this epoch
+    try {
+      await program.methods
+        .closePrekeyClaims()
+        .accounts({ prekeyClaims, rentPayer: bob.publicKey })
+        .rpc();
+      assert.fail("Should have thrown PrekeyClaimsActive");
+    } catch (err) {
+      assert.include(err.toString(), "PrekeyClaimsActive");
+    }
+
+    // The first key was handed out and removed from the pool
+    const bundle = await program.account.prekeyBundle.fetch(prekeyBundle);
+    assert.equal(bundle.oneTimePrekeys.length, 1);
+    assert.equal(bundle.oneTimePrekeys[0].id, 2);
+    assert.equal(bundle.signedPrekey.id, 1);
+  });
+
+  it("Alice and Bob verify each other's keys, stale after a rotation", async () => {
+    const relationshipPda = getRelationshipPda(alice.publicKey, bob.publicKey);
+    const crypto = require('crypto');
+
+    // Safety number over both wallets and keys, in participants order
+    const fingerprint = async () => {
+      const { participants } = await program.account.relationship.fetch(relationshipPda);
+      const hasher = crypto.createHash('sha256');
+      for (const p of participants) {
+        const profile = await program.account.userProfile.fetch(getUserProfilePda(p));
+        hasher.update(p.toBuffer()).update(Buffer.from(profile.encryptionPublicKey));
+      }
+      return Array.from(hasher.digest()) as number[];
+    };
+
+    const verify = async (me: Keypair, peer: PublicKey) =>
+      program.methods
+        .verifyContactKey(await fingerprint())
+        .accounts({
+          authority: me.publicKey,
+          peer,
+          userProfile: getUserProfilePda(me.publicKey),
+          peerProfile: getUserProfilePda(peer),
+          relationship: relationshipPda,
+        })
+        .signers([me])
+        .rpc();
+
+    await verify(alice, bob.publicKey);
+    await verify(bob, alice.publicKey);
+
+    let relationship = await program.account.relationship.fetch(relationshipPda);
+    const [a, b] = relationship.verifications;
+    assert.deepEqual(a.fingerprint, b.fingerprint);
+    assert.deepEqual(a.keyEpochs, b.keyEpochs);
+
+    // A wrong safety number is refused
+    try {
+      await program.methods
+        .verifyContactKey(Array(32).fill(0))
+        .accounts({
+          authority: alice.publicKey,
+          peer: bob.publicKey,
+          userProfile: getUserProfilePda(alice.publicKey),
+          peerProfile: getUserProfilePda(bob.publicKey),
+          relationship: relationshipPda,
+        })
+        .signers([alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "KeyFingerprintMismatch");
+    }
+
+    // Bob rotates his key: the attestations no longer cover his current epoch
+    await program.methods
+      .updateProfile(null, null, null, Array(32).fill(6))
+      .accounts({
+        userProfile: bobUserProfile,
+        keyRecord: getKeyRecordPda(bob.publicKey, 2),
+        keyLog,
+        authority: bob.publicKey,
+        feePayer: bob.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const bobProfile = await program.account.userProfile.fetch(bobUserProfile);
+    relationship = await program.account.relationship.fetch(relationshipPda);
+    const bobSide = relationship.participants.findIndex((p: PublicKey) => p.equals(bob.publicKey));
+    assert.notEqual(relationship.verifications[0].keyEpochs[bobSide], bobProfile.keyEpoch);
+    assert.notDeepEqual(relationship.verifications[0].fingerprint, await fingerprint());
+  });
+
+  it("A contacts-only profile hides its details and wraps the key for contacts", async () => {
+    const setVisibility = (visibility: any, sealed: any) =>
+      program.methods
+        .setProfileVisibility(visibility, sealed)
+        .accounts({
+          userProfile: aliceUserProfile,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+
+    const sealed = { ciphertext: Buffer.from("sealed name and bio"), nonce: Array(24).fill(1), keyVersion: 1 };
+    await setVisibility({ contactsOnly: {} }, sealed);
+
+    let userProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.deepEqual(userProfile.visibility, { contactsOnly: {} });
+    assert.equal(userProfile.displayName, "");
+    assert.equal(userProfile.bio, "");
+    assert.equal(Buffer.from(userProfile.sealed.ciphertext).toString(), "sealed name and bio");
+
+    // Plaintext updates would leak what is sealed
+    try {
+      await program.methods
+        .updateProfile("Alice", null, null, null)
+        .accounts({
+          userProfile: aliceUserProfile,
+          keyRecord: null,
+          keyLog: null,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "ProfileIsPrivate");
+    }
+
+    // Bob is an accepted contact, so he gets the profile key
+    const profileKeyShare = getProfileKeySharePda(alice.publicKey, bob.publicKey);
+    const shareKey = () =>
+      program.methods
+        .shareProfileKey(Buffer.from(Array(48).fill(2)), Array(24).fill(3), 1)
+        .accounts({
+          profileKeyShare,
+          contact: bob.publicKey,
+          relationship: getRelationshipPda(alice.publicKey, bob.publicKey),
+          userProfile: aliceUserProfile,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+    await shareKey();
+
+    const share = await program.account.profileKeyShare.fetch(profileKeyShare);
+    assert.ok(share.contact.equals(bob.publicKey));
+    assert.equal(share.keyVersion, 1);
+
+    // Either side can drop the share
+    await program.methods
+      .closeProfileKey()
+      .accounts({ profileKeyShare, authority: bob.publicKey, rentPayer: alice.publicKey })
+      .signers([bob])
+      .rpc();
+
+    // Shared again; removing Bob as a contact later closes it
+    await shareKey();
+    assert.isNull(await provider.connection.getAccountInfo(profileKeyShare));
+
+    await setVisibility({ public: {} }, null);
+    userProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.deepEqual(userProfile.visibility, { public: {} });
+    assert.isNull(userProfile.sealed);
+  });
+
+  it("Cannot invite the same person twice", async () => {
+    const charlie = Keypair.generate();
+
+    // Airdrop to Charlie
+    const airdrop = await provider.connection.requestAirdrop(
+      charlie.publicKey,
+      2 * anchor.web3.LAMPORTS_PER_SOL
+    );
+    await provider.connection.confirmTransaction(airdrop);
+
+    // Register Charlie
+    const [charlieWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), charlie.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+
+    const [charlieUserProfile] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_profile"), charlie.publicKey.toBuffer(), USER_PROFILE_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .register("Charlie")
+      .accounts({
+        walletDescriptor: charlieWalletDescriptor,
+        userProfile: charlieUserProfile,
+        keyRecord: getKeyRecordPda(charlie.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(charlie.publicKey),
+        authority: charlie.publicKey,
+        feePayer: charlie.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([charlie])
+      .rpc();
+
+    // Alice invites Charlie
+    const chatHash1 = getChatHash(alice.publicKey, charlie.publicKey);
+    const [conversation1] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash1, CONVERSATION_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .invite(Array.from(chatHash1), null, null)
+      .accounts({
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        inviteeLink: getWalletLinkPda(charlie.publicKey),
+        invitee: charlie.publicKey,
+        inviteeProfile: getUserProfilePda(charlie.publicKey),
+        authorityDescriptor: aliceWalletDescriptor,
+        relationship: getRelationshipPda(alice.publicKey, charlie.publicKey),
+        conversation: conversation1,
+        tombstone: getTombstonePda(chatHash1),
+        pairBlock: getPairBlockPda(chatHash1),
+        inviteNote: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    // Try to invite again - should fail (already invited error)
+    try {
+      const chatHash2 = getChatHash(alice.publicKey, charlie.publicKey);
+      const [conversation2] = PublicKey.findProgramAddressSync(
+        [Buffer.from("conversation"), chatHash2, CONVERSATION_VERSION],
+        program.programId
+      );
+
+      await program.methods
+        .invite(Array.from(chatHash2), null, null)
+        .accounts({
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          inviteeLink: getWalletLinkPda(charlie.publicKey),
+          invitee: charlie.publicKey,
+          inviteeProfile: getUserProfilePda(charlie.publicKey),
+          authorityDescriptor: aliceWalletDescriptor,
+          relationship: getRelationshipPda(alice.publicKey, charlie.publicKey),
+          conversation: conversation2,
+          tombstone: getTombstonePda(chatHash2),
+          pairBlock: getPairBlockPda(chatHash2),
+          inviteNote: null,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+
+      assert.fail("Should have failed");
+    } catch (err) {
+      // Should fail because either AlreadyInvited or conversation account already exists
+      assert.ok(
+        err.toString().includes("AlreadyInvited") ||
+        err.toString().includes("already in use") ||
+        err.toString().includes("custom program error: 0x0")
+      );
+    }
+  });
+
+  it("Can reject an invitation", async () => {
+    const dave = Keypair.generate();
+
+    // Setup Dave
+    const airdrop = await provider.connection.requestAirdrop(
+      dave.publicKey,
+      2 * anchor.web3.LAMPORTS_PER_SOL
+    );
+    await provider.connection.confirmTransaction(airdrop);
+
+    const [daveWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), dave.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+
+    const [daveUserProfile] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_profile"), dave.publicKey.toBuffer(), USER_PROFILE_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .register("Dave")
+      .accounts({
+        walletDescriptor: daveWalletDescriptor,
+        userProfile: daveUserProfile,
+        keyRecord: getKeyRecordPda(dave.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(dave.publicKey),
+        authority: dave.publicKey,
+        feePayer: dave.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([dave])
+      .rpc();
+
+    // Alice invites Dave with an intro note
+    const chatHash = getChatHash(alice.publicKey, dave.publicKey);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+    const [inviteNote] = PublicKey.findProgramAddressSync(
+      [Buffer.from("invite_note"), chatHash, INVITE_NOTE_VERSION],
+      program.programId
+    );
+    const intro = { ciphertext: Buffer.from("sealed intro"), nonce: Array(24).fill(7) };
+
+    await program.methods
+      .invite(Array.from(chatHash), null, intro)
+      .accounts({
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        inviteeLink: getWalletLinkPda(dave.publicKey),
+        invitee: dave.publicKey,
+        inviteeProfile: getUserProfilePda(dave.publicKey),
+        authorityDescriptor: aliceWalletDescriptor,
+        relationship: getRelationshipPda(alice.publicKey, dave.publicKey),
+        conversation,
+        tombstone: getTombstonePda(chatHash),
+        pairBlock: getPairBlockPda(chatHash),
+        inviteNote,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const note = await program.account.inviteNote.fetch(inviteNote);
+    assert.ok(note.inviter.equals(alice.publicKey));
+    assert.ok(Buffer.from(note.ciphertext).equals(intro.ciphertext));
+    const pending = await program.account.relationship.fetch(getRelationshipPda(alice.publicKey, dave.publicKey));
+    assert.isTrue(pending.hasIntro);
+
+    // The note can't be left behind
+    try {
+      await program.methods
+        .reject()
+        .accounts({
+          wallet: dave.publicKey,
+          authority: dave.publicKey,
+          sessionKey: null,
+          peer: alice.publicKey,
+          relationship: getRelationshipPda(dave.publicKey, alice.publicKey),
+          inviteNote: null,
+          inviteRentPayer: alice.publicKey,
+          ...profileKeyShareAccounts(dave.publicKey, alice.publicKey, alice.publicKey),
+        })
+        .signers([dave])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "IntroAccountMismatch");
+    }
+
+    // Dave rejects
+    await program.methods
+      .reject()
+      .accounts({
+        wallet: dave.publicKey,
+        authority: dave.publicKey,
+        sessionKey: null,
+        peer: alice.publicKey,
+        relationship: getRelationshipPda(dave.publicKey, alice.publicKey),
+        inviteNote,
+        inviteRentPayer: alice.publicKey,
+        ...profileKeyShareAccounts(dave.publicKey, alice.publicKey, alice.publicKey),
+      })
+      .signers([dave])
+      .rpc();
+
+    // Verify rejected state
+    const relationship = await program.account.relationship.fetch(
+      getRelationshipPda(alice.publicKey, dave.publicKey)
+    );
+    assert.deepEqual(stateOf(relationship, dave.publicKey), { rejected: {} });
+    assert.isFalse(relationship.hasIntro);
+
+    // Intro note is wiped and its rent returned to Alice
+    assert.isNull(await program.account.inviteNote.fetchNullable(inviteNote));
+
+    // Alice can't wipe her rejection by removing the relationship, cooldown or not
+    try {
+      await program.methods
+        .removeContact()
+        .accounts({
+          authority: alice.publicKey,
+          peer: dave.publicKey,
+          relationship: getRelationshipPda(alice.publicKey, dave.publicKey),
+          conversation,
+          conversationRentPayer: alice.publicKey,
+          rentPayer: alice.publicKey,
+          ...profileKeyShareAccounts(alice.publicKey, dave.publicKey, alice.publicKey),
+        })
+        .signers([alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "RelationshipLocked");
+    }
+
+    // Nor re-invite before the first cooldown is over
+    const reinvite = (inviter: Keypair, descriptor: PublicKey, invitee: PublicKey) =>
+      program.methods
+        .invite(Array.from(chatHash), null, null)
+        .accounts({
+          authority: inviter.publicKey,
+          feePayer: inviter.publicKey,
+          inviteeLink: getWalletLinkPda(invitee),
+          invitee,
+          inviteeProfile: getUserProfilePda(invitee),
+          authorityDescriptor: descriptor,
+          relationship: getRelationshipPda(alice.publicKey, dave.publicKey),
+          conversation,
+          tombstone: getTombstonePda(chatHash),
+          pairBlock: getPairBlockPda(chatHash),
+          inviteNote: null,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([inviter])
+        .rpc();
+
+    try {
+      await reinvite(alice, aliceWalletDescriptor, dave.publicKey);
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "ReinviteCooldown");
+    }
+
+    // Dave invites Alice back and withdraws: the relationship stays, idle,
+    // so Alice's rejection and cooldown survive
+    await reinvite(dave, daveWalletDescriptor, alice.publicKey);
+    await program.methods
+      .cancelInvite()
+      .accounts({
+        authority: dave.publicKey,
+        peer: alice.publicKey,
+        relationship: getRelationshipPda(dave.publicKey, alice.publicKey),
+        conversation,
+        conversationRentPayer: dave.publicKey,
+        rentPayer: alice.publicKey,
+        inviteNote: null,
+        inviteRentPayer: dave.publicKey,
+      })
+      .signers([dave])
+      .rpc();
+
+    const withdrawn = await program.account.relationship.fetch(getRelationshipPda(alice.publicKey, dave.publicKey));
+    assert.deepEqual(stateOf(withdrawn, alice.publicKey), { rejected: {} });
+    assert.deepEqual(stateOf(withdrawn, dave.publicKey), { rejected: {} });
+    const aliceSide = withdrawn.participants.findIndex((p: PublicKey) => p.equals(alice.publicKey));
+    assert.equal(withdrawn.rejections[aliceSide], 1);
+    assert.equal(withdrawn.rejections[1 - aliceSide], 0);
+    assert.isFalse(withdrawn.hasConversation);
+
+    try {
+      await reinvite(alice, aliceWalletDescriptor, dave.publicKey);
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "ReinviteCooldown");
+    }
+  });
+
+  it("Caps the invites a wallet can send per epoch", async () => {
+    const ivy = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(ivy.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+    );
+
+    const [ivyWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), ivy.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .register("Ivy")
+      .accounts({
+        walletDescriptor: ivyWalletDescriptor,
+        userProfile: getUserProfilePda(ivy.publicKey),
+        keyRecord: getKeyRecordPda(ivy.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(ivy.publicKey),
+        authority: ivy.publicKey,
+        feePayer: ivy.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([ivy])
+      .rpc();
+
+    const inviteNew = () => {
+      const invitee = Keypair.generate().publicKey;
+      const chatHash = getChatHash(ivy.publicKey, invitee);
+      const [conversation] = PublicKey.findProgramAddressSync(
+        [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+        program.programId
+      );
+      return program.methods
+        .invite(Array.from(chatHash), null, null)
+        .accounts({
+          authority: ivy.publicKey,
+          feePayer: ivy.publicKey,
+          inviteeLink: getWalletLinkPda(invitee),
+          invitee,
+          inviteeProfile: getUserProfilePda(invitee),
+          authorityDescriptor: ivyWalletDescriptor,
+          relationship: getRelationshipPda(ivy.publicKey, invitee),
+          conversation,
+          tombstone: getTombstonePda(chatHash),
+          pairBlock: getPairBlockPda(chatHash),
+          inviteNote: null,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([ivy])
+        .rpc();
+    };
+
+    // MAX_INVITES_PER_EPOCH
+    for (let i = 0; i < 50; i++) {
+      await inviteNew();
+    }
+    const descriptor = await program.account.walletDescriptor.fetch(ivyWalletDescriptor);
+    assert.equal(descriptor.invitesThisEpoch, 50);
+
+    try {
+      await inviteNew();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InviteRateLimited");
+    }
+  });
+
+  it("Inviter can cancel a pending invite", async () => {
+    const eve = Keypair.generate();
+
+    const chatHash = getChatHash(alice.publicKey, eve.publicKey);
+    const relationship = getRelationshipPda(alice.publicKey, eve.publicKey);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+
+    // Alice invites Eve with an expiry one hour out
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+    await program.methods
+      .invite(Array.from(chatHash), expiresAt, null)
+      .accounts({
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        inviteeLink: getWalletLinkPda(eve.publicKey),
+        invitee: eve.publicKey,
+        inviteeProfile: getUserProfilePda(eve.publicKey),
+        authorityDescriptor: aliceWalletDescriptor,
+        relationship,
+        conversation,
+        tombstone: getTombstonePda(chatHash),
+        pairBlock: getPairBlockPda(chatHash),
+        inviteNote: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const pending = await program.account.relationship.fetch(relationship);
+    assert.ok(pending.inviteExpiresAt.eq(expiresAt));
+    assert.ok(pending.rentPayer.equals(alice.publicKey));
+
+    // Alice withdraws the invite
+    await program.methods
+      .cancelInvite()
+      .accounts({
+        authority: alice.publicKey,
+        peer: eve.publicKey,
+        relationship,
+        conversation,
+        conversationRentPayer: alice.publicKey,
+        rentPayer: alice.publicKey,
+        inviteNote: null,
+        inviteRentPayer: alice.publicKey,
+      })
+      .signers([alice])
+      .rpc();
+
+    // Both accounts are closed
+    assert.isNull(await program.account.relationship.fetchNullable(relationship));
+    assert.isNull(await program.account.conversation.fetchNullable(conversation));
+  });
+
+  it("Invites must pass the invitee's invite policy", async () => {
+    const fiona = Keypair.generate();
+    const ursula = Keypair.generate();
+    for (const wallet of [fiona, ursula]) {
+      await provider.connection.confirmTransaction(
+        await provider.connection.requestAirdrop(wallet.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+      );
+    }
+
+    const descriptorOf = (wallet: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("wallet_descriptor"), wallet.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+        program.programId
+      )[0];
+
+    for (const [wallet, name] of [[fiona, "Fiona"], [ursula, "Ursula"]] as [Keypair, string][]) {
+      await program.methods
+        .register(name)
+        .accounts({
+          walletDescriptor: descriptorOf(wallet.publicKey),
+          userProfile: getUserProfilePda(wallet.publicKey),
+          keyRecord: getKeyRecordPda(wallet.publicKey, 0),
+          keyLog,
+          walletLink: getWalletLinkPda(wallet.publicKey),
+          authority: wallet.publicKey,
+          feePayer: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+    }
+
+    const invite = (inviter: Keypair, invitee: PublicKey, proof: object = {}) => {
+      const chatHash = getChatHash(inviter.publicKey, invitee);
+      const [conversation] = PublicKey.findProgramAddressSync(
+        [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+        program.programId
+      );
+      return program.methods
+        .invite(Array.from(chatHash), null, null)
+        .accounts({
+          authority: inviter.publicKey,
+          feePayer: inviter.publicKey,
+          inviteeLink: getWalletLinkPda(invitee),
+          invitee,
+          inviteeProfile: getUserProfilePda(invitee),
+          authorityDescriptor: descriptorOf(inviter.publicKey),
+          relationship: getRelationshipPda(inviter.publicKey, invitee),
+          conversation,
+          tombstone: getTombstonePda(chatHash),
+          pairBlock: getPairBlockPda(chatHash),
+          inviteNote: null,
+          systemProgram: SystemProgram.programId,
+          ...proof,
+        })
+        .signers([inviter])
+        .rpc();
+    };
+
+    const setPolicy = (policy: any) =>
+      program.methods
+        .setInvitePolicy(policy)
+        .accounts({
+          userProfile: getUserProfilePda(fiona.publicKey),
+          keyRecord: null,
+          keyLog: null,
+          authority: fiona.publicKey,
+          feePayer: fiona.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([fiona])
+        .rpc();
+
+    const expectRejected = async (attempt: Promise<string>, error: string) => {
+      try {
+        await attempt;
+        assert.fail("Should have failed");
+      } catch (err) {
+        assert.include(err.toString(), error);
+      }
+    };
+
+    // Alice and Fiona become contacts while Fiona still takes anyone
+    await invite(alice, fiona.publicKey);
+    await program.methods
+      .accept()
+      .accounts({
+        wallet: fiona.publicKey,
+        authority: fiona.publicKey,
+        sessionKey: null,
+        passkeyProfile: null,
+        instructionsSysvar: null,
+        peer: alice.publicKey,
+        relationship: getRelationshipPda(fiona.publicKey, alice.publicKey),
+        inviteNote: null,
+        inviteRentPayer: alice.publicKey,
+      })
+      .signers([fiona])
+      .rpc();
+
+    // Nobody
+    await setPolicy({ nobody: {} });
+    await expectRejected(invite(ursula, fiona.publicKey), "InvitesDisabled");
+
+    // TokenHolders: Ursula holds an account of the mint, but with no balance
+    const TOKEN_PROGRAM_ID = new PublicKey("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+    const mint = Keypair.generate();
+    const tokenAccount = Keypair.generate();
+    const rent = async (space: number) =>
+      provider.connection.getMinimumBalanceForRentExemption(space);
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        SystemProgram.createAccount({
+          fromPubkey: ursula.publicKey,
+          newAccountPubkey: mint.publicKey,
+          lamports: await rent(82),
+          space: 82,
+          programId: TOKEN_PROGRAM_ID,
+        }),
+        // InitializeMint2: 0 decimals, Ursula as authority, no freeze authority
+        new anchor.web3.TransactionInstruction({
+          programId: TOKEN_PROGRAM_ID,
+          keys: [{ pubkey: mint.publicKey, isSigner: false, isWritable: true }],
+          data: Buffer.concat([Buffer.from([20, 0]), ursula.publicKey.toBuffer(), Buffer.from([0])]),
+        }),
+        SystemProgram.createAccount({
+          fromPubkey: ursula.publicKey,
+          newAccountPubkey: tokenAccount.publicKey,
+          lamports: await rent(165),
+          space: 165,
+          programId: TOKEN_PROGRAM_ID,
+        }),
+        // InitializeAccount3 owned by Ursula
+        new anchor.web3.TransactionInstruction({
+          programId: TOKEN_PROGRAM_ID,
+          keys: [
+            { pubkey: tokenAccount.publicKey, isSigner: false, isWritable: true },
+            { pubkey: mint.publicKey, isSigner: false, isWritable: false },
+          ],
+          data: Buffer.concat([Buffer.from([18]), ursula.publicKey.toBuffer()]),
+        })
+      ),
+      [ursula, mint, tokenAccount]
+    );
+
+    await setPolicy({ tokenHolders: { 0: { tokenMint: mint.publicKey, minBalance: new anchor.BN(1) } } });
+    await expectRejected(invite(ursula, fiona.publicKey), "TokenAccountRequired");
+    await expectRejected(
+      invite(ursula, fiona.publicKey, { gateTokenAccount: tokenAccount.publicKey }),
+      "InsufficientTokenBalance"
+    );
+
+    // ContactsOfContacts: Bob knows Alice, who knows Fiona; Ursula's
+    // invite to Alice is still pending, so Alice is no mutual contact
+    await setPolicy({ contactsOfContacts: {} });
+    await invite(ursula, alice.publicKey);
+    await expectRejected(invite(ursula, fiona.publicKey), "InvitePolicyNotMet");
+    await expectRejected(
+      invite(ursula, fiona.publicKey, {
+        inviterMutualRelationship: getRelationshipPda(ursula.publicKey, alice.publicKey),
+        inviteeMutualRelationship: getRelationshipPda(alice.publicKey, fiona.publicKey),
+      }),
+      "InvitePolicyNotMet"
+    );
+
+    await invite(bob, fiona.publicKey, {
+      inviterMutualRelationship: getRelationshipPda(bob.publicKey, alice.publicKey),
+      inviteeMutualRelationship: getRelationshipPda(alice.publicKey, fiona.publicKey),
+    });
+    const relationship = await program.account.relationship.fetch(getRelationshipPda(bob.publicKey, fiona.publicKey));
+    assert.deepEqual(stateOf(relationship, fiona.publicKey), { requested: {} });
+  });
+
+  it("A blocked pair cannot invite each other until unblocked", async () => {
+    const frank = Keypair.generate();
+
+    const airdrop = await provider.connection.requestAirdrop(
+      frank.publicKey,
+      2 * anchor.web3.LAMPORTS_PER_SOL
+    );
+    await provider.connection.confirmTransaction(airdrop);
+
+    const [frankWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), frank.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+    const [frankUserProfile] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_profile"), frank.publicKey.toBuffer(), USER_PROFILE_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .register("Frank", "🦊", Array(32).fill(0))
+      .accounts({
+        walletDescriptor: frankWalletDescriptor,
+        userProfile: frankUserProfile,
+        keyRecord: getKeyRecordPda(frank.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(frank.publicKey),
+        authority: frank.publicKey,
+        feePayer: frank.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([frank])
+      .rpc();
+
+    // Alice blocks Frank before they ever talked
+    const { blindedPeer, blockEntry, pairBlock } = getBlockEntry(alice.publicKey, frank.publicKey);
+    await program.methods
+      .block(blindedPeer, sealedPeer, sealNonce)
+      .accounts({
+        wallet: alice.publicKey,
+        authority: alice.publicKey,
+        sessionKey: null,
+        passkeyProfile: null,
+        instructionsSysvar: null,
+        feePayer: alice.publicKey,
+        peer: frank.publicKey,
+        blockEntry,
+        pairBlock,
+        relationship: null,
+        inviteEscrow: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    // The entry names Frank only through the salted hash, and the pair's
+    // marker doesn't say who blocked
+    const entry = await program.account.blockEntry.fetch(blockEntry);
+    assert.ok(entry.owner.equals(alice.publicKey));
+    assert.deepEqual(entry.blindedPeer, blindedPeer);
+    const marker = await program.account.pairBlock.fetch(pairBlock);
+    assert.equal(marker.blocks, 1);
+
+    // No relationship is created, and Frank's invite fails like one to an existing contact
+    const relationship = getRelationshipPda(alice.publicKey, frank.publicKey);
+    assert.isNull(await program.account.relationship.fetchNullable(relationship));
+
+    const chatHash = getChatHash(frank.publicKey, alice.publicKey);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+    const frankInvites = () =>
+      program.methods
+        .invite(Array.from(chatHash), null, null)
+        .accounts({
+          authority: frank.publicKey,
+          feePayer: frank.publicKey,
+          inviteeLink: getWalletLinkPda(alice.publicKey),
+          invitee: alice.publicKey,
+          inviteeProfile: getUserProfilePda(alice.publicKey),
+          authorityDescriptor: frankWalletDescriptor,
+          relationship,
+          conversation,
+          tombstone: getTombstonePda(chatHash),
+          pairBlock,
+          inviteNote: null,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([frank])
+        .rpc();
+
+    try {
+      await frankInvites();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "AlreadyInvited");
+    }
+    assert.isNull(await program.account.relationship.fetchNullable(relationship));
+
+    // Unblocking closes the entry and, with the last block, the marker
+    await program.methods
+      .unblock()
+      .accounts({
+        wallet: alice.publicKey,
+        authority: alice.publicKey,
+        sessionKey: null,
+        blockEntry,
+        rentPayer: alice.publicKey,
+        peer: frank.publicKey,
+        pairBlock,
+        pairBlockRentPayer: alice.publicKey,
+      })
+      .signers([alice])
+      .rpc();
+
+    assert.isNull(await program.account.blockEntry.fetchNullable(blockEntry));
+    assert.isNull(await program.account.pairBlock.fetchNullable(pairBlock));
+
+    await frankInvites();
+    const invited = await program.account.relationship.fetch(relationship);
+    assert.deepEqual(stateOf(invited, frank.publicKey), { invited: {} });
+    assert.deepEqual(stateOf(invited, alice.publicKey), { requested: {} });
+  });
+
+  it("A session key acts for the wallet until revoked", async () => {
+    const judy = Keypair.generate();
+    const ephemeral = Keypair.generate();
+    const SESSION_BLOCK = 1 << 2;
+
+    const [session] = PublicKey.findProgramAddressSync(
+      [Buffer.from("session_key"), alice.publicKey.toBuffer(), ephemeral.publicKey.toBuffer(), SESSION_KEY_VERSION],
+      program.programId
+    );
+
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+    await program.methods
+      .createSessionKey(ephemeral.publicKey, expiresAt, SESSION_BLOCK)
+      .accounts({
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        session,
+        walletDescriptor: aliceWalletDescriptor,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    // The session key blocks Judy for Alice; the provider wallet relays fees
+    const { blindedPeer, blockEntry, pairBlock } = getBlockEntry(alice.publicKey, judy.publicKey);
+    await program.methods
+      .block(blindedPeer, sealedPeer, sealNonce)
+      .accounts({
+        wallet: alice.publicKey,
+        authority: ephemeral.publicKey,
+        sessionKey: session,
+        passkeyProfile: null,
+        instructionsSysvar: null,
+        feePayer: provider.wallet.publicKey,
+        peer: judy.publicKey,
+        blockEntry,
+        pairBlock,
+        relationship: null,
+        inviteEscrow: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([ephemeral])
+      .rpc();
+
+    const blocked = await program.account.blockEntry.fetch(blockEntry);
+    assert.ok(blocked.owner.equals(alice.publicKey));
+
+    await program.methods
+      .revokeSessionKey()
+      .accounts({
+        authority: alice.publicKey,
+        session,
+        rentPayer: alice.publicKey,
+        walletDescriptor: aliceWalletDescriptor,
+      })
+      .signers([alice])
+      .rpc();
+
+    // A revoked key can no longer act
+    try {
+      await program.methods
+        .unblock()
+        .accounts({
+          wallet: alice.publicKey,
+          authority: ephemeral.publicKey,
+          sessionKey: session,
+          blockEntry,
+          rentPayer: provider.wallet.publicKey,
+          peer: judy.publicKey,
+          pairBlock,
+          pairBlockRentPayer: provider.wallet.publicKey,
+        })
+        .signers([ephemeral])
+        .rpc();
+
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.ok(err.toString().includes("AccountNotInitialized"));
+    }
+  });
+
+  it("A passkey can act for the wallet only with a secp256r1 signature", async () => {
+    const crypto = require('crypto');
+    const kate = Keypair.generate();
+    const passkey = crypto.createECDH("prime256v1");
+    passkey.generateKeys();
+    const passkeyPubkey = Array.from(passkey.getPublicKey(null, "compressed"));
+
+    const setPasskey = (key: number[] | null) =>
+      program.methods
+        .setPasskey(key)
+        .accounts({
+          userProfile: aliceUserProfile,
+          keyRecord: null,
+          keyLog: null,
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+
+    // Only compressed keys (0x02/0x03 prefix) are accepted
+    try {
+      await setPasskey([0x04, ...passkeyPubkey.slice(1)]);
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidPasskey");
+    }
+
+    await setPasskey(passkeyPubkey);
+    let profile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.deepEqual(profile.passkey, passkeyPubkey);
+
+    // The relayer signs the transaction but no secp256r1 verification precedes it
+    try {
+      const { blindedPeer, blockEntry, pairBlock } = getBlockEntry(alice.publicKey, kate.publicKey);
+      await program.methods
+        .block(blindedPeer, sealedPeer, sealNonce)
+        .accounts({
+          wallet: alice.publicKey,
+          authority: provider.wallet.publicKey,
+          sessionKey: null,
+          passkeyProfile: aliceUserProfile,
+          instructionsSysvar: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          feePayer: provider.wallet.publicKey,
+          peer: kate.publicKey,
+          blockEntry,
+          pairBlock,
+          relationship: null,
+          inviteEscrow: null,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidPasskeySignature");
+    }
+
+    profile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.equal(profile.passkeyNonce.toNumber(), 0);
+
+    await setPasskey(null);
+    profile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.isNull(profile.passkey);
+  });
+
+  it("Invitee keeps the deposit of a rejected or blocked invite", async () => {
+    const gina = Keypair.generate();
+
+    const airdrop = await provider.connection.requestAirdrop(
+      gina.publicKey,
+      2 * anchor.web3.LAMPORTS_PER_SOL
+    );
+    await provider.connection.confirmTransaction(airdrop);
+
+    const [ginaWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), gina.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .register("Gina", "🌻", Array(32).fill(0))
+      .accounts({
+        walletDescriptor: ginaWalletDescriptor,
+        userProfile: getUserProfilePda(gina.publicKey),
+        keyRecord: getKeyRecordPda(gina.publicKey, 0),
+        keyLog: null,
+        walletLink: getWalletLinkPda(gina.publicKey),
+        authority: gina.publicKey,
+        feePayer: gina.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([gina])
+      .rpc();
+
+    // Gina registered without touching the key log; anyone can log her key afterwards
+    const ginaKeyRecord = getKeyRecordPda(gina.publicKey, 0);
+    assert.isNull((await program.account.encryptionKeyRecord.fetch(ginaKeyRecord)).logIndex);
+    await program.methods
+      .logKeyRecord()
+      .accounts({ keyRecord: ginaKeyRecord, keyLog })
+      .rpc();
+    const ginaLog = await program.account.keyLog.fetch(keyLog);
+    const ginaRecord = await program.account.encryptionKeyRecord.fetch(ginaKeyRecord);
+    assert.equal(ginaRecord.logIndex.toNumber(), ginaLog.treeSize.toNumber() - 1);
+
+    // Bob requires a 0.01 SOL deposit from inviters
+    const amount = new anchor.BN(0.01 * anchor.web3.LAMPORTS_PER_SOL);
+    await program.methods
+      .setInviteDeposit({ mint: null, amount })
+      .accounts({
+        userProfile: bobUserProfile,
+        keyRecord: null,
+        keyLog: null,
+        authority: bob.publicKey,
+        feePayer: bob.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const chatHash = getChatHash(gina.publicKey, bob.publicKey);
+    const relationship = getRelationshipPda(gina.publicKey, bob.publicKey);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+    const [inviteEscrow] = PublicKey.findProgramAddressSync(
+      [Buffer.from("invite_escrow"), chatHash, INVITE_ESCROW_VERSION],
+      program.programId
+    );
+
+    await program.methods
+      .invite(Array.from(chatHash), null, null)
+      .accounts({
+        authority: gina.publicKey,
+        feePayer: gina.publicKey,
+        inviteeLink: getWalletLinkPda(bob.publicKey),
+        invitee: bob.publicKey,
+        inviteeProfile: bobUserProfile,
+        authorityDescriptor: ginaWalletDescriptor,
+        relationship,
+        conversation,
+        tombstone: getTombstonePda(chatHash),
+        pairBlock: getPairBlockPda(chatHash),
+        inviteNote: null,
+        inviteEscrow,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([gina])
+      .rpc();
+
+    const escrow = await program.account.inviteEscrow.fetch(inviteEscrow);
+    assert.ok(escrow.amount.eq(amount));
+
+    // Bob rejects and keeps the deposit
+    const bobBalanceBefore = await provider.connection.getBalance(bob.publicKey);
+    await program.methods
+      .reject()
+      .accounts({
+        wallet: bob.publicKey,
+        authority: bob.publicKey,
+        sessionKey: null,
+        peer: gina.publicKey,
+        relationship,
+        inviteNote: null,
+        inviteEscrow,
+        inviteRentPayer: gina.publicKey,
+        ...profileKeyShareAccounts(bob.publicKey, gina.publicKey, gina.publicKey),
+      })
+      .signers([bob])
+      .rpc();
+
+    const bobBalanceAfter = await provider.connection.getBalance(bob.publicKey);
+    assert.isAbove(bobBalanceAfter, bobBalanceBefore);
+    assert.isNull(await program.account.inviteEscrow.fetchNullable(inviteEscrow));
+
+    // Hank invites Bob with a deposit too, and Bob blocks him instead
+    const hank = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(hank.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+    );
+    const [hankWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), hank.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+    await program.methods
+      .register("Hank", "🔨", Array(32).fill(0))
+      .accounts({
+        walletDescriptor: hankWalletDescriptor,
+        userProfile: getUserProfilePda(hank.publicKey),
+        keyRecord: getKeyRecordPda(hank.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(hank.publicKey),
+        authority: hank.publicKey,
+        feePayer: hank.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([hank])
+      .rpc();
+
+    const hankHash = getChatHash(hank.publicKey, bob.publicKey);
+    const hankRelationship = getRelationshipPda(hank.publicKey, bob.publicKey);
+    const [hankConversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), hankHash, CONVERSATION_VERSION],
+      program.programId
+    );
+    const [hankEscrow] = PublicKey.findProgramAddressSync(
+      [Buffer.from("invite_escrow"), hankHash, INVITE_ESCROW_VERSION],
+      program.programId
+    );
+    await program.methods
+      .invite(Array.from(hankHash), null, null)
+      .accounts({
+        authority: hank.publicKey,
+        feePayer: hank.publicKey,
+        inviteeLink: getWalletLinkPda(bob.publicKey),
+        invitee: bob.publicKey,
+        inviteeProfile: bobUserProfile,
+        authorityDescriptor: hankWalletDescriptor,
+        relationship: hankRelationship,
+        conversation: hankConversation,
+        tombstone: getTombstonePda(hankHash),
+        pairBlock: getPairBlockPda(hankHash),
+        inviteNote: null,
+        inviteEscrow: hankEscrow,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([hank])
+      .rpc();
+
+    // The relayer pays for the block, so Bob's balance only moves by the deposit
+    const bobBalanceBeforeBlock = await provider.connection.getBalance(bob.publicKey);
+    const { blindedPeer, blockEntry, pairBlock } = getBlockEntry(bob.publicKey, hank.publicKey);
+    await program.methods
+      .block(blindedPeer, sealedPeer, sealNonce)
+      .accounts({
+        wallet: bob.publicKey,
+        authority: bob.publicKey,
+        sessionKey: null,
+        passkeyProfile: null,
+        instructionsSysvar: null,
+        feePayer: provider.wallet.publicKey,
+        peer: hank.publicKey,
+        blockEntry,
+        pairBlock,
+        relationship: hankRelationship,
+        inviteEscrow: hankEscrow,
+        escrowRentPayer: hank.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const bobBalanceAfterBlock = await provider.connection.getBalance(bob.publicKey);
+    assert.equal(bobBalanceAfterBlock - bobBalanceBeforeBlock, amount.toNumber());
+    assert.isNull(await program.account.inviteEscrow.fetchNullable(hankEscrow));
+    assert.isFalse((await program.account.relationship.fetch(hankRelationship)).hasDeposit);
+  });
+
+  it("Removing a contact closes the relationship and conversation", async () => {
+    const chatHash = getChatHash(alice.publicKey, bob.publicKey);
+    const relationship = getRelationshipPda(alice.publicKey, bob.publicKey);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+
+    // Alice paid for both accounts when she invited Bob
+    const conversationAccount = await program.account.conversation.fetch(conversation);
+    assert.ok(conversationAccount.rentPayer.equals(alice.publicKey));
+    const aliceBalanceBefore = await provider.connection.getBalance(alice.publicKey);
+
+    await program.methods
+      .removeContact()
+      .accounts({
+        authority: bob.publicKey,
+        peer: alice.publicKey,
+        relationship,
+        conversation,
+        conversationRentPayer: alice.publicKey,
+        rentPayer: alice.publicKey,
+        ...profileKeyShareAccounts(bob.publicKey, alice.publicKey, alice.publicKey),
+      })
+      .signers([bob])
+      .rpc();
+
+    assert.isNull(await program.account.relationship.fetchNullable(relationship));
+    assert.isNull(await program.account.conversation.fetchNullable(conversation));
+
+    // Alice's profile key share for Bob is gone and its key version retired
+    assert.isNull(await provider.connection.getAccountInfo(getProfileKeySharePda(alice.publicKey, bob.publicKey)));
+    const aliceProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.equal(aliceProfile.profileKeyFloor, 2);
+
+    const aliceBalanceAfter = await provider.connection.getBalance(alice.publicKey);
+    assert.isAbove(aliceBalanceAfter, aliceBalanceBefore);
+  });
+
+  it("Rejecting an accepted contact ends the profile key shares between them", async () => {
+    const nina = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(nina.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+    );
+
+    const [ninaWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), nina.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+    await program.methods
+      .register("Nina", "🧭", Array(32).fill(0))
+      .accounts({
+        walletDescriptor: ninaWalletDescriptor,
+        userProfile: getUserProfilePda(nina.publicKey),
+        keyRecord: getKeyRecordPda(nina.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(nina.publicKey),
+        authority: nina.publicKey,
+        feePayer: nina.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([nina])
+      .rpc();
+
+    // Alice invites Nina, who accepts
+    const chatHash = getChatHash(alice.publicKey, nina.publicKey);
+    const relationship = getRelationshipPda(alice.publicKey, nina.publicKey);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+    await program.methods
+      .invite(Array.from(chatHash), null, null)
+      .accounts({
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        inviteeLink: getWalletLinkPda(nina.publicKey),
+        invitee: nina.publicKey,
+        inviteeProfile: getUserProfilePda(nina.publicKey),
+        authorityDescriptor: aliceWalletDescriptor,
+        relationship,
+        conversation,
+        tombstone: getTombstonePda(chatHash),
+        pairBlock: getPairBlockPda(chatHash),
+        inviteNote: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+    await program.methods
+      .accept()
+      .accounts({
+        wallet: nina.publicKey,
+        authority: nina.publicKey,
+        sessionKey: null,
+        passkeyProfile: null,
+        instructionsSysvar: null,
+        peer: alice.publicKey,
+        relationship,
+        inviteNote: null,
+        inviteRentPayer: alice.publicKey,
+      })
+      .signers([nina])
       .rpc();
 
-    // Verify rejected state
-    const daveDescriptor = await program.account.walletDescriptor.fetch(daveWalletDescriptor);
-    const davePeer = daveDescriptor.peers.find(p => p.wallet.equals(alice.publicKey));
-    assert.deepEqual(davePeer.state, { rejected: {} });
+    // Alice wraps her current profile key for Nina
+    const floor = (await program.account.userProfile.fetch(aliceUserProfile)).profileKeyFloor;
+    const profileKeyShare = getProfileKeySharePda(alice.publicKey, nina.publicKey);
+    await program.methods
+      .shareProfileKey(Buffer.from(Array(48).fill(2)), Array(24).fill(3), floor)
+      .accounts({
+        profileKeyShare,
+        contact: nina.publicKey,
+        relationship,
+        userProfile: aliceUserProfile,
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    // Nina drops Alice with reject instead of remove_contact
+    await program.methods
+      .reject()
+      .accounts({
+        wallet: nina.publicKey,
+        authority: nina.publicKey,
+        sessionKey: null,
+        peer: alice.publicKey,
+        relationship,
+        inviteNote: null,
+        inviteEscrow: null,
+        inviteRentPayer: alice.publicKey,
+        ...profileKeyShareAccounts(nina.publicKey, alice.publicKey, alice.publicKey),
+      })
+      .signers([nina])
+      .rpc();
+
+    const rejected = await program.account.relationship.fetch(relationship);
+    assert.deepEqual(stateOf(rejected, nina.publicKey), { rejected: {} });
+
+    // The share is gone and Alice must rotate past the key Nina held
+    assert.isNull(await provider.connection.getAccountInfo(profileKeyShare));
+    const aliceProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.equal(aliceProfile.profileKeyFloor, floor + 1);
+  });
+
+  it("Deleting an account closes its relationships, keeping a tombstone of the peer's rejection", async () => {
+    const oscar = Keypair.generate();
+    const [oscarWalletDescriptor] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wallet_descriptor"), oscar.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+      program.programId
+    );
+    const oscarUserProfile = getUserProfilePda(oscar.publicKey);
+    const [accountDeletion] = PublicKey.findProgramAddressSync(
+      [Buffer.from("account_deletion"), oscar.publicKey.toBuffer(), ACCOUNT_DELETION_VERSION],
+      program.programId
+    );
+
+    // A sponsor pays for everything, Oscar holds no SOL
+    await program.methods
+      .register("Oscar", "🗑️", Array(32).fill(0))
+      .accounts({
+        walletDescriptor: oscarWalletDescriptor,
+        userProfile: oscarUserProfile,
+        keyRecord: getKeyRecordPda(oscar.publicKey, 0),
+        keyLog,
+        walletLink: getWalletLinkPda(oscar.publicKey),
+        authority: oscar.publicKey,
+        feePayer: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([oscar])
+      .rpc();
+
+    // Alice turns down Oscar's invite, so Oscar is serving a re-invite cooldown
+    const chatHash = getChatHash(oscar.publicKey, alice.publicKey);
+    const relationship = getRelationshipPda(oscar.publicKey, alice.publicKey);
+    const tombstone = getTombstonePda(chatHash);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+    await program.methods
+      .invite(Array.from(chatHash), null, null)
+      .accounts({
+        authority: oscar.publicKey,
+        feePayer: provider.wallet.publicKey,
+        inviteeLink: getWalletLinkPda(alice.publicKey),
+        invitee: alice.publicKey,
+        inviteeProfile: aliceUserProfile,
+        authorityDescriptor: oscarWalletDescriptor,
+        relationship,
+        conversation,
+        tombstone,
+        inviteNote: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([oscar])
+      .rpc();
+    await program.methods
+      .reject()
+      .accounts({
+        wallet: alice.publicKey,
+        authority: alice.publicKey,
+        sessionKey: null,
+        peer: oscar.publicKey,
+        relationship,
+        inviteNote: null,
+        inviteEscrow: null,
+        inviteRentPayer: provider.wallet.publicKey,
+        ...profileKeyShareAccounts(alice.publicKey, oscar.publicKey, provider.wallet.publicKey),
+      })
+      .signers([alice])
+      .rpc();
+
+    await program.methods
+      .startAccountDeletion()
+      .accounts({
+        userProfile: oscarUserProfile,
+        walletDescriptor: oscarWalletDescriptor,
+        profileRentPayer: provider.wallet.publicKey,
+        accountDeletion,
+        authority: oscar.publicKey,
+        feePayer: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([oscar])
+      .rpc();
+
+    assert.isNull(await program.account.userProfile.fetchNullable(oscarUserProfile));
+    assert.isNull(await program.account.walletDescriptor.fetchNullable(oscarWalletDescriptor));
+
+    const deleteRelationship = (withTombstone: boolean) =>
+      program.methods
+        .deleteRelationship()
+        .accounts({
+          accountDeletion,
+          authority: oscar.publicKey,
+          feePayer: provider.wallet.publicKey,
+          peer: alice.publicKey,
+          relationship,
+          conversation,
+          conversationRentPayer: provider.wallet.publicKey,
+          rentPayer: provider.wallet.publicKey,
+          tombstone: withTombstone ? tombstone : null,
+          inviteNote: null,
+          inviteRentPayer: provider.wallet.publicKey,
+          inviteEscrow: null,
+          ...profileKeyShareAccounts(oscar.publicKey, alice.publicKey, provider.wallet.publicKey),
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([oscar])
+        .rpc();
+
+    // Alice's rejection can't be wiped along with the account
+    try {
+      await deleteRelationship(false);
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "TombstoneMismatch");
+    }
+    await deleteRelationship(true);
+
+    assert.isNull(await program.account.relationship.fetchNullable(relationship));
+    assert.isNull(await program.account.conversation.fetchNullable(conversation));
+    const deletion = await program.account.accountDeletion.fetch(accountDeletion);
+    assert.equal(deletion.relationshipsClosed, 1);
+
+    // The tombstone keeps the cooldown for Alice without naming Oscar
+    const kept = await program.account.rejectionTombstone.fetch(tombstone);
+    assert.ok(kept.peer.equals(alice.publicKey));
+    assert.equal(kept.rejections, 1);
+
+    await program.methods
+      .closeTombstone()
+      .accounts({ tombstone, authority: alice.publicKey, rentPayer: provider.wallet.publicKey })
+      .signers([alice])
+      .rpc();
+    assert.isNull(await program.account.rejectionTombstone.fetchNullable(tombstone));
+
+    // Every other account of the wallet has its own delete path, like the key record from registration
+    const keyRecord = getKeyRecordPda(oscar.publicKey, 0);
+    await program.methods
+      .deleteKeyRecord()
+      .accounts({ accountDeletion, keyRecord, authority: oscar.publicKey, rentPayer: provider.wallet.publicKey })
+      .signers([oscar])
+      .rpc();
+    assert.isNull(await program.account.encryptionKeyRecord.fetchNullable(keyRecord));
+    assert.equal((await program.account.accountDeletion.fetch(accountDeletion)).accountsClosed, 1);
+
+    await program.methods
+      .finishAccountDeletion()
+      .accounts({
+        accountDeletion,
+        authority: oscar.publicKey,
+        rentPayer: provider.wallet.publicKey,
+      })
+      .signers([oscar])
+      .rpc();
+
+    assert.isNull(await program.account.accountDeletion.fetchNullable(accountDeletion));
+  });
+
+  it("Guardians can propose a recovery that waits out the time-lock and the owner cancels", async () => {
+    const carol = Keypair.generate();
+    const [guardianSet] = PublicKey.findProgramAddressSync(
+      [Buffer.from("guardian_set"), alice.publicKey.toBuffer(), Buffer.from([1])],
+      program.programId
+    );
+    const [recovery] = PublicKey.findProgramAddressSync(
+      [Buffer.from("recovery"), alice.publicKey.toBuffer(), bob.publicKey.toBuffer(), Buffer.from([1])],
+      program.programId
+    );
+    const oneDay = 24 * 60 * 60;
+
+    await program.methods
+      .setGuardians([bob.publicKey, carol.publicKey], 2, new anchor.BN(oneDay))
+      .accounts({
+        guardianSet,
+        authority: alice.publicKey,
+        feePayer: alice.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([alice])
+      .rpc();
+
+    const newWallet = Keypair.generate().publicKey;
+    await program.methods
+      .proposeRecovery({ moveIdentity: { newWallet } })
+      .accounts({
+        guardianSet,
+        recovery,
+        authority: bob.publicKey,
+        feePayer: bob.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    let pending = await program.account.recovery.fetch(recovery);
+    assert.isNull(pending.approvedAt);
+
+    // Carol has no SOL; her approval meets the threshold and starts the time-lock
+    await program.methods
+      .approveRecovery()
+      .accounts({ guardianSet, recovery, authority: carol.publicKey })
+      .signers([carol])
+      .rpc();
+
+    pending = await program.account.recovery.fetch(recovery);
+    assert.isNotNull(pending.approvedAt);
+    assert.isTrue(pending.proposer.equals(bob.publicKey));
+
+    // An approved proposal can't be expired out from under the owner
+    try {
+      await program.methods
+        .expireRecovery()
+        .accounts({ guardianSet, recovery, rentPayer: bob.publicKey })
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "RecoveryNotExpired");
+    }
+
+    try {
+      await program.methods
+        .executeRecovery()
+        .accounts({
+          userProfile: aliceUserProfile,
+          guardianSet,
+          recovery,
+          rentPayer: bob.publicKey,
+          keyRecord: null,
+          keyLog: null,
+          feePayer: bob.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "RecoveryTimelocked");
+    }
+
+    // Alice still has her wallet and stops it
+    await program.methods
+      .cancelRecovery()
+      .accounts({ recovery, authority: alice.publicKey, rentPayer: bob.publicKey })
+      .signers([alice])
+      .rpc();
+
+    assert.isNull(await provider.connection.getAccountInfo(recovery));
+    const userProfile = await program.account.userProfile.fetch(aliceUserProfile);
+    assert.isNull(userProfile.migrationTarget);
+  });
+
+  it("Links a second wallet to Alice's profile with both signatures", async () => {
+    const ledger = Keypair.generate();
+    const getLinkPda = getWalletLinkPda;
+    const link = (linked: Keypair) =>
+      program.methods
+        .linkWallet()
+        .accounts({
+          authority: alice.publicKey,
+          linkedWallet: linked.publicKey,
+          feePayer: alice.publicKey,
+          userProfile: aliceUserProfile,
+          linkedProfile: getUserProfilePda(linked.publicKey),
+          primaryLink: getLinkPda(alice.publicKey),
+          walletLink: getLinkPda(linked.publicKey),
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice, linked])
+        .rpc();
+
+    // Bob has his own profile, so he can't become part of Alice's identity
+    try {
+      await link(bob);
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidWalletLink");
+    }
+
+    await link(ledger);
+    const walletLink = await program.account.walletLink.fetch(getLinkPda(ledger.publicKey));
+    assert.ok(walletLink.primary.equals(alice.publicKey));
+    assert.ok(walletLink.linked.equals(ledger.publicKey));
+
+    // The linked wallet can't start an identity of its own
+    try {
+      await program.methods
+        .register("Ledger")
+        .accounts({
+          walletDescriptor: PublicKey.findProgramAddressSync(
+            [Buffer.from("wallet_descriptor"), ledger.publicKey.toBuffer(), WALLET_DESCRIPTOR_VERSION],
+            program.programId
+          )[0],
+          userProfile: getUserProfilePda(ledger.publicKey),
+          keyRecord: getKeyRecordPda(ledger.publicKey, 0),
+          keyLog: null,
+          walletLink: getLinkPda(ledger.publicKey),
+          authority: ledger.publicKey,
+          feePayer: alice.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([ledger, alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidWalletLink");
+    }
+
+    // Inviting the linked wallet invites Alice, so a chat hash for the ledger is refused
+    const ledgerHash = getChatHash(bob.publicKey, ledger.publicKey);
+    try {
+      await program.methods
+        .invite(Array.from(ledgerHash), null, null)
+        .accounts({
+          authority: bob.publicKey,
+          feePayer: bob.publicKey,
+          invitee: ledger.publicKey,
+          inviteeLink: getLinkPda(ledger.publicKey),
+          inviteeProfile: getUserProfilePda(alice.publicKey),
+          authorityDescriptor: bobWalletDescriptor,
+          relationship: getRelationshipPda(bob.publicKey, ledger.publicKey),
+          conversation: PublicKey.findProgramAddressSync(
+            [Buffer.from("conversation"), ledgerHash, CONVERSATION_VERSION],
+            program.programId
+          )[0],
+          tombstone: getTombstonePda(ledgerHash),
+          pairBlock: getPairBlockPda(ledgerHash),
+          inviteNote: null,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidHash");
+    }
+
+    // Either side can unlink; Alice paid, so she gets the rent back
+    await program.methods
+      .unlinkWallet()
+      .accounts({ walletLink: getLinkPda(ledger.publicKey), authority: ledger.publicKey, rentPayer: alice.publicKey })
+      .signers([ledger])
+      .rpc();
+    assert.isNull(await provider.connection.getAccountInfo(getLinkPda(ledger.publicKey)));
+  });
+
+  it("Handles can be claimed, used to invite, transferred and released", async () => {
+    const treasury = Keypair.generate();
+    const claimFee = 10_000_000;
+    const [handleConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("handle_config"), Buffer.from([1])],
+      program.programId
+    );
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+    );
+    const getHandlePda = (name: string) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("handle"), Buffer.from(name), Buffer.from([1])],
+        program.programId
+      )[0];
+
+    // Only the upgrade authority (the provider wallet here) sets the fee
+    await program.methods
+      .setHandleConfig(new anchor.BN(claimFee), treasury.publicKey)
+      .accounts({
+        authority: provider.wallet.publicKey,
+        feePayer: provider.wallet.publicKey,
+        handleConfig,
+        program: program.programId,
+        programData,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const claim = (name: string) =>
+      program.methods
+        .claimHandle(name)
+        .accounts({
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          handle: getHandlePda(name),
+          handleConfig,
+          treasury: treasury.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([alice])
+        .rpc();
+
+    try {
+      await claim("Alice!");
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidHandle");
+    }
+
+    const handle = getHandlePda("alice");
+    await claim("alice");
+    assert.equal(await provider.connection.getBalance(treasury.publicKey), claimFee);
+    assert.ok((await program.account.handle.fetch(handle)).owner.equals(alice.publicKey));
+
+    // Bob invites by handle: the program invites whoever owns it
+    const dave = Keypair.generate();
+    const invite = (invitee: PublicKey | null) => {
+      const chatHash = getChatHash(bob.publicKey, dave.publicKey);
+      const [conversation] = PublicKey.findProgramAddressSync(
+        [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+        program.programId
+      );
+      return program.methods
+        .invite(Array.from(chatHash), null, null)
+        .accounts({
+          authority: bob.publicKey,
+          feePayer: bob.publicKey,
+          inviteeLink: getWalletLinkPda(dave.publicKey),
+          invitee,
+          inviteeProfile: getUserProfilePda(dave.publicKey),
+          authorityDescriptor: bobWalletDescriptor,
+          relationship: getRelationshipPda(bob.publicKey, dave.publicKey),
+          conversation,
+          tombstone: getTombstonePda(chatHash),
+          pairBlock: getPairBlockPda(chatHash),
+          inviteNote: null,
+          inviteeHandle: handle,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+    };
+
+    // Either the wallet or its handle, not both
+    try {
+      await invite(dave.publicKey);
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InviteeAccountMismatch");
+    }
+
+    // The handle still belongs to Alice, so the chat hash for Dave is wrong
+    try {
+      await invite(null);
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidHash");
+    }
+
+    // A transfer needs the recipient's signature too
+    try {
+      await program.methods
+        .transferHandle()
+        .accounts({ authority: alice.publicKey, handle, newOwner: dave.publicKey })
+        .signers([alice])
+        .rpc();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.include(err.toString(), "Signature verification failed");
+    }
+
+    await program.methods
+      .transferHandle()
+      .accounts({ authority: alice.publicKey, handle, newOwner: dave.publicKey })
+      .signers([alice, dave])
+      .rpc();
+    await invite(null);
+
+    const relationship = await program.account.relationship.fetch(
+      getRelationshipPda(bob.publicKey, dave.publicKey)
+    );
+    assert.deepEqual(stateOf(relationship, dave.publicKey), { requested: {} });
+
+    // Dave releases it and Alice, who paid, gets the rent back
+    const aliceBalanceBefore = await provider.connection.getBalance(alice.publicKey);
+    await program.methods
+      .releaseHandle()
+      .accounts({ authority: dave.publicKey, handle, rentPayer: alice.publicKey })
+      .signers([dave])
+      .rpc();
+
+    assert.isNull(await provider.connection.getAccountInfo(handle));
+    assert.isAbove(await provider.connection.getBalance(alice.publicKey), aliceBalanceBefore);
+  });
+
+  it("Redeems an invite signed off-chain by an inviter with no SOL", async () => {
+    const holly = Keypair.generate();
+
+    const chatHash = getChatHash(holly.publicKey, alice.publicKey);
+    const relationship = getRelationshipPda(holly.publicKey, alice.publicKey);
+    const [conversation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("conversation"), chatHash, CONVERSATION_VERSION],
+      program.programId
+    );
+
+    const expiresAt = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+    const nonce = new anchor.BN(1);
+    const [usedInviteToken] = PublicKey.findProgramAddressSync(
+      [Buffer.from("invite_token"), holly.publicKey.toBuffer(), nonce.toArrayLike(Buffer, "le", 8), USED_INVITE_TOKEN_VERSION],
+      program.programId
+    );
+
+    // Holly signs the invite off-chain, e.g. into a QR code
+    const message = Buffer.concat([
+      Buffer.from("mukon-messenger:invite"),
+      program.programId.toBuffer(),
+      chatHash,
+      expiresAt.toArrayLike(Buffer, "le", 8),
+      nonce.toArrayLike(Buffer, "le", 8),
+    ]);
+    const ed25519Ix = anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: holly.secretKey,
+      message,
+    });
+
+    // Alice redeems it and pays for everything
+    const redeem = () =>
+      program.methods
+        .acceptSignedInvite(Array.from(chatHash), expiresAt, nonce)
+        .accounts({
+          authority: alice.publicKey,
+          feePayer: alice.publicKey,
+          inviter: holly.publicKey,
+          relationship,
+          conversation,
+          usedInviteToken,
+          pairBlock: getPairBlockPda(chatHash),
+          instructionsSysvar: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          systemProgram: SystemProgram.programId,
+        })
+        .preInstructions([ed25519Ix])
+        .signers([alice])
+        .rpc();
+
+    await redeem();
+
+    const accepted = await program.account.relationship.fetch(relationship);
+    assert.deepEqual(stateOf(accepted, alice.publicKey), { accepted: {} });
+    assert.deepEqual(stateOf(accepted, holly.publicKey), { accepted: {} });
+
+    // The same token can't be redeemed twice
+    try {
+      await redeem();
+      assert.fail("Should have failed");
+    } catch (err) {
+      assert.ok(err.toString().includes("already in use"));
+    }
   });
 });